@@ -1,18 +1,325 @@
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The firmware's size on flash with the default features, in bytes: the text, rodata, data and
+/// vector table sections from `cargo size --release --bin tilda-stm -- -A`.
+const DEFAULT_FLASH: u32 = 22720;
+
+/// What each optional feature adds to the default build's size on flash, in bytes, measured the
+/// same way. Features share code, so these don't quite add up, but it's near enough to catch a
+/// combination which can't fit before it gets to the linker. Measure them again when the firmware
+/// changes much.
+const FEATURE_FLASH: &[(&str, u32)] = &[
+    ("vbus-sense", 360),
+    ("battery-sense", 168),
+    ("production-lock", 248),
+    ("fixture", 784),
+    ("unlock", 620),
+    ("error-log", 772),
+    ("rate-limit", 540),
+    ("loop-load", 120),
+    ("esp32c3", 16),
+    ("esp32s3", 4),
+    ("jtag", 556),
+    ("spi-flash", 776),
+    ("flasher", 788),
+    ("strap-pin", 108),
+    ("pattern-watch", 652),
+    ("throttle", 252),
+    ("fault-inject", 564),
+    ("esp-counters", 668),
+    ("line-buffer", 196),
+    ("escapes", 784),
+    ("led-dim", 224),
+    ("ms-os-10", 300),
+    ("sof-timing", 76),
+];
 
 fn main() {
-    // Put the linker script somewhere the linker can find it
+    // Put the linker scripts somewhere the linker can find them. The firmware and the bootloader
+    // each need their own memory.x, so they get their own search paths. (A memory.x in the crate
+    // root would be found first by both.)
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
+    for (dir, script) in [
+        ("app", &include_bytes!("memory-app.x")[..]),
+        ("bootloader", &include_bytes!("memory-bootloader.x")[..]),
+    ] {
+        fs::create_dir_all(out.join(dir)).unwrap();
+        File::create(out.join(dir).join("memory.x"))
+            .unwrap()
+            .write_all(script)
+            .unwrap();
+    }
+    let app = out.join("app");
+    println!("cargo:rustc-link-arg-bin=tilda-stm=-L{}", app.display());
+    println!("cargo:rustc-link-arg-tests=-L{}", app.display());
+    println!("cargo:rustc-link-arg-bin=bootloader=-L{}", out.join("bootloader").display());
+
+    // defmt's linker script is only needed by the on-target test suite, which is empty when built
+    // for anything but the badge.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        println!("cargo:rustc-link-arg-tests=-Tdefmt.x");
+    }
+
+    // The identity key shared with the EMF web services, as 32 hex digits. Builds without it
+    // answer identity challenges with an all-zero key, which the web services won't accept. The
+    // unlock handshake would take anyone's answer made with that key, so firmware with the `unlock`
+    // feature won't build without one. (The host tests make their own identities.)
+    let key = env::var("TILDA_IDENTITY_KEY").unwrap_or_default();
+    let key = if key.is_empty() {
+        let firmware = env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none");
+        assert!(
+            !(firmware && env::var_os("CARGO_FEATURE_UNLOCK").is_some()),
+            "the unlock feature needs TILDA_IDENTITY_KEY"
+        );
+        [0u8; 16]
+    } else {
+        parse_hex::<16>(&key).expect("TILDA_IDENTITY_KEY must be 32 hex digits")
+    };
+    File::create(out.join("identity_key.rs"))
+        .unwrap()
+        .write_all(format!("{:?}", key).as_bytes())
+        .unwrap();
+
+    // Build metadata for the host: the build time (SOURCE_DATE_EPOCH if set, for reproducible
+    // builds), whether the tree had uncommitted changes, and the commit hash.
+    let time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|time| time.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+    let commit = git(&["rev-parse", "HEAD"])
+        .as_deref()
+        .and_then(parse_hex::<20>)
+        .unwrap_or([0; 20]);
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .is_some_and(|status| !status.is_empty());
+
+    let mut info = Vec::new();
+    info.extend_from_slice(&(time as u32).to_le_bytes());
+    info.push(dirty as u8);
+    info.extend_from_slice(&commit);
+    File::create(out.join("build_info.rs"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(format!("{:?}", info).as_bytes())
         .unwrap();
-    println!("cargo:rustc-link-search={}", out.display());
 
-    // Only re-run the build script when memory.x is changed,
-    // instead of when any part of the source code changes.
-    println!("cargo:rerun-if-changed=memory.x");
+    // The board's pin assignments and ESP32 variant, from the board file named by TILDA_BOARD.
+    let path = env::var("TILDA_BOARD").unwrap_or_else(|_| "boards/tilda-mkv.toml".to_string());
+    let board = parse_board(&path);
+    File::create(out.join("board.rs"))
+        .unwrap()
+        .write_all(board_pins(&path, &board).as_bytes())
+        .unwrap();
+    let chip = if env::var_os("CARGO_FEATURE_ESP32C3").is_some() {
+        "esp32c3"
+    } else if env::var_os("CARGO_FEATURE_ESP32S3").is_some() {
+        "esp32s3"
+    } else {
+        value(&path, &board, "esp.chip")
+    };
+    assert!(
+        ["esp32", "esp32c3", "esp32s3"].contains(&chip),
+        "{}: esp.chip must be esp32, esp32c3 or esp32s3",
+        path
+    );
+    println!("cargo:rustc-check-cfg=cfg(esp_chip, values(\"esp32\", \"esp32c3\", \"esp32s3\"))");
+    println!("cargo:rustc-cfg=esp_chip=\"{}\"", chip);
+
+    // A release build with features which can't fit in the firmware's flash stops with a
+    // compile_error! from src/main.rs, which includes flash_check.rs. (Debug builds never fit.)
+    let flash = flash_length(include_str!("memory-app.x"));
+    let features: Vec<_> = FEATURE_FLASH
+        .iter()
+        .filter(|(feature, _)| {
+            env::var_os(format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_")))
+                .is_some()
+        })
+        .collect();
+    let needed = DEFAULT_FLASH + features.iter().map(|(_, bytes)| bytes).sum::<u32>();
+    let check = if env::var("PROFILE").as_deref() == Ok("release") && needed > flash {
+        let names: Vec<_> = features.iter().map(|(feature, _)| *feature).collect();
+        let message = format!(
+            "the firmware with {} needs about {} bytes of flash, but only has {}",
+            names.join(", "),
+            needed,
+            flash
+        );
+        format!("compile_error!({:?});", message)
+    } else {
+        String::new()
+    };
+    File::create(out.join("flash_check.rs"))
+        .unwrap()
+        .write_all(check.as_bytes())
+        .unwrap();
+
+    // Only re-run the build script when the memory layouts, the key, the board file or the checked
+    // out commit is changed, instead of when any part of the source code changes.
+    println!("cargo:rerun-if-changed=memory-app.x");
+    println!("cargo:rerun-if-changed=memory-bootloader.x");
+    println!("cargo:rerun-if-env-changed=TILDA_IDENTITY_KEY");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=TILDA_BOARD");
+    println!("cargo:rerun-if-changed={}", path);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
+
+/// The length of the FLASH region in a memory.x, in bytes.
+fn flash_length(script: &str) -> u32 {
+    let length = script
+        .lines()
+        .find(|line| line.trim_start().starts_with("FLASH"))
+        .and_then(|line| line.split("LENGTH =").nth(1))
+        .expect("memory-app.x has no FLASH region")
+        .trim();
+    match length.strip_suffix('K') {
+        Some(k) => k.parse::<u32>().unwrap() * 1024,
+        None => length.parse().unwrap(),
+    }
+}
+
+/// Runs git, returning its output if it succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok().map(|out| out.trim().to_string())
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Reads a board file. Board files are TOML, but only `[section]` headers and `key = value` lines
+/// are needed, so that's all this understands. Values are kept as written, less the quotes around
+/// strings, keyed by `section.key`.
+fn parse_board(path: &str) -> HashMap<String, String> {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("can't read {}: {}", path, e));
+    let mut board = HashMap::new();
+    let mut section = "";
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            section = name.trim();
+        } else if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            board.insert(format!("{}.{}", section, key.trim()), value.to_string());
+        } else {
+            panic!("{}:{}: expected `[section]` or `key = value`", path, number + 1);
+        }
+    }
+    board
+}
+
+fn value<'a>(path: &str, board: &'a HashMap<String, String>, key: &str) -> &'a str {
+    board.get(key).unwrap_or_else(|| panic!("{} has no {}", path, key))
+}
+
+/// Splits a pin's name, such as PA2, into the HAL's names for its port and the pin, and its bit.
+fn parse_pin(path: &str, pin: &str) -> (String, String, u32) {
+    let bit = pin.get(2..).and_then(|bit| bit.parse().ok()).filter(|&bit| bit < 16);
+    match (pin.get(..2), bit) {
+        (Some(port @ ("PA" | "PB")), Some(bit)) => {
+            let port = port.to_lowercase().replace('p', "gpio");
+            (port, pin.to_lowercase(), bit)
+        }
+        _ => panic!("{}: {} isn't a pin on GPIOA or GPIOB", path, pin),
+    }
+}
+
+/// Generates board.rs, which `src/board.rs` includes: the types and constants which depend on the
+/// board, and a `board_pins!` macro which takes its pins from the GPIO ports.
+fn board_pins(path: &str, board: &HashMap<String, String>) -> String {
+    let get = |key| value(path, board, key);
+    let usart = get("uart.usart");
+    assert!(usart == "1" || usart == "2", "{}: uart.usart must be 1 or 2", path);
+    let active_high: bool = get("led.active_high")
+        .parse()
+        .unwrap_or_else(|_| panic!("{}: led.active_high must be true or false", path));
+
+    // The USART pins are on alternate function 1, apart from PB6 and PB7 on 0.
+    let uart_pin = |key| {
+        let (port, pin, bit) = parse_pin(path, get(key));
+        let af = if port == "gpiob" && (bit == 6 || bit == 7) { 0 } else { 1 };
+        (
+            format!("gpio::{}::{}<gpio::Alternate<gpio::AF{}>>", port, pin.to_uppercase(), af),
+            format!("${}.{}.into_alternate_af{}($cs)", port, pin, af),
+        )
+    };
+    let (tx_type, tx) = uart_pin("uart.tx");
+    let (rx_type, rx) = uart_pin("uart.rx");
+    let output = |key| {
+        let (port, pin, _) = parse_pin(path, get(key));
+        format!("${}.{}.into_push_pull_output($cs).downgrade()", port, pin)
+    };
+    let (en_port, _, en_bit) = parse_pin(path, get("esp.en"));
+    let (io0_port, _, io0_bit) = parse_pin(path, get("esp.io0"));
+
+    format!(
+        "// Generated by build.rs from {path}.
+
+pub type EspUart = Serial<stm32::USART{usart}, {tx_type}, {rx_type}>;
+
+/// The USART which `set_uart_baud` changes.
+type EspUsart = stm32::USART{usart};
+
+/// The USART's interrupt, which `power::sleep_until_traffic` wakes on.
+pub const ESP_UART_INTERRUPT: stm32::Interrupt = stm32::Interrupt::USART{usart};
+
+/// Where the ESP32's boot pins are, for `esp_pin_levels`.
+type EspEnPort = stm32::{en_port_upper};
+const ESP_EN_BIT: u32 = {en_bit};
+type EspGpio0Port = stm32::{io0_port_upper};
+const ESP_GPIO0_BIT: u32 = {io0_bit};
+
+/// Whether driving the LED's pin high lights it.
+const LED_ACTIVE_HIGH: bool = {active_high};
+
+/// Takes the board's pins from GPIOA and GPIOB: the UART's TX and RX, EN, IO0, the LED and the
+/// USB state line.
+macro_rules! board_pins {{
+    ($gpioa:ident, $gpiob:ident, $cs:ident) => {{
+        (
+            {tx},
+            {rx},
+            {en},
+            {io0},
+            {led},
+            {usb_state},
+        )
+    }};
+}}
+
+/// Sets up the board's USART.
+macro_rules! esp_uart {{
+    ($dp:ident, $pins:expr, $rcc:expr) => {{
+        Serial::usart{usart}($dp.USART{usart}, $pins, DEFAULT_BAUD.bps(), $rcc)
+    }};
+}}
+",
+        en_port_upper = en_port.to_uppercase(),
+        io0_port_upper = io0_port.to_uppercase(),
+        en = output("esp.en"),
+        io0 = output("esp.io0"),
+        led = output("led.pin"),
+        usb_state = output("usb_state.pin"),
+    )
 }
@@ -0,0 +1,233 @@
+//! The bridge's WebUSB interface, over libusb.
+
+use rusb::{Direction, GlobalContext, Recipient, RequestType, TransferType};
+use std::time::Duration;
+use tilda_bridge_protocol::request;
+use tilda_bridge_protocol::unlock;
+use tilda_bridge_protocol::version::{self, Version};
+
+use crate::report;
+use crate::Result;
+
+const VID: u16 = 0x16c0;
+const PID: u16 = 0x27dd;
+
+/// Vendor-specific interface class, used by both WebUSB interfaces.
+const CLASS_VENDOR: u8 = 0xFF;
+
+/// SET_CONTROL_LINE_STATE, which the communication interface takes like a CDC-ACM one.
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An open bridge.
+pub struct Bridge {
+    handle: rusb::DeviceHandle<GlobalContext>,
+    comm_if: u8,
+    notify_ep: Option<u8>,
+    data_if: u8,
+    read_ep: u8,
+    write_ep: u8,
+
+    /// The bridge's protocol version and features.
+    pub version: Version,
+}
+
+impl Bridge {
+    /// Opens the first bridge plugged in, claims its WebUSB communication interface and checks it
+    /// speaks a compatible protocol version.
+    pub fn open() -> Result<Bridge> {
+        let handle = rusb::open_device_with_vid_pid(VID, PID)
+            .ok_or("no bridge found (looking for 16c0:27dd)")?;
+        handle.set_auto_detach_kernel_driver(true).ok();
+
+        // The communication interface is vendor subclass 0, and the data interface subclass 1.
+        let config = handle.device().active_config_descriptor()?;
+        let (mut comm_if, mut data_if, mut read_ep, mut write_ep) = (None, None, None, None);
+        let mut notify_ep = None;
+        for interface in config.interfaces() {
+            for desc in interface.descriptors() {
+                if desc.class_code() != CLASS_VENDOR {
+                    continue;
+                }
+                match desc.sub_class_code() {
+                    0 => {
+                        comm_if = Some(desc.interface_number());
+                        notify_ep = desc
+                            .endpoint_descriptors()
+                            .find(|ep| ep.transfer_type() == TransferType::Interrupt)
+                            .map(|ep| ep.address());
+                    }
+                    1 => {
+                        data_if = Some(desc.interface_number());
+                        for ep in desc.endpoint_descriptors() {
+                            if ep.transfer_type() != TransferType::Bulk {
+                                continue;
+                            }
+                            match ep.direction() {
+                                Direction::In => read_ep = Some(ep.address()),
+                                Direction::Out => write_ep = Some(ep.address()),
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut bridge = Bridge {
+            comm_if: comm_if.ok_or("bridge has no WebUSB interface")?,
+            notify_ep,
+            data_if: data_if.ok_or("bridge has no WebUSB data interface")?,
+            read_ep: read_ep.ok_or("bridge has no WebUSB IN endpoint")?,
+            write_ep: write_ep.ok_or("bridge has no WebUSB OUT endpoint")?,
+            handle,
+            version: Version::default(),
+        };
+        bridge.handle.claim_interface(bridge.comm_if)?;
+        bridge.version = bridge.handshake()?;
+        Ok(bridge)
+    }
+
+    /// Exchanges protocol versions with the bridge.
+    fn handshake(&self) -> Result<Version> {
+        let ours = Version::current(0);
+        let request_type =
+            rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Interface);
+        let mut buf = [0; request::VERSION_LENGTH];
+        let theirs = match self.handle.read_control(
+            request_type,
+            request::GET_VERSION,
+            ours.value(),
+            self.comm_if.into(),
+            &mut buf,
+            TIMEOUT,
+        ) {
+            Ok(count) => Version::parse(&buf[..count]).ok_or("short GET_VERSION response")?,
+            // Firmware from before the handshake
+            Err(rusb::Error::Pipe) => Version::from_value(0x0100),
+            Err(err) => return Err(err.into()),
+        };
+        if !ours.compatible_with(&theirs) {
+            return Err(format!(
+                "bridge speaks protocol version {}.{}, but this tool only knows {}.x",
+                theirs.major, theirs.minor, ours.major
+            )
+            .into());
+        }
+        Ok(theirs)
+    }
+
+    /// Sends an IN vendor request, returning the response.
+    pub fn vendor_in(&self, request: u8, length: usize) -> Result<Vec<u8>> {
+        self.vendor_in_value(request, 0, length)
+    }
+
+    /// Sends an IN vendor request with a `wValue`, returning the response.
+    pub fn vendor_in_value(&self, request: u8, value: u16, length: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; length];
+        let request_type =
+            rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Interface);
+        let count = self.handle.read_control(
+            request_type,
+            request,
+            value,
+            self.comm_if.into(),
+            &mut buf,
+            TIMEOUT,
+        )?;
+        buf.truncate(count);
+        Ok(buf)
+    }
+
+    /// Sends an OUT vendor request. Bridges with the `unlock` feature are unlocked first for the
+    /// requests they guard.
+    pub fn vendor_out(&self, request: u8, value: u16, data: &[u8]) -> Result<()> {
+        if unlock::GUARDED.contains(&request) && self.version.has(version::FEATURE_UNLOCK) {
+            self.unlock()?;
+        }
+        let request_type =
+            rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Interface);
+        self.handle
+            .write_control(request_type, request, value, self.comm_if.into(), data, TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Answers the bridge's unlock nonce with the identity key in `TILDA_IDENTITY_KEY`.
+    fn unlock(&self) -> Result<()> {
+        let key = match std::env::var("TILDA_IDENTITY_KEY") {
+            Ok(hex) if !hex.is_empty() => report::parse_key(&hex)?,
+            _ => return Err("the bridge is locked; set TILDA_IDENTITY_KEY to unlock it".into()),
+        };
+        // The unique ID starts the identity response, which needs a challenge first.
+        self.vendor_out(request::SET_CHALLENGE, 0, &[0; request::CHALLENGE_LENGTH])?;
+        let identity = self.vendor_in(request::GET_IDENTITY, request::IDENTITY_LENGTH)?;
+        report::check_length(&identity, request::IDENTITY_LENGTH)?;
+        let nonce = self.vendor_in(request::GET_UNLOCK_NONCE, unlock::NONCE_LENGTH)?;
+        report::check_length(&nonce, unlock::NONCE_LENGTH)?;
+
+        let mut uid = [0; request::UID_LENGTH];
+        uid.copy_from_slice(&identity[..request::UID_LENGTH]);
+        let mut nonce_bytes = [0; unlock::NONCE_LENGTH];
+        nonce_bytes.copy_from_slice(&nonce);
+        let response = unlock::response(&key, &uid, &nonce_bytes);
+        match self.vendor_out(request::UNLOCK, 0, &response) {
+            Err(_) => Err("the bridge refused the unlock; is TILDA_IDENTITY_KEY right?".into()),
+            ok => ok,
+        }
+    }
+
+    /// Reads a notification from the communication interface, or nothing if there's been none for a
+    /// while.
+    pub fn read_notification(&self) -> Result<Vec<u8>> {
+        let notify_ep = self.notify_ep.ok_or("bridge has no notification endpoint")?;
+        let mut buf = vec![0; 16];
+        match self.handle.read_interrupt(notify_ep, &mut buf, TIMEOUT) {
+            Ok(count) => buf.truncate(count),
+            Err(rusb::Error::Timeout) => buf.clear(),
+            Err(err) => return Err(err.into()),
+        }
+        Ok(buf)
+    }
+
+    /// Sets DTR and RTS, which drive the ESP32's EN and IO0 pins like an ESP32 dev board's
+    /// auto-reset circuit.
+    pub fn set_control_lines(&self, dtr: bool, rts: bool) -> Result<()> {
+        let request_type =
+            rusb::request_type(Direction::Out, RequestType::Class, Recipient::Interface);
+        let value = dtr as u16 | (rts as u16) << 1;
+        self.handle.write_control(
+            request_type,
+            REQ_SET_CONTROL_LINE_STATE,
+            value,
+            self.comm_if.into(),
+            &[],
+            TIMEOUT,
+        )?;
+        Ok(())
+    }
+
+    /// Claims the data interface, so the ESP32's UART output can be read.
+    pub fn claim_data(&self) -> Result<()> {
+        self.handle.claim_interface(self.data_if)?;
+        Ok(())
+    }
+
+    /// Reads whatever the ESP32 has sent, or nothing if it's been quiet for a while.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        match self.handle.read_bulk(self.read_ep, buf, TIMEOUT) {
+            Ok(count) => Ok(count),
+            Err(rusb::Error::Timeout) => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes to the data interface, returning how much the bridge took before the timeout.
+    pub fn write(&self, data: &[u8]) -> Result<usize> {
+        match self.handle.write_bulk(self.write_ep, data, TIMEOUT) {
+            Ok(count) => Ok(count),
+            Err(rusb::Error::Timeout) => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! ESP32 ROM loader commands
+//!
+//! The bridge sends each command packet and waits for the response, so only the order of the
+//! commands, and checking what was written, is left to do here.
+
+use std::convert::TryInto;
+use tilda_bridge_protocol::flasher::{
+    md5_command, md5_timeout, BUSY, DONE, MAX_COMMAND_LENGTH, TIMEOUT, TIMEOUT_UNIT_MS,
+};
+
+use crate::Result;
+
+const FLASH_BEGIN: u8 = 0x02;
+const FLASH_DATA: u8 = 0x03;
+const FLASH_END: u8 = 0x04;
+const SYNC: u8 = 0x08;
+const SPI_ATTACH: u8 = 0x0D;
+
+/// As big a block as fits in a `ROM_COMMAND` alongside the headers.
+pub const BLOCK_SIZE: usize = MAX_COMMAND_LENGTH - 8 - 16;
+
+/// esptool gives up on syncing after about a second.
+const SYNC_TIMEOUT_MS: u32 = 100;
+const SYNC_ATTEMPTS: usize = 4;
+
+const COMMAND_TIMEOUT_MS: u32 = 3000;
+
+/// Worst case erase time, from esptool.
+const ERASE_MS_PER_MB: u32 = 30_000;
+
+/// How a `ROM_COMMAND` ended.
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// The ROM's response packet.
+    Done(Vec<u8>),
+    TimedOut,
+}
+
+impl Response {
+    /// Parses a `GET_ROM_RESPONSE` response, returning `None` while the bridge is still waiting.
+    pub fn parse(buf: &[u8]) -> Result<Option<Response>> {
+        match buf.split_first() {
+            Some((&BUSY, _)) => Ok(None),
+            Some((&DONE, packet)) => Ok(Some(Response::Done(packet.to_vec()))),
+            Some((&TIMEOUT, _)) => Ok(Some(Response::TimedOut)),
+            _ => Err("the bridge isn't waiting for a ROM loader command".into()),
+        }
+    }
+}
+
+/// The ESP32's ROM loader, through the bridge.
+pub struct RomLoader<T> {
+    /// Sends `ROM_COMMAND` with the given timeout and packet, and waits for the response.
+    command: T,
+    /// Whether `FLASH_BEGIN` takes the extra encryption word, as on the ESP32-C3 and ESP32-S3.
+    encrypted_word: bool,
+}
+
+impl<T: FnMut(u16, &[u8]) -> Result<Response>> RomLoader<T> {
+    pub fn new(command: T, encrypted_word: bool) -> Self {
+        RomLoader { command, encrypted_word }
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        let mut data = vec![0x07, 0x07, 0x12, 0x20];
+        data.resize(36, 0x55);
+        for _ in 0..SYNC_ATTEMPTS {
+            if self.try_command(SYNC, &data, 0, SYNC_TIMEOUT_MS)?.is_some() {
+                return Ok(());
+            }
+        }
+        Err("the ESP32's ROM loader didn't answer".into())
+    }
+
+    /// Connects the ROM loader to the flash.
+    pub fn attach(&mut self) -> Result<()> {
+        self.command(SPI_ATTACH, &[0; 8], 0, COMMAND_TIMEOUT_MS)?;
+        Ok(())
+    }
+
+    /// Writes `image` to the flash at `address`, and checks its MD5.
+    pub fn write(&mut self, address: u32, image: &[u8]) -> Result<()> {
+        let blocks = image.len().div_ceil(BLOCK_SIZE) as u32;
+        let size = image.len() as u32;
+        let mut begin = words(&[size, blocks, BLOCK_SIZE as u32, address]);
+        if self.encrypted_word {
+            begin.extend_from_slice(&[0; 4]);
+        }
+        self.command(FLASH_BEGIN, &begin, 0, erase_timeout_ms(size))?;
+
+        for (seq, block) in image.chunks(BLOCK_SIZE).enumerate() {
+            let mut data = words(&[BLOCK_SIZE as u32, seq as u32, 0, 0]);
+            data.extend_from_slice(block);
+            data.resize(16 + BLOCK_SIZE, 0xFF);
+            let checksum = data[16..].iter().fold(0xEF, |sum, byte| sum ^ byte);
+            self.command(FLASH_DATA, &data, checksum as u32, COMMAND_TIMEOUT_MS)?;
+        }
+
+        self.verify(address, image)?;
+
+        // Stay in the ROM loader, as the ESP32 is reset with the control lines afterwards.
+        self.command(FLASH_END, &words(&[1]), 0, COMMAND_TIMEOUT_MS)?;
+        Ok(())
+    }
+
+    /// Checks that the flash at `address` holds `image`, by its MD5.
+    pub fn verify(&mut self, address: u32, image: &[u8]) -> Result<()> {
+        let length = image.len() as u32;
+        let response = self.send(&md5_command(address, length), md5_timeout(length))?;
+        let md5 = response.ok_or("the ROM loader didn't answer the MD5 command")?;
+        let expected = format!("{:x}", md5::compute(image));
+        if md5.get(..32) != Some(expected.as_bytes()) {
+            return Err(format!("the flash at {:#x} doesn't match the image", address).into());
+        }
+        Ok(())
+    }
+
+    /// Sends a command, and returns the data from its response with the status bytes removed.
+    fn command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout_ms: u32,
+    ) -> Result<Vec<u8>> {
+        let response = self.try_command(command, data, checksum, timeout_ms)?;
+        let err = || format!("the ROM loader didn't answer command {:#04x}", command).into();
+        response.ok_or_else(err)
+    }
+
+    fn try_command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout_ms: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut packet = vec![0, command];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(data);
+        let timeout = timeout_ms.div_ceil(TIMEOUT_UNIT_MS).min(u16::MAX as u32) as u16;
+        self.send(&packet, timeout)
+    }
+
+    /// Sends a command packet, and returns the data from its response with the status bytes
+    /// removed, or `None` if the ROM didn't answer.
+    fn send(&mut self, packet: &[u8], timeout: u16) -> Result<Option<Vec<u8>>> {
+        let command = packet[1];
+        let response = match (self.command)(timeout, packet)? {
+            Response::Done(response) => response,
+            Response::TimedOut => return Ok(None),
+        };
+
+        // The status bytes (status, error, and two reserved) end the response data.
+        let size = match response.get(2..4) {
+            Some(size) => u16::from_le_bytes(size.try_into().unwrap()) as usize,
+            None => 0,
+        };
+        let data = response.get(8..8 + size).filter(|data| data.len() >= 4);
+        let data = data.ok_or("short ROM loader response")?;
+        let (data, status) = data.split_at(size - 4);
+        if status[0] != 0 {
+            let err = format!("command {:#04x} failed with ROM error {:#04x}", command, status[1]);
+            return Err(err.into());
+        }
+        Ok(Some(data.to_vec()))
+    }
+}
+
+/// Timeout for erasing `size` bytes of flash.
+fn erase_timeout_ms(size: u32) -> u32 {
+    COMMAND_TIMEOUT_MS.max((ERASE_MS_PER_MB as u64 * size as u64 / 0x10_0000) as u32)
+}
+
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough of the ROM loader to check the command sequence.
+    #[derive(Default)]
+    struct FakeRom {
+        synced: bool,
+        encrypted_word: bool,
+        flash: Vec<u8>,
+        /// Address and block size from `FLASH_BEGIN`
+        begin: Option<(usize, usize)>,
+        ended: bool,
+    }
+
+    impl FakeRom {
+        fn command(&mut self, packet: &[u8]) -> Response {
+            let word = |i: usize| u32::from_le_bytes(packet[8 + i * 4..][..4].try_into().unwrap());
+            let mut data = Vec::new();
+            let mut status = 0;
+            match packet[1] {
+                // The first attempt is lost, as if the ESP32 was still booting.
+                SYNC if !self.synced => {
+                    self.synced = true;
+                    return Response::TimedOut;
+                }
+                SYNC | SPI_ATTACH => {}
+                FLASH_BEGIN => {
+                    let length = if self.encrypted_word { 20 } else { 16 };
+                    assert_eq!(packet.len(), 8 + length);
+                    self.begin = Some((word(3) as usize, word(2) as usize));
+                    // No flash to erase
+                    if self.flash.is_empty() {
+                        status = 1;
+                    }
+                }
+                FLASH_DATA => {
+                    let (address, block_size) = self.begin.unwrap();
+                    let block = &packet[8 + 16..];
+                    assert_eq!(block.len(), block_size);
+                    let checksum = block.iter().fold(0xEF, |sum, byte| sum ^ byte);
+                    assert_eq!(packet[4..8], (checksum as u32).to_le_bytes());
+                    let start = address + word(1) as usize * block_size;
+                    self.flash[start..start + block_size].copy_from_slice(block);
+                }
+                // SPI_FLASH_MD5
+                0x13 => {
+                    let (address, size) = (word(0) as usize, word(1) as usize);
+                    let md5 = md5::compute(&self.flash[address..address + size]);
+                    data = format!("{:x}", md5).into();
+                }
+                FLASH_END => self.ended = true,
+                _ => status = 1,
+            }
+            data.extend_from_slice(&[status, 5, 0, 0]);
+            let mut response = vec![1, packet[1]];
+            response.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            response.extend_from_slice(&[0; 4]);
+            response.extend_from_slice(&data);
+            Response::Done(response)
+        }
+    }
+
+    #[test]
+    fn write() {
+        for &encrypted_word in &[false, true] {
+            let mut rom = FakeRom {
+                encrypted_word,
+                flash: vec![0; 0x2000],
+                ..FakeRom::default()
+            };
+            let mut loader = RomLoader::new(
+                |timeout, packet: &[u8]| {
+                    assert!(packet.len() <= MAX_COMMAND_LENGTH);
+                    if packet[1] == SYNC {
+                        assert_eq!(timeout as u32 * TIMEOUT_UNIT_MS, SYNC_TIMEOUT_MS);
+                    }
+                    Ok(rom.command(packet))
+                },
+                encrypted_word,
+            );
+            loader.sync().unwrap();
+            loader.attach().unwrap();
+            let image: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+            loader.write(0x1000, &image).unwrap();
+            assert!(loader.verify(0x1000, &image[1..]).is_err());
+            assert!(rom.ended);
+            assert_eq!(rom.flash[0x1000..0x1000 + image.len()], image[..]);
+            // The last block is padded.
+            assert_eq!(rom.flash[0x1000 + image.len()], 0xFF);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Response::parse(&[BUSY, 0]).unwrap(), None);
+        assert_eq!(Response::parse(&[TIMEOUT, 0]).unwrap(), Some(Response::TimedOut));
+        assert_eq!(Response::parse(&[DONE, 1, 8]).unwrap(), Some(Response::Done(vec![1, 8])));
+        assert!(Response::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let mut loader = RomLoader::new(|_, _: &[u8]| Ok(Response::TimedOut), false);
+        assert!(loader.sync().is_err());
+
+        let mut rom = FakeRom::default();
+        let mut loader = RomLoader::new(|_, packet: &[u8]| Ok(rom.command(packet)), false);
+        let err = loader.write(0, &[1, 2, 3]).unwrap_err();
+        assert_eq!(err.to_string(), "command 0x02 failed with ROM error 0x05");
+    }
+}
@@ -0,0 +1,90 @@
+//! OpenOCD `remote_bitbang` server
+//!
+//! OpenOCD sends one byte per pin state, and expects an answer straight away only when it asks
+//! for TDO, so the states are sent to the bridge in batches and clocked out there.
+
+use tilda_bridge_protocol::jtag::MAX_BATCH_LENGTH;
+
+use crate::Result;
+
+/// Turns a `remote_bitbang` byte stream into batches.
+#[derive(Default)]
+pub struct RemoteBitbang {
+    batch: Vec<u8>,
+    tdo: bool,
+}
+
+impl RemoteBitbang {
+    /// Handles bytes from OpenOCD. `shift` sends a batch to the bridge and returns its TDO samples.
+    /// Returns the replies for OpenOCD, and whether it has asked to quit.
+    pub fn handle(
+        &mut self,
+        input: &[u8],
+        mut shift: impl FnMut(&[u8]) -> Result<Vec<u8>>,
+    ) -> Result<(Vec<u8>, bool)> {
+        let mut replies = Vec::new();
+        for &byte in input {
+            match byte {
+                b'0'..=b'7' => {
+                    self.batch.push(byte - b'0');
+                    if self.batch.len() == MAX_BATCH_LENGTH {
+                        self.flush(&mut shift)?;
+                    }
+                }
+                b'R' => {
+                    self.flush(&mut shift)?;
+                    replies.push(if self.tdo { b'1' } else { b'0' });
+                }
+                b'Q' => {
+                    self.flush(&mut shift)?;
+                    return Ok((replies, true));
+                }
+                // Blinking and the reset lines aren't wired up.
+                _ => {}
+            }
+        }
+        // Nothing more may come until OpenOCD has seen these happen.
+        self.flush(&mut shift)?;
+        Ok((replies, false))
+    }
+
+    fn flush(&mut self, shift: &mut impl FnMut(&[u8]) -> Result<Vec<u8>>) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let tdo = shift(&self.batch)?;
+        let last = self.batch.len() - 1;
+        let byte = tdo.get(last / 8).ok_or("short GET_JTAG_TDO response")?;
+        self.tdo = byte & 1 << (last % 8) != 0;
+        self.batch.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches() {
+        let mut bitbang = RemoteBitbang::default();
+        let mut sent = Vec::new();
+        // A bridge with TDI looped back to TDO
+        let mut shift = |batch: &[u8]| -> Result<Vec<u8>> {
+            sent.push(batch.to_vec());
+            let mut tdo = vec![0; batch.len().div_ceil(8)];
+            for (i, state) in batch.iter().enumerate() {
+                tdo[i / 8] |= (state & 1) << (i % 8);
+            }
+            Ok(tdo)
+        };
+
+        let (replies, quit) = bitbang.handle(b"015R4R", &mut shift).unwrap();
+        assert_eq!(replies, b"10");
+        assert!(!quit);
+        let (replies, quit) = bitbang.handle(b"RBbQ", &mut shift).unwrap();
+        assert_eq!(replies, b"0");
+        assert!(quit);
+        assert_eq!(sent, [vec![0, 1, 5], vec![4]]);
+    }
+}
@@ -0,0 +1,556 @@
+//! Command line tool for the TiLDA MkV USB bridge
+//!
+//! Talks to the bridge's WebUSB interface with the vendor requests in `tilda-bridge-protocol`, so
+//! the bridge can be inspected and configured without writing WebUSB JavaScript.
+
+mod bridge;
+mod flasher;
+mod jtag;
+mod report;
+mod spiflash;
+
+use bridge::Bridge;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tilda_bridge_protocol::errorlog::LOG_LENGTH;
+use tilda_bridge_protocol::fixture::FixtureMap;
+use tilda_bridge_protocol::flasher::RESPONSE_LENGTH;
+use tilda_bridge_protocol::frame;
+use tilda_bridge_protocol::jtag::tdo_length;
+use tilda_bridge_protocol::request;
+use tilda_bridge_protocol::reset::ResetCause;
+use tilda_bridge_protocol::settings::Config;
+use tilda_bridge_protocol::telemetry;
+use tilda_bridge_protocol::throughput;
+use tilda_bridge_protocol::version;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "\
+Usage: tilda-bridge <command>
+
+Commands:
+  info                  Show the firmware build, protocol version and why the bridge last reset
+  telemetry             Show supply voltages, temperature, uptime and boot counts
+  errors [reset]        Show USB and UART error counters, and with reset start them again
+                        from zero
+  error-log [clear]     Show the errors the bridge has logged in flash over its last few boots,
+                        and with clear empty the log
+  config [name=value]   Show the settings, or change them
+  self-test             Run the end-of-line self-test
+  fixture               Walk the bridge's pins on a bed-of-nails test fixture and show which are
+                        connected to which, with the ESP32 held in reset by the fixture
+  reset                 Reset the ESP32
+  boot                  Reset the ESP32 into its ROM bootloader
+  log [-t|-x|-l]        Print the ESP32's serial output until interrupted, with -t
+                        prefixing each line with the bridge's uptime, -x printing a
+                        hex dump of the serial port's traffic instead, or -l having the
+                        bridge send whole lines
+  capture               Print the ESP32's recent output, from before anyone was listening
+  watch [action=]<pattern>...
+                        Print a line whenever the ESP32's output contains one of up to four
+                        patterns, with what followed it, until interrupted. The bridge can also
+                        reset the ESP32, reset it into download mode or keep the capture,
+                        with action reset, download or snapshot
+  dfu                   Restart the bridge into its DFU bootloader
+  ping [count]          Time the round trip of a vendor request (default 100 of them)
+  throughput [seconds]  Measure how fast data gets through USB each way, leaving the UART out
+                        of it (default 2 seconds each way)
+  throttle to-esp|from-esp <bytes per second>
+                        Limit one direction to emulate a slow link, or lift the limit with 0.
+                        What the ESP32 sends over the limit is dropped
+  faults off|to-esp|from-esp|both <drop%> <duplicate%> <flip%>
+                        Drop, duplicate or flip a bit in that percentage of the bytes going
+                        each way, for testing error handling, until turned off
+  profile compat|full   Re-enumerate without the WebUSB and Microsoft OS 2.0 descriptors, for
+                        hosts which can't parse them, or with them again
+  strap follow|low|release
+                        Make the ESP32's second strapping pin follow IO0, hold it low, or
+                        leave it to the module, for boards with it wired to the bridge
+  flash <address> <file>
+                        Write an image to the ESP32's flash through its ROM bootloader,
+                        with the bridge handling the timing
+  verify <address> <file>
+                        Check the ESP32's flash against an image by its MD5, without
+                        reading it back
+  jtag [port]           Serve OpenOCD's remote_bitbang driver on localhost (default port
+                        3335), for boards with the ESP32's JTAG pins wired to the bridge
+  spi-flash id | read <address> <length> <file> | write <address> <file>
+                        Show the ESP32's flash chip ID, or read or write the flash, holding
+                        the ESP32 in reset, for boards with its flash wired to the bridge
+";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => {
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(command, &args[1..]) {
+        eprintln!("tilda-bridge: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: &str, args: &[String]) -> Result<()> {
+    if command == "help" || command == "--help" {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+
+    let bridge = Bridge::open()?;
+    match command {
+        "info" => {
+            let info = bridge.vendor_in(request::GET_BUILD_INFO, request::BUILD_INFO_LENGTH)?;
+            report::check_length(&info, request::BUILD_INFO_LENGTH)?;
+            print!("{}", report::build_info(&info));
+
+            let status = bridge.vendor_in(request::GET_STATUS, request::STATUS_LENGTH)?;
+            report::check_length(&status, request::STATUS_LENGTH)?;
+            println!("Last reset: {}", report::reset_cause(ResetCause::from_bits(status[0])));
+            print!("{}", report::version(&bridge.version));
+        }
+        "telemetry" => {
+            let buf = bridge.vendor_in(request::GET_TELEMETRY, request::TELEMETRY_LENGTH)?;
+            // Firmware before protocol version 1.15 doesn't report the stack, and only builds with
+            // the esp-counters or loop-load features report the ESP32's boots and crashes.
+            let length = if bridge.version.minor < 15 {
+                32
+            } else if bridge.version.has(version::FEATURE_LOOP_LOAD) {
+                request::TELEMETRY_LENGTH
+            } else if bridge.version.has(version::FEATURE_ESP_COUNTERS) {
+                telemetry::LOOP_LOAD_OFFSET
+            } else {
+                telemetry::ESP_COUNTS_OFFSET
+            };
+            report::check_length(&buf, length)?;
+            print!("{}", report::telemetry(&buf, &bridge.version));
+        }
+        "errors" => {
+            let request = match args {
+                [] => request::GET_ERRORS,
+                [reset] if reset == "reset" => {
+                    if bridge.version.minor < 19 {
+                        return Err("the bridge firmware is too old to reset its counters".into());
+                    }
+                    request::TAKE_ERRORS
+                }
+                _ => return Err(USAGE.into()),
+            };
+            let buf = bridge.vendor_in(request, request::ERRORS_LENGTH)?;
+            // Firmware before protocol version 1.20 doesn't count dropped output.
+            let length = if bridge.version.minor < 20 { 37 } else { request::ERRORS_LENGTH };
+            report::check_length(&buf, length)?;
+            print!("{}", report::errors(&buf));
+        }
+        "error-log" => {
+            if !bridge.version.has(version::FEATURE_ERROR_LOG) {
+                return Err("the bridge firmware wasn't built with the error-log feature".into());
+            }
+            match args {
+                [] => {}
+                [clear] if clear == "clear" => {
+                    bridge.vendor_out(request::CLEAR_ERROR_LOG, request::ERROR_LOG_KEY, &[])?;
+                    // The flash is written from the bridge's main loop, once the request has
+                    // been answered.
+                    sleep(Duration::from_millis(100));
+                }
+                _ => return Err(USAGE.into()),
+            }
+            let buf = bridge.vendor_in(request::GET_ERROR_LOG, LOG_LENGTH)?;
+            print!("{}", report::error_log(&buf));
+        }
+        "config" => {
+            // Payloads from older firmware are shorter, which parse fills in with defaults.
+            let payload = bridge.vendor_in(request::GET_CONFIG, 64)?;
+            let mut config = Config::parse(&payload).ok_or("bridge sent invalid settings")?;
+            if !args.is_empty() {
+                for arg in args {
+                    report::set_setting(&mut config, arg)?;
+                }
+                bridge.vendor_out(request::SET_CONFIG, 0, &config.to_payload())?;
+            }
+            print!("{}", report::settings(&config));
+        }
+        "self-test" => {
+            // The bridge stops servicing USB while the test runs, so the request may time out.
+            bridge.vendor_out(request::SELF_TEST, 0, &[]).ok();
+            sleep(Duration::from_secs(2));
+            let buf = bridge.vendor_in(request::GET_SELF_TEST, request::SELF_TEST_LENGTH)?;
+            report::check_length(&buf, request::SELF_TEST_LENGTH)?;
+            print!("{}", report::self_test(&buf));
+        }
+        "fixture" => {
+            if !bridge.version.has(version::FEATURE_FIXTURE) {
+                return Err("the bridge firmware wasn't built with the fixture feature".into());
+            }
+            bridge.vendor_out(request::FIXTURE_TEST, request::FIXTURE_KEY, &[])?;
+            // The walk runs from the bridge's main loop, once the request has been answered.
+            sleep(Duration::from_millis(100));
+            let buf = bridge.vendor_in(request::GET_FIXTURE, request::FIXTURE_LENGTH)?;
+            let map = FixtureMap::parse(&buf).ok_or("bridge sent an invalid connectivity map")?;
+            print!("{}", report::fixture(&map));
+        }
+        "reset" | "boot" => {
+            // So DTR/RTS from a terminal left open on the serial port don't get in the way.
+            claim(&bridge, 2)?;
+            let result = if command == "reset" {
+                reset(&bridge)
+            } else {
+                reset_into_loader(&bridge)
+            };
+            claim(&bridge, 0)?;
+            result?;
+        }
+        "log" => {
+            // Watch without getting in the way of anything using the serial port.
+            if args.iter().any(|arg| arg == "-x") {
+                if bridge.version.minor < 5 {
+                    return Err("the bridge firmware is too old for hex dumps".into());
+                }
+                bridge.vendor_out(request::SET_MONITOR, 2, &[])?;
+            } else if bridge.version.minor >= 2 {
+                bridge.vendor_out(request::SET_MONITOR, 1, &[])?;
+            }
+            let timestamps = args.iter().any(|arg| arg == "-t");
+            if timestamps {
+                if bridge.version.minor < 4 {
+                    return Err("the bridge firmware is too old for timestamps".into());
+                }
+                bridge.vendor_out(request::SET_TIMESTAMPS, 1, &[])?;
+            } else if args.iter().any(|arg| arg == "-l") {
+                if !bridge.version.has(version::FEATURE_LINE_BUFFER) {
+                    return Err(
+                        "the bridge firmware wasn't built with the line-buffer feature".into()
+                    );
+                }
+                bridge.vendor_out(request::SET_LINE_BUFFER, 100, &[])?;
+            }
+            bridge.claim_data()?;
+            let mut buf = [0u8; 64];
+            let mut stream = Vec::new();
+            let mut lines = report::TimestampedLines::default();
+            let stdout = std::io::stdout();
+            loop {
+                let count = bridge.read(&mut buf)?;
+                let mut out = stdout.lock();
+                if !timestamps {
+                    out.write_all(&buf[..count])?;
+                } else {
+                    stream.extend_from_slice(&buf[..count]);
+                    let mut rest = &stream[..];
+                    while let Some((timestamp, data, next)) = frame::split(rest) {
+                        out.write_all(&lines.format(timestamp, data))?;
+                        rest = next;
+                    }
+                    stream.drain(..stream.len() - rest.len());
+                }
+                out.flush()?;
+            }
+        }
+        "capture" => {
+            if bridge.version.minor < 3 {
+                return Err("the bridge firmware is too old to keep a capture".into());
+            }
+            let mut capture = Vec::new();
+            loop {
+                let offset = capture.len() as u16;
+                let chunk = bridge.vendor_in_value(
+                    request::GET_CAPTURE,
+                    offset,
+                    request::CAPTURE_CHUNK_LENGTH,
+                )?;
+                capture.extend_from_slice(&chunk);
+                if chunk.len() < request::CAPTURE_CHUNK_LENGTH {
+                    break;
+                }
+            }
+            std::io::stdout().write_all(&capture)?;
+        }
+        "watch" => {
+            if !bridge.version.has(version::FEATURE_PATTERN_WATCH) {
+                return Err(
+                    "the bridge firmware wasn't built with the pattern-watch feature".into()
+                );
+            }
+            if args.is_empty() || args.len() > request::WATCH_SLOTS {
+                return Err(USAGE.into());
+            }
+            let mut patterns = Vec::new();
+            for (slot, arg) in args.iter().enumerate() {
+                let (action, pattern) = report::parse_watch(arg)?;
+                if action != 0 && bridge.version.minor < 22 {
+                    return Err("the bridge firmware is too old to act on a match".into());
+                }
+                let value = (action as u16) << 8 | slot as u16;
+                bridge.vendor_out(request::SET_WATCH, value, pattern.as_bytes())?;
+                patterns.push(pattern.to_string());
+            }
+            loop {
+                let notification = bridge.read_notification()?;
+                if let Some(line) = report::pattern(&notification, &patterns) {
+                    println!("{}", line);
+                }
+            }
+        }
+        "throttle" => {
+            if !bridge.version.has(version::FEATURE_THROTTLE) {
+                return Err("the bridge firmware wasn't built with the throttle feature".into());
+            }
+            let (direction, rate) = match args {
+                [direction, rate] if direction == "to-esp" => (0, parse_number(rate)?),
+                [direction, rate] if direction == "from-esp" => (1, parse_number(rate)?),
+                _ => return Err(USAGE.into()),
+            };
+            bridge.vendor_out(request::SET_THROTTLE, direction, &rate.to_le_bytes())?;
+        }
+        "faults" => {
+            if !bridge.version.has(version::FEATURE_FAULT_INJECT) {
+                return Err("the bridge firmware wasn't built with the fault-inject feature".into());
+            }
+            let settings = report::parse_faults(args)?;
+            bridge.vendor_out(request::SET_FAULTS, request::FAULTS_KEY, &settings)?;
+        }
+        "profile" => {
+            if bridge.version.minor < 12 {
+                return Err("the bridge firmware is too old to change its descriptors".into());
+            }
+            let compatibility = match args {
+                [profile] if profile == "compat" => 1,
+                [profile] if profile == "full" => 0,
+                _ => return Err(USAGE.into()),
+            };
+            bridge.vendor_out(request::SET_PROFILE, compatibility, &[])?;
+            println!("Re-enumerating");
+        }
+        "strap" => {
+            if !bridge.version.has(version::FEATURE_STRAP_PIN) {
+                return Err("the bridge firmware wasn't built with the strap-pin feature".into());
+            }
+            let mode = match args {
+                [mode] if mode == "follow" => 0,
+                [mode] if mode == "low" => 1,
+                [mode] if mode == "release" => 2,
+                _ => return Err(USAGE.into()),
+            };
+            bridge.vendor_out(request::SET_STRAP, mode, &[])?;
+        }
+        "ping" => {
+            if bridge.version.minor < 16 {
+                return Err("the bridge firmware is too old to answer pings".into());
+            }
+            let count = match args {
+                [] => 100,
+                [count] => parse_number(count)?,
+                _ => return Err(USAGE.into()),
+            };
+            let mut pings = report::Pings::default();
+            for sequence in 0..count as u16 {
+                let sent = Instant::now();
+                let buf = bridge.vendor_in_value(request::PING, sequence, request::PING_LENGTH)?;
+                let round_trip_us = sent.elapsed().as_micros() as u32;
+                report::check_length(&buf, request::PING_LENGTH)?;
+                pings.record(sequence, &buf, round_trip_us)?;
+            }
+            print!("{}", pings.summary());
+        }
+        "throughput" => {
+            if bridge.version.minor < 17 {
+                return Err("the bridge firmware is too old to run the throughput test".into());
+            }
+            let seconds = match args {
+                [] => 2,
+                [seconds] => parse_number(seconds)?,
+                _ => return Err(USAGE.into()),
+            };
+            let duration = Duration::from_secs(seconds.into());
+            bridge.claim_data()?;
+            bridge.vendor_out(request::SET_THROUGHPUT, 1, &[])?;
+            let result = throughput_test(&bridge, duration);
+            bridge.vendor_out(request::SET_THROUGHPUT, 0, &[])?;
+            let (received, out_of_sequence) = result?;
+            let counts = bridge.vendor_in(request::GET_THROUGHPUT, request::THROUGHPUT_LENGTH)?;
+            report::check_length(&counts, request::THROUGHPUT_LENGTH)?;
+            print!("{}", report::throughput(&counts, received, out_of_sequence, duration));
+        }
+        "dfu" => {
+            bridge.vendor_out(request::DETACH, 0, &[])?;
+            println!("Restarting into the DFU bootloader (16c0:05dc)");
+        }
+        "flash" | "verify" => {
+            if !bridge.version.has(version::FEATURE_FLASHER) {
+                return Err("the bridge firmware wasn't built with the flasher feature".into());
+            }
+            let (address, image) = match args {
+                [address, file] => (parse_number(address)?, std::fs::read(file)?),
+                _ => return Err(USAGE.into()),
+            };
+            let encrypted_word = bridge.version.has(version::FEATURE_ESP32C3)
+                || bridge.version.has(version::FEATURE_ESP32S3);
+            claim(&bridge, 2)?;
+            bridge.vendor_out(request::SET_FLASHER, 1, &[])?;
+            reset_into_loader(&bridge)?;
+            let mut loader = flasher::RomLoader::new(
+                |timeout, packet: &[u8]| {
+                    bridge.vendor_out(request::ROM_COMMAND, timeout, packet)?;
+                    loop {
+                        let buf = bridge.vendor_in(request::GET_ROM_RESPONSE, RESPONSE_LENGTH)?;
+                        if let Some(response) = flasher::Response::parse(&buf)? {
+                            return Ok(response);
+                        }
+                        sleep(Duration::from_millis(1));
+                    }
+                },
+                encrypted_word,
+            );
+            let result = (|| {
+                loader.sync()?;
+                loader.attach()?;
+                if command == "flash" {
+                    loader.write(address, &image)
+                } else {
+                    loader.verify(address, &image)
+                }
+            })();
+            // Give the UART back and run whatever is on the flash, whatever happened.
+            bridge.vendor_out(request::SET_FLASHER, 0, &[])?;
+            reset(&bridge)?;
+            claim(&bridge, 0)?;
+            result?;
+            if command == "flash" {
+                println!("Wrote {} bytes", image.len());
+            } else {
+                println!("The flash matches {}", args[1]);
+            }
+        }
+        "jtag" => {
+            if !bridge.version.has(version::FEATURE_JTAG) {
+                return Err("the bridge firmware wasn't built with the jtag feature".into());
+            }
+            let port = match args.first() {
+                Some(port) => port.parse().map_err(|_| format!("invalid port {}", port))?,
+                None => 3335,
+            };
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            println!("Waiting for OpenOCD on port {} (adapter driver remote_bitbang)", port);
+            let (mut stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+
+            let mut bitbang = jtag::RemoteBitbang::default();
+            let mut buf = [0u8; 4096];
+            loop {
+                let count = stream.read(&mut buf)?;
+                if count == 0 {
+                    break;
+                }
+                let (replies, quit) = bitbang.handle(&buf[..count], |batch| {
+                    bridge.vendor_out(request::JTAG_SHIFT, 0, batch)?;
+                    bridge.vendor_in(request::GET_JTAG_TDO, tdo_length(batch.len()))
+                })?;
+                stream.write_all(&replies)?;
+                if quit {
+                    break;
+                }
+            }
+        }
+        "spi-flash" => {
+            if !bridge.version.has(version::FEATURE_SPI_FLASH) {
+                return Err("the bridge firmware wasn't built with the spi-flash feature".into());
+            }
+            bridge.vendor_out(request::SET_SPI_FLASH, 1, &[])?;
+            let mut flash = spiflash::SpiFlash::new(|data: &[u8], keep_cs| {
+                let value = if keep_cs { tilda_bridge_protocol::spiflash::KEEP_CS } else { 0 };
+                bridge.vendor_out(request::SPI_TRANSFER, value, data)?;
+                bridge.vendor_in(request::GET_SPI_RESULT, data.len())
+            });
+            let result = match args {
+                [op] if op == "id" => flash.jedec_id().map(|id| {
+                    println!("JEDEC ID: {:02x} {:02x} {:02x}", id[0], id[1], id[2]);
+                }),
+                [op, address, length, file] if op == "read" => (|| {
+                    let data = flash.read(parse_number(address)?, parse_number(length)? as usize)?;
+                    std::fs::write(file, data)?;
+                    Ok(())
+                })(),
+                [op, address, file] if op == "write" => (|| {
+                    let data = std::fs::read(file)?;
+                    flash.write(parse_number(address)?, &data)?;
+                    println!("Wrote {} bytes", data.len());
+                    Ok(())
+                })(),
+                _ => Err(USAGE.into()),
+            };
+            // Let the ESP32 run again, whatever happened.
+            bridge.vendor_out(request::SET_SPI_FLASH, 0, &[])?;
+            result?;
+        }
+        _ => return Err(format!("unknown command {}\n\n{}", command, USAGE).into()),
+    }
+    Ok(())
+}
+
+/// Gives the ESP32's control lines and the UART to one port (1 = serial, 2 = WebUSB), or shares
+/// them again with 0. Older firmware always shares them.
+fn claim(bridge: &Bridge, owner: u16) -> Result<()> {
+    if bridge.version.minor >= 11 {
+        bridge.vendor_out(request::CLAIM, owner, &[])?;
+    }
+    Ok(())
+}
+
+/// Resets the ESP32. RTS alone holds EN low, the same as esptool's reset.
+fn reset(bridge: &Bridge) -> Result<()> {
+    bridge.set_control_lines(false, true)?;
+    sleep(Duration::from_millis(100));
+    bridge.set_control_lines(false, false)
+}
+
+/// esptool's classic reset into the ROM bootloader: EN low, then EN high with IO0 low.
+fn reset_into_loader(bridge: &Bridge) -> Result<()> {
+    bridge.set_control_lines(false, true)?;
+    sleep(Duration::from_millis(100));
+    bridge.set_control_lines(true, false)?;
+    sleep(Duration::from_millis(50));
+    bridge.set_control_lines(false, false)
+}
+
+/// Parses a decimal or `0x` hex number.
+/// Reads the throughput test's pattern for `duration`, then sends it for as long again. Returns
+/// how much was read, and how many bytes of it didn't follow the one before.
+fn throughput_test(bridge: &Bridge, duration: Duration) -> Result<(u64, u64)> {
+    let mut buf = vec![0; 4096];
+    let (mut received, mut out_of_sequence, mut expected) = (0u64, 0u64, 0u8);
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        let count = bridge.read(&mut buf)?;
+        for &byte in &buf[..count] {
+            if byte != expected {
+                out_of_sequence += 1;
+            }
+            expected = byte.wrapping_add(1);
+        }
+        received += count as u64;
+    }
+
+    let mut sent = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        throughput::fill(sent, &mut buf);
+        sent = sent.wrapping_add(bridge.write(&buf)? as u32);
+    }
+    Ok((received, out_of_sequence))
+}
+
+fn parse_number(arg: &str) -> Result<u32> {
+    let number = match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    number.map_err(|_| format!("invalid number {}", arg).into())
+}
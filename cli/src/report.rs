@@ -0,0 +1,736 @@
+//! Formatting the bridge's reports for people.
+
+use std::fmt::Write;
+use std::time::Duration;
+use tilda_bridge_protocol::errorlog::{self, Entry, ENTRY_LENGTH};
+use tilda_bridge_protocol::event;
+use tilda_bridge_protocol::fixture::{self, FixtureMap};
+use tilda_bridge_protocol::request::{
+    FAULTS_FROM_ESP, FAULTS_LENGTH, FAULTS_TO_ESP, WATCH_PATTERN_LENGTH,
+};
+use tilda_bridge_protocol::reset::ResetCause;
+use tilda_bridge_protocol::selftest::SelfTestReport;
+use tilda_bridge_protocol::settings::{
+    Config, ControlLinePolicy, EspSuspendPolicy, LineCodingPolicy, PortClosePolicy, LINE_CR_TO_LF,
+    LINE_ECHO, LINE_LF_TO_CRLF,
+};
+use tilda_bridge_protocol::telemetry::{NOT_AVAILABLE, TEMPERATURE_NOT_AVAILABLE};
+use tilda_bridge_protocol::version::{self, Version};
+
+use crate::Result;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+fn millivolts(value: u16) -> String {
+    match value {
+        NOT_AVAILABLE => "n/a".to_string(),
+        mv => format!("{}.{:03}V", mv / 1000, mv % 1000),
+    }
+}
+
+fn temperature(value: i16) -> String {
+    match value {
+        TEMPERATURE_NOT_AVAILABLE => "n/a".to_string(),
+        t => format!("{:.1}°C", t as f32 / 10.0),
+    }
+}
+
+/// Checks a response is as long as expected.
+pub fn check_length(buf: &[u8], length: usize) -> Result<()> {
+    if buf.len() < length {
+        return Err(format!("short response: {} bytes, expected {}", buf.len(), length).into());
+    }
+    Ok(())
+}
+
+/// Formats a `GET_TELEMETRY` response, from a bridge with `version`.
+pub fn telemetry(buf: &[u8], version: &Version) -> String {
+    let mut out = String::new();
+    for (i, rail) in ["VDD", "VBUS", "Battery"].iter().enumerate() {
+        let latest = u16_at(buf, i * 2);
+        let lowest = u16_at(buf, 6 + i * 2);
+        writeln!(out, "{:<12} {} (lowest {})", rail, millivolts(latest), millivolts(lowest)).unwrap();
+    }
+    let latest = u16_at(buf, 12) as i16;
+    let highest = u16_at(buf, 14) as i16;
+    writeln!(out, "{:<12} {} (highest {})", "Temperature", temperature(latest), temperature(highest))
+        .unwrap();
+    writeln!(out, "{:<12} {} / {}", "VREFINT", u16_at(buf, 16), u16_at(buf, 18)).unwrap();
+    writeln!(out, "{:<12} {}s", "Uptime", u32_at(buf, 20)).unwrap();
+    writeln!(out, "{:<12} {} ({} not from power-on)", "Boots", u32_at(buf, 24), u32_at(buf, 28))
+        .unwrap();
+    if buf.len() >= 36 {
+        writeln!(out, "{:<12} {} of {} bytes", "Stack", u16_at(buf, 32), u16_at(buf, 34)).unwrap();
+    }
+    // Builds with loop-load but not esp-counters send zero for the ESP32's counts.
+    if buf.len() >= 44 && version.has(version::FEATURE_ESP_COUNTERS) {
+        writeln!(out, "{:<12} {} ({} crashes)", "ESP32 boots", u32_at(buf, 36), u32_at(buf, 40))
+            .unwrap();
+    }
+    if buf.len() >= 48 && version.has(version::FEATURE_LOOP_LOAD) {
+        let (latest, highest) = (u16_at(buf, 44) as f32 / 10.0, u16_at(buf, 46) as f32 / 10.0);
+        writeln!(out, "{:<12} {:.1}% (highest {:.1}%)", "Loop load", latest, highest).unwrap();
+    }
+    out
+}
+
+/// Formats a `GET_ERRORS` response.
+pub fn errors(buf: &[u8]) -> String {
+    let fields = [
+        ("Serial read errors", u32_at(buf, 0)),
+        ("WebUSB read errors", u32_at(buf, 4)),
+        ("Recoveries", u32_at(buf, 8)),
+        ("Last error code", buf[12] as u32),
+        ("Re-attaches", u32_at(buf, 13)),
+        ("UART overruns", u32_at(buf, 17)),
+        ("UART framing", u32_at(buf, 21)),
+        ("UART noise", u32_at(buf, 25)),
+        ("UART parity", u32_at(buf, 29)),
+        ("Rejected line codings", u32_at(buf, 33)),
+    ];
+    let mut out = String::new();
+    for (name, value) in fields.iter() {
+        writeln!(out, "{:<22} {}", name, value).unwrap();
+    }
+    if buf.len() >= 45 {
+        writeln!(out, "{:<22} {}", "Serial bytes dropped", u32_at(buf, 37)).unwrap();
+        writeln!(out, "{:<22} {}", "WebUSB bytes dropped", u32_at(buf, 41)).unwrap();
+    }
+    out
+}
+
+/// Formats a `GET_BUILD_INFO` response.
+pub fn build_info(buf: &[u8]) -> String {
+    let commit: String = buf[5..25].iter().map(|b| format!("{:02x}", b)).collect();
+    let dirty = if buf[4] != 0 { " (modified)" } else { "" };
+    format!("Built at {} (Unix time) from {}{}\n", u32_at(buf, 0), commit, dirty)
+}
+
+/// Names the flags in a `GET_STATUS` reset cause.
+pub fn reset_cause(cause: ResetCause) -> String {
+    let names = [
+        (ResetCause::OPTION_BYTE_LOAD, "option byte load"),
+        (ResetCause::PIN, "reset pin"),
+        (ResetCause::POWER_ON, "power-on/brown-out"),
+        (ResetCause::SOFTWARE, "software"),
+        (ResetCause::INDEPENDENT_WATCHDOG, "independent watchdog"),
+        (ResetCause::WINDOW_WATCHDOG, "window watchdog"),
+        (ResetCause::LOW_POWER, "low-power"),
+        (ResetCause::STACK_OVERFLOW, "stack overflow"),
+    ];
+    let set: Vec<_> = names
+        .iter()
+        .filter(|(flag, _)| cause.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    if set.is_empty() {
+        "unknown".to_string()
+    } else {
+        set.join(", ")
+    }
+}
+
+/// Formats a `GET_ERROR_LOG` response, a line for each record.
+pub fn error_log(buf: &[u8]) -> String {
+    if buf.is_empty() {
+        return "No errors logged\n".to_string();
+    }
+    let mut out = String::new();
+    for entry in buf.chunks(ENTRY_LENGTH).filter_map(Entry::parse) {
+        let error = match entry.kind {
+            errorlog::RESET => {
+                format!("reset by {}", reset_cause(ResetCause::from_bits(entry.detail)))
+            }
+            errorlog::PANIC => "panic".to_string(),
+            errorlog::USB_RECOVERY => "WebUSB endpoint recovered".to_string(),
+            errorlog::REATTACH => "re-attached to the bus".to_string(),
+            errorlog::UART_OVERRUN => "UART overrun".to_string(),
+            kind => format!("unknown error {}", kind),
+        };
+        writeln!(out, "Boot {:<5} {:>7}s  {}", entry.boot, entry.uptime, error).unwrap();
+    }
+    out
+}
+
+/// Formats the bridge's protocol version and the features it was built with.
+pub fn version(version: &Version) -> String {
+    let names = [
+        (version::FEATURE_VBUS_SENSE, "vbus-sense"),
+        (version::FEATURE_BATTERY_SENSE, "battery-sense"),
+        (version::FEATURE_PRODUCTION_LOCK, "production-lock"),
+        (version::FEATURE_ESP32C3, "esp32c3"),
+        (version::FEATURE_ESP32S3, "esp32s3"),
+        (version::FEATURE_JTAG, "jtag"),
+        (version::FEATURE_SPI_FLASH, "spi-flash"),
+        (version::FEATURE_FLASHER, "flasher"),
+        (version::FEATURE_STRAP_PIN, "strap-pin"),
+        (version::FEATURE_PATTERN_WATCH, "pattern-watch"),
+        (version::FEATURE_THROTTLE, "throttle"),
+        (version::FEATURE_FAULT_INJECT, "fault-inject"),
+        (version::FEATURE_ESP_COUNTERS, "esp-counters"),
+        (version::FEATURE_LINE_BUFFER, "line-buffer"),
+        (version::FEATURE_ESCAPES, "escapes"),
+        (version::FEATURE_LED_DIM, "led-dim"),
+        (version::FEATURE_FIXTURE, "fixture"),
+        (version::FEATURE_UNLOCK, "unlock"),
+        (version::FEATURE_RATE_LIMIT, "rate-limit"),
+        (version::FEATURE_ERROR_LOG, "error-log"),
+        (version::FEATURE_LOOP_LOAD, "loop-load"),
+    ];
+    let set: Vec<_> = names
+        .iter()
+        .filter(|(feature, _)| version.has(*feature))
+        .map(|(_, name)| *name)
+        .collect();
+    let features = if set.is_empty() { "none".to_string() } else { set.join(", ") };
+    format!("Protocol version {}.{}, features: {}\n", version.major, version.minor, features)
+}
+
+/// Puts timestamps at the start of lines of timestamped UART output.
+#[derive(Default)]
+pub struct TimestampedLines {
+    mid_line: bool,
+}
+
+impl TimestampedLines {
+    /// Formats one frame's data, prefixing each line which starts in it with its timestamp.
+    pub fn format(&mut self, timestamp_ms: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &byte in data {
+            if !self.mid_line {
+                let prefix = format!("[{:6}.{:03}] ", timestamp_ms / 1000, timestamp_ms % 1000);
+                out.extend_from_slice(prefix.as_bytes());
+                self.mid_line = true;
+            }
+            out.push(byte);
+            if byte == b'\n' {
+                self.mid_line = false;
+            }
+        }
+        out
+    }
+}
+
+/// Formats the throughput test's results: what the host read in `duration`, and the bridge's
+/// `GET_THROUGHPUT` counts for what the host sent in as long again.
+pub fn throughput(counts: &[u8], received: u64, out_of_sequence: u64, duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    let rate = |bytes: f64| bytes / seconds / 1000.0;
+    format!(
+        "To the host:   {} bytes, {:.1} kB/s, {} out of sequence\n\
+         From the host: {} bytes, {:.1} kB/s, {} out of sequence\n",
+        received,
+        rate(received as f64),
+        out_of_sequence,
+        u32_at(counts, 4),
+        rate(u32_at(counts, 4) as f64),
+        u32_at(counts, 8)
+    )
+}
+
+/// Collects `PING` round trips.
+#[derive(Default)]
+pub struct Pings {
+    round_trips_us: Vec<u32>,
+    first_micros: Option<u32>,
+    last_micros: u32,
+}
+
+impl Pings {
+    /// Records a `PING` response to `sequence`, and how long the host waited for it.
+    pub fn record(&mut self, sequence: u16, buf: &[u8], round_trip_us: u32) -> Result<()> {
+        if u16_at(buf, 0) != sequence {
+            return Err(format!("ping {} came back as {}", sequence, u16_at(buf, 0)).into());
+        }
+        self.round_trips_us.push(round_trip_us);
+        self.first_micros.get_or_insert(u32_at(buf, 2));
+        self.last_micros = u32_at(buf, 2);
+        Ok(())
+    }
+
+    /// Formats the round trip times, and how long the bridge's clock says the pings took.
+    pub fn summary(&self) -> String {
+        let times = &self.round_trips_us;
+        let (min, max) = match (times.iter().min(), times.iter().max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return "No pings\n".to_string(),
+        };
+        let average = times.iter().map(|&t| t as u64).sum::<u64>() / times.len() as u64;
+        let bridge_us = self.last_micros.wrapping_sub(self.first_micros.unwrap_or(0));
+        format!(
+            "{} pings: round trip min {}us, average {}us, max {}us\n\
+             Bridge clock from first to last: {}us\n",
+            times.len(),
+            min,
+            average,
+            max,
+            bridge_us
+        )
+    }
+}
+
+/// Formats a `PATTERN` notification, given the patterns in their slots. Other notifications give
+/// `None`.
+pub fn pattern(notification: &[u8], patterns: &[String]) -> Option<String> {
+    if notification.len() < 9 || notification[1] != event::PATTERN {
+        return None;
+    }
+    let pattern = patterns.get(notification[8] as usize)?;
+    Some(format!("{}{}", pattern, String::from_utf8_lossy(&notification[9..])))
+}
+
+/// Names of the `SET_WATCH` actions, from 1.
+const WATCH_ACTIONS: [&str; 3] = ["reset", "download", "snapshot"];
+
+/// Parses a `watch` argument, `[action=]pattern`, into the `SET_WATCH` action and the pattern.
+pub fn parse_watch(arg: &str) -> Result<(u8, &str)> {
+    let (action, pattern) = match arg.split_once('=') {
+        Some((name, pattern)) => match WATCH_ACTIONS.iter().position(|action| *action == name) {
+            Some(i) => (i as u8 + 1, pattern),
+            None => (0, arg),
+        },
+        None => (0, arg),
+    };
+    if pattern.is_empty() || pattern.len() > WATCH_PATTERN_LENGTH {
+        return Err(format!("patterns must be 1 to {} bytes long", WATCH_PATTERN_LENGTH).into());
+    }
+    Ok((action, pattern))
+}
+
+/// Parses the `faults` arguments, `off` or a direction then the percentages of bytes to drop,
+/// duplicate and flip a bit in, into the `SET_FAULTS` data.
+pub fn parse_faults(args: &[String]) -> Result<[u8; FAULTS_LENGTH]> {
+    let (direction, percentages) = match args.split_first() {
+        Some((direction, [])) if direction == "off" => return Ok([0; FAULTS_LENGTH]),
+        Some((direction, rest)) if rest.len() == 3 => (direction, rest),
+        _ => return Err("expected off, or a direction and three percentages".into()),
+    };
+    let mut settings = [0; FAULTS_LENGTH];
+    settings[0] = match direction.as_str() {
+        "to-esp" => FAULTS_TO_ESP,
+        "from-esp" => FAULTS_FROM_ESP,
+        "both" => FAULTS_TO_ESP | FAULTS_FROM_ESP,
+        _ => return Err(format!("unknown direction {}", direction).into()),
+    };
+    for (chance, arg) in settings[1..].iter_mut().zip(percentages) {
+        let percent: f64 = match arg.parse() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => percent,
+            _ => return Err(format!("invalid percentage {}", arg).into()),
+        };
+        // In 256ths, so 100% comes out a byte in 256 short.
+        *chance = (percent * 2.56).round().min(255.0) as u8;
+    }
+    Ok(settings)
+}
+
+/// Formats a `GET_SELF_TEST` response.
+pub fn self_test(buf: &[u8]) -> String {
+    let status = match buf[0] {
+        SelfTestReport::NOT_RUN => "not run",
+        SelfTestReport::PASS => "pass",
+        SelfTestReport::FAIL => "FAIL",
+        _ => "unknown",
+    };
+    let mut out = format!("Self-test: {}\n", status);
+    for (bit, name) in ["EN", "IO0", "UART SYNC", "Config page"].iter().enumerate() {
+        let result = if buf[1] & 1 << bit != 0 { "ok" } else { "failed" };
+        writeln!(out, "  {:<12} {}", name, result).unwrap();
+    }
+    out
+}
+
+/// Names the pins in a fixture pin mask, e.g. `PA1,PB4`.
+fn pin_names(mask: u32) -> String {
+    let names: Vec<_> = (0..32)
+        .filter(|pin| mask & 1 << pin != 0)
+        .map(|pin| format!("P{}{}", if pin < 16 { 'A' } else { 'B' }, pin % 16))
+        .collect();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(",")
+    }
+}
+
+/// Formats the connectivity map from a `GET_FIXTURE` response.
+pub fn fixture(map: &FixtureMap) -> String {
+    let mut out = format!(
+        "Stuck high  {}\nStuck low   {}\n",
+        pin_names(map.stuck_high),
+        pin_names(map.stuck_low)
+    );
+    for (pin, link) in fixture::pins().zip(map.links.iter()) {
+        let name = pin_names(1 << pin);
+        if link & 1 << pin == 0 {
+            writeln!(out, "  {:<5} doesn't read back", name).unwrap();
+        } else if link & !(1 << pin) != 0 {
+            writeln!(out, "  {:<5} linked to {}", name, pin_names(link & !(1 << pin))).unwrap();
+        } else {
+            writeln!(out, "  {:<5} ok", name).unwrap();
+        }
+    }
+    out
+}
+
+/// Names of the line option bits.
+const LINE_OPTIONS: [(u8, &str); 3] =
+    [(LINE_CR_TO_LF, "cr-to-lf"), (LINE_LF_TO_CRLF, "lf-to-crlf"), (LINE_ECHO, "echo")];
+
+/// Formats line option bits as a comma-separated list.
+fn line_options(options: u8) -> String {
+    let set: Vec<_> = LINE_OPTIONS
+        .iter()
+        .filter(|(bit, _)| options & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if set.is_empty() {
+        "none".to_string()
+    } else {
+        set.join(",")
+    }
+}
+
+/// Parses a list of line options, as formatted by `line_options`.
+fn parse_line_options(value: &str) -> Result<u8> {
+    let mut options = 0;
+    for name in value.split(',').filter(|name| *name != "none") {
+        let (bit, _) = LINE_OPTIONS
+            .iter()
+            .find(|(_, option)| *option == name)
+            .ok_or("line options are none, or some of cr-to-lf, lf-to-crlf and echo")?;
+        options |= bit;
+    }
+    Ok(options)
+}
+
+/// Parses an identity key: 32 hex digits.
+pub fn parse_key(hex: &str) -> Result<[u8; 16]> {
+    let mut key = [0u8; 16];
+    if hex.len() != 2 * key.len() || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err("the identity key is 32 hex digits".into());
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(key)
+}
+
+/// Formats the settings, one `name=value` per line, as taken by `set_setting`.
+pub fn settings(config: &Config) -> String {
+    let esp_suspend = match config.esp_suspend {
+        EspSuspendPolicy::PowerDown => "power-down",
+        EspSuspendPolicy::KeepRunning => "keep-running",
+    };
+    let port_close = match config.port_close {
+        PortClosePolicy::Reset => "reset",
+        PortClosePolicy::KeepRunning => "keep-running",
+        PortClosePolicy::Strapping => "strapping",
+    };
+    let control_lines = match config.control_lines {
+        ControlLinePolicy::Either => "either",
+        ControlLinePolicy::LastChanged => "last-changed",
+        ControlLinePolicy::SerialFirst => "serial-first",
+        ControlLinePolicy::WebUsbFirst => "webusb-first",
+    };
+    let line_coding = match config.line_coding {
+        LineCodingPolicy::LastChanged => "last-changed",
+        LineCodingPolicy::Serial => "serial",
+        LineCodingPolicy::WebUsb => "webusb",
+    };
+    format!(
+        "esp-suspend={}\nidle-timeout={}\ncontrol-line-grace={}\nport-close={}\n\
+         close-strapping={}\nserial-line={}\nwebusb-line={}\ncontrol-lines={}\nlanding-page={}\n\
+         nickname={}\nbattery-brightness={}\nbattery-cutoff={}\nline-coding={}\n",
+        esp_suspend,
+        config.idle_timeout,
+        config.control_line_grace,
+        port_close,
+        config.close_strapping,
+        line_options(config.serial_line),
+        line_options(config.webusb_line),
+        control_lines,
+        if config.landing_page { "on" } else { "off" },
+        config.nickname(),
+        config.battery_brightness,
+        config.battery_cutoff,
+        line_coding,
+    )
+}
+
+/// Changes one setting from a `name=value` argument.
+pub fn set_setting(config: &mut Config, arg: &str) -> Result<()> {
+    let (name, value) = arg.split_once('=').ok_or("settings are given as name=value")?;
+    let number = || -> Result<u8> { Ok(value.parse()?) };
+    match name {
+        "esp-suspend" => {
+            config.esp_suspend = match value {
+                "power-down" => EspSuspendPolicy::PowerDown,
+                "keep-running" => EspSuspendPolicy::KeepRunning,
+                _ => return Err("esp-suspend is power-down or keep-running".into()),
+            }
+        }
+        "idle-timeout" => config.idle_timeout = number()?,
+        "control-line-grace" => config.control_line_grace = number()?,
+        "port-close" => {
+            config.port_close = match value {
+                "reset" => PortClosePolicy::Reset,
+                "keep-running" => PortClosePolicy::KeepRunning,
+                "strapping" => PortClosePolicy::Strapping,
+                _ => return Err("port-close is reset, keep-running or strapping".into()),
+            }
+        }
+        "close-strapping" => config.close_strapping = number()?,
+        "serial-line" => config.serial_line = parse_line_options(value)?,
+        "webusb-line" => config.webusb_line = parse_line_options(value)?,
+        "control-lines" => {
+            config.control_lines = match value {
+                "either" => ControlLinePolicy::Either,
+                "last-changed" => ControlLinePolicy::LastChanged,
+                "serial-first" => ControlLinePolicy::SerialFirst,
+                "webusb-first" => ControlLinePolicy::WebUsbFirst,
+                _ => {
+                    let err = "control-lines is either, last-changed, serial-first or webusb-first";
+                    return Err(err.into());
+                }
+            }
+        }
+        "landing-page" => {
+            config.landing_page = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err("landing-page is on or off".into()),
+            }
+        }
+        "nickname" => {
+            if !config.set_nickname(value) {
+                return Err("nickname is up to 16 characters of printable ASCII".into());
+            }
+        }
+        "battery-brightness" => config.battery_brightness = number()?,
+        "battery-cutoff" => config.battery_cutoff = number()?,
+        "line-coding" => {
+            config.line_coding = match value {
+                "last-changed" => LineCodingPolicy::LastChanged,
+                "serial" => LineCodingPolicy::Serial,
+                "webusb" => LineCodingPolicy::WebUsb,
+                _ => return Err("line-coding is last-changed, serial or webusb".into()),
+            }
+        }
+        _ => return Err(format!("unknown setting {}", name).into()),
+    }
+
+    // Catch anything the firmware would reject
+    *config = Config::parse(&config.to_payload()).ok_or("invalid value")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture_report() {
+        let mut map = FixtureMap {
+            stuck_low: 1 << 3,
+            ..FixtureMap::default()
+        };
+        for (link, pin) in map.links.iter_mut().zip(fixture::pins()) {
+            *link = 1 << pin;
+        }
+        map.links[1] |= 1 << 20;
+        map.links[3] = 0;
+
+        let out = fixture(&map);
+        assert!(out.starts_with("Stuck high  none\nStuck low   PA3\n"));
+        assert!(out.contains("  PA0   ok\n"));
+        assert!(out.contains("  PA1   linked to PB4\n"));
+        assert!(out.contains("  PA3   doesn't read back\n"));
+        assert!(out.ends_with("  PB7   ok\n"));
+    }
+
+    #[test]
+    fn telemetry_report() {
+        let mut buf = [0u8; 32];
+        buf[0..2].copy_from_slice(&3300u16.to_le_bytes());
+        buf[2..12].copy_from_slice(&[0xFF; 10]);
+        buf[12..14].copy_from_slice(&253i16.to_le_bytes());
+        buf[14..16].copy_from_slice(&TEMPERATURE_NOT_AVAILABLE.to_le_bytes());
+        buf[24] = 7;
+
+        let bridge = Version::current(version::FEATURE_ESP_COUNTERS);
+        let out = telemetry(&buf, &bridge);
+        assert!(out.contains("VDD          3.300V (lowest n/a)"));
+        assert!(out.contains("Temperature  25.3°C (highest n/a)"));
+        assert!(out.contains("Boots        7 (0 not from power-on)"));
+        assert!(!out.contains("Stack"));
+
+        let mut buf = buf.to_vec();
+        buf.extend_from_slice(&[0xB0, 0x04, 0x10, 0x0E]);
+        assert!(telemetry(&buf, &bridge).contains("Stack        1200 of 3600 bytes"));
+        assert!(!telemetry(&buf, &bridge).contains("ESP32"));
+
+        buf.extend_from_slice(&[12, 0, 0, 0, 2, 0, 0, 0]);
+        assert!(telemetry(&buf, &bridge).contains("ESP32 boots  12 (2 crashes)"));
+
+        // Without esp-counters, their place is kept with zeros.
+        buf.extend_from_slice(&[0x7B, 0, 0xE8, 0x03]);
+        let out = telemetry(&buf, &Version::current(version::FEATURE_LOOP_LOAD));
+        assert!(!out.contains("ESP32"));
+        assert!(out.contains("Loop load    12.3% (highest 100.0%)"));
+    }
+
+    #[test]
+    fn ping_summary() {
+        let mut pings = Pings::default();
+        assert_eq!(pings.summary(), "No pings\n");
+        pings.record(0, &[0, 0, 0xE8, 0x03, 0, 0], 300).unwrap();
+        pings.record(1, &[1, 0, 0x40, 0x06, 0, 0], 500).unwrap();
+        assert!(pings.record(2, &[3, 0, 0, 0, 0, 0], 400).is_err());
+        assert_eq!(
+            pings.summary(),
+            "2 pings: round trip min 300us, average 400us, max 500us\n\
+             Bridge clock from first to last: 600us\n"
+        );
+    }
+
+    #[test]
+    fn throughput_report() {
+        let counts = [0, 0, 0, 0, 0x40, 0x42, 0x0F, 0, 2, 0, 0, 0];
+        assert_eq!(
+            throughput(&counts, 2_000_000, 0, Duration::from_secs(2)),
+            "To the host:   2000000 bytes, 1000.0 kB/s, 0 out of sequence\n\
+             From the host: 1000000 bytes, 500.0 kB/s, 2 out of sequence\n"
+        );
+    }
+
+    #[test]
+    fn pattern_notification() {
+        let patterns = ["boot:".to_string(), "Guru Meditation".to_string()];
+        let mut notification = vec![0xC1, event::PATTERN, 0, 0, 0, 0, 8, 0, 1];
+        notification.extend_from_slice(b" Error:");
+        assert_eq!(pattern(&notification, &patterns).unwrap(), "Guru Meditation Error:");
+        let notification = [0xC1, event::SUPPLY, 0, 0, 0, 0, 1, 0, 1];
+        assert_eq!(pattern(&notification, &patterns), None);
+    }
+
+    #[test]
+    fn watch_arguments() {
+        assert_eq!(parse_watch("boot:").unwrap(), (0, "boot:"));
+        assert_eq!(parse_watch("download=Guru Meditation").unwrap(), (2, "Guru Meditation"));
+        assert_eq!(parse_watch("a=b").unwrap(), (0, "a=b"));
+        assert!(parse_watch("reset=").is_err());
+        assert!(parse_watch("Guru Meditation Error").is_err());
+    }
+
+    #[test]
+    fn fault_arguments() {
+        let args = |args: &str| args.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(parse_faults(&args("off")).unwrap(), [0; FAULTS_LENGTH]);
+        assert_eq!(parse_faults(&args("both 1 0 50")).unwrap(), [3, 3, 0, 128]);
+        assert_eq!(parse_faults(&args("from-esp 100 0.5 0")).unwrap(), [2, 255, 1, 0]);
+        assert!(parse_faults(&args("to-esp 1 2")).is_err());
+        assert!(parse_faults(&args("sideways 1 2 3")).is_err());
+        assert!(parse_faults(&args("to-esp 1 2 101")).is_err());
+    }
+
+    #[test]
+    fn identity_keys() {
+        let key = parse_key("00112233445566778899aAbBcCdDeEfF").unwrap();
+        assert_eq!(key[..3], [0x00, 0x11, 0x22]);
+        assert_eq!(key[15], 0xFF);
+        assert!(parse_key("0011223344556677").is_err());
+        assert!(parse_key("+0112233445566778899aabbccddeeff").is_err());
+        assert!(parse_key("00112233445566778899aabbccddeefg").is_err());
+    }
+
+    #[test]
+    fn reset_causes() {
+        let cause = ResetCause::from_bits(ResetCause::PIN | ResetCause::SOFTWARE);
+        assert_eq!(reset_cause(cause), "reset pin, software");
+        assert_eq!(reset_cause(ResetCause::default()), "unknown");
+    }
+
+    #[test]
+    fn error_log_report() {
+        assert_eq!(error_log(&[]), "No errors logged\n");
+        let detail = ResetCause::WINDOW_WATCHDOG;
+        let reset = Entry { kind: errorlog::RESET, detail, boot: 12, uptime: 0 };
+        let overrun = Entry { kind: errorlog::UART_OVERRUN, detail: 0, boot: 13, uptime: 3601 };
+        let buf = [reset.to_bytes(), overrun.to_bytes()].concat();
+        assert_eq!(
+            error_log(&buf),
+            "Boot 12          0s  reset by window watchdog\nBoot 13       3601s  UART overrun\n"
+        );
+    }
+
+    #[test]
+    fn version_report() {
+        let bridge =
+            Version::current(version::FEATURE_VBUS_SENSE | version::FEATURE_PRODUCTION_LOCK);
+        assert_eq!(
+            version(&bridge),
+            format!(
+                "Protocol version {}.{}, features: vbus-sense, production-lock\n",
+                version::MAJOR,
+                version::MINOR
+            )
+        );
+    }
+
+    #[test]
+    fn timestamped_lines() {
+        let mut lines = TimestampedLines::default();
+        let out = lines.format(1500, b"ets Jun  8\r\nrst:");
+        assert_eq!(out, b"[     1.500] ets Jun  8\r\n[     1.500] rst:");
+        assert_eq!(lines.format(1502, b"0x1\n"), b"0x1\n");
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut config = Config::default();
+        for line in settings(&Config::default()).lines() {
+            set_setting(&mut config, line).unwrap();
+        }
+        assert_eq!(config, Config::default());
+
+        set_setting(&mut config, "port-close=strapping").unwrap();
+        set_setting(&mut config, "idle-timeout=0").unwrap();
+        assert_eq!(config.port_close, PortClosePolicy::Strapping);
+        assert_eq!(config.idle_timeout, 0);
+
+        set_setting(&mut config, "webusb-line=cr-to-lf,echo").unwrap();
+        assert_eq!(config.webusb_line, LINE_CR_TO_LF | LINE_ECHO);
+        assert!(settings(&config).contains("webusb-line=cr-to-lf,echo\n"));
+        assert!(set_setting(&mut config, "serial-line=crlf").is_err());
+
+        set_setting(&mut config, "control-lines=serial-first").unwrap();
+        assert_eq!(config.control_lines, ControlLinePolicy::SerialFirst);
+        assert!(set_setting(&mut config, "control-lines=both").is_err());
+
+        set_setting(&mut config, "line-coding=serial").unwrap();
+        assert_eq!(config.line_coding, LineCodingPolicy::Serial);
+        assert!(set_setting(&mut config, "line-coding=either").is_err());
+
+        set_setting(&mut config, "landing-page=off").unwrap();
+        assert!(!config.landing_page);
+        assert!(set_setting(&mut config, "landing-page=no").is_err());
+        set_setting(&mut config, "nickname=Ada").unwrap();
+        assert_eq!(config.nickname(), "Ada");
+        assert!(set_setting(&mut config, "nickname=Ada, Countess of Lovelace").is_err());
+
+        set_setting(&mut config, "battery-brightness=8").unwrap();
+        set_setting(&mut config, "battery-cutoff=34").unwrap();
+        assert_eq!((config.battery_brightness, config.battery_cutoff), (8, 34));
+        assert!(set_setting(&mut config, "battery-brightness=9").is_err());
+
+        assert!(set_setting(&mut config, "close-strapping=4").is_err());
+        assert!(set_setting(&mut config, "port-close=off").is_err());
+        assert!(set_setting(&mut config, "volume=11").is_err());
+    }
+}
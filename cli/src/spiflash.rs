@@ -0,0 +1,187 @@
+//! SPI NOR flash commands
+//!
+//! The bridge only clocks bytes in and out of the ESP32's flash; the flash commands themselves
+//! (the common 25-series set, which the ESP32's flash chips all speak) are sent from here.
+
+use tilda_bridge_protocol::spiflash::MAX_TRANSFER_LENGTH;
+
+use crate::Result;
+
+const READ_JEDEC_ID: u8 = 0x9F;
+const READ_DATA: u8 = 0x03;
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const SECTOR_ERASE: u8 = 0x20;
+const PAGE_PROGRAM: u8 = 0x02;
+
+/// Write in progress bit of the status register.
+const STATUS_BUSY: u8 = 1 << 0;
+
+pub const SECTOR_SIZE: u32 = 4096;
+const PAGE_SIZE: u32 = 256;
+
+/// Status polls before giving up on an erase or program. A sector erase can take a few hundred
+/// milliseconds, and each poll is a couple of USB round trips.
+const BUSY_POLLS: usize = 10_000;
+
+/// A flash chip behind the bridge.
+pub struct SpiFlash<T> {
+    /// Sends `SPI_TRANSFER` with the given data and keep-CS flag, and returns the bytes clocked in.
+    transfer: T,
+}
+
+impl<T: FnMut(&[u8], bool) -> Result<Vec<u8>>> SpiFlash<T> {
+    pub fn new(transfer: T) -> Self {
+        SpiFlash { transfer }
+    }
+
+    /// Manufacturer, memory type and capacity.
+    pub fn jedec_id(&mut self) -> Result<[u8; 3]> {
+        let id = self.command(&[READ_JEDEC_ID], 3)?;
+        Ok([id[0], id[1], id[2]])
+    }
+
+    pub fn read(&mut self, address: u32, length: usize) -> Result<Vec<u8>> {
+        self.command(&with_address(READ_DATA, address), length)
+    }
+
+    /// Erases the sectors `data` covers and writes it. `address` must be at the start of a sector.
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        if !address.is_multiple_of(SECTOR_SIZE) {
+            return Err(format!("{:#x} isn't at the start of a flash sector", address).into());
+        }
+        let end = address + data.len() as u32;
+        for sector in (address..end).step_by(SECTOR_SIZE as usize) {
+            self.command(&[WRITE_ENABLE], 0)?;
+            self.command(&with_address(SECTOR_ERASE, sector), 0)?;
+            self.wait()?;
+        }
+        for (i, page) in data.chunks(PAGE_SIZE as usize).enumerate() {
+            self.command(&[WRITE_ENABLE], 0)?;
+            let mut command = with_address(PAGE_PROGRAM, address + i as u32 * PAGE_SIZE).to_vec();
+            command.extend_from_slice(page);
+            self.command(&command, 0)?;
+            self.wait()?;
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        for _ in 0..BUSY_POLLS {
+            if self.command(&[READ_STATUS], 1)?[0] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+        }
+        Err("the flash stayed busy".into())
+    }
+
+    /// Sends a command, then reads `length` bytes of its response, in as many transfers as it
+    /// takes with CS held low throughout.
+    fn command(&mut self, command: &[u8], length: usize) -> Result<Vec<u8>> {
+        let mut out = command.to_vec();
+        out.resize(command.len() + length, 0);
+        let mut response = Vec::with_capacity(out.len());
+        let mut chunks = out.chunks(MAX_TRANSFER_LENGTH).peekable();
+        while let Some(chunk) = chunks.next() {
+            let received = (self.transfer)(chunk, chunks.peek().is_some())?;
+            if received.len() != chunk.len() {
+                return Err("short GET_SPI_RESULT response".into());
+            }
+            response.extend_from_slice(&received);
+        }
+        Ok(response.split_off(command.len()))
+    }
+}
+
+fn with_address(command: u8, address: u32) -> [u8; 4] {
+    let [_, a2, a1, a0] = address.to_be_bytes();
+    [command, a2, a1, a0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough of a 25-series flash chip to check the command sequences.
+    struct FakeFlash {
+        memory: Vec<u8>,
+        /// Bytes clocked in since CS went low.
+        command: Vec<u8>,
+        write_enabled: bool,
+    }
+
+    impl FakeFlash {
+        fn transfer(&mut self, data: &[u8], keep_cs: bool) -> Vec<u8> {
+            let mut received = Vec::new();
+            for &byte in data {
+                received.push(self.reply());
+                self.command.push(byte);
+            }
+            if !keep_cs {
+                self.finish();
+            }
+            received
+        }
+
+        fn reply(&self) -> u8 {
+            let position = self.command.len();
+            match self.command.first() {
+                Some(&READ_JEDEC_ID) => [0xEF, 0x40, 0x16].get(position - 1).copied().unwrap_or(0),
+                Some(&READ_STATUS) => 0,
+                Some(&READ_DATA) if position >= 4 => self.memory[self.address() + position - 4],
+                _ => 0xFF,
+            }
+        }
+
+        fn address(&self) -> usize {
+            u32::from_be_bytes([0, self.command[1], self.command[2], self.command[3]]) as usize
+        }
+
+        /// CS goes high.
+        fn finish(&mut self) {
+            match self.command[0] {
+                WRITE_ENABLE => self.write_enabled = true,
+                SECTOR_ERASE if self.write_enabled => {
+                    let sector = self.address();
+                    self.memory[sector..sector + SECTOR_SIZE as usize].fill(0xFF);
+                }
+                PAGE_PROGRAM if self.write_enabled => {
+                    assert!(self.command.len() - 4 <= PAGE_SIZE as usize);
+                    let address = self.address();
+                    for (i, &byte) in self.command[4..].iter().enumerate() {
+                        self.memory[address + i] &= byte;
+                    }
+                }
+                _ => {}
+            }
+            if self.command[0] != WRITE_ENABLE && self.command[0] != READ_STATUS {
+                self.write_enabled = false;
+            }
+            self.command.clear();
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let mut chip = FakeFlash {
+            memory: vec![0; 4 * SECTOR_SIZE as usize],
+            command: Vec::new(),
+            write_enabled: false,
+        };
+        let mut flash = SpiFlash::new(|data: &[u8], keep_cs| {
+            assert!(data.len() <= MAX_TRANSFER_LENGTH);
+            Ok(chip.transfer(data, keep_cs))
+        });
+
+        assert_eq!(flash.jedec_id().unwrap(), [0xEF, 0x40, 0x16]);
+        assert!(flash.write(0x100, &[1]).is_err());
+
+        let image: Vec<u8> = (0..5000u32).map(|i| i as u8 ^ 0x5A).collect();
+        flash.write(SECTOR_SIZE, &image).unwrap();
+        assert_eq!(flash.read(SECTOR_SIZE, image.len()).unwrap(), image);
+        // The rest of the last sector is erased, and its neighbours are left alone.
+        assert_eq!(flash.read(SECTOR_SIZE + 5000, 3).unwrap(), [0xFF; 3]);
+        assert_eq!(flash.read(0, 2).unwrap(), [0; 2]);
+        assert_eq!(flash.read(3 * SECTOR_SIZE, 2).unwrap(), [0; 2]);
+    }
+}
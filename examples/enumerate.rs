@@ -0,0 +1,69 @@
+//! Enumerates the bridge's composite USB device on a simulated bus and dumps every descriptor the
+//! hardware would send, without needing a badge:
+//!
+//! `cargo run --example enumerate --features std --target x86_64-unknown-linux-gnu`
+
+use tilda_stm::mock::MockBus;
+use tilda_stm::webusb::WebUSB;
+use usb_device::class_prelude::*;
+use usb_device::prelude::*;
+use usbd_serial::SerialPort;
+
+const DESCRIPTOR_DEVICE: u8 = 0x01;
+const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_STRING: u8 = 0x03;
+const DESCRIPTOR_BOS: u8 = 0x0F;
+
+fn dump(name: &str, data: &[u8]) {
+    println!("{} ({} bytes):", name, data.len());
+    for line in data.chunks(16) {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("    {}", hex.join(" "));
+    }
+}
+
+fn main() {
+    let bus = MockBus::new();
+    let alloc = UsbBusAllocator::new(bus.clone());
+
+    // Same composite device as the firmware
+    let mut usb_serial = SerialPort::new(&alloc);
+    let mut webusb = WebUSB::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("Electromagnetic Field")
+        .product("TiLDA MkV")
+        .serial_number("SIMULATED")
+        .max_power(500)
+        .build();
+
+    let mut poll = || {
+        usb_dev.poll(&mut [&mut usb_serial, &mut webusb]);
+    };
+
+    bus.enumerate(&mut poll).expect("enumeration failed");
+
+    let device = bus.get_descriptor(&mut poll, DESCRIPTOR_DEVICE, 0, 18).unwrap();
+    dump("Device descriptor", &device);
+
+    let config = bus.get_descriptor(&mut poll, DESCRIPTOR_CONFIGURATION, 0, 0xFF).unwrap();
+    dump("Configuration descriptor", &config);
+
+    let bos = bus.get_descriptor(&mut poll, DESCRIPTOR_BOS, 0, 0xFF).unwrap();
+    dump("BOS descriptor", &bos);
+
+    for index in 1..=3 {
+        let string = bus.get_descriptor(&mut poll, DESCRIPTOR_STRING, index, 0xFF).unwrap();
+        let text: Vec<u16> = string[2..]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        println!("String {}: {:?}", index, String::from_utf16_lossy(&text));
+    }
+
+    // WebUSB and MS OS 2.0 vendor codes from the BOS platform capabilities
+    let url = bus.control_read(&mut poll, 0xC0, 0x42, 1, 0x02, 0xFF).unwrap();
+    dump("WebUSB URL descriptor", &url);
+
+    let ms_os = bus.control_read(&mut poll, 0xC0, 0x43, 0, 0x07, 0xFFFF).unwrap();
+    dump("MS OS 2.0 descriptor set", &ms_os);
+}
@@ -0,0 +1,72 @@
+//! Feeds arbitrary control transfers and bulk packets into the WebUSB class.
+//!
+//! The input is a sequence of operations, each starting with an opcode byte:
+//!
+//! * `0` - an 8 byte SETUP packet on endpoint 0
+//! * `1` - a length byte followed by an OUT data packet on endpoint 0
+//! * `2` - a length byte followed by an OUT packet on the WebUSB bulk endpoint
+//! * `3` - a length byte followed by data written to the WebUSB port
+//! * `4` - a bus reset
+//!
+//! The device is polled after every operation, and anything it sends is drained.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tilda_stm::mock::MockBus;
+use tilda_stm::webusb::WebUSB;
+use usb_device::class_prelude::*;
+use usb_device::prelude::*;
+
+/// The WebUSB class's bulk OUT endpoint, when it's the only class on the bus.
+const BULK_OUT: usize = 1;
+
+fuzz_target!(|data: &[u8]| {
+    let bus = MockBus::new();
+    let alloc = UsbBusAllocator::new(bus.clone());
+    let mut webusb = WebUSB::new(&alloc);
+    let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+
+    let mut input = data;
+    let mut buf = [0u8; 256];
+
+    loop {
+        let (&op, rest) = match input.split_first() {
+            Some(split) => split,
+            None => break,
+        };
+        input = rest;
+
+        match op % 5 {
+            0 if input.len() >= 8 => {
+                let mut setup = [0u8; 8];
+                setup.copy_from_slice(&input[..8]);
+                bus.push_setup(&setup);
+                input = &input[8..];
+            }
+            1..=3 if !input.is_empty() => {
+                let len = (input[0] as usize).min(input.len() - 1);
+                let payload = &input[1..1 + len];
+                input = &input[1 + len..];
+
+                match op % 5 {
+                    1 => bus.push_out(0, &payload[..len.min(64)]),
+                    2 => bus.push_out(BULK_OUT, &payload[..len.min(64)]),
+                    _ => {
+                        webusb.write(payload).ok();
+                    }
+                }
+            }
+            4 => bus.bus_reset(),
+            _ => break,
+        }
+
+        for _ in 0..4 {
+            usb_dev.poll(&mut [&mut webusb]);
+            webusb.read(&mut buf).ok();
+        }
+        for ep in 0..3 {
+            bus.take_in(ep);
+        }
+    }
+});
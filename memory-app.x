@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* The bootloader takes the first 8K, see src/dfu.rs. The last 1K page is reserved for
+     configuration, see src/config.rs */
+  FLASH (rx) : ORIGIN = 0x08002000, LENGTH = 23K
+  /* The first 256 bytes hold a copy of the vector table and the boot flag */
+  RAM  (rwx) : ORIGIN = 0x20000100, LENGTH =  6K - 256
+}
@@ -0,0 +1,7 @@
+/* Memory layout for the bootloader, see src/dfu.rs */
+MEMORY
+{
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 8K
+  /* The boot flag lives below this, and must survive the bootloader's RAM initialisation */
+  RAM  (rwx) : ORIGIN = 0x20000100, LENGTH =  6K - 256
+}
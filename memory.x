@@ -1,5 +0,0 @@
-MEMORY
-{
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 32K
-  RAM  (rwx) : ORIGIN = 0x20000000, LENGTH =  6K
-}
@@ -0,0 +1,85 @@
+//! Error log
+//!
+//! Builds with the `error-log` feature keep the last few errors in the configuration page, so a
+//! badge which fails now and then in the field leaves evidence behind. Each kind of error is
+//! logged at most once a boot, so one which repeats doesn't wear the flash out. `GET_ERROR_LOG`
+//! returns the records, oldest first, and `CLEAR_ERROR_LOG` empties the log. Each record is:
+//!
+//! | Offset | Size | Field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 1    | Kind (`RESET` to `UART_OVERRUN`)                       |
+//! | 1      | 1    | Detail: the `ResetCause` bits for `RESET`, otherwise 0 |
+//! | 2      | 2    | Boot it happened in, the low half of the boot count    |
+//! | 4      | 4    | Seconds since that boot started                        |
+
+/// Length of a record.
+pub const ENTRY_LENGTH: usize = 8;
+
+/// Most records kept. Once the log is full, the older half is dropped to make room.
+pub const ENTRIES: usize = 16;
+
+/// Longest `GET_ERROR_LOG` response.
+pub const LOG_LENGTH: usize = ENTRY_LENGTH * ENTRIES;
+
+/// Kind: the bridge was reset by a watchdog, a low-power mode or a stack overflow.
+pub const RESET: u8 = 1;
+
+/// Kind: the firmware panicked, and restarted.
+pub const PANIC: u8 = 2;
+
+/// Kind: the WebUSB OUT endpoint got stuck, and was recovered.
+pub const USB_RECOVERY: u8 = 3;
+
+/// Kind: the bridge dropped off the bus and re-attached, as the host had stopped talking to it.
+pub const REATTACH: u8 = 4;
+
+/// Kind: a byte from the ESP32 arrived before the last one was read, and was lost.
+pub const UART_OVERRUN: u8 = 5;
+
+/// An error record.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Entry {
+    pub kind: u8,
+    pub detail: u8,
+    pub boot: u16,
+    pub uptime: u32,
+}
+
+impl Entry {
+    /// Encodes the record.
+    pub fn to_bytes(self) -> [u8; ENTRY_LENGTH] {
+        let mut buf = [0u8; ENTRY_LENGTH];
+        buf[0] = self.kind;
+        buf[1] = self.detail;
+        buf[2..4].copy_from_slice(&self.boot.to_le_bytes());
+        buf[4..].copy_from_slice(&self.uptime.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a record. Returns `None` if it's the wrong length.
+    pub fn parse(buf: &[u8]) -> Option<Entry> {
+        if buf.len() != ENTRY_LENGTH {
+            return None;
+        }
+        Some(Entry {
+            kind: buf[0],
+            detail: buf[1],
+            boot: u16::from_le_bytes([buf[2], buf[3]]),
+            uptime: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = Entry { kind: RESET, detail: 0x12, boot: 0x0304, uptime: 0x0506_0708 };
+        let buf = entry.to_bytes();
+        assert_eq!(buf, [1, 0x12, 4, 3, 8, 7, 6, 5]);
+        assert_eq!(Entry::parse(&buf), Some(entry));
+        assert_eq!(Entry::parse(&buf[1..]), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Notifications on the interrupt endpoint
+//!
+//! UART receive errors are reported as CDC `SERIAL_STATE` notifications: an 8 byte notification
+//! header with `bNotification` = `SERIAL_STATE`, followed by a little-endian `u16` of these bits.
+//!
+//! When the ESP32 prints its reset banner, an `ESP_RESET` vendor notification (`bmRequestType`
+//! 0xC1) follows with two bytes: the ESP32's reset cause (the `rst:` value, see `esp_reset_name`)
+//! and its boot mode strapping (the `boot:` value).
+//!
+//! When the bridge's supply sags below its brown-out warning threshold, a `SUPPLY` vendor
+//! notification follows with one byte: 1 when it drops, and 0 when it comes back. Settings aren't
+//! written to flash in between.
+//!
+//! When the ESP32's output matches one of the host's `SET_WATCH` patterns, a `PATTERN` vendor
+//! notification follows with the slot, then what came after the match up to the end of the line or
+//! `PATTERN_SNIPPET_LENGTH` bytes, whichever is first. More can be read back with `GET_CAPTURE`.
+//!
+//! When the UART changes to a baud rate set on the serial port, or the host sets one on the WebUSB
+//! port which the UART doesn't take as the `line_coding` setting has it follow the serial port, a
+//! `UART_RATE` vendor notification follows with five bytes: the rate the UART really runs at (a
+//! little-endian `u32`), then the port whose line coding it follows (`UART_RATE_SERIAL` or
+//! `UART_RATE_WEBUSB`).
+
+/// `bNotification` of a SERIAL_STATE notification.
+pub const SERIAL_STATE: u8 = 0x20;
+
+/// A framing error was seen.
+pub const SERIAL_STATE_FRAMING: u16 = 1 << 4;
+
+/// A parity error was seen.
+pub const SERIAL_STATE_PARITY: u16 = 1 << 5;
+
+/// Received data was lost.
+pub const SERIAL_STATE_OVERRUN: u16 = 1 << 6;
+
+/// `bNotification` of an ESP_RESET notification.
+pub const ESP_RESET: u8 = 0x80;
+
+/// ESP32 reset cause: the supply dipped below the brown-out threshold.
+pub const ESP_RESET_BROWNOUT: u8 = 0x0F;
+
+/// `bNotification` of a SUPPLY notification.
+pub const SUPPLY: u8 = 0x81;
+
+/// `bNotification` of a PATTERN notification.
+pub const PATTERN: u8 = 0x82;
+
+/// `bNotification` of a UART_RATE notification.
+pub const UART_RATE: u8 = 0x83;
+
+/// UART_RATE port: the CDC serial port.
+pub const UART_RATE_SERIAL: u8 = 0;
+
+/// UART_RATE port: the WebUSB port.
+pub const UART_RATE_WEBUSB: u8 = 1;
+
+/// Longest snippet in a PATTERN notification, which with the header and slot fills the 16 byte
+/// interrupt endpoint.
+pub const PATTERN_SNIPPET_LENGTH: usize = 7;
+
+/// Names an ESP32 reset cause, as the ROM banner does.
+pub fn esp_reset_name(cause: u8) -> Option<&'static str> {
+    Some(match cause {
+        0x01 => "POWERON_RESET",
+        0x03 => "SW_RESET",
+        0x04 => "OWDT_RESET",
+        0x05 => "DEEPSLEEP_RESET",
+        0x06 => "SDIO_RESET",
+        0x07 => "TG0WDT_SYS_RESET",
+        0x08 => "TG1WDT_SYS_RESET",
+        0x09 => "RTCWDT_SYS_RESET",
+        0x0A => "INTRUSION_RESET",
+        0x0B => "TGWDT_CPU_RESET",
+        0x0C => "SW_CPU_RESET",
+        0x0D => "RTCWDT_CPU_RESET",
+        0x0E => "EXT_CPU_RESET",
+        ESP_RESET_BROWNOUT => "RTCWDT_BROWN_OUT_RESET",
+        0x10 => "RTCWDT_RTC_RESET",
+        _ => return None,
+    })
+}
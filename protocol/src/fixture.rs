@@ -0,0 +1,101 @@
+//! Fixture pin walk
+//!
+//! For bed-of-nails testing of the bridge section, builds with the `fixture` feature walk the
+//! bridge's GPIOs when the host sends `FIXTURE_TEST`, and `GET_FIXTURE` returns the connectivity
+//! map. Every walked pin is pulled down while each in turn is driven high, then pulled up while
+//! each is driven low, sampling all of them each time. A pin which followed both ways is connected
+//! to the one being driven, through the board or the fixture.
+//!
+//! Pins are numbered as bits of a `u32`: GPIOA's in bits 0 to 15, and GPIOB's in 16 to 31. The
+//! response is little-endian `u32` pin masks:
+//!
+//! | Offset | Field                                                                      |
+//! |--------|----------------------------------------------------------------------------|
+//! | 0      | Pins which read high with everything pulled down                           |
+//! | 4      | Pins which read low with everything pulled up                              |
+//! | 8      | For each pin in `PINS`, lowest first, the pins which followed it, including |
+//! |        | itself if it read back what it was driven to                               |
+
+use crate::request::FIXTURE_LENGTH;
+
+/// The pins walked: PA0 to PA10, PA15 and PB0 to PB7. USB (PA11, PA12) and SWD (PA13, PA14) are
+/// left alone.
+pub const PINS: u32 = 0x00FF_87FF;
+
+/// Number of pins walked.
+pub const PIN_COUNT: usize = PINS.count_ones() as usize;
+
+/// What the walk found.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct FixtureMap {
+    /// Pins which read high with everything pulled down.
+    pub stuck_high: u32,
+
+    /// Pins which read low with everything pulled up.
+    pub stuck_low: u32,
+
+    /// For each pin in `PINS`, lowest first, the pins which followed it both ways.
+    pub links: [u32; PIN_COUNT],
+}
+
+impl FixtureMap {
+    /// Encodes the map for the host.
+    pub fn to_bytes(&self) -> [u8; FIXTURE_LENGTH] {
+        let mut buf = [0u8; FIXTURE_LENGTH];
+        let (stuck, links) = buf.split_at_mut(8);
+        stuck[..4].copy_from_slice(&self.stuck_high.to_le_bytes());
+        stuck[4..].copy_from_slice(&self.stuck_low.to_le_bytes());
+        for (chunk, link) in links.chunks_exact_mut(4).zip(&self.links) {
+            chunk.copy_from_slice(&link.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decodes a `GET_FIXTURE` response. Returns `None` if it's the wrong length.
+    pub fn parse(buf: &[u8]) -> Option<FixtureMap> {
+        if buf.len() != FIXTURE_LENGTH {
+            return None;
+        }
+        let mut masks = buf.chunks_exact(4).map(|chunk| {
+            u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])
+        });
+        let mut map = FixtureMap {
+            stuck_high: masks.next()?,
+            stuck_low: masks.next()?,
+            ..FixtureMap::default()
+        };
+        for (link, mask) in map.links.iter_mut().zip(masks) {
+            *link = mask;
+        }
+        Some(map)
+    }
+}
+
+/// The walked pins' numbers, lowest first.
+pub fn pins() -> impl Iterator<Item = u32> {
+    (0..32).filter(|pin| PINS & 1 << pin != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_round_trip() {
+        let mut map = FixtureMap {
+            stuck_high: 1 << 17,
+            stuck_low: 1 << 3,
+            ..FixtureMap::default()
+        };
+        map.links[PIN_COUNT - 1] = 0x8000_0001;
+        let buf = map.to_bytes();
+        assert_eq!(buf[..4], [0, 0, 2, 0]);
+        assert_eq!(buf[FIXTURE_LENGTH - 4..], [1, 0, 0, 0x80]);
+        assert_eq!(FixtureMap::parse(&buf), Some(map));
+        assert_eq!(FixtureMap::parse(&buf[1..]), None);
+
+        assert_eq!(pins().count(), PIN_COUNT);
+        assert_eq!(pins().nth(11), Some(15));
+        assert_eq!(pins().last(), Some(23));
+    }
+}
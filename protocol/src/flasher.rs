@@ -0,0 +1,107 @@
+//! ESP32 ROM loader commands through the bridge
+//!
+//! Builds with the `flasher` feature talk to the ESP32's ROM loader themselves, so flashing
+//! doesn't depend on the host getting esptool's timing right over USB. `SET_FLASHER` with `wValue`
+//! = 1 takes the UART away from both ports until it's sent with 0 or the next bus reset. The ESP32
+//! is reset into and out of its ROM loader with the WebUSB port's control lines, as esptool does.
+//!
+//! While the bridge has the UART, each `ROM_COMMAND` is one of esptool's serial protocol command
+//! packets, unframed: the 8 byte header (direction, command, size and checksum) and the data.
+//! `wValue` is how long to wait for the response, in units of `TIMEOUT_UNIT_MS`. The bridge SLIP
+//! frames the packet, and waits for the ROM's response, sending the packet again if it doesn't come
+//! in time. The host polls `GET_ROM_RESPONSE` until the state isn't `BUSY`; with `DONE`, the ROM's
+//! response packet follows, unframed, and its size field and status bytes say how long it is and
+//! whether the command worked. The order of the commands is up to the host.
+//!
+//! To check what was written without reading megabytes back over USB, send `md5_command` with
+//! `md5_timeout` and take the ROM's digest out of the response with `md5_digest`.
+
+/// Longest `ROM_COMMAND` packet: a `FLASH_DATA` header, its 16 byte block header and a 64 byte
+/// block.
+pub const MAX_COMMAND_LENGTH: usize = 8 + 16 + 64;
+
+/// Length of the `GET_ROM_RESPONSE` response: the state, then room for a response packet with the
+/// ROM's hex MD5 and status bytes.
+pub const RESPONSE_LENGTH: usize = 1 + 8 + 32 + 4;
+
+/// Unit of the `ROM_COMMAND` timeout.
+pub const TIMEOUT_UNIT_MS: u32 = 10;
+
+/// Times a `ROM_COMMAND` is sent before giving up.
+pub const ATTEMPTS: u8 = 3;
+
+/// Response state: no command has been sent.
+pub const IDLE: u8 = 0;
+
+/// Response state: waiting for the ROM to answer. Further `ROM_COMMAND`s are stalled.
+pub const BUSY: u8 = 1;
+
+/// Response state: the ROM answered, and its response packet follows.
+pub const DONE: u8 = 2;
+
+/// Response state: the ROM didn't answer, however many times the command was sent.
+pub const TIMEOUT: u8 = 3;
+
+/// Length of the `SPI_FLASH_MD5` command packet built by `md5_command`.
+pub const MD5_COMMAND_LENGTH: usize = 8 + 16;
+
+/// The ROM loader's `SPI_FLASH_MD5` command.
+const SPI_FLASH_MD5: u8 = 0x13;
+
+/// Usual ROM loader command timeout, and how long the ROM can take to hash a megabyte on top.
+const MD5_BASE_TIMEOUT_MS: u32 = 3000;
+const MD5_MS_PER_MB: u32 = 8000;
+
+/// Builds the `ROM_COMMAND` packet which asks the ROM loader for the MD5 of `length` bytes of
+/// flash at `address`, so a host can check what it wrote without reading it all back.
+pub fn md5_command(address: u32, length: u32) -> [u8; MD5_COMMAND_LENGTH] {
+    let mut packet = [0; MD5_COMMAND_LENGTH];
+    packet[1] = SPI_FLASH_MD5;
+    packet[2] = 16;
+    packet[8..12].copy_from_slice(&address.to_le_bytes());
+    packet[12..16].copy_from_slice(&length.to_le_bytes());
+    packet
+}
+
+/// The `ROM_COMMAND` timeout for `md5_command`.
+pub fn md5_timeout(length: u32) -> u16 {
+    let ms = MD5_BASE_TIMEOUT_MS as u64 + MD5_MS_PER_MB as u64 * length as u64 / 0x10_0000;
+    (ms / TIMEOUT_UNIT_MS as u64).min(u16::MAX as u64) as u16
+}
+
+/// The digest, as 32 lowercase hex digits, from the `GET_ROM_RESPONSE` response to `md5_command`.
+/// Returns `None` if the ROM hasn't answered or the command failed.
+pub fn md5_digest(response: &[u8]) -> Option<&[u8]> {
+    // The state, the response header, the digest, then the ROM's status bytes
+    let packet = response.get(1..RESPONSE_LENGTH)?;
+    if response[0] != DONE || packet[1] != SPI_FLASH_MD5 || packet[8 + 32] != 0 {
+        return None;
+    }
+    Some(&packet[8..8 + 32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn md5() {
+        let packet = md5_command(0x10000, 0x20_0000);
+        assert_eq!(packet[..4], [0, 0x13, 16, 0]);
+        assert_eq!(packet[8..16], [0, 0, 1, 0, 0, 0, 0x20, 0]);
+        assert_eq!(md5_timeout(0), 300);
+        assert_eq!(md5_timeout(0x20_0000), 1900);
+        assert_eq!(md5_timeout(u32::MAX), u16::MAX);
+
+        let mut response = [0; RESPONSE_LENGTH];
+        response[..5].copy_from_slice(&[DONE, 1, 0x13, 36, 0]);
+        response[9..41].copy_from_slice(b"d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5_digest(&response), Some(&b"d41d8cd98f00b204e9800998ecf8427e"[..]));
+        response[41] = 1;
+        assert_eq!(md5_digest(&response), None);
+        response[41] = 0;
+        response[0] = BUSY;
+        assert_eq!(md5_digest(&response), None);
+        assert_eq!(md5_digest(&response[..10]), None);
+    }
+}
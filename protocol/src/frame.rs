@@ -0,0 +1,53 @@
+//! Timestamped UART frames
+//!
+//! In timestamp mode (see `request::SET_TIMESTAMPS`) the ESP32's output on the WebUSB data
+//! interface comes in frames: the bridge's uptime in milliseconds when the first byte arrived
+//! (little-endian `u32`), the number of data bytes, then the data. Frames follow each other with
+//! nothing in between, and may be split across packets.
+
+/// Length of a frame header.
+pub const HEADER_LENGTH: usize = 5;
+
+/// Most data in one frame, so a whole frame fits in a full-speed bulk packet.
+pub const MAX_DATA_LENGTH: usize = 64 - HEADER_LENGTH;
+
+/// Encodes a frame header.
+pub fn header(timestamp_ms: u32, length: u8) -> [u8; HEADER_LENGTH] {
+    let ts = timestamp_ms.to_le_bytes();
+    [ts[0], ts[1], ts[2], ts[3], length]
+}
+
+/// Splits the first frame off a stream, returning its timestamp, its data and the rest of the
+/// stream. Returns `None` if the stream doesn't hold a whole frame yet.
+pub fn split(stream: &[u8]) -> Option<(u32, &[u8], &[u8])> {
+    if stream.len() < HEADER_LENGTH {
+        return None;
+    }
+    let timestamp = u32::from_le_bytes([stream[0], stream[1], stream[2], stream[3]]);
+    let end = HEADER_LENGTH + stream[4] as usize;
+    if stream.len() < end {
+        return None;
+    }
+    Some((timestamp, &stream[HEADER_LENGTH..end], &stream[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_frames() {
+        let mut stream = header(1500, 3).to_vec();
+        stream.extend_from_slice(b"ets");
+        stream.extend_from_slice(&header(1502, 2));
+        stream.extend_from_slice(b"\r");
+
+        let (timestamp, data, rest) = split(&stream).unwrap();
+        assert_eq!((timestamp, data), (1500, &b"ets"[..]));
+        assert_eq!(split(rest), None);
+
+        stream.push(b'\n');
+        let (_, _, rest) = split(&stream).unwrap();
+        assert_eq!(split(rest), Some((1502, &b"\r\n"[..], &[][..])));
+    }
+}
@@ -0,0 +1,23 @@
+//! JTAG bit-banging
+//!
+//! Builds with the `jtag` feature drive the ESP32's JTAG pins from `JTAG_SHIFT` batches. Each byte
+//! of a batch sets TCK, TMS and TDI (the same bits as OpenOCD's `remote_bitbang` protocol), and TDO
+//! is sampled after each one. `GET_JTAG_TDO` then returns the samples, byte `i` of the batch in bit
+//! `i % 8` of byte `i / 8`.
+
+/// TCK bit of a batch byte.
+pub const TCK: u8 = 1 << 2;
+
+/// TMS bit of a batch byte.
+pub const TMS: u8 = 1 << 1;
+
+/// TDI bit of a batch byte.
+pub const TDI: u8 = 1 << 0;
+
+/// Longest batch the bridge takes.
+pub const MAX_BATCH_LENGTH: usize = 64;
+
+/// Length of the `GET_JTAG_TDO` response for a batch of `batch_length` bytes.
+pub const fn tdo_length(batch_length: usize) -> usize {
+    batch_length.div_ceil(8)
+}
@@ -0,0 +1,24 @@
+//! Wire formats of the TiLDA MkV USB bridge
+//!
+//! The vendor request numbers, payload layouts and notification bits used on the bridge's WebUSB
+//! interface. Both the firmware and host tools build against this crate, so they can't disagree
+//! about what goes over the wire. It's `no_std` unless the `std` feature is enabled.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod errorlog;
+pub mod event;
+pub mod fixture;
+pub mod flasher;
+pub mod frame;
+pub mod jtag;
+pub mod request;
+pub mod reset;
+pub mod selftest;
+pub mod settings;
+pub mod siphash;
+pub mod spiflash;
+pub mod telemetry;
+pub mod throughput;
+pub mod unlock;
+pub mod version;
@@ -0,0 +1,255 @@
+//! Vendor requests
+//!
+//! Vendor control requests addressed to the WebUSB communication interface (`bmRequestType`
+//! recipient = interface, `wIndex` = interface number). The README describes what each one does.
+
+/// OUT: run the end-of-line self-test.
+pub const SELF_TEST: u8 = 0x01;
+
+/// IN: the self-test report (`selftest::SelfTestReport`).
+pub const GET_SELF_TEST: u8 = 0x02;
+
+/// IN: the settings payload (`settings::Config`).
+pub const GET_CONFIG: u8 = 0x03;
+
+/// OUT: write the settings payload.
+pub const SET_CONFIG: u8 = 0x04;
+
+/// IN: supply rail, chip and uptime telemetry.
+pub const GET_TELEMETRY: u8 = 0x05;
+
+/// IN: USB and UART error counters.
+pub const GET_ERRORS: u8 = 0x06;
+
+/// OUT: identity challenge.
+pub const SET_CHALLENGE: u8 = 0x07;
+
+/// IN: identity response.
+pub const GET_IDENTITY: u8 = 0x08;
+
+/// IN: build time and commit.
+pub const GET_BUILD_INFO: u8 = 0x09;
+
+/// OUT: restart into the DFU bootloader.
+pub const DETACH: u8 = 0x0A;
+
+/// OUT: turn on flash readout protection. `wValue` must be `LOCK_KEY`.
+pub const LOCK: u8 = 0x0B;
+
+/// IN: status, currently just the reset cause (`reset::ResetCause`).
+pub const GET_STATUS: u8 = 0x0C;
+
+/// IN: protocol version and features (`version::Version`). `wValue` is the host's version.
+pub const GET_VERSION: u8 = 0x0D;
+
+/// OUT: `wValue` = 1 makes the WebUSB port a read-only monitor until the next bus reset, 2 makes it
+/// a monitor which gets a hex dump of the serial port's traffic instead, and 0 turns that off again.
+pub const SET_MONITOR: u8 = 0x0E;
+
+/// IN: a chunk of the ESP32's recent UART output. `wValue` is the offset into it; read
+/// `CAPTURE_CHUNK_LENGTH` at a time until a shorter chunk comes back.
+pub const GET_CAPTURE: u8 = 0x0F;
+
+/// OUT: `wValue` = 1 frames the ESP32's output on the WebUSB port with timestamps (`frame`) until
+/// the next bus reset, 0 turns that off again.
+pub const SET_TIMESTAMPS: u8 = 0x10;
+
+/// OUT: a batch of JTAG pin states to clock out (`jtag`). Only accepted by builds with the `jtag`
+/// feature.
+pub const JTAG_SHIFT: u8 = 0x11;
+
+/// IN: the TDO samples from the last `JTAG_SHIFT` batch.
+pub const GET_JTAG_TDO: u8 = 0x12;
+
+/// OUT: `wValue` = 1 holds the ESP32 in reset and takes over its SPI flash (`spiflash`), 0 lets
+/// go again. Only accepted by builds with the `spi-flash` feature.
+pub const SET_SPI_FLASH: u8 = 0x13;
+
+/// OUT: data to clock out to the ESP32's SPI flash. `wValue` is `spiflash::KEEP_CS` or 0.
+pub const SPI_TRANSFER: u8 = 0x14;
+
+/// IN: the data clocked in during the last `SPI_TRANSFER`.
+pub const GET_SPI_RESULT: u8 = 0x15;
+
+/// OUT: `wValue` = 1 takes the UART away from both ports for `ROM_COMMAND`s (`flasher`), and 0
+/// gives it back. Only accepted by builds with the `flasher` feature.
+pub const SET_FLASHER: u8 = 0x16;
+
+/// OUT: a command packet for the ESP32's ROM loader, with the response timeout in `wValue`.
+pub const ROM_COMMAND: u8 = 0x17;
+
+/// IN: the state of the last `ROM_COMMAND`, then the ROM's response to it.
+pub const GET_ROM_RESPONSE: u8 = 0x18;
+
+/// OUT: `wValue` = 1 gives the CDC serial port the ESP32's control lines and the UART until it's
+/// sent with 0 or the next bus reset, and 2 gives them to the WebUSB port. The other port still
+/// gets the ESP32's output, but its data and DTR/RTS are ignored.
+pub const CLAIM: u8 = 0x19;
+
+/// OUT: `wValue` = 1 makes the bridge drop off the bus and come back in compatibility mode, without
+/// the WebUSB and Microsoft OS 2.0 capabilities in its BOS descriptor, for hosts which can't parse
+/// them; 0 brings them back the same way. Compatibility mode lasts until the bridge restarts.
+pub const SET_PROFILE: u8 = 0x1A;
+
+/// OUT: what drives the ESP32's second strapping pin until the next bus reset: `wValue` = 0 pulls
+/// it low whenever IO0 is, 1 holds it low and 2 leaves it to the module. Only accepted by builds
+/// with the `strap-pin` feature.
+pub const SET_STRAP: u8 = 0x1B;
+
+/// IN: echoes `wValue`, followed by the bridge's microsecond clock (`u32`, wrapping after about 71
+/// minutes) as the request was handled, so hosts can measure the control path's latency and how
+/// long the firmware's loop takes to get round to a request.
+pub const PING: u8 = 0x1C;
+
+/// OUT: `wValue` = 1 starts the USB throughput test on the WebUSB data interface, with the counts
+/// from zero, until it's sent with 0 or the next bus reset. See `throughput`.
+pub const SET_THROUGHPUT: u8 = 0x1D;
+
+/// IN: the throughput test's counts. See `throughput`.
+pub const GET_THROUGHPUT: u8 = 0x1E;
+
+/// IN: how many bytes from the host are still waiting to go out of the UART, then how many it can
+/// hold (little-endian `u16`s). Both ports share it. While it's full, nothing more is read from
+/// USB and the host is NAKed, so a host which sends no more than the space left can pace itself
+/// without relying on NAKs.
+pub const GET_UART_QUEUE: u8 = 0x1F;
+
+/// IN: as `GET_ERRORS`, then the counters start again from zero, in one go so nothing counted in
+/// between is lost. For measuring over one session, such as a flash, rather than since startup.
+pub const TAKE_ERRORS: u8 = 0x20;
+
+/// OUT: `wValue` = slot (0 to `WATCH_SLOTS` - 1), data = a pattern of up to `WATCH_PATTERN_LENGTH`
+/// bytes to look for in the ESP32's output, or nothing to clear the slot. Each match is reported
+/// with a `PATTERN` notification (see `event`). The slots are cleared by a bus reset. Only accepted
+/// by builds with the `pattern-watch` feature.
+///
+/// From protocol version 1.22, the high byte of `wValue` is what the bridge does itself on a
+/// match, so it can recover a crashed ESP32 without waiting for the host: 0 nothing, 1 reset it,
+/// 2 reset it into download mode, 3 freeze the capture (see `GET_CAPTURE`) so it keeps what led up
+/// to the match until the host reads it.
+pub const SET_WATCH: u8 = 0x21;
+
+/// OUT: `wValue` = 0 for the host to the ESP32, or 1 for the ESP32 to the host, data = the most
+/// bytes a second to let through that way (little-endian `u32`), or 0 for no limit, to emulate a
+/// slow link. The ESP32 can't be held up, so what it sends over the limit is dropped, and counted
+/// as in `GET_ERRORS`. Both are lifted by a bus reset. Only accepted by builds with the `throttle`
+/// feature.
+pub const SET_THROTTLE: u8 = 0x22;
+
+/// OUT: `wValue` must be `FAULTS_KEY`, data = which ways to inject faults into the bridged bytes
+/// (`FAULTS_TO_ESP` and `FAULTS_FROM_ESP` bits), then the chances, in 256ths, of each byte being
+/// dropped, duplicated or having a bit flipped, for testing a protocol's error handling. All zero
+/// turns it off again, as does a bus reset. Only accepted by builds with the `fault-inject`
+/// feature.
+pub const SET_FAULTS: u8 = 0x23;
+
+/// OUT: `wValue` = the longest, in ms, to hold the start of a line of the ESP32's output on the
+/// WebUSB port waiting for the rest of it, up to `LINE_BUFFER_MAX_MS`, or 0 to send it as it comes
+/// again. Held output goes out at the end of a line, or once there's a packet's worth. Lifted by a
+/// bus reset, and has no effect in timestamp mode. Only accepted by builds with the `line-buffer`
+/// feature.
+pub const SET_LINE_BUFFER: u8 = 0x24;
+
+// 0x25 was `SET_PROVISION`, for the `provisioning` feature, which is gone. It isn't reused.
+
+/// OUT: `wValue` must be `FIXTURE_KEY`. Walks the bridge's GPIOs for a bed-of-nails test fixture
+/// (see `fixture`), driving them all in turn, so the ESP32 should be held in reset by the fixture
+/// or not fitted. USB isn't serviced while it runs, for under a millisecond. The bridge resets the
+/// ESP32 afterwards. Only accepted by builds with the `fixture` feature.
+pub const FIXTURE_TEST: u8 = 0x26;
+
+/// IN: the connectivity map from the last `FIXTURE_TEST` (`fixture::FixtureMap`). Stalled until the
+/// walk has run.
+pub const GET_FIXTURE: u8 = 0x27;
+
+/// IN: a new unlock nonce (see `unlock`), which replaces any earlier one. Only answered by builds
+/// with the `unlock` feature.
+pub const GET_UNLOCK_NONCE: u8 = 0x28;
+
+/// OUT: data = `unlock::response` for the last nonce, which lets the host send the requests in
+/// `unlock::GUARDED` until the next bus reset, or nothing to lock them again. A wrong response is
+/// stalled. Either way the nonce is used up. Only accepted by builds with the `unlock` feature.
+pub const UNLOCK: u8 = 0x29;
+
+/// IN: the error log (see `errorlog`), oldest record first, up to `errorlog::LOG_LENGTH` bytes.
+/// Only answered by builds with the `error-log` feature.
+pub const GET_ERROR_LOG: u8 = 0x2A;
+
+/// OUT: `wValue` must be `ERROR_LOG_KEY`. Clears the error log. Only accepted by builds with the
+/// `error-log` feature.
+pub const CLEAR_ERROR_LOG: u8 = 0x2B;
+
+/// Number of `SET_WATCH` slots.
+pub const WATCH_SLOTS: usize = 4;
+
+/// Longest `SET_WATCH` pattern.
+pub const WATCH_PATTERN_LENGTH: usize = 16;
+
+/// wValue required by `LOCK`, so it can't be sent by accident.
+pub const LOCK_KEY: u16 = 0x4C4B;
+
+/// Longest `SET_LINE_BUFFER` hold.
+pub const LINE_BUFFER_MAX_MS: u16 = 1000;
+
+/// `wValue` for `FIXTURE_TEST` ("FX").
+pub const FIXTURE_KEY: u16 = 0x4658;
+
+/// `wValue` for `CLEAR_ERROR_LOG` ("EL").
+pub const ERROR_LOG_KEY: u16 = 0x454C;
+
+/// `wValue` for `SET_FAULTS` ("FI").
+pub const FAULTS_KEY: u16 = 0x4649;
+
+/// `SET_FAULTS` direction bit: the host to the ESP32.
+pub const FAULTS_TO_ESP: u8 = 1 << 0;
+
+/// `SET_FAULTS` direction bit: the ESP32 to the host.
+pub const FAULTS_FROM_ESP: u8 = 1 << 1;
+
+/// Length of the `GET_SELF_TEST` response.
+pub const SELF_TEST_LENGTH: usize = 2;
+
+/// Length of the `GET_TELEMETRY` response.
+pub const TELEMETRY_LENGTH: usize = 48;
+
+/// Length of the `GET_ERRORS` response.
+pub const ERRORS_LENGTH: usize = 45;
+
+/// Length of the device's unique ID.
+pub const UID_LENGTH: usize = 12;
+
+/// Length of the `SET_CHALLENGE` data.
+pub const CHALLENGE_LENGTH: usize = 16;
+
+/// Length of the `GET_IDENTITY` response: the unique ID followed by the little-endian MAC.
+pub const IDENTITY_LENGTH: usize = UID_LENGTH + 8;
+
+/// Length of the `GET_BUILD_INFO` response.
+pub const BUILD_INFO_LENGTH: usize = 25;
+
+/// Length of the `GET_STATUS` response.
+pub const STATUS_LENGTH: usize = 1;
+
+/// Length of the `GET_VERSION` response.
+pub const VERSION_LENGTH: usize = 6;
+
+/// Length of the `PING` response.
+pub const PING_LENGTH: usize = 6;
+
+/// Length of the `GET_THROUGHPUT` response.
+pub const THROUGHPUT_LENGTH: usize = 12;
+
+/// Length of the `SET_THROTTLE` data.
+pub const THROTTLE_LENGTH: usize = 4;
+
+/// Length of the `SET_FAULTS` data.
+pub const FAULTS_LENGTH: usize = 4;
+
+/// Length of the `GET_UART_QUEUE` response.
+pub const UART_QUEUE_LENGTH: usize = 4;
+
+/// Length of the `GET_FIXTURE` response.
+pub const FIXTURE_LENGTH: usize = 8 + 4 * crate::fixture::PIN_COUNT;
+
+/// Largest chunk of the `GET_CAPTURE` response a host should ask for.
+pub const CAPTURE_CHUNK_LENGTH: usize = 64;
@@ -0,0 +1,78 @@
+//! Reset cause reporting
+//!
+//! The RCC latches a flag for each kind of reset until the firmware clears them. They're read once
+//! at startup and reported to the host, so a badge which rebooted by itself can be told apart from
+//! one which was unplugged or reset by the watchdog.
+
+/// Why the chip last reset. More than one flag can be set: every reset also pulses the reset pin,
+/// so the pin flag is set alongside the others, and the bootloader passing control to the firmware
+/// doesn't clear anything.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct ResetCause(u8);
+
+impl ResetCause {
+    /// The option bytes were reloaded, e.g. after the readout protection level changed.
+    pub const OPTION_BYTE_LOAD: u8 = 1 << 0;
+
+    /// The NRST pin was pulled low, e.g. by a debugger.
+    pub const PIN: u8 = 1 << 1;
+
+    /// Power-on or brown-out. The STM32F0 doesn't distinguish them.
+    pub const POWER_ON: u8 = 1 << 2;
+
+    /// Software reset, e.g. a restart into or out of the bootloader.
+    pub const SOFTWARE: u8 = 1 << 3;
+
+    /// Independent watchdog.
+    pub const INDEPENDENT_WATCHDOG: u8 = 1 << 4;
+
+    /// Window watchdog.
+    pub const WINDOW_WATCHDOG: u8 = 1 << 5;
+
+    /// Entering standby or stop mode while the option bytes forbid it.
+    pub const LOW_POWER: u8 = 1 << 6;
+
+    /// The firmware restarted itself because its stack had nearly run into its statics. Not an
+    /// RCC flag: it's passed across the reset in RAM, alongside a `SOFTWARE` reset.
+    pub const STACK_OVERFLOW: u8 = 1 << 7;
+
+    /// Decodes the flags in the RCC_CSR register.
+    pub fn from_csr(csr: u32) -> ResetCause {
+        // OBLRSTF to LPWRRSTF are bits 25 to 31, skipping the flag clear bit at 24 which reads as 0.
+        ResetCause((csr >> 25) as u8 & 0x7F)
+    }
+
+    /// Makes a cause from a bitfield of the constants above.
+    pub const fn from_bits(bits: u8) -> ResetCause {
+        ResetCause(bits)
+    }
+
+    /// The flags, as a bitfield of the constants above.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether any of `flags` are set.
+    pub fn contains(self, flags: u8) -> bool {
+        self.0 & flags != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_csr() {
+        // Power-on: PORRSTF and PINRSTF, plus the LSI bits in the bottom of the register
+        let cause = ResetCause::from_csr(0x0C00_0003);
+        assert_eq!(cause.bits(), ResetCause::POWER_ON | ResetCause::PIN);
+        assert!(!cause.contains(ResetCause::INDEPENDENT_WATCHDOG));
+
+        let cause = ResetCause::from_csr(0x2400_0000);
+        assert!(cause.contains(ResetCause::INDEPENDENT_WATCHDOG));
+        assert_eq!(ResetCause::from_csr(0x8000_0000).bits(), ResetCause::LOW_POWER);
+        assert_eq!(ResetCause::from_csr(0x0200_0000).bits(), ResetCause::OPTION_BYTE_LOAD);
+        assert_eq!(ResetCause::from_csr(0xFFFF_FFFF).bits(), 0x7F);
+    }
+}
@@ -0,0 +1,66 @@
+//! Self-test report
+//!
+//! Returned by `GET_SELF_TEST` after the host has run the end-of-line self-test with `SELF_TEST`.
+
+use crate::request::SELF_TEST_LENGTH;
+
+/// Results of the individual self-test checks.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct SelfTestReport {
+    /// EN pin followed both output levels.
+    pub esp_en: bool,
+
+    /// IO0 pin followed both output levels.
+    pub esp_gpio0: bool,
+
+    /// The ESP32 ROM loader answered a SYNC over the UART.
+    pub uart_sync: bool,
+
+    /// The configuration page is erased or valid.
+    pub config: bool,
+}
+
+impl SelfTestReport {
+    /// Report status byte: the test hasn't been run yet (or is running).
+    pub const NOT_RUN: u8 = 0;
+
+    /// Report status byte: all checks passed.
+    pub const PASS: u8 = 1;
+
+    /// Report status byte: at least one check failed.
+    pub const FAIL: u8 = 2;
+
+    /// Whether every check passed.
+    pub fn passed(&self) -> bool {
+        self.esp_en && self.esp_gpio0 && self.uart_sync && self.config
+    }
+
+    /// Encodes the report for the host: a status byte followed by a bitfield of passed checks.
+    pub fn to_bytes(self) -> [u8; SELF_TEST_LENGTH] {
+        let status = if self.passed() { Self::PASS } else { Self::FAIL };
+        let checks = (self.esp_en as u8)
+            | (self.esp_gpio0 as u8) << 1
+            | (self.uart_sync as u8) << 2
+            | (self.config as u8) << 3;
+        [status, checks]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_encoding() {
+        let mut report = SelfTestReport {
+            esp_en: true,
+            esp_gpio0: true,
+            uart_sync: true,
+            config: true,
+        };
+        assert_eq!(report.to_bytes(), [SelfTestReport::PASS, 0x0F]);
+
+        report.uart_sync = false;
+        assert_eq!(report.to_bytes(), [SelfTestReport::FAIL, 0x0B]);
+    }
+}
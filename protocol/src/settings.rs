@@ -0,0 +1,396 @@
+//! Settings
+//!
+//! The settings payload read with `GET_CONFIG` and written with `SET_CONFIG`, which is also what
+//! the firmware stores in flash. It holds one byte per setting at a fixed offset, then the
+//! nickname, then the settings added after it. A shorter payload written by older firmware or host
+//! tools leaves the newer settings at their defaults.
+
+/// Length of the settings payload written by this version.
+pub const PAYLOAD_LENGTH: usize = 12 + NICKNAME_LENGTH;
+
+/// Longest nickname, in characters.
+pub const NICKNAME_LENGTH: usize = 16;
+
+/// LED brightness at full, in eighths.
+pub const FULL_BRIGHTNESS: u8 = 8;
+
+/// Offset of the first setting after the nickname.
+const AFTER_NICKNAME: usize = 9 + NICKNAME_LENGTH;
+
+/// Line option bit: CR from the host goes to the ESP32 as LF.
+pub const LINE_CR_TO_LF: u8 = 1 << 0;
+
+/// Line option bit: LF from the ESP32 goes to the host as CR LF.
+pub const LINE_LF_TO_CRLF: u8 = 1 << 1;
+
+/// Line option bit: data from the host is echoed back to it, as the ESP32's output would be.
+pub const LINE_ECHO: u8 = 1 << 2;
+
+const LINE_OPTIONS: u8 = LINE_CR_TO_LF | LINE_LF_TO_CRLF | LINE_ECHO;
+
+/// What to do with the ESP32 while the USB bus is suspended.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EspSuspendPolicy {
+    /// Hold the ESP32 in reset (EN low) until the bus resumes.
+    PowerDown = 0,
+
+    /// Leave the ESP32 running.
+    KeepRunning = 1,
+}
+
+impl EspSuspendPolicy {
+    fn from_u8(value: u8) -> Option<EspSuspendPolicy> {
+        match value {
+            0 => Some(EspSuspendPolicy::PowerDown),
+            1 => Some(EspSuspendPolicy::KeepRunning),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with the ESP32 when the host closes the serial port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PortClosePolicy {
+    /// Reset the ESP32.
+    Reset = 0,
+
+    /// Leave the ESP32 running.
+    KeepRunning = 1,
+
+    /// Set EN and IO0 from `Config::close_strapping`.
+    Strapping = 2,
+}
+
+impl PortClosePolicy {
+    fn from_u8(value: u8) -> Option<PortClosePolicy> {
+        match value {
+            0 => Some(PortClosePolicy::Reset),
+            1 => Some(PortClosePolicy::KeepRunning),
+            2 => Some(PortClosePolicy::Strapping),
+            _ => None,
+        }
+    }
+}
+
+/// Whose DTR/RTS drive the ESP32 when both ports are open.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ControlLinePolicy {
+    /// A line is asserted if it's asserted on either port.
+    Either = 0,
+
+    /// The port which changed its lines most recently.
+    LastChanged = 1,
+
+    /// The CDC serial port's, while it has DTR or RTS asserted, otherwise the WebUSB port's.
+    SerialFirst = 2,
+
+    /// The WebUSB port's, while it has DTR or RTS asserted, otherwise the CDC serial port's.
+    WebUsbFirst = 3,
+}
+
+impl ControlLinePolicy {
+    fn from_u8(value: u8) -> Option<ControlLinePolicy> {
+        match value {
+            0 => Some(ControlLinePolicy::Either),
+            1 => Some(ControlLinePolicy::LastChanged),
+            2 => Some(ControlLinePolicy::SerialFirst),
+            3 => Some(ControlLinePolicy::WebUsbFirst),
+            _ => None,
+        }
+    }
+}
+
+/// Whose line coding sets the UART's baud rate. There's one UART, so the other port is only told
+/// what it's really running at.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineCodingPolicy {
+    /// The port which set its line coding most recently.
+    LastChanged = 0,
+
+    /// The CDC serial port's, once it has set one.
+    Serial = 1,
+
+    /// The WebUSB port's, once it has set one.
+    WebUsb = 2,
+}
+
+impl LineCodingPolicy {
+    fn from_u8(value: u8) -> Option<LineCodingPolicy> {
+        match value {
+            0 => Some(LineCodingPolicy::LastChanged),
+            1 => Some(LineCodingPolicy::Serial),
+            2 => Some(LineCodingPolicy::WebUsb),
+            _ => None,
+        }
+    }
+}
+
+/// Bridge settings stored in the configuration page.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// ESP32 power while the USB bus is suspended.
+    pub esp_suspend: EspSuspendPolicy,
+
+    /// Seconds without USB or UART traffic before entering low power mode, or 0 to never.
+    pub idle_timeout: u8,
+
+    /// Tenths of a second after enumeration during which DTR/RTS changes are ignored.
+    pub control_line_grace: u8,
+
+    /// ESP32 handling when the host closes the serial port.
+    pub port_close: PortClosePolicy,
+
+    /// EN (bit 0) and IO0 (bit 1) levels for `PortClosePolicy::Strapping`.
+    pub close_strapping: u8,
+
+    /// `LINE_*` bits for the CDC serial port.
+    pub serial_line: u8,
+
+    /// `LINE_*` bits for the WebUSB port.
+    pub webusb_line: u8,
+
+    /// How the two ports' DTR/RTS are combined.
+    pub control_lines: ControlLinePolicy,
+
+    /// Whether the WebUSB descriptor advertises the landing page, which Chrome offers to open when
+    /// the bridge is plugged in. Takes effect when the bridge next enumerates.
+    pub landing_page: bool,
+
+    /// A name for the badge, added to the USB product string so it can be picked out from others.
+    /// Printable ASCII, padded with zeros. Takes effect when the bridge next starts.
+    pub nickname: [u8; NICKNAME_LENGTH],
+
+    /// LED brightness in eighths while the badge runs from its battery, with the `led-dim` feature.
+    pub battery_brightness: u8,
+
+    /// Battery voltage in tenths of a volt below which the LED stays off, with the `led-dim` and
+    /// `battery-sense` features, or 0 to never turn it off.
+    pub battery_cutoff: u8,
+
+    /// Which port's line coding the UART follows.
+    pub line_coding: LineCodingPolicy,
+}
+
+impl Default for Config {
+    #[inline(never)]
+    fn default() -> Self {
+        Config {
+            esp_suspend: EspSuspendPolicy::KeepRunning,
+            idle_timeout: 60,
+            control_line_grace: 10,
+            port_close: PortClosePolicy::KeepRunning,
+            close_strapping: 0b11,
+            serial_line: 0,
+            webusb_line: 0,
+            control_lines: ControlLinePolicy::Either,
+            landing_page: true,
+            nickname: [0; NICKNAME_LENGTH],
+            battery_brightness: 2,
+            battery_cutoff: 0,
+            line_coding: LineCodingPolicy::LastChanged,
+        }
+    }
+}
+
+impl Config {
+    /// Parses a settings payload. Returns `None` if any setting has an invalid value.
+    pub fn parse(payload: &[u8]) -> Option<Config> {
+        let mut config = Config::default();
+        if let Some(&value) = payload.first() {
+            config.esp_suspend = EspSuspendPolicy::from_u8(value)?;
+        }
+        if let Some(&value) = payload.get(1) {
+            config.idle_timeout = value;
+        }
+        if let Some(&value) = payload.get(2) {
+            config.control_line_grace = value;
+        }
+        if let Some(&value) = payload.get(3) {
+            config.port_close = PortClosePolicy::from_u8(value)?;
+        }
+        if let Some(&value) = payload.get(4) {
+            if value > 0b11 {
+                return None;
+            }
+            config.close_strapping = value;
+        }
+        if let Some(&value) = payload.get(5) {
+            if value & !LINE_OPTIONS != 0 {
+                return None;
+            }
+            config.serial_line = value;
+        }
+        if let Some(&value) = payload.get(6) {
+            if value & !LINE_OPTIONS != 0 {
+                return None;
+            }
+            config.webusb_line = value;
+        }
+        if let Some(&value) = payload.get(7) {
+            config.control_lines = ControlLinePolicy::from_u8(value)?;
+        }
+        if let Some(&value) = payload.get(8) {
+            if value > 1 {
+                return None;
+            }
+            config.landing_page = value == 1;
+        }
+        if let Some(nickname) = payload.get(9..) {
+            let len = nickname.len().min(NICKNAME_LENGTH);
+            config.nickname[..len].copy_from_slice(&nickname[..len]);
+            if !valid_name(&config.nickname) {
+                return None;
+            }
+        }
+        if let Some(&value) = payload.get(AFTER_NICKNAME) {
+            if value > FULL_BRIGHTNESS {
+                return None;
+            }
+            config.battery_brightness = value;
+        }
+        if let Some(&value) = payload.get(AFTER_NICKNAME + 1) {
+            config.battery_cutoff = value;
+        }
+        if let Some(&value) = payload.get(AFTER_NICKNAME + 2) {
+            config.line_coding = LineCodingPolicy::from_u8(value)?;
+        }
+        Some(config)
+    }
+
+    /// The nickname, or an empty string if there isn't one.
+    pub fn nickname(&self) -> &str {
+        name_str(&self.nickname)
+    }
+
+    /// Sets the nickname. Returns false, leaving it as it was, if it's longer than
+    /// `NICKNAME_LENGTH` or isn't printable ASCII.
+    pub fn set_nickname(&mut self, nickname: &str) -> bool {
+        pad_name(nickname, &mut self.nickname)
+    }
+
+    /// Encodes the settings payload.
+    #[inline(never)]
+    pub fn to_payload(self) -> [u8; PAYLOAD_LENGTH] {
+        let mut payload = [0u8; PAYLOAD_LENGTH];
+        payload[0] = self.esp_suspend as u8;
+        payload[1] = self.idle_timeout;
+        payload[2] = self.control_line_grace;
+        payload[3] = self.port_close as u8;
+        payload[4] = self.close_strapping;
+        payload[5] = self.serial_line;
+        payload[6] = self.webusb_line;
+        payload[7] = self.control_lines as u8;
+        payload[8] = self.landing_page as u8;
+        payload[9..AFTER_NICKNAME].copy_from_slice(&self.nickname);
+        payload[AFTER_NICKNAME] = self.battery_brightness;
+        payload[AFTER_NICKNAME + 1] = self.battery_cutoff;
+        payload[AFTER_NICKNAME + 2] = self.line_coding as u8;
+        payload
+    }
+}
+
+/// Whether a name, such as the nickname, is printable ASCII followed by zeros. Anything after the
+/// first zero has to be padding, so the payload reads back the same.
+pub(crate) fn valid_name(name: &[u8]) -> bool {
+    let len = name.iter().take_while(|&&b| b != 0).count();
+    name[..len].iter().all(|&b| (0x20..0x7F).contains(&b)) && name[len..].iter().all(|&b| b == 0)
+}
+
+/// A name padded with zeros, or an empty string if it isn't valid.
+pub(crate) fn name_str(name: &[u8]) -> &str {
+    if !valid_name(name) {
+        return "";
+    }
+    let len = name.iter().take_while(|&&b| b != 0).count();
+    // Safe as it's all ASCII. Not `from_utf8`, which is a lot of flash for the firmware.
+    unsafe { core::str::from_utf8_unchecked(&name[..len]) }
+}
+
+/// Pads a name with zeros into `padded`. Returns false, leaving `padded` as it was, if it doesn't
+/// fit or isn't printable ASCII.
+pub(crate) fn pad_name(name: &str, padded: &mut [u8]) -> bool {
+    if name.len() > padded.len() || !name.bytes().all(|b| (0x20..0x7F).contains(&b)) {
+        return false;
+    }
+    padded.fill(0);
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trip() {
+        let config = Config {
+            esp_suspend: EspSuspendPolicy::PowerDown,
+            idle_timeout: 0,
+            control_line_grace: 25,
+            port_close: PortClosePolicy::Strapping,
+            close_strapping: 0b10,
+            serial_line: LINE_CR_TO_LF,
+            webusb_line: LINE_LF_TO_CRLF | LINE_ECHO,
+            control_lines: ControlLinePolicy::WebUsbFirst,
+            landing_page: false,
+            nickname: *b"Ada\0\0\0\0\0\0\0\0\0\0\0\0\0",
+            battery_brightness: 5,
+            battery_cutoff: 34,
+            line_coding: LineCodingPolicy::WebUsb,
+        };
+        assert_eq!(Config::parse(&config.to_payload()), Some(config));
+        assert_eq!(config.nickname(), "Ada");
+    }
+
+    #[test]
+    fn nickname() {
+        let mut config = Config::default();
+        assert_eq!(config.nickname(), "");
+        assert!(config.set_nickname("Grace Hopper's"));
+        assert_eq!(config.nickname(), "Grace Hopper's");
+        assert!(!config.set_nickname("Ada, Countess of Lovelace"));
+        assert!(!config.set_nickname("A\0B"));
+        assert!(!config.set_nickname("Zoë"));
+        assert_eq!(config.nickname(), "Grace Hopper's");
+        assert!(config.set_nickname(""));
+        assert_eq!(config.nickname(), "");
+
+        // A shorter name is fine, but padding has to be zeros, and the name printable.
+        let mut payload = Config::default().to_payload().to_vec();
+        payload.truncate(12);
+        payload[9..12].copy_from_slice(b"Ada");
+        assert_eq!(Config::parse(&payload).unwrap().nickname(), "Ada");
+        payload.extend_from_slice(&[0, b'x']);
+        assert_eq!(Config::parse(&payload), None);
+        payload.truncate(12);
+        payload[11] = b'\n';
+        assert_eq!(Config::parse(&payload), None);
+    }
+
+    #[test]
+    fn short_payload() {
+        assert_eq!(Config::parse(&[]), Some(Config::default()));
+
+        let config = Config::parse(&[0]).unwrap();
+        assert_eq!(config.esp_suspend, EspSuspendPolicy::PowerDown);
+        assert_eq!(config.idle_timeout, Config::default().idle_timeout);
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(Config::parse(&[2]), None);
+        assert_eq!(Config::parse(&[0, 0, 0, 3]), None);
+        assert_eq!(Config::parse(&[0, 0, 0, 2, 4]), None);
+        assert_eq!(Config::parse(&[0, 0, 0, 2, 3, 0, 8]), None);
+        assert_eq!(Config::parse(&[0, 0, 0, 2, 3, 0, 0, 4]), None);
+        assert_eq!(Config::parse(&[0, 0, 0, 2, 3, 0, 0, 0, 2]), None);
+
+        let mut payload = Config::default().to_payload();
+        payload[AFTER_NICKNAME] = FULL_BRIGHTNESS + 1;
+        assert_eq!(Config::parse(&payload), None);
+
+        let mut payload = Config::default().to_payload();
+        payload[AFTER_NICKNAME + 2] = 3;
+        assert_eq!(Config::parse(&payload), None);
+    }
+}
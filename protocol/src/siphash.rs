@@ -0,0 +1,78 @@
+//! SipHash-2-4
+//!
+//! The MAC used by the identity challenge-response and the unlock handshake. The bridge and host
+//! tools which know the key both need it, so it lives here.
+
+/// SipHash-2-4 of `data` with a 128-bit key.
+pub fn siphash(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = read_u64(&key[..8]);
+    let k1 = read_u64(&key[8..]);
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = read_u64(chunk);
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    // The last block holds the remaining bytes and the length of the message.
+    let mut last = [0u8; 8];
+    let remainder = chunks.remainder();
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[7] = data.len() as u8;
+    let m = read_u64(&last);
+    v[3] ^= m;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xFF;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    #[test]
+    fn reference_vectors() {
+        // From the SipHash paper's reference implementation: key 00..0f, message 00..(n-1)
+        let message: Vec<u8> = (0..16).collect();
+        assert_eq!(siphash(&KEY, &message[..0]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash(&KEY, &message[..1]), 0x74f8_39c5_93dc_67fd);
+        assert_eq!(siphash(&KEY, &message[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash(&KEY, &message[..15]), 0xa129_ca61_49be_45e5);
+    }
+}
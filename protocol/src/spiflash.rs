@@ -0,0 +1,16 @@
+//! Direct SPI flash access
+//!
+//! Builds with the `spi-flash` feature can program the ESP32's SPI flash themselves, for modules
+//! whose ROM loader can't be reached. `SET_SPI_FLASH` with `wValue` = 1 holds the ESP32 in reset and
+//! drives the flash pins, and 0 lets go of them again.
+//!
+//! In between, each `SPI_TRANSFER` clocks its data out to the flash (mode 0, most significant bit
+//! first) with CS low, and `GET_SPI_RESULT` returns what came back at the same time. CS goes high
+//! again at the end of the transfer unless `wValue` has `KEEP_CS` set, so a command longer than one
+//! transfer (a page program, or a long read) can be split up.
+
+/// `SPI_TRANSFER` `wValue` bit: leave CS low afterwards.
+pub const KEEP_CS: u16 = 1;
+
+/// Longest transfer the bridge takes.
+pub const MAX_TRANSFER_LENGTH: usize = 64;
@@ -0,0 +1,34 @@
+//! Telemetry report
+//!
+//! Returned by `GET_TELEMETRY`. All fields are little-endian:
+//!
+//! | Offset | Field                                                              |
+//! |--------|--------------------------------------------------------------------|
+//! | 0      | Latest VDD, VBUS and battery (`u16` mV, `NOT_AVAILABLE` if missing)|
+//! | 6      | Lowest VDD, VBUS and battery since the previous report             |
+//! | 12     | Latest temperature (`i16` 0.1°C, `TEMPERATURE_NOT_AVAILABLE` if missing) |
+//! | 14     | Highest temperature since the previous report                      |
+//! | 16     | Latest VREFINT ADC reading                                         |
+//! | 18     | VREFINT_CAL                                                        |
+//! | 20     | Uptime in seconds (`u32`), not counting time asleep                |
+//! | 24     | Boots and non-power-on resets (`u32`s)                             |
+//! | 32     | Most stack used since startup, and the stack's size (`u16` bytes)  |
+//! | 36     | ESP32 reset banners and crashes seen on the UART (`u32`s)          |
+//! | 44     | Loop load, last second and highest since previous report (`u16` ‰) |
+//!
+//! Firmware before protocol version 1.15 sends only the first 32 bytes. The ESP32's counts are
+//! only sent by builds with the `esp-counters` feature, from protocol version 1.25, and the loop
+//! load only by builds with the `loop-load` feature, from protocol version 1.34. Builds with
+//! `loop-load` but not `esp-counters` send zero for the ESP32's counts.
+
+/// Offset of the ESP32's counts, where the report ends in builds without them.
+pub const ESP_COUNTS_OFFSET: usize = 36;
+
+/// Offset of the loop load, where the report ends in builds without it.
+pub const LOOP_LOAD_OFFSET: usize = 44;
+
+/// Encoded value for a rail which isn't measured on this board, or hasn't been sampled yet.
+pub const NOT_AVAILABLE: u16 = 0xFFFF;
+
+/// Encoded value for a temperature which hasn't been measured yet.
+pub const TEMPERATURE_NOT_AVAILABLE: i16 = i16::MIN;
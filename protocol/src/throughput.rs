@@ -0,0 +1,32 @@
+//! USB throughput test
+//!
+//! While the test runs (see `request::SET_THROUGHPUT`), the WebUSB data interface carries a
+//! counter pattern instead of the ESP32's output: byte `n` of the stream is `n` modulo 256, counting
+//! from when the test started. The bridge sends it on the bulk IN endpoint as fast as the host
+//! takes it, and checks that whatever the host sends on the bulk OUT endpoint follows the same
+//! pattern. `GET_THROUGHPUT` returns the counts, all little-endian `u32`s:
+//!
+//! | Offset | Field                                                 |
+//! |--------|-------------------------------------------------------|
+//! | 0      | Bytes sent                                            |
+//! | 4      | Bytes received                                        |
+//! | 8      | Bytes received which didn't follow the one before     |
+
+/// Fills `buf` with the pattern, starting from byte `offset` of the stream.
+pub fn fill(offset: u32, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = offset.wrapping_add(i as u32) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern() {
+        let mut buf = [0u8; 4];
+        fill(254, &mut buf);
+        assert_eq!(buf, [254, 255, 0, 1]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Unlock handshake
+//!
+//! Any page the user lets at the bridge over WebUSB can send it vendor requests. Builds with the
+//! `unlock` feature refuse the requests in `GUARDED`, which could leave the badge unusable or
+//! needing a trip back to the manufacturing line, until the host has unlocked them. The host reads a
+//! nonce with `GET_UNLOCK_NONCE` and sends back `response` with `UNLOCK`, which needs the identity
+//! key shared with the EMF web services (see `identity` in the firmware). The unlock lasts until the
+//! next bus reset.
+//!
+//! Each `GET_UNLOCK_NONCE` gets a new random nonce, which is used up by the next `UNLOCK`, right or
+//! wrong, so a response seen on the bus can't be sent again. The MAC is over the nonce and the
+//! unique ID, 16 bytes, which can't be mistaken for an identity response's 28 bytes, so
+//! `SET_CHALLENGE` and `GET_IDENTITY` can't be used to work it out.
+
+use crate::request::{
+    CLEAR_ERROR_LOG, DETACH, FIXTURE_TEST, LOCK, SET_CONFIG, SET_SPI_FLASH, UID_LENGTH,
+};
+use crate::siphash::siphash;
+
+/// Length of the `GET_UNLOCK_NONCE` response.
+pub const NONCE_LENGTH: usize = 4;
+
+/// Length of the `UNLOCK` data.
+pub const RESPONSE_LENGTH: usize = 8;
+
+/// Requests refused until the host has unlocked them: writing the settings to flash, restarting
+/// into the bootloader for an update, turning on readout protection, holding the ESP32 in reset to
+/// write its flash, the fixture pin walk, and clearing the error log.
+pub const GUARDED: [u8; 6] =
+    [SET_CONFIG, DETACH, LOCK, SET_SPI_FLASH, FIXTURE_TEST, CLEAR_ERROR_LOG];
+
+/// The `UNLOCK` data for a badge's key, unique ID and nonce: the little-endian SipHash-2-4 of the
+/// nonce followed by the ID.
+pub fn response(
+    key: &[u8; 16],
+    uid: &[u8; UID_LENGTH],
+    nonce: &[u8; NONCE_LENGTH],
+) -> [u8; RESPONSE_LENGTH] {
+    let mut message = [0u8; NONCE_LENGTH + UID_LENGTH];
+    message[..NONCE_LENGTH].copy_from_slice(nonce);
+    message[NONCE_LENGTH..].copy_from_slice(uid);
+    siphash(key, &message).to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_depends_on_everything() {
+        let key = [0x11; 16];
+        let uid = [0xAA; UID_LENGTH];
+        let nonce = 7u32.to_le_bytes();
+        let expected = response(&key, &uid, &nonce);
+        let mut message = vec![7, 0, 0, 0];
+        message.extend_from_slice(&uid);
+        assert_eq!(expected, siphash(&key, &message).to_le_bytes());
+
+        assert_ne!(response(&[0x12; 16], &uid, &nonce), expected);
+        assert_ne!(response(&key, &[0xAB; UID_LENGTH], &nonce), expected);
+        assert_ne!(response(&key, &uid, &8u32.to_le_bytes()), expected);
+    }
+}
@@ -0,0 +1,173 @@
+//! Protocol version handshake
+//!
+//! Hosts should send `GET_VERSION` before any other vendor request, with their own protocol
+//! version in `wValue` (major in the high byte, minor in the low byte). The bridge answers with its
+//! version and the optional features it was built with. A minor version bump only adds requests,
+//! payload fields or feature bits, so a host can talk to any firmware with the same major version;
+//! a major version bump means existing requests have changed. Firmware from before the handshake
+//! stalls `GET_VERSION`, and speaks version 1.0 without the feature bits.
+
+use crate::request::VERSION_LENGTH;
+
+/// Major version of the protocol described by this crate.
+pub const MAJOR: u8 = 1;
+
+/// Minor version of the protocol described by this crate.
+pub const MINOR: u8 = 36;
+
+/// Feature bit: VBUS is wired to the ADC, so telemetry reports it.
+pub const FEATURE_VBUS_SENSE: u32 = 1 << 0;
+
+/// Feature bit: the battery is wired to the ADC, so telemetry reports it.
+pub const FEATURE_BATTERY_SENSE: u32 = 1 << 1;
+
+/// Feature bit: `LOCK` is accepted.
+pub const FEATURE_PRODUCTION_LOCK: u32 = 1 << 2;
+
+/// Feature bit: the ESP32 is an ESP32-C3.
+pub const FEATURE_ESP32C3: u32 = 1 << 3;
+
+/// Feature bit: the ESP32 is an ESP32-S3.
+pub const FEATURE_ESP32S3: u32 = 1 << 4;
+
+/// Feature bit: `JTAG_SHIFT` is accepted.
+pub const FEATURE_JTAG: u32 = 1 << 5;
+
+/// Feature bit: `SET_SPI_FLASH` is accepted.
+pub const FEATURE_SPI_FLASH: u32 = 1 << 6;
+
+/// Feature bit: `SET_FLASHER` is accepted.
+pub const FEATURE_FLASHER: u32 = 1 << 7;
+
+/// Feature bit: `SET_STRAP` is accepted.
+pub const FEATURE_STRAP_PIN: u32 = 1 << 8;
+
+/// Feature bit: `SET_WATCH` is accepted.
+pub const FEATURE_PATTERN_WATCH: u32 = 1 << 9;
+
+/// Feature bit: `SET_THROTTLE` is accepted.
+pub const FEATURE_THROTTLE: u32 = 1 << 10;
+
+/// Feature bit: `SET_FAULTS` is accepted.
+pub const FEATURE_FAULT_INJECT: u32 = 1 << 11;
+
+/// Feature bit: telemetry includes the ESP32's boot and crash counts.
+pub const FEATURE_ESP_COUNTERS: u32 = 1 << 12;
+
+/// Feature bit: `SET_LINE_BUFFER` is accepted.
+pub const FEATURE_LINE_BUFFER: u32 = 1 << 13;
+
+/// Feature bit: the serial port takes in-band escapes (`~` at the start of a line).
+pub const FEATURE_ESCAPES: u32 = 1 << 14;
+
+/// Feature bit: the LED is dimmed on battery, as the `battery_brightness` and `battery_cutoff`
+/// settings say.
+pub const FEATURE_LED_DIM: u32 = 1 << 15;
+
+// Bit 16 was the `provisioning` feature, which is gone. It isn't reused.
+
+/// Feature bit: `FIXTURE_TEST` is accepted, to walk the GPIOs for a bed-of-nails test fixture.
+pub const FEATURE_FIXTURE: u32 = 1 << 17;
+
+/// Feature bit: the requests in `unlock::GUARDED` are refused until the host has sent `UNLOCK`.
+pub const FEATURE_UNLOCK: u32 = 1 << 18;
+
+/// Feature bit: vendor requests and the WebUSB port's control line changes are rate limited, and
+/// stalled over the limit.
+pub const FEATURE_RATE_LIMIT: u32 = 1 << 19;
+
+/// Feature bit: errors are logged in flash, and `GET_ERROR_LOG` and `CLEAR_ERROR_LOG` are accepted.
+pub const FEATURE_ERROR_LOG: u32 = 1 << 20;
+
+/// Feature bit: the telemetry report ends with the main loop's load.
+pub const FEATURE_LOOP_LOAD: u32 = 1 << 21;
+
+/// A protocol version and feature set, as sent in the `GET_VERSION` response.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+
+    /// `FEATURE_*` bits.
+    pub features: u32,
+}
+
+impl Version {
+    /// The version described by this crate, with the given features.
+    pub const fn current(features: u32) -> Version {
+        Version {
+            major: MAJOR,
+            minor: MINOR,
+            features,
+        }
+    }
+
+    /// The version a host sends in `wValue`.
+    pub const fn from_value(value: u16) -> Version {
+        Version {
+            major: (value >> 8) as u8,
+            minor: value as u8,
+            features: 0,
+        }
+    }
+
+    /// `wValue` for a host sending this version.
+    pub const fn value(&self) -> u16 {
+        (self.major as u16) << 8 | self.minor as u16
+    }
+
+    /// Whether a host built against this version can use a bridge with version `other`.
+    pub fn compatible_with(&self, other: &Version) -> bool {
+        self.major == other.major
+    }
+
+    /// Whether all of the given feature bits are set.
+    pub fn has(&self, features: u32) -> bool {
+        self.features & features == features
+    }
+
+    /// Parses a `GET_VERSION` response.
+    pub fn parse(buf: &[u8]) -> Option<Version> {
+        if buf.len() < VERSION_LENGTH {
+            return None;
+        }
+        Some(Version {
+            major: buf[0],
+            minor: buf[1],
+            features: u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]),
+        })
+    }
+
+    /// Encodes the `GET_VERSION` response: major, minor, then the little-endian feature bits.
+    pub fn to_bytes(self) -> [u8; VERSION_LENGTH] {
+        let mut buf = [0u8; VERSION_LENGTH];
+        buf[0] = self.major;
+        buf[1] = self.minor;
+        buf[2..].copy_from_slice(&self.features.to_le_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_round_trip() {
+        let version = Version::current(FEATURE_VBUS_SENSE | FEATURE_PRODUCTION_LOCK);
+        assert_eq!(version.to_bytes(), [MAJOR, MINOR, 0x05, 0, 0, 0]);
+        assert_eq!(Version::parse(&version.to_bytes()), Some(version));
+        assert_eq!(Version::parse(&[MAJOR, MINOR]), None);
+
+        assert!(version.has(FEATURE_VBUS_SENSE));
+        assert!(!version.has(FEATURE_VBUS_SENSE | FEATURE_BATTERY_SENSE));
+    }
+
+    #[test]
+    fn compatibility() {
+        let host = Version::from_value(0x0100);
+        assert_eq!(host.value(), 0x0100);
+        assert!(host.compatible_with(&Version::current(0)));
+        assert!(!host.compatible_with(&Version::from_value(0x0200)));
+    }
+}
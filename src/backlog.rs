@@ -0,0 +1,150 @@
+//! USB to UART flow control
+//!
+//! Data read from the USB OUT endpoints is held here until the UART has sent it, and nothing more
+//! is read from USB until then. The unread packets stay in the endpoint buffers, so the USB
+//! hardware NAKs the host until there's room, rather than us spinning on the UART.
+
+use embedded_hal::serial;
+
+/// Size of the backlog: one full-speed bulk packet.
+pub const BACKLOG_SIZE: usize = 64;
+
+/// Bytes read from USB which are waiting to go out of the UART.
+pub struct UartBacklog {
+    buf: [u8; BACKLOG_SIZE],
+    start: usize,
+    end: usize,
+}
+
+impl Default for UartBacklog {
+    fn default() -> Self {
+        UartBacklog {
+            buf: [0; BACKLOG_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl UartBacklog {
+    /// Whether everything has been sent, and more can be read from USB.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Number of bytes still to go out of the UART.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Refills the backlog with `read`, which is given the whole buffer and returns how much it
+    /// filled. Must only be called when the backlog is empty.
+    pub fn fill<E>(&mut self, read: impl FnOnce(&mut [u8]) -> Result<usize, E>) -> Result<usize, E> {
+        debug_assert!(self.is_empty());
+
+        let count = read(&mut self.buf)?;
+        self.start = 0;
+        self.end = count.min(BACKLOG_SIZE);
+        Ok(count)
+    }
+
+    /// Sends as much of the backlog as the UART will take without blocking, while `pass` lets each
+    /// byte through (see `throttle`).
+    #[inline(never)]
+    pub fn drain<W: serial::Write<u8>>(&mut self, uart: &mut W, mut pass: impl FnMut() -> bool) {
+        while self.start < self.end && pass() {
+            match uart.write(self.buf[self.start]) {
+                Ok(()) => self.start += 1,
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// UART which accepts `space` bytes before blocking.
+    struct Uart {
+        space: usize,
+        sent: Vec<u8>,
+    }
+
+    impl serial::Write<u8> for Uart {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            if self.space == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.space -= 1;
+            self.sent.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let mut backlog = UartBacklog::default();
+        let mut uart = Uart {
+            space: 3,
+            sent: Vec::new(),
+        };
+
+        let count = backlog
+            .fill(|buf| {
+                buf[..5].copy_from_slice(b"hello");
+                Ok::<_, ()>(5)
+            })
+            .unwrap();
+        assert_eq!(count, 5);
+
+        backlog.drain(&mut uart, || true);
+        assert_eq!(uart.sent, b"hel");
+        assert!(!backlog.is_empty());
+        assert_eq!(backlog.len(), 2);
+
+        uart.space = 10;
+        backlog.drain(&mut uart, || true);
+        assert_eq!(uart.sent, b"hello");
+        assert!(backlog.is_empty());
+    }
+
+    #[test]
+    fn throttled() {
+        use crate::throttle::Throttle;
+
+        let mut backlog = UartBacklog::default();
+        let mut uart = Uart {
+            space: 10,
+            sent: Vec::new(),
+        };
+        backlog
+            .fill(|buf| {
+                buf[..5].copy_from_slice(b"hello");
+                Ok::<_, ()>(5)
+            })
+            .unwrap();
+
+        // Two bytes a millisecond
+        let mut throttle = Throttle::default();
+        throttle.set(2000);
+        backlog.drain(&mut uart, || throttle.take());
+        assert!(uart.sent.is_empty());
+        throttle.tick(1);
+        backlog.drain(&mut uart, || throttle.take());
+        assert_eq!(uart.sent, b"he");
+    }
+
+    #[test]
+    fn failed_fill() {
+        let mut backlog = UartBacklog::default();
+        assert_eq!(backlog.fill(|_| Err(())), Err(()));
+        assert!(backlog.is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+//! ESP32 reset banner detection
+//!
+//! The ESP32 ROM prints a banner on every reset, starting with a line like
+//!
+//! `rst:0xf (BROWNOUT_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)`
+//!
+//! The bridge watches for it so the host can be told why the ESP32 reset (see
+//! `tilda_bridge_protocol::event::ESP_RESET`) without having to pick it out of the console itself.
+
+/// Where the detector is in a banner line.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    /// Looking for `rst:0x`.
+    Rst,
+    RstValue,
+    /// Looking for `boot:0x` on the same line.
+    Boot,
+    BootValue,
+}
+
+/// Watches the ESP32's output for reset banners.
+#[derive(Debug)]
+pub struct BannerDetector {
+    state: State,
+    matched: usize,
+    rst: u8,
+    boot: u8,
+}
+
+impl Default for BannerDetector {
+    fn default() -> Self {
+        BannerDetector {
+            state: State::Rst,
+            matched: 0,
+            rst: 0,
+            boot: 0,
+        }
+    }
+}
+
+impl BannerDetector {
+    /// Feeds a received byte to the detector. Returns the reset cause and boot mode at the end of a
+    /// banner line.
+    pub fn feed(&mut self, byte: u8) -> Option<(u8, u8)> {
+        let digit = (byte as char).to_digit(16);
+        match self.state {
+            State::Rst => {
+                if self.expect(b"rst:0x", byte) {
+                    self.state = State::RstValue;
+                    self.rst = 0;
+                }
+            }
+            State::RstValue => match digit {
+                Some(digit) => self.rst = self.rst << 4 | digit as u8,
+                None => self.state = State::Boot,
+            },
+            State::Boot => {
+                if byte == b'\n' {
+                    self.state = State::Rst;
+                } else if self.expect(b"boot:0x", byte) {
+                    self.state = State::BootValue;
+                    self.boot = 0;
+                }
+            }
+            State::BootValue => match digit {
+                Some(digit) => self.boot = self.boot << 4 | digit as u8,
+                None => {
+                    self.state = State::Rst;
+                    return Some((self.rst, self.boot));
+                }
+            },
+        }
+        None
+    }
+
+    /// Matches `byte` against the next byte of `pattern`, and returns true once all of it has been
+    /// seen.
+    fn expect(&mut self, pattern: &[u8], byte: u8) -> bool {
+        if pattern.get(self.matched) == Some(&byte) {
+            self.matched += 1;
+        } else {
+            self.matched = (pattern.first() == Some(&byte)) as usize;
+        }
+        if self.matched == pattern.len() {
+            self.matched = 0;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banners(rx: &[u8]) -> Vec<(u8, u8)> {
+        let mut detector = BannerDetector::default();
+        rx.iter().filter_map(|&b| detector.feed(b)).collect()
+    }
+
+    #[test]
+    fn reset_banner() {
+        let rx = b"I (1234) app: bye\r\nets Jun  8 2016 00:22:57\r\n\r\n\
+                   rst:0xf (BROWNOUT_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\nconfigsip: 0\r\n";
+        assert_eq!(banners(rx), [(0x0F, 0x13)]);
+    }
+
+    #[test]
+    fn partial_banners() {
+        // No boot mode on the line
+        assert!(banners(b"rst:0x1\r\nboot:0x13 \r\n").is_empty());
+        assert_eq!(banners(b"rrst:0x3,boot:0x8\n"), [(0x03, 0x08)]);
+    }
+}
@@ -0,0 +1,256 @@
+//! UART baud rate changes
+//!
+//! esptool starts talking to the ESP32's ROM at 115200, then asks it to switch to something much
+//! faster and sends SET_LINE_CODING to follow. Data the host sent before the change has to go out
+//! at the old rate, so a change is only made once the backlog has drained and the UART has finished
+//! sending, and nothing more is read from USB in the meantime. The UART is read throughout, so
+//! nothing the ESP32 sends is lost.
+
+use crate::config::LineCodingPolicy;
+use crate::stats::Port;
+
+/// Baud rate the UART starts at, and goes back to when the host goes away.
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+/// The rate in both ports' line coding until the host sets one, which no host asks for.
+const UNSET_BAUD: u32 = 8_000;
+
+/// The USART's clock, the APB clock, which stays put as the core changes speed (see `speed`).
+pub const UART_CLOCK_HZ: u32 = 24_000_000;
+
+/// The USART's BRR value for a baud rate: the clock divided by it, as far as the register goes.
+pub fn divisor(rate: u32) -> u32 {
+    (UART_CLOCK_HZ / rate).clamp(16, 0xFFFF)
+}
+
+/// The baud rate the UART really runs at when set to `rate`.
+pub fn achieved(rate: u32) -> u32 {
+    UART_CLOCK_HZ / divisor(rate)
+}
+
+/// Follows the baud rate the host has asked for on either port.
+pub struct BaudSwitch {
+    /// Each port's baud rate when last checked, or `UNSET_BAUD` if the device wasn't configured.
+    seen: (u32, u32),
+    current: u32,
+    pending: Option<u32>,
+    /// The port whose rate the UART is at or about to change to, or `None` for `DEFAULT_BAUD`.
+    source: Option<Port>,
+    /// A port whose rate was ignored as the UART is bound to the other one's, until taken.
+    overruled: Option<Port>,
+}
+
+impl Default for BaudSwitch {
+    fn default() -> Self {
+        BaudSwitch {
+            seen: (UNSET_BAUD, UNSET_BAUD),
+            current: DEFAULT_BAUD,
+            pending: None,
+            source: None,
+            overruled: None,
+        }
+    }
+}
+
+impl BaudSwitch {
+    /// Checks the CDC serial and WebUSB ports' baud rates, or `None` if the device isn't
+    /// configured. Whichever port last changed its rate wins, unless `policy` binds the UART to one
+    /// port and that port has set a rate.
+    ///
+    /// Some hosts (ChromeOS, for one) send SET_LINE_CODING before configuring the device, so once
+    /// it's configured, a port whose rate isn't the one it starts with has been set meanwhile.
+    ///
+    /// usbd-serial takes any rate, so a rate of 0, which the UART can't divide by, is ignored.
+    #[inline(never)]
+    pub fn update(&mut self, rates: Option<(u32, u32)>, policy: LineCodingPolicy) {
+        let rates = match rates {
+            Some((serial, webusb)) => (
+                if serial == 0 { self.seen.0 } else { serial },
+                if webusb == 0 { self.seen.1 } else { webusb },
+            ),
+            None => {
+                self.seen = (UNSET_BAUD, UNSET_BAUD);
+                self.source = None;
+                self.overruled = None;
+                self.request(DEFAULT_BAUD);
+                return;
+            }
+        };
+
+        let serial_changed = self.seen.0 != rates.0;
+        let webusb_changed = self.seen.1 != rates.1;
+        let bound = match policy {
+            LineCodingPolicy::Serial if rates.0 != UNSET_BAUD => Some(Port::Serial),
+            LineCodingPolicy::WebUsb if rates.1 != UNSET_BAUD => Some(Port::WebUsb),
+            _ => None,
+        };
+        match bound {
+            Some(Port::Serial) if webusb_changed => self.overruled = Some(Port::WebUsb),
+            Some(Port::WebUsb) if serial_changed => self.overruled = Some(Port::Serial),
+            _ => (),
+        }
+        self.source = match bound {
+            Some(port) => Some(port),
+            None if serial_changed => Some(Port::Serial),
+            None if webusb_changed => Some(Port::WebUsb),
+            None => self.source,
+        };
+        self.seen = rates;
+        match self.source {
+            Some(Port::Serial) => self.request(rates.0),
+            Some(Port::WebUsb) => self.request(rates.1),
+            None => self.request(self.target()),
+        }
+    }
+
+    fn request(&mut self, rate: u32) {
+        self.pending = Some(rate).filter(|&rate| rate != self.current);
+    }
+
+    /// Whether a change is waiting for the UART to finish sending. No more should be read from USB
+    /// until it's been made.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Takes the pending baud rate, once the backlog is empty and the UART has finished sending.
+    pub fn take(&mut self) -> Option<u32> {
+        let rate = self.pending.take()?;
+        self.current = rate;
+        Some(rate)
+    }
+
+    /// The baud rate the UART is at, or about to change to.
+    pub fn rate(&self) -> u32 {
+        self.target()
+    }
+
+    /// The port whose line coding the UART follows, or `None` if neither has set a rate.
+    pub fn source(&self) -> Option<Port> {
+        self.source
+    }
+
+    /// Takes the port whose rate was last ignored, as the UART is bound to the other one's.
+    pub fn take_overruled(&mut self) -> Option<Port> {
+        self.overruled.take()
+    }
+
+    fn target(&self) -> u32 {
+        self.pending.unwrap_or(self.current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LineCodingPolicy::LastChanged;
+
+    #[test]
+    fn esptool_speedup() {
+        let mut baud = BaudSwitch::default();
+        baud.update(Some((8_000, 8_000)), LastChanged);
+        assert!(!baud.is_pending());
+        baud.update(Some((115_200, 8_000)), LastChanged);
+        assert!(!baud.is_pending());
+
+        baud.update(Some((921_600, 8_000)), LastChanged);
+        assert!(baud.is_pending());
+        assert_eq!(baud.source(), Some(Port::Serial));
+        // Still waiting for the UART
+        baud.update(Some((921_600, 8_000)), LastChanged);
+        assert_eq!(baud.take(), Some(921_600));
+        assert_eq!(baud.take(), None);
+
+        // The web IDE opens its port
+        baud.update(Some((921_600, 115_200)), LastChanged);
+        assert_eq!(baud.take(), Some(115_200));
+        assert_eq!(baud.source(), Some(Port::WebUsb));
+    }
+
+    #[test]
+    fn achieved_rates() {
+        assert_eq!(achieved(115_200), 115_384);
+        assert_eq!(achieved(1_500_000), 1_500_000);
+        // Too fast or too slow for the divisor
+        assert_eq!(achieved(3_000_000), 1_500_000);
+        assert_eq!(achieved(300), 366);
+    }
+
+    #[test]
+    fn back_to_default() {
+        let mut baud = BaudSwitch::default();
+        baud.update(Some((8_000, 8_000)), LastChanged);
+        baud.update(Some((460_800, 8_000)), LastChanged);
+        assert_eq!(baud.take(), Some(460_800));
+
+        baud.update(None, LastChanged);
+        assert_eq!(baud.take(), Some(DEFAULT_BAUD));
+        assert_eq!(baud.source(), None);
+        // Configured again, with the ports' rates reset
+        baud.update(Some((8_000, 8_000)), LastChanged);
+        assert!(!baud.is_pending());
+    }
+
+    #[test]
+    fn set_before_configured() {
+        let mut baud = BaudSwitch::default();
+        baud.update(None, LastChanged);
+        // SET_LINE_CODING came before SET_CONFIGURATION
+        baud.update(Some((8_000, 460_800)), LastChanged);
+        assert_eq!(baud.take(), Some(460_800));
+        baud.update(Some((8_000, 460_800)), LastChanged);
+        assert!(!baud.is_pending());
+
+        // And again after a bus reset, on the serial port this time
+        baud.update(None, LastChanged);
+        assert_eq!(baud.take(), Some(DEFAULT_BAUD));
+        baud.update(Some((921_600, 8_000)), LastChanged);
+        assert_eq!(baud.take(), Some(921_600));
+    }
+
+    #[test]
+    fn zero_rate() {
+        let mut baud = BaudSwitch::default();
+        baud.update(Some((8_000, 8_000)), LastChanged);
+        baud.update(Some((0, 8_000)), LastChanged);
+        assert!(!baud.is_pending());
+        assert_eq!(baud.source(), None);
+
+        baud.update(Some((460_800, 8_000)), LastChanged);
+        assert_eq!(baud.take(), Some(460_800));
+        baud.update(Some((0, 8_000)), LastChanged);
+        assert!(!baud.is_pending());
+        assert_eq!(baud.rate(), 460_800);
+        // A real rate after it is still a change
+        baud.update(Some((115_200, 8_000)), LastChanged);
+        assert_eq!(baud.take(), Some(115_200));
+    }
+
+    #[test]
+    fn bound_to_serial() {
+        let mut baud = BaudSwitch::default();
+        let serial = LineCodingPolicy::Serial;
+        // Until the serial port sets a rate, the WebUSB port's is taken
+        baud.update(Some((8_000, 460_800)), serial);
+        assert_eq!(baud.take(), Some(460_800));
+        assert_eq!(baud.source(), Some(Port::WebUsb));
+        assert_eq!(baud.take_overruled(), None);
+
+        baud.update(Some((115_200, 460_800)), serial);
+        assert_eq!(baud.take(), Some(115_200));
+        assert_eq!(baud.source(), Some(Port::Serial));
+
+        // After which the WebUSB port's are ignored
+        baud.update(Some((115_200, 921_600)), serial);
+        assert!(!baud.is_pending());
+        assert_eq!(baud.take_overruled(), Some(Port::WebUsb));
+        assert_eq!(baud.take_overruled(), None);
+        baud.update(Some((115_200, 921_600)), serial);
+        assert_eq!(baud.take_overruled(), None);
+
+        // Unless the setting changes
+        baud.update(Some((115_200, 921_600)), LineCodingPolicy::WebUsb);
+        assert_eq!(baud.take(), Some(921_600));
+        assert_eq!(baud.source(), Some(Port::WebUsb));
+    }
+}
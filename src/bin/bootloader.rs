@@ -0,0 +1,65 @@
+//! DFU bootloader for the bridge firmware. See `tilda_stm::dfu`.
+
+#![no_std]
+#![no_main]
+
+extern crate panic_reset;
+
+#[path = "../flash.rs"]
+mod flash;
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::entry;
+use stm32_device_signature::device_id_hex;
+use stm32_usbd::UsbBus;
+use stm32f0xx_hal::{prelude::*, stm32};
+use tilda_stm::dfu::{self, Boot, DfuClass};
+use usb_device::prelude::*;
+
+#[entry]
+fn main() -> ! {
+    let flag = dfu::BOOT_FLAG_ADDRESS as *mut u32;
+    let vector_table = unsafe { core::slice::from_raw_parts(dfu::APP_ADDRESS as *const u32, 2) };
+    let (boot, next_flag) = dfu::boot(
+        unsafe { core::ptr::read_volatile(flag) },
+        dfu::is_valid_app(vector_table),
+    );
+    unsafe { core::ptr::write_volatile(flag, next_flag) };
+
+    // Nothing has been set up yet, so the firmware starts from a clean slate.
+    if boot == Boot::App {
+        unsafe { cortex_m::asm::bootload(dfu::APP_ADDRESS as *const u32) }
+    }
+
+    let mut dp = stm32::Peripherals::take().unwrap();
+    dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+    dp.SYSCFG.cfgr1.modify(|_, w| w.pa11_pa12_rmp().remapped());
+
+    let mut rcc = dp
+        .RCC
+        .configure()
+        .hsi48()
+        .enable_crs(dp.CRS)
+        .sysclk(48.mhz())
+        .pclk(24.mhz())
+        .freeze(&mut dp.FLASH);
+
+    let gpioa = dp.GPIOA.split(&mut rcc);
+    let usb_bus = UsbBus::new(dp.USB, (gpioa.pa11, gpioa.pa12));
+
+    let mut dfu = DfuClass::new(&usb_bus, flash::Flash);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x05dc))
+        .manufacturer("Electromagnetic Field")
+        .product("TiLDA MkV bootloader")
+        .serial_number(device_id_hex())
+        .max_packet_size_0(64)
+        .build();
+
+    loop {
+        usb_dev.poll(&mut [&mut dfu]);
+        if dfu.take_reboot_request() {
+            unsafe { core::ptr::write_volatile(flag, dfu::TRIAL_BOOT) };
+            SCB::sys_reset();
+        }
+    }
+}
@@ -0,0 +1,606 @@
+//! Board support for the TiLDA MkV
+//!
+//! The UART, ESP32 boot, LED and USB state pins, the LED's polarity and the ESP32 variant come from
+//! a board file in `boards/` (`TILDA_BOARD`, by default `boards/tilda-mkv.toml`), which build.rs
+//! turns into `board_pins!` and the types and constants below it. The rest are fixed. On the
+//! TiLDA MkV they are:
+//!
+//! | Pin       | Function                                              |
+//! |-----------|-------------------------------------------------------|
+//! | PA0       | VBUS sense (optional, see `sensors` and `cable`)      |
+//! | PA1       | ESP32 EN                                              |
+//! | PA2, PA3  | USART2 TX/RX to the ESP32                             |
+//! | PA4       | ESP32 IO0                                             |
+//! | PA5       | Battery sense (optional, see `sensors`)               |
+//! | PA6       | USB state to the ESP32 (high when configured)         |
+//! | PA7, PA8  | ESP32 TCK/TMS (optional, see `jtag`)                  |
+//! | PA15, PB0 | ESP32 TDI/TDO (optional, see `jtag`)                  |
+//! | PA11, PA12| USB                                                   |
+//! | PB1       | LED                                                   |
+//! | PB3, PB4  | ESP32 flash CLK/Q (optional, see `spiflash`)          |
+//! | PB5, PB6  | ESP32 flash D/CS (optional, see `spiflash`)           |
+//! | PB7       | ESP32 second strapping pin (optional, see `esp`)      |
+
+use crate::sensors::Sensors;
+use core::convert::Infallible;
+use core::cell::Cell;
+use cortex_m::interrupt::Mutex;
+use cortex_m_rt::exception;
+use stm32_usbd::{UsbBus, UsbBusType};
+use stm32f0xx_hal::{
+    adc::Adc,
+    gpio::{self, Output, Pin, PushPull},
+    prelude::*,
+    serial::Serial,
+    stm32,
+};
+#[cfg(any(feature = "jtag", feature = "spi-flash"))]
+use stm32f0xx_hal::gpio::{Floating, Input};
+#[cfg(feature = "jtag")]
+use stm32f0xx_hal::gpio::{
+    gpioa::{PA15, PA7, PA8},
+    gpiob::PB0,
+};
+#[cfg(feature = "spi-flash")]
+use stm32f0xx_hal::gpio::gpiob::{PB3, PB4, PB5, PB6};
+#[cfg(feature = "strap-pin")]
+use stm32f0xx_hal::gpio::{gpiob::PB7, OpenDrain};
+use tilda_stm::{
+    baud::{self, DEFAULT_BAUD},
+    dfu,
+    esp::{self, EspBootControl},
+    recovery::ClockSync,
+    stack,
+};
+#[cfg(feature = "error-log")]
+use tilda_stm::errorlog;
+#[cfg(feature = "fixture")]
+use tilda_stm::fixture::{self, FixtureMap, PINS};
+#[cfg(feature = "led-dim")]
+use tilda_stm::led::Dimmer;
+use tilda_bridge_protocol::reset::ResetCause;
+use usb_device::bus::UsbBusAllocator;
+
+/// APB clock, which drives the USART.
+const PCLK_HZ: u32 = baud::UART_CLOCK_HZ;
+
+extern "C" {
+    // From cortex-m-rt's linker script: the end of the statics, and the top of RAM.
+    static mut __sheap: u32;
+    static _stack_start: u32;
+}
+
+/// The CRS's starting trim, which leaves the HSI48 as calibrated at the factory.
+const CRS_DEFAULT_TRIM: u8 = 32;
+
+/// GPIOB bit numbers of the pins driving the ESP32's flash.
+#[cfg(feature = "spi-flash")]
+const FLASH_OUTPUT_BITS: [u32; 3] = [3, 5, 6];
+#[cfg(feature = "spi-flash")]
+const FLASH_CLK_BIT: u32 = 3;
+#[cfg(feature = "spi-flash")]
+const FLASH_CS_BIT: u32 = 6;
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
+
+pub type OutputPin = Pin<Output<PushPull>>;
+
+/// The LED, whichever way round it's wired: high lights it. With `led-dim`, a lit LED is dimmed to
+/// the brightness set with `set_brightness` as `tick` is called.
+pub struct Led {
+    pin: OutputPin,
+    #[cfg(feature = "led-dim")]
+    lit: bool,
+    #[cfg(feature = "led-dim")]
+    dimmer: Dimmer,
+}
+
+impl Led {
+    fn new(pin: OutputPin) -> Self {
+        Led {
+            pin,
+            #[cfg(feature = "led-dim")]
+            lit: false,
+            #[cfg(feature = "led-dim")]
+            dimmer: Dimmer::default(),
+        }
+    }
+
+    #[inline(never)]
+    fn drive(&mut self, on: bool) -> Result<(), Infallible> {
+        if on == LED_ACTIVE_HIGH {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        }
+    }
+
+    /// Sets how bright the LED is when lit, in eighths.
+    #[cfg(feature = "led-dim")]
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.dimmer.set_brightness(brightness);
+    }
+
+    /// Advances the dimming by a millisecond.
+    #[cfg(feature = "led-dim")]
+    pub fn tick(&mut self) {
+        let on = self.dimmer.tick();
+        let _ = self.drive(self.lit && on);
+    }
+}
+
+impl embedded_hal::digital::v2::OutputPin for Led {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        #[cfg(feature = "led-dim")]
+        {
+            self.lit = false;
+        }
+        self.drive(false)
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        #[cfg(feature = "led-dim")]
+        {
+            self.lit = true;
+            self.drive(self.dimmer.on())
+        }
+        #[cfg(not(feature = "led-dim"))]
+        self.drive(true)
+    }
+}
+
+#[cfg(feature = "jtag")]
+pub type JtagPins = tilda_stm::jtag::JtagPins<
+    PA7<Output<PushPull>>,
+    PA8<Output<PushPull>>,
+    PA15<Output<PushPull>>,
+    PB0<Input<Floating>>,
+>;
+
+#[cfg(feature = "spi-flash")]
+pub type SpiPins = tilda_stm::spiflash::SpiPins<
+    PB3<Output<PushPull>>,
+    PB5<Output<PushPull>>,
+    PB6<Output<PushPull>>,
+    PB4<Input<Floating>>,
+>;
+
+/// The board's peripherals, configured and ready to use.
+pub struct Board {
+    pub usb_bus: UsbBusAllocator<UsbBusType>,
+    pub uart: EspUart,
+    pub esp: EspBootControl<OutputPin>,
+    pub led: Led,
+
+    /// Tells the ESP32 whether a USB host is using us.
+    pub usb_state: OutputPin,
+
+    pub sensors: Sensors,
+
+    #[cfg(feature = "jtag")]
+    pub jtag: JtagPins,
+
+    /// Only driven while the host has the flash; see `drive_flash_pins`.
+    #[cfg(feature = "spi-flash")]
+    pub spi_flash: SpiPins,
+
+    /// Open drain, so released it leaves the ESP32's own pull to decide; see `esp::StrapMode`.
+    #[cfg(feature = "strap-pin")]
+    pub esp_strap: PB7<Output<OpenDrain>>,
+
+    /// Why the chip last reset.
+    pub reset_cause: ResetCause,
+
+    /// Whether the last boot ended in a panic.
+    #[cfg(feature = "error-log")]
+    pub panicked: bool,
+}
+
+impl Board {
+    /// Sets up the clocks and pins, leaving the ESP32 running.
+    pub fn new(mut dp: stm32::Peripherals) -> Board {
+        dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        // The flags stay set until they're cleared, so clear them for next time.
+        let mut reset_cause = ResetCause::from_csr(dp.RCC.csr.read().bits());
+        dp.RCC.csr.modify(|_, w| w.rmvf().set_bit());
+        let overflow_flag = stack::OVERFLOW_FLAG_ADDRESS as *mut u32;
+        if unsafe { core::ptr::read_volatile(overflow_flag) } == stack::OVERFLOW_FLAG {
+            unsafe { core::ptr::write_volatile(overflow_flag, 0) };
+            reset_cause = ResetCause::from_bits(reset_cause.bits() | ResetCause::STACK_OVERFLOW);
+        }
+        #[cfg(feature = "error-log")]
+        let panicked = {
+            let panic_flag = errorlog::PANIC_FLAG_ADDRESS as *mut u32;
+            let panicked = unsafe { core::ptr::read_volatile(panic_flag) } == errorlog::PANIC_FLAG;
+            unsafe { core::ptr::write_volatile(panic_flag, 0) };
+            panicked
+        };
+
+        // The bootloader's vector table is the one at the start of flash, and the Cortex-M0 can't
+        // be pointed at another, so ours is copied to the start of RAM and RAM is mapped at 0.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                dfu::APP_ADDRESS as *const u8,
+                0x2000_0000 as *mut u8,
+                dfu::VECTOR_TABLE_LENGTH,
+            );
+        }
+        dp.SYSCFG
+            .cfgr1
+            .modify(|_, w| w.pa11_pa12_rmp().remapped().mem_mode().sram());
+
+        let mut rcc = dp
+            .RCC
+            .configure()
+            .hsi48()
+            .enable_crs(dp.CRS)
+            .sysclk(48.mhz())
+            .pclk(PCLK_HZ.hz())
+            .freeze(&mut dp.FLASH);
+        // Setting the flash wait state, the HAL also turns off the prefetch buffer, which is on
+        // out of reset. Without it every instruction fetch at 48 MHz waits for the flash.
+        dp.FLASH.acr.modify(|_, w| w.prftbe().set_bit());
+
+        let gpioa = dp.GPIOA.split(&mut rcc);
+        let gpiob = dp.GPIOB.split(&mut rcc);
+
+        // Set up inside the same critical section, as the closure takes the whole of GPIOA.
+        #[cfg(feature = "jtag")]
+        let mut jtag = None;
+        #[cfg(feature = "spi-flash")]
+        let mut spi_flash = None;
+        #[cfg(feature = "strap-pin")]
+        let mut esp_strap = None;
+
+        let (
+            usb_dm,
+            usb_dp,
+            (
+                uart_tx,
+                uart_rx,
+                mut esp_en,
+                mut esp_gpio0,
+                led,
+                mut usb_state,
+            ),
+            vbus,
+            battery,
+        ) = cortex_m::interrupt::free(|cs| {
+            #[cfg(feature = "jtag")]
+            {
+                jtag = Some(JtagPins {
+                    tck: gpioa.pa7.into_push_pull_output(cs),
+                    tms: gpioa.pa8.into_push_pull_output(cs),
+                    tdi: gpioa.pa15.into_push_pull_output(cs),
+                    tdo: gpiob.pb0.into_floating_input(cs),
+                });
+            }
+            #[cfg(feature = "spi-flash")]
+            {
+                spi_flash = Some(SpiPins {
+                    clk: gpiob.pb3.into_push_pull_output(cs),
+                    mosi: gpiob.pb5.into_push_pull_output(cs),
+                    cs: gpiob.pb6.into_push_pull_output(cs),
+                    miso: gpiob.pb4.into_floating_input(cs),
+                });
+            }
+            #[cfg(feature = "strap-pin")]
+            {
+                esp_strap = Some(gpiob.pb7.into_open_drain_output(cs));
+            }
+            (
+                gpioa.pa11,
+                gpioa.pa12,
+                board_pins!(gpioa, gpiob, cs),
+                gpioa.pa0.into_analog(cs),
+                gpioa.pa5.into_analog(cs),
+            )
+        });
+
+        let _ = esp_en.set_high();
+        let _ = esp_gpio0.set_high();
+        let mut led = Led::new(led);
+        let _ = led.set_high();
+        let _ = usb_state.set_low();
+        #[cfg(feature = "spi-flash")]
+        drive_flash_pins(false);
+        #[cfg(feature = "strap-pin")]
+        let mut esp_strap = esp_strap.unwrap();
+        #[cfg(feature = "strap-pin")]
+        let _ = esp_strap.set_high();
+
+        Board {
+            usb_bus: UsbBus::new(dp.USB, (usb_dm, usb_dp)),
+            uart: esp_uart!(dp, (uart_tx, uart_rx), &mut rcc),
+            esp: EspBootControl::new(esp_en, esp_gpio0, esp::CHIP),
+            led,
+            usb_state,
+            sensors: Sensors::new(Adc::new(dp.ADC, &mut rcc), vbus, battery),
+            #[cfg(feature = "jtag")]
+            jtag: jtag.unwrap(),
+            #[cfg(feature = "spi-flash")]
+            spi_flash: spi_flash.unwrap(),
+            #[cfg(feature = "strap-pin")]
+            esp_strap,
+            reset_cause,
+            #[cfg(feature = "error-log")]
+            panicked,
+        }
+    }
+}
+
+/// Runs the core at 48 MHz, or at 24 MHz to save power. PCLK stays at 24 MHz either way, so the
+/// UART's baud rate doesn't change, and the USB peripheral still has the 10 MHz it needs.
+pub fn set_full_speed(full: bool) {
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    rcc.cfgr.modify(|_, w| {
+        if full {
+            w.hpre().div1().ppre().div2()
+        } else {
+            w.hpre().div2().ppre().div1()
+        }
+    });
+}
+
+/// Core clock cycles per millisecond at the current speed.
+#[inline(never)]
+pub fn cycles_per_ms() -> u32 {
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    if rcc.cfgr.read().hpre().is_div1() {
+        48_000
+    } else {
+        24_000
+    }
+}
+
+/// SysTick periods the main loop hasn't taken yet, counted by its exception so that none are lost
+/// while we sleep.
+static TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+#[exception]
+fn SysTick() {
+    cortex_m::interrupt::free(|cs| {
+        let ticks = TICKS.borrow(cs);
+        ticks.set(ticks.get().wrapping_add(1));
+    });
+}
+
+/// Takes one of the SysTick periods which have gone by, if there are any.
+pub fn take_tick() -> bool {
+    cortex_m::interrupt::free(|cs| {
+        let ticks = TICKS.borrow(cs);
+        let taken = ticks.get() != 0;
+        ticks.set(ticks.get().saturating_sub(1));
+        taken
+    })
+}
+
+/// Microseconds since SysTick last wrapped.
+pub fn tick_micros() -> u32 {
+    use cortex_m::peripheral::SYST;
+    let reload = SYST::get_reload();
+    (reload - SYST::get_current()) * 1000 / (reload + 1)
+}
+
+/// The USB peripheral's frame number, once it has locked to the host's start of frame packets.
+#[cfg(feature = "sof-timing")]
+pub fn usb_frame() -> Option<u16> {
+    let fnr = unsafe { (*stm32::USB::ptr()).fnr.read() };
+    fnr.lck().bit_is_set().then(|| fnr.fn_().bits())
+}
+
+/// Reads back the actual levels of the EN and IO0 pins.
+pub fn esp_pin_levels() -> (bool, bool) {
+    let en = unsafe { (*EspEnPort::ptr()).idr.read().bits() };
+    let gpio0 = unsafe { (*EspGpio0Port::ptr()).idr.read().bits() };
+    (en & (1 << ESP_EN_BIT) != 0, gpio0 & (1 << ESP_GPIO0_BIT) != 0)
+}
+
+/// Takes the ESP32's flash pins, with CS high and CLK low, or lets go of them so the ESP32 can use
+/// its flash. The HAL fixes a pin's mode in its type, so this goes behind its back.
+#[cfg(feature = "spi-flash")]
+#[inline(never)]
+pub fn drive_flash_pins(drive: bool) {
+    let gpiob = unsafe { &*stm32::GPIOB::ptr() };
+    gpiob
+        .bsrr
+        .write(|w| unsafe { w.bits(1 << FLASH_CS_BIT | 1 << (FLASH_CLK_BIT + 16)) });
+    let mask = FLASH_OUTPUT_BITS.iter().fold(0, |mask, bit| mask | 0b11 << (bit * 2));
+    let output = FLASH_OUTPUT_BITS.iter().fold(0, |mode, bit| mode | 0b01 << (bit * 2));
+    gpiob.moder.modify(|r, w| unsafe {
+        w.bits(r.bits() & !mask | if drive { output } else { 0 })
+    });
+}
+
+/// A 2-bit field of 0b01 for each walked pin in GPIOA and GPIOB, as their mode and pull registers
+/// have.
+#[cfg(feature = "fixture")]
+const PIN_FIELDS: [u32; 2] = [pin_fields(PINS & 0xFFFF), pin_fields(PINS >> 16)];
+
+#[cfg(feature = "fixture")]
+const fn pin_fields(pins: u32) -> u32 {
+    let mut fields = 0;
+    let mut bit = 0;
+    while bit < 16 {
+        fields |= (pins >> bit & 1) << (bit * 2);
+        bit += 1;
+    }
+    fields
+}
+
+/// Walks the pins for a test fixture (see `fixture`), then puts them back as they were. The HAL
+/// fixes a pin's mode in its type, so this goes behind its back.
+#[cfg(feature = "fixture")]
+#[inline(never)]
+pub fn walk_pins() -> FixtureMap {
+    // GPIOA's registers are laid out as GPIOB's, although the PAC gives them different types.
+    let ports: [&stm32::gpiof::RegisterBlock; 2] =
+        unsafe { [&*(stm32::GPIOA::ptr() as *const _), &*stm32::GPIOB::ptr()] };
+    let saved = ports.map(|port| {
+        let odr = port.odr.read().bits();
+        (odr, port.otyper.read().bits(), port.pupdr.read().bits(), port.moder.read().bits())
+    });
+
+    let map = fixture::walk(|pull_up, driven| {
+        for (i, port) in ports.iter().enumerate() {
+            let shift = i as u32 * 16;
+            let pins = PINS >> shift & 0xFFFF;
+            let fields = PIN_FIELDS[i];
+            let modes = fields * 0b11;
+            let pulls = fields * if pull_up { 0b01 } else { 0b10 };
+            let mut outputs = 0;
+            if let Some(bit) = driven.map(|pin| pin.wrapping_sub(shift)).filter(|&bit| bit < 16) {
+                outputs = 0b01 << (bit * 2);
+                let level = if pull_up { 1 << (bit + 16) } else { 1 << bit };
+                port.bsrr.write(|w| unsafe { w.bits(level) });
+            }
+            port.otyper.modify(|r, w| unsafe { w.bits(r.bits() & !pins) });
+            port.pupdr.modify(|r, w| unsafe { w.bits(r.bits() & !modes | pulls) });
+            port.moder.modify(|r, w| unsafe { w.bits(r.bits() & !modes | outputs) });
+        }
+        // Long enough for the pulls to charge the fixture's wiring.
+        cortex_m::asm::delay(cycles_per_ms() / 50);
+        ports[0].idr.read().bits() | ports[1].idr.read().bits() << 16
+    });
+
+    for (port, (odr, otyper, pupdr, moder)) in ports.iter().zip(saved.iter()) {
+        port.odr.write(|w| unsafe { w.bits(*odr) });
+        port.otyper.write(|w| unsafe { w.bits(*otyper) });
+        port.pupdr.write(|w| unsafe { w.bits(*pupdr) });
+        port.moder.write(|w| unsafe { w.bits(*moder) });
+    }
+    map
+}
+
+/// Disconnects from the bus by dropping the D+ pull-up. stm32-usbd doesn't expose the pull-up, so
+/// this goes behind its back.
+pub fn usb_detach() {
+    let usb = unsafe { &*stm32::USB::ptr() };
+    usb.bcdr.modify(|_, w| w.dppu().clear_bit());
+}
+
+/// Connects to the bus with the D+ pull-up.
+pub fn usb_attach() {
+    let usb = unsafe { &*stm32::USB::ptr() };
+    usb.bcdr.modify(|_, w| w.dppu().set_bit());
+}
+
+/// Disconnects from the bus, then reconnects so the host enumerates us again.
+pub fn usb_reattach() {
+    usb_detach();
+    crate::delay_ms(100);
+    usb_attach();
+}
+
+/// What the CRS has made of the host's start of frame packets since the last call. Its flags are
+/// cleared for next time.
+pub fn crs_sync() -> ClockSync {
+    let crs = unsafe { &*stm32::CRS::ptr() };
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    let isr = crs.isr.read();
+    crs.icr
+        .write(|w| w.syncokc().set_bit().syncwarnc().set_bit().errc().set_bit().esyncc().set_bit());
+    if isr.errf().bit_is_set() || rcc.cr2.read().hsi48rdy().bit_is_clear() {
+        ClockSync::Error
+    } else if isr.syncokf().bit_is_set() || isr.syncwarnf().bit_is_set() {
+        ClockSync::Ok
+    } else {
+        ClockSync::None
+    }
+}
+
+/// Noise for the unlock nonces: the CRS's last measure of the HSI48's error against the host's start
+/// of frame packets, and where the SysTick has counted to.
+#[cfg(feature = "unlock")]
+pub fn entropy() -> u32 {
+    use cortex_m::peripheral::SYST;
+    let crs = unsafe { &*stm32::CRS::ptr() };
+    (crs.isr.read().bits() & 0xFFFF_8000) ^ SYST::get_current()
+}
+
+/// Restarts the CRS from the HSI48's factory trim, in case it has trimmed itself somewhere it
+/// can't get back from.
+#[inline(never)]
+pub fn crs_relock() {
+    let crs = unsafe { &*stm32::CRS::ptr() };
+    // The trim can only be written with automatic trimming off.
+    crs.cr.modify(|_, w| w.cen().clear_bit().autotrimen().clear_bit());
+    crs.cr.write(|w| unsafe { w.trim().bits(CRS_DEFAULT_TRIM) });
+    crs.cr.modify(|_, w| w.autotrimen().set_bit().cen().set_bit());
+}
+
+/// Changes the ESP32 UART's baud rate, returning the rate it really runs at. The HAL can only set
+/// it when the UART is created, so this goes behind its back. Rates outside what the USART can do
+/// (366 to 1.5M) are clamped.
+pub fn set_uart_baud(rate: u32) -> u32 {
+    let usart = unsafe { &*EspUsart::ptr() };
+    let divisor = baud::divisor(rate);
+    usart.cr1.modify(|_, w| w.ue().clear_bit());
+    usart.brr.write(|w| unsafe { w.bits(divisor) });
+    usart.cr1.modify(|_, w| w.ue().set_bit());
+    PCLK_HZ / divisor
+}
+
+/// Tells the bootloader that this image works, ending any trial boot.
+pub fn confirm_boot() {
+    unsafe { core::ptr::write_volatile(dfu::BOOT_FLAG_ADDRESS as *mut u32, 0) };
+}
+
+/// Restarts in the bootloader's DFU mode.
+pub fn reboot_to_bootloader() -> ! {
+    unsafe { core::ptr::write_volatile(dfu::BOOT_FLAG_ADDRESS as *mut u32, dfu::DFU_REQUEST) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Paints the stack below the caller with `stack::PAINT`. Call it first thing, before anything
+/// else has used the stack.
+#[inline(never)]
+pub fn paint_stack() {
+    // Leave a little below the stack pointer for this function's own call.
+    let top = (cortex_m::register::msp::read() - 64) as *mut u32;
+    let mut word = core::ptr::addr_of_mut!(__sheap);
+    while word < top {
+        unsafe {
+            word.write_volatile(stack::PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// Returns the most stack used since it was painted, and the stack's size, in bytes.
+pub fn stack_usage() -> (usize, usize) {
+    let bottom = core::ptr::addr_of_mut!(__sheap);
+    let top = unsafe { &_stack_start as *const u32 };
+    let words = (top as usize - bottom as usize) / 4;
+    let used = stack::used(unsafe { core::slice::from_raw_parts(bottom, words) });
+    (used, words * 4)
+}
+
+/// Restarts, so the next boot reports a stack overflow, if the stack has reached its guard.
+#[inline(never)]
+pub fn check_stack() {
+    let guard = core::ptr::addr_of!(__sheap);
+    if stack::overflowed(unsafe { core::slice::from_raw_parts(guard, stack::GUARD_BYTES / 4) }) {
+        restart_after_overflow();
+    }
+}
+
+/// Restarts, so the next boot reports a stack overflow.
+fn restart_after_overflow() -> ! {
+    let overflow_flag = stack::OVERFLOW_FLAG_ADDRESS as *mut u32;
+    unsafe { core::ptr::write_volatile(overflow_flag, stack::OVERFLOW_FLAG) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Restarts after a panic, as panic-reset does without the `error-log` feature, so the next boot
+/// logs it.
+#[cfg(feature = "error-log")]
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    let panic_flag = errorlog::PANIC_FLAG_ADDRESS as *mut u32;
+    unsafe { core::ptr::write_volatile(panic_flag, errorlog::PANIC_FLAG) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,1743 @@
+//! The bridge's vendor requests
+//!
+//! `WebUsbClass` only answers the CDC requests and the host's requests for its WebUSB and Microsoft
+//! OS descriptors. `Bridge` is the `VendorRequests` handler for the bridge's own requests
+//! (`tilda_bridge_protocol::request`), and keeps what they report and change, other than the WebUSB
+//! port's modes, which it sets on the class. The main loop reaches it through the port (see
+//! `BridgePort`), whose methods here tie the two together.
+//!
+//! Firmware wanting to answer one of the bridge's requests differently, or add its own, can wrap
+//! `Bridge` in a handler of its own which passes the rest on to it.
+
+use crate::backlog::BACKLOG_SIZE;
+use crate::banner::BannerDetector;
+use crate::build_info::{BUILD_INFO, VERSION};
+use crate::capture::{Capture, CAPTURE_LENGTH};
+use crate::config::Config;
+use crate::counters::{BootCounts, EspCounts};
+#[cfg(feature = "esp-counters")]
+use crate::crash::CrashDetector;
+#[cfg(feature = "error-log")]
+use crate::errorlog::{self, ErrorLog, ENTRY_LENGTH};
+use crate::esp::{self, StrapMode};
+#[cfg(feature = "escapes")]
+use crate::escape::{self, Command, Escapes, Mode, STATS_LINE_LENGTH};
+#[cfg(feature = "fault-inject")]
+use crate::faults::Faults;
+#[cfg(feature = "fixture")]
+use crate::fixture::FixtureMap;
+#[cfg(feature = "flasher")]
+use crate::flasher::Flasher;
+use crate::hexdump::Direction;
+use crate::identity::{Identity, CHALLENGE_LENGTH};
+#[cfg(feature = "jtag")]
+use crate::jtag::{JtagBatch, JtagPins};
+#[cfg(feature = "rate-limit")]
+use crate::ratelimit::RateLimits;
+use crate::selftest::SelfTestReport;
+#[cfg(feature = "spi-flash")]
+use crate::spiflash::{SpiPins, SpiTransfer};
+use crate::stats::{self, Port, ReadErrors, UartError, UartErrors};
+use crate::telemetry::{ChipReadings, SupplyReadings, Telemetry};
+#[cfg(feature = "throttle")]
+use crate::throttle::Throttle;
+#[cfg(feature = "unlock")]
+use crate::unlock::{self, Unlock};
+#[cfg(feature = "pattern-watch")]
+use crate::watch::{Action, PatternWatch};
+use crate::webusb::{DefaultBufferStore, VendorRequests, WebUSB, WebUsbClass};
+use core::borrow::BorrowMut;
+use core::convert::TryInto;
+#[cfg(any(feature = "jtag", feature = "spi-flash"))]
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+#[cfg(feature = "flasher")]
+use embedded_hal::serial;
+use tilda_bridge_protocol::reset::ResetCause;
+#[cfg(feature = "spi-flash")]
+use tilda_bridge_protocol::spiflash;
+use tilda_bridge_protocol::{event, request};
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+/// The bridge's WebUSB port, with its vendor requests.
+pub type BridgePort<'a, B> = WebUSB<'a, B, DefaultBufferStore, DefaultBufferStore, Bridge>;
+
+/// The state behind the bridge's vendor requests.
+pub struct Bridge {
+    strap: StrapMode,
+    uart_queued: u16,
+    self_test_requested: bool,
+    detach_requested: bool,
+    lock_requested: bool,
+    reattach_requested: bool,
+    self_test_report: Option<SelfTestReport>,
+    #[cfg(feature = "unlock")]
+    unlock: Unlock,
+    /// Noise to stir into the unlock nonces.
+    #[cfg(feature = "unlock")]
+    entropy: fn() -> u32,
+    #[cfg(feature = "fixture")]
+    fixture_requested: bool,
+    #[cfg(feature = "fixture")]
+    fixture_map: Option<[u8; request::FIXTURE_LENGTH]>,
+    config: Config,
+    config_changed: bool,
+    telemetry: Telemetry,
+    capture: Capture,
+    #[cfg(feature = "jtag")]
+    jtag: JtagBatch,
+    #[cfg(feature = "spi-flash")]
+    spi_flash: bool,
+    #[cfg(feature = "spi-flash")]
+    spi_flash_changed: bool,
+    #[cfg(feature = "spi-flash")]
+    spi: SpiTransfer,
+    /// Only built in with the feature, as it's big enough to cost flash in code which has to
+    /// reach past it.
+    #[cfg(feature = "flasher")]
+    flasher: Flasher,
+    read_errors: ReadErrors,
+    uart_errors: UartErrors,
+    #[cfg(feature = "rate-limit")]
+    rate_limits: RateLimits,
+    #[cfg(feature = "throttle")]
+    to_esp: Throttle,
+    #[cfg(feature = "throttle")]
+    from_esp: Throttle,
+    #[cfg(feature = "fault-inject")]
+    faults: Faults,
+    identity: Option<Identity>,
+    challenge: Option<[u8; CHALLENGE_LENGTH]>,
+    #[cfg(feature = "error-log")]
+    error_log: ErrorLog,
+    /// The configuration page, for the host to read the error log from.
+    #[cfg(feature = "error-log")]
+    config_page: &'static [u8],
+    #[cfg(feature = "error-log")]
+    error_log_cleared: bool,
+    reset_cause: ResetCause,
+    tick_micros: fn() -> u32,
+    banner: BannerDetector,
+    pending_esp_reset: Option<(u8, u8)>,
+    #[cfg(feature = "esp-counters")]
+    crash: CrashDetector,
+    #[cfg(feature = "escapes")]
+    escapes: Escapes,
+    #[cfg(feature = "pattern-watch")]
+    watch: PatternWatch,
+    /// Whether a pattern has asked for the ESP32 to be reset, and into download mode or not.
+    #[cfg(feature = "pattern-watch")]
+    watch_reset: Option<bool>,
+    supply_low: bool,
+    pending_supply: bool,
+}
+
+impl Default for Bridge {
+    #[inline(never)]
+    fn default() -> Self {
+        Bridge {
+            strap: esp::CHIP.strap,
+            uart_queued: 0,
+            self_test_requested: false,
+            detach_requested: false,
+            lock_requested: false,
+            reattach_requested: false,
+            self_test_report: None,
+            #[cfg(feature = "unlock")]
+            unlock: Unlock::default(),
+            #[cfg(feature = "unlock")]
+            entropy: || 0,
+            #[cfg(feature = "fixture")]
+            fixture_requested: false,
+            #[cfg(feature = "fixture")]
+            fixture_map: None,
+            config: Config::default(),
+            config_changed: false,
+            telemetry: Telemetry::default(),
+            capture: Capture::default(),
+            #[cfg(feature = "jtag")]
+            jtag: JtagBatch::default(),
+            #[cfg(feature = "spi-flash")]
+            spi_flash: false,
+            #[cfg(feature = "spi-flash")]
+            spi_flash_changed: false,
+            #[cfg(feature = "spi-flash")]
+            spi: SpiTransfer::default(),
+            #[cfg(feature = "flasher")]
+            flasher: Flasher::default(),
+            read_errors: ReadErrors::default(),
+            uart_errors: UartErrors::default(),
+            #[cfg(feature = "rate-limit")]
+            rate_limits: RateLimits::default(),
+            #[cfg(feature = "throttle")]
+            to_esp: Throttle::default(),
+            #[cfg(feature = "throttle")]
+            from_esp: Throttle::default(),
+            #[cfg(feature = "fault-inject")]
+            faults: Faults::default(),
+            identity: None,
+            challenge: None,
+            #[cfg(feature = "error-log")]
+            error_log: ErrorLog::default(),
+            #[cfg(feature = "error-log")]
+            config_page: &[],
+            #[cfg(feature = "error-log")]
+            error_log_cleared: false,
+            reset_cause: ResetCause::default(),
+            tick_micros: || 0,
+            banner: BannerDetector::default(),
+            pending_esp_reset: None,
+            #[cfg(feature = "esp-counters")]
+            crash: CrashDetector::default(),
+            #[cfg(feature = "escapes")]
+            escapes: Escapes::default(),
+            #[cfg(feature = "pattern-watch")]
+            watch: PatternWatch::default(),
+            #[cfg(feature = "pattern-watch")]
+            watch_reset: None,
+            supply_low: false,
+            pending_supply: false,
+        }
+    }
+}
+
+impl Bridge {
+    /// Notes an error for the error log, unless one of its kind already has been this boot.
+    #[cfg(feature = "error-log")]
+    fn record_error(&mut self, kind: u8, detail: u8) {
+        self.error_log.record(kind, detail, self.telemetry.uptime());
+    }
+
+    /// Tells the host the ESP32 has reset. If the endpoint is busy it's left pending for the next
+    /// poll.
+    fn send_esp_reset<B: UsbBus>(&mut self, port: &WebUsbClass<'_, B>) {
+        let (cause, boot) = match self.pending_esp_reset {
+            Some(reset) => reset,
+            None => return,
+        };
+
+        if port.notify(event::ESP_RESET, &[cause, boot]) {
+            self.pending_esp_reset = None;
+        }
+    }
+
+    /// Tells the host one of its patterns has matched. If the endpoint is busy it's left pending
+    /// for the next poll, and a later match replaces it.
+    #[cfg(feature = "pattern-watch")]
+    fn send_pattern<B: UsbBus>(&mut self, port: &WebUsbClass<'_, B>) {
+        let (len, payload) = match self.watch.found() {
+            Some(snippet) => snippet.payload(),
+            None => return,
+        };
+
+        if port.notify(event::PATTERN, &payload[..len]) {
+            self.watch.sent();
+        }
+    }
+
+    /// Sends a pending SUPPLY notification with the latest state. If the endpoint is busy it's left
+    /// pending for the next poll.
+    fn send_supply<B: UsbBus>(&mut self, port: &WebUsbClass<'_, B>) {
+        if self.pending_supply && port.notify(event::SUPPLY, &[self.supply_low as u8]) {
+            self.pending_supply = false;
+        }
+    }
+}
+
+impl<B: UsbBus> VendorRequests<B> for Bridge {
+    fn control_in(&mut self, port: &mut WebUsbClass<'_, B>, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        #[cfg(feature = "rate-limit")]
+        if !self.rate_limits.vendor(req.request) {
+            xfer.reject().ok();
+            return;
+        }
+
+        match req.request {
+            #[cfg(feature = "unlock")]
+            request::GET_UNLOCK_NONCE => match &self.identity {
+                Some(identity) => {
+                    xfer.accept_with(&self.unlock.nonce(identity, (self.entropy)())).ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            },
+            #[cfg(feature = "error-log")]
+            request::GET_ERROR_LOG => {
+                let (_, records) = errorlog::records(self.config_page);
+                xfer.accept_with(records).ok();
+            }
+            request::GET_SELF_TEST => {
+                let report = match self.self_test_report {
+                    Some(report) => report.to_bytes(),
+                    None => [SelfTestReport::NOT_RUN, 0],
+                };
+                xfer.accept_with(&report).ok();
+            }
+            #[cfg(feature = "fixture")]
+            request::GET_FIXTURE => match &self.fixture_map {
+                Some(map) => {
+                    xfer.accept_with(map).ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            },
+            request::GET_CONFIG => {
+                xfer.accept_with(&self.config.to_payload()).ok();
+            }
+            request::GET_TELEMETRY => {
+                xfer.accept_with(&self.telemetry.report()).ok();
+            }
+            request::GET_ERRORS | request::TAKE_ERRORS => {
+                let take = req.request == request::TAKE_ERRORS;
+                let mut report = [0u8; request::ERRORS_LENGTH];
+                report[..stats::REPORT_LENGTH].copy_from_slice(&self.read_errors.to_bytes());
+                report[stats::REPORT_LENGTH..stats::REPORT_LENGTH + 16]
+                    .copy_from_slice(&self.uart_errors.to_bytes());
+                report[stats::REPORT_LENGTH + 16..stats::REPORT_LENGTH + 20]
+                    .copy_from_slice(&port.line_coding_rejects().to_le_bytes());
+                report[stats::REPORT_LENGTH + 20..]
+                    .copy_from_slice(&port.output_drops().to_bytes());
+                if xfer.accept_with(&report).is_ok() && take {
+                    self.read_errors.clear();
+                    self.uart_errors = UartErrors::default();
+                    port.clear_errors();
+                }
+            }
+            request::GET_BUILD_INFO => {
+                xfer.accept_with(&BUILD_INFO).ok();
+            }
+            request::GET_STATUS => {
+                xfer.accept_with(&[self.reset_cause.bits()]).ok();
+            }
+            request::GET_VERSION => {
+                xfer.accept_with(&VERSION.to_bytes()).ok();
+            }
+            request::GET_THROUGHPUT => {
+                xfer.accept_with(&port.throughput_report()).ok();
+            }
+            request::GET_UART_QUEUE => {
+                let mut response = [0u8; request::UART_QUEUE_LENGTH];
+                response[..2].copy_from_slice(&self.uart_queued.to_le_bytes());
+                response[2..].copy_from_slice(&(BACKLOG_SIZE as u16).to_le_bytes());
+                xfer.accept_with(&response).ok();
+            }
+            request::PING => {
+                let micros = port.millis().wrapping_mul(1000).wrapping_add((self.tick_micros)());
+                let mut response = [0u8; request::PING_LENGTH];
+                response[..2].copy_from_slice(&req.value.to_le_bytes());
+                response[2..].copy_from_slice(&micros.to_le_bytes());
+                xfer.accept_with(&response).ok();
+            }
+            request::GET_CAPTURE => {
+                let (offset, length) = (req.value as usize, req.length as usize);
+                let capture = &mut self.capture;
+                xfer.accept(|data| {
+                    let len = data.len().min(length);
+                    Ok(capture.read(offset, &mut data[..len]))
+                })
+                .ok();
+            }
+            #[cfg(feature = "jtag")]
+            request::GET_JTAG_TDO => match self.jtag.tdo() {
+                Some(tdo) => {
+                    xfer.accept_with(tdo).ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            },
+            #[cfg(feature = "spi-flash")]
+            request::GET_SPI_RESULT => match self.spi.result() {
+                Some(result) => {
+                    xfer.accept_with(result).ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            },
+            #[cfg(feature = "flasher")]
+            request::GET_ROM_RESPONSE => {
+                xfer.accept_with(self.flasher.response()).ok();
+            }
+            request::GET_IDENTITY => match (&self.identity, &self.challenge) {
+                (Some(identity), Some(challenge)) => {
+                    xfer.accept_with(&identity.respond(challenge)).ok();
+                }
+                _ => {
+                    xfer.reject().ok();
+                }
+            },
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_out(&mut self, port: &mut WebUsbClass<'_, B>, xfer: &ControlOut<B>) -> bool {
+        let req = xfer.request();
+
+        #[cfg(feature = "rate-limit")]
+        if !self.rate_limits.vendor(req.request) {
+            return false;
+        }
+
+        match req.request {
+            #[cfg(feature = "unlock")]
+            guarded if unlock::GUARDED.contains(&guarded) && !self.unlock.unlocked() => false,
+            #[cfg(feature = "unlock")]
+            request::UNLOCK => self.unlock.answer(xfer.data()),
+            request::SELF_TEST => {
+                self.self_test_requested = true;
+                self.self_test_report = None;
+                true
+            }
+            #[cfg(feature = "fixture")]
+            request::FIXTURE_TEST if req.value == request::FIXTURE_KEY => {
+                self.fixture_requested = true;
+                self.fixture_map = None;
+                true
+            }
+            #[cfg(feature = "error-log")]
+            request::CLEAR_ERROR_LOG if req.value == request::ERROR_LOG_KEY => {
+                self.error_log_cleared = true;
+                true
+            }
+            request::DETACH => {
+                self.detach_requested = true;
+                true
+            }
+            request::SET_MONITOR if req.value <= 2 => {
+                port.set_monitor(req.value != 0, req.value == 2);
+                true
+            }
+            request::CLAIM if req.value <= 2 => {
+                port.set_owner(match req.value {
+                    1 => Some(Port::Serial),
+                    2 => Some(Port::WebUsb),
+                    _ => None,
+                });
+                true
+            }
+            request::SET_PROFILE if req.value <= 1 => {
+                port.set_compatibility(req.value == 1);
+                self.reattach_requested = true;
+                true
+            }
+            #[cfg(feature = "strap-pin")]
+            request::SET_STRAP => {
+                match StrapMode::from_u16(req.value) {
+                    Some(strap) => {
+                        self.strap = strap;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            #[cfg(feature = "production-lock")]
+            request::LOCK if req.value == request::LOCK_KEY => {
+                self.lock_requested = true;
+                true
+            }
+            #[cfg(feature = "jtag")]
+            request::JTAG_SHIFT if self.jtag.load(xfer.data()) => true,
+            #[cfg(feature = "spi-flash")]
+            request::SET_SPI_FLASH if req.value <= 1 => {
+                self.spi_flash = req.value == 1;
+                self.spi_flash_changed = true;
+                true
+            }
+            #[cfg(feature = "spi-flash")]
+            request::SPI_TRANSFER
+                if self.spi_flash && self.spi.load(xfer.data(), req.value & spiflash::KEEP_CS != 0) =>
+            {
+                true
+            }
+            #[cfg(feature = "flasher")]
+            request::SET_FLASHER if req.value <= 1 => {
+                self.flasher.set_active(req.value == 1);
+                true
+            }
+            #[cfg(feature = "flasher")]
+            request::ROM_COMMAND if self.flasher.load(req.value, xfer.data()) => true,
+            #[cfg(feature = "throttle")]
+            request::SET_THROTTLE if req.value <= 1 => match xfer.data().try_into() {
+                Ok(rate) => {
+                    let throttle =
+                        if req.value == 0 { &mut self.to_esp } else { &mut self.from_esp };
+                    throttle.set(u32::from_le_bytes(rate));
+                    true
+                }
+                Err(_) => false,
+            },
+            #[cfg(feature = "fault-inject")]
+            request::SET_FAULTS if req.value == request::FAULTS_KEY => {
+                matches!(xfer.data().try_into(), Ok(settings) if self.faults.set(settings))
+            }
+            #[cfg(feature = "line-buffer")]
+            request::SET_LINE_BUFFER if req.value <= request::LINE_BUFFER_MAX_MS => {
+                port.set_line_buffer_ms(req.value);
+                true
+            }
+            request::SET_TIMESTAMPS if req.value <= 1 => {
+                port.set_timestamps(req.value == 1);
+                true
+            }
+            request::SET_THROUGHPUT if req.value <= 1 => {
+                port.set_throughput(req.value == 1);
+                true
+            }
+            request::SET_CONFIG => match Config::parse(xfer.data()) {
+                Some(config) => {
+                    port.set_landing_page(config.landing_page);
+                    self.config = config;
+                    self.config_changed = true;
+                    true
+                }
+                None => false,
+            },
+            #[cfg(feature = "pattern-watch")]
+            request::SET_WATCH
+                if Action::from_u8((req.value >> 8) as u8).is_some_and(|action| {
+                    self.watch.set(req.value as u8 as usize, action, xfer.data())
+                }) =>
+            {
+                true
+            }
+            request::SET_CHALLENGE => match xfer.data().try_into() {
+                Ok(challenge) => {
+                    self.challenge = Some(challenge);
+                    true
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+
+    #[cfg(feature = "rate-limit")]
+    fn allow_control_lines(&mut self) -> bool {
+        self.rate_limits.control_lines()
+    }
+
+    fn reset(&mut self) {
+        #[cfg(feature = "throttle")]
+        {
+            self.to_esp.set(0);
+            self.from_esp.set(0);
+        }
+        #[cfg(feature = "fault-inject")]
+        {
+            self.faults = Faults::default();
+        }
+        #[cfg(feature = "escapes")]
+        {
+            self.escapes = Escapes::default();
+        }
+        self.self_test_requested = false;
+        #[cfg(feature = "fixture")]
+        {
+            self.fixture_requested = false;
+        }
+        #[cfg(feature = "unlock")]
+        self.unlock.lock();
+        self.detach_requested = false;
+        self.lock_requested = false;
+        self.reattach_requested = false;
+        self.strap = esp::CHIP.strap;
+        self.capture.thaw();
+        self.challenge = None;
+        self.pending_esp_reset = None;
+        #[cfg(feature = "pattern-watch")]
+        {
+            self.watch = PatternWatch::default();
+            self.watch_reset = None;
+        }
+        self.pending_supply = false;
+        // Give the UART back, and let the ESP32 run again if the host went away while it had the
+        // flash.
+        #[cfg(feature = "flasher")]
+        self.flasher.set_active(false);
+        #[cfg(feature = "spi-flash")]
+        if self.spi_flash {
+            self.spi_flash = false;
+            self.spi_flash_changed = true;
+        }
+    }
+
+    fn poll(&mut self, port: &mut WebUsbClass<'_, B>) {
+        self.send_esp_reset(port);
+        self.send_supply(port);
+        #[cfg(feature = "pattern-watch")]
+        self.send_pattern(port);
+    }
+}
+
+impl<B, RS, WS> WebUSB<'_, B, RS, WS, Bridge>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    fn bridge(&self) -> &Bridge {
+        self.parts().1
+    }
+
+    fn bridge_mut(&mut self) -> &mut Bridge {
+        self.vendor_requests()
+    }
+
+    /// What should drive the ESP32's second strapping pin.
+    pub fn strap(&self) -> StrapMode {
+        self.bridge().strap
+    }
+
+    /// Returns true (once) if the host has asked for the end-of-line self-test to be run.
+    pub fn take_self_test_request(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().self_test_requested, false)
+    }
+
+    /// Returns true (once) if the host has asked for the fixture pin walk to be run.
+    #[cfg(feature = "fixture")]
+    pub fn take_fixture_request(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().fixture_requested, false)
+    }
+
+    /// Returns true (once) if the host has asked to restart in the DFU bootloader.
+    pub fn take_detach_request(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().detach_requested, false)
+    }
+
+    /// Returns true (once) if the host has changed the descriptor profile, so the device has to
+    /// drop off the bus and come back for the host to see it.
+    pub fn take_reattach_request(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().reattach_requested, false)
+    }
+
+    /// Returns true (once) if the host has asked for readout protection to be turned on. Only
+    /// builds with the `production-lock` feature accept the request.
+    pub fn take_lock_request(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().lock_requested, false)
+    }
+
+    /// Clocks out a `JTAG_SHIFT` batch from the host, if there's one waiting.
+    #[cfg(feature = "jtag")]
+    pub fn shift_jtag<TCK, TMS, TDI, TDO>(&mut self, pins: &mut JtagPins<TCK, TMS, TDI, TDO>)
+    where
+        TCK: OutputPin,
+        TMS: OutputPin,
+        TDI: OutputPin,
+        TDO: InputPin,
+    {
+        self.bridge_mut().jtag.shift(pins);
+    }
+
+    /// Whether the host has taken over the ESP32's SPI flash. Only builds with the `spi-flash`
+    /// feature accept the request.
+    #[cfg(feature = "spi-flash")]
+    pub fn spi_flash(&self) -> bool {
+        self.bridge().spi_flash
+    }
+
+    #[cfg(not(feature = "spi-flash"))]
+    pub fn spi_flash(&self) -> bool {
+        false
+    }
+
+    /// Returns whether the host now has the SPI flash (once) if it's taken it or let go of it.
+    #[cfg(feature = "spi-flash")]
+    pub fn take_spi_flash_change(&mut self) -> Option<bool> {
+        let bridge = self.bridge_mut();
+        if core::mem::replace(&mut bridge.spi_flash_changed, false) {
+            Some(bridge.spi_flash)
+        } else {
+            None
+        }
+    }
+
+    /// Returns (once) whether to reset the ESP32 into download mode, if a pattern has asked for it
+    /// to be reset.
+    #[cfg(feature = "pattern-watch")]
+    pub fn take_watch_reset(&mut self) -> Option<bool> {
+        self.bridge_mut().watch_reset.take()
+    }
+
+    /// Clocks out an `SPI_TRANSFER` from the host, if there's one waiting.
+    #[cfg(feature = "spi-flash")]
+    pub fn run_spi<CLK, MOSI, CS, MISO>(&mut self, pins: &mut SpiPins<CLK, MOSI, CS, MISO>)
+    where
+        CLK: OutputPin,
+        MOSI: OutputPin,
+        CS: OutputPin,
+        MISO: InputPin,
+    {
+        self.bridge_mut().spi.run(pins);
+    }
+
+    /// Whether the host has taken over the UART to talk to the ESP32's ROM loader. Only builds
+    /// with the `flasher` feature accept the request.
+    #[cfg(feature = "flasher")]
+    pub fn flashing(&self) -> bool {
+        self.bridge().flasher.active()
+    }
+
+    #[cfg(not(feature = "flasher"))]
+    pub fn flashing(&self) -> bool {
+        false
+    }
+
+    /// Sends the host's ROM command to the ESP32, if it's time to.
+    #[cfg(feature = "flasher")]
+    pub fn run_flasher<W: serial::Write<u8>>(&mut self, uart: &mut W) {
+        let (port, bridge) = self.parts_mut();
+        bridge.flasher.poll(port.millis(), uart);
+    }
+
+    /// Stores the self-test result for the host to collect.
+    pub fn set_self_test_report(&mut self, report: SelfTestReport) {
+        self.bridge_mut().self_test_report = Some(report);
+    }
+
+    /// Stores the fixture pin walk's connectivity map for the host to collect.
+    #[cfg(feature = "fixture")]
+    #[inline(never)]
+    pub fn set_fixture_map(&mut self, map: FixtureMap) {
+        self.bridge_mut().fixture_map = Some(map.to_bytes());
+    }
+
+    /// Gets the current settings.
+    pub fn config(&self) -> &Config {
+        &self.bridge().config
+    }
+
+    /// Sets the settings reported to the host, e.g. after loading them from flash.
+    #[inline(never)]
+    pub fn set_config(&mut self, config: Config) {
+        let (port, bridge) = self.parts_mut();
+        port.set_landing_page(config.landing_page);
+        bridge.config = config;
+    }
+
+    /// Returns true (once) if the host has changed the settings, which should then be saved.
+    pub fn take_config_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().config_changed, false)
+    }
+
+    /// Records a new set of supply rail readings for the host to collect.
+    pub fn record_supply(&mut self, readings: SupplyReadings) {
+        self.bridge_mut().telemetry.record_supply(readings);
+    }
+
+    /// The latest supply rail readings.
+    pub fn latest_supply(&self) -> SupplyReadings {
+        self.bridge().telemetry.latest()
+    }
+
+    /// Records a new set of internal sensor readings for the host to collect. Their noise goes
+    /// into the unlock nonces.
+    pub fn record_chip(&mut self, readings: ChipReadings) {
+        let bridge = self.bridge_mut();
+        #[cfg(feature = "unlock")]
+        bridge.unlock.stir(u32::from(readings.temperature) | u32::from(readings.vrefint) << 16);
+        bridge.telemetry.record_chip(readings);
+    }
+
+    /// Counts a busy pass of the main loop, from `board::tick_micros` at its start and end.
+    #[cfg(feature = "loop-load")]
+    pub fn record_busy(&mut self, start_us: u32, end_us: u32) {
+        self.bridge_mut().telemetry.record_busy(start_us, end_us);
+    }
+
+    /// Advances the uptime reported to the host, fills the throttles' and rate limits' budgets,
+    /// and sends any output the port has held (see `tick`).
+    pub fn tick_uptime(&mut self, elapsed_ms: u32) {
+        let bridge = self.bridge_mut();
+        bridge.telemetry.tick(elapsed_ms);
+        #[cfg(feature = "rate-limit")]
+        bridge.rate_limits.tick(elapsed_ms);
+        #[cfg(feature = "throttle")]
+        {
+            bridge.to_esp.tick(elapsed_ms);
+            bridge.from_esp.tick(elapsed_ms);
+        }
+        #[cfg(feature = "escapes")]
+        bridge.escapes.tick(bridge.telemetry.uptime());
+        self.tick(elapsed_ms);
+    }
+
+    /// Spends a byte from the host's throttle, for draining the UART backlog. Returns false if
+    /// it's over the limit. Only builds with the `throttle` feature accept a limit.
+    #[cfg(feature = "throttle")]
+    pub fn pass_to_esp(&mut self) -> bool {
+        self.bridge_mut().to_esp.take()
+    }
+
+    #[cfg(not(feature = "throttle"))]
+    pub fn pass_to_esp(&mut self) -> bool {
+        true
+    }
+
+    /// Spends a byte of the ESP32's output from its throttle. Returns false, and counts it as
+    /// dropped by both ports, if it's over the limit.
+    #[cfg(feature = "throttle")]
+    pub fn pass_from_esp(&mut self) -> bool {
+        let (port, bridge) = self.parts_mut();
+        if bridge.from_esp.take() {
+            return true;
+        }
+        port.record_dropped(Port::Serial, 1);
+        port.record_dropped(Port::WebUsb, 1);
+        false
+    }
+
+    #[cfg(not(feature = "throttle"))]
+    pub fn pass_from_esp(&mut self) -> bool {
+        true
+    }
+
+    /// Injects the host's faults into the first `count` bytes of `buf` (see `faults`), and returns
+    /// how many there are now. Only builds with the `fault-inject` feature have any to inject.
+    #[cfg(feature = "fault-inject")]
+    pub fn inject_faults(&mut self, direction: Direction, buf: &mut [u8], count: usize) -> usize {
+        self.bridge_mut().faults.inject(direction, buf, count)
+    }
+
+    #[cfg(not(feature = "fault-inject"))]
+    pub fn inject_faults(&mut self, _direction: Direction, _buf: &mut [u8], count: usize) -> usize {
+        count
+    }
+
+    /// Sets the boot counters reported to the host.
+    pub fn set_boot_counts(&mut self, counts: BootCounts) {
+        let bridge = self.bridge_mut();
+        bridge.telemetry.set_boot_counts(counts);
+        #[cfg(feature = "error-log")]
+        bridge.error_log.set_boots(counts.boots);
+    }
+
+    /// Sets the ESP32's boot and crash counts reported to the host, from the configuration page.
+    pub fn set_esp_counts(&mut self, counts: EspCounts) {
+        self.bridge_mut().telemetry.set_esp_counts(counts);
+    }
+
+    /// Takes a configuration page boot log entry for the ESP32's boots or crashes which haven't
+    /// been logged yet (see `counters`).
+    #[cfg(feature = "esp-counters")]
+    pub fn take_esp_log_entry(&mut self) -> Option<[u8; 2]> {
+        self.bridge_mut().telemetry.esp_counter().take_log_entry()
+    }
+
+    /// Takes the escapes out of data from the serial port's terminal in place (see `escape`), and
+    /// returns how much of it is left for the ESP32.
+    #[cfg(feature = "escapes")]
+    #[inline(never)]
+    pub fn filter_escapes(&mut self, data: &mut [u8]) -> usize {
+        self.bridge_mut().escapes.filter(data)
+    }
+
+    /// Returns (once) what the serial port's terminal has asked the bridge to do with an escape.
+    #[cfg(feature = "escapes")]
+    pub fn take_escape_command(&mut self) -> Option<Command> {
+        self.bridge_mut().escapes.take_command()
+    }
+
+    /// Whether the serial port should get the ESP32's output, which it doesn't while its terminal
+    /// has escaped to the bridge. Only builds with the `escapes` feature take escapes.
+    #[cfg(feature = "escapes")]
+    pub fn serial_shows_esp(&self) -> bool {
+        self.bridge().escapes.mode() == Mode::Esp
+    }
+
+    #[cfg(not(feature = "escapes"))]
+    pub fn serial_shows_esp(&self) -> bool {
+        true
+    }
+
+    /// Formats a line for the serial port's stats view (see `escape`).
+    #[cfg(feature = "escapes")]
+    pub fn stats_line(&self) -> [u8; STATS_LINE_LENGTH] {
+        let (port, bridge) = self.parts();
+        let dropped = port.output_drops().count(Port::Serial);
+        escape::stats_line(bridge.telemetry.uptime(), bridge.telemetry.latest(), dropped)
+    }
+
+    /// Records the most stack used so far, and the stack's size, for the host to collect.
+    pub fn record_stack(&mut self, used: u16, size: u16) {
+        self.bridge_mut().telemetry.record_stack(used, size);
+    }
+
+    /// Records the result of reading from a port. Returns true if it was a genuine error.
+    pub fn record_read<T>(&mut self, port: Port, result: &Result<T>) -> bool {
+        self.bridge_mut().read_errors.record(port, result)
+    }
+
+    /// Gets the read error counters.
+    pub fn read_errors(&self) -> &ReadErrors {
+        &self.bridge().read_errors
+    }
+
+    /// Re-arms the OUT endpoint after an error, discarding any packet it was holding.
+    pub fn recover_read_ep(&mut self) {
+        let (port, bridge) = self.parts_mut();
+        port.recover_read_ep();
+        bridge.read_errors.recovered();
+        #[cfg(feature = "error-log")]
+        bridge.record_error(errorlog::USB_RECOVERY, 0);
+    }
+
+    /// Records the device being re-attached to the bus.
+    pub fn record_reattach(&mut self) {
+        let bridge = self.bridge_mut();
+        bridge.read_errors.reattached();
+        #[cfg(feature = "error-log")]
+        bridge.record_error(errorlog::REATTACH, 0);
+    }
+
+    /// Gives the capture of the ESP32's output its buffer. Nothing is captured until then.
+    pub fn set_capture_buffer(&mut self, buf: &'static mut [u8; CAPTURE_LENGTH]) {
+        self.bridge_mut().capture = Capture::new(buf);
+    }
+
+    /// Sets the identity used to answer challenges from the host.
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.bridge_mut().identity = Some(identity);
+    }
+
+    /// Sets up this boot's unlock handshake.
+    #[cfg(feature = "unlock")]
+    pub fn set_unlock(&mut self, unlock: Unlock) {
+        self.bridge_mut().unlock = unlock;
+    }
+
+    /// Sets where the noise for the unlock nonces comes from, sampled as each is asked for.
+    #[cfg(feature = "unlock")]
+    pub fn set_entropy(&mut self, entropy: fn() -> u32) {
+        self.bridge_mut().entropy = entropy;
+    }
+
+    /// Sets the configuration page, whose error log the host can read.
+    #[cfg(feature = "error-log")]
+    pub fn set_config_page(&mut self, page: &'static [u8]) {
+        self.bridge_mut().config_page = page;
+    }
+
+    /// Notes an error for the error log, unless one of its kind already has been this boot.
+    #[cfg(feature = "error-log")]
+    pub fn record_error(&mut self, kind: u8, detail: u8) {
+        self.bridge_mut().record_error(kind, detail);
+    }
+
+    /// Takes an error log record which hasn't been saved yet, or `None` once they all have.
+    #[cfg(feature = "error-log")]
+    pub fn take_error_log_entry(&mut self) -> Option<[u8; ENTRY_LENGTH]> {
+        self.bridge_mut().error_log.take_entry()
+    }
+
+    /// Returns true (once) if the host has asked for the error log to be cleared.
+    #[cfg(feature = "error-log")]
+    pub fn take_error_log_cleared(&mut self) -> bool {
+        core::mem::replace(&mut self.bridge_mut().error_log_cleared, false)
+    }
+
+    /// Sets the cause of the last reset, for the host to collect, and the error log if it's one
+    /// worth logging.
+    pub fn set_reset_cause(&mut self, cause: ResetCause) {
+        let bridge = self.bridge_mut();
+        bridge.reset_cause = cause;
+        #[cfg(feature = "error-log")]
+        bridge.error_log.record_reset(cause);
+    }
+
+    /// Sets how many bytes from the host are still waiting to go out of the UART, for
+    /// `GET_UART_QUEUE`.
+    pub fn set_uart_queued(&mut self, queued: usize) {
+        self.bridge_mut().uart_queued = queued as u16;
+    }
+
+    /// Sets the clock which gives the microseconds since the uptime last ticked, for `PING`.
+    pub fn set_tick_clock(&mut self, tick_micros: fn() -> u32) {
+        self.bridge_mut().tick_micros = tick_micros;
+    }
+
+    /// Keeps UART output for the host to fetch later (see `capture`), and watches it for the
+    /// ESP32's reset banner, its crashes (see `crash`) and the host's patterns (see `watch`).
+    #[inline(never)]
+    pub fn record_uart_output(&mut self, data: &[u8]) {
+        let (port, bridge) = self.parts_mut();
+        bridge.capture.record(data);
+        for &byte in data {
+            #[cfg(feature = "flasher")]
+            bridge.flasher.feed(byte);
+            if let Some(reset) = bridge.banner.feed(byte) {
+                bridge.pending_esp_reset = Some(reset);
+                #[cfg(feature = "esp-counters")]
+                bridge.telemetry.esp_counter().record_boot();
+            }
+            #[cfg(feature = "esp-counters")]
+            if bridge.crash.feed(byte) {
+                bridge.telemetry.esp_counter().record_crash();
+            }
+            #[cfg(feature = "pattern-watch")]
+            match bridge.watch.feed(byte) {
+                Some(Action::Reset) => bridge.watch_reset = Some(false),
+                Some(Action::Download) => bridge.watch_reset = Some(true),
+                Some(Action::Snapshot) => bridge.capture.freeze(),
+                _ => {}
+            }
+        }
+        bridge.send_esp_reset(port);
+        #[cfg(feature = "pattern-watch")]
+        bridge.send_pattern(port);
+    }
+
+    /// Records a UART receive error, and lets the host know with a SERIAL_STATE notification.
+    #[inline(never)]
+    pub fn record_uart_error(&mut self, error: UartError) {
+        let (port, bridge) = self.parts_mut();
+        bridge.uart_errors.record(error);
+        #[cfg(feature = "error-log")]
+        if error == UartError::Overrun {
+            bridge.record_error(errorlog::UART_OVERRUN, 0);
+        }
+        port.notify_serial_state(match error {
+            UartError::Overrun => event::SERIAL_STATE_OVERRUN,
+            UartError::Framing => event::SERIAL_STATE_FRAMING,
+            UartError::Parity => event::SERIAL_STATE_PARITY,
+            // CDC has no bit for noise
+            UartError::Noise => 0,
+        });
+    }
+
+    /// Records whether the supply is below the brown-out warning threshold, and lets the host know
+    /// with a SUPPLY notification when that changes.
+    pub fn set_supply_low(&mut self, low: bool) {
+        let (port, bridge) = self.parts_mut();
+        if low != bridge.supply_low {
+            bridge.supply_low = low;
+            bridge.pending_supply = true;
+        }
+        bridge.send_supply(port);
+    }
+
+    /// Whether the supply is below the brown-out warning threshold, so flash shouldn't be written.
+    pub fn supply_low(&self) -> bool {
+        self.bridge().supply_low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errorlog;
+    use crate::mock::{MockBus, TransferError, TransferResult};
+    use crate::webusb::{WebUsbBuilder, WebUsbEvent};
+    use tilda_bridge_protocol::flasher;
+    use tilda_bridge_protocol::version::Version;
+    use usb_device::prelude::*;
+
+    const DESCRIPTOR_BOS: u8 = 0x0F;
+    const REQ_SET_LINE_CODING: u8 = 0x20;
+    const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+    fn bridge_port(alloc: &UsbBusAllocator<MockBus>) -> BridgePort<'_, MockBus> {
+        let mut webusb = WebUsbBuilder::new(alloc).vendor_requests(Bridge::default()).build();
+        webusb.set_capture_buffer(Box::leak(Box::new([0; CAPTURE_LENGTH])));
+        webusb
+    }
+
+    /// Runs `f` against an enumerated device with just the bridge's WebUSB port on a simulated
+    /// bus.
+    fn with_device(f: impl FnOnce(&MockBus, &mut dyn FnMut())) {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        webusb.set_identity(identity());
+        webusb.set_reset_cause(ResetCause::from_csr(0x0C00_0000));
+        #[cfg(feature = "unlock")]
+        webusb.set_unlock(Unlock::new(7));
+        #[cfg(feature = "error-log")]
+        webusb.set_config_page(error_log_page());
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+
+        bus.enumerate(&mut poll).unwrap();
+        // So the other tests can use the guarded requests.
+        if cfg!(feature = "unlock") {
+            unlock(&bus, &mut poll).unwrap();
+        }
+        f(&bus, &mut poll);
+    }
+
+    /// Does the unlock handshake as a host would.
+    fn unlock(bus: &MockBus, poll: &mut dyn FnMut()) -> TransferResult<()> {
+        let nonce = bus.control_read(poll, 0xC1, request::GET_UNLOCK_NONCE, 0, 0, 8)?;
+        let response = identity().unlock_response(&nonce.try_into().unwrap());
+        bus.control_write(poll, 0x41, request::UNLOCK, 0, 0, &response)
+    }
+
+    fn identity() -> Identity {
+        Identity::new([0xAA; 12], [0x11; 16])
+    }
+
+    /// A configuration page whose error log has a cleared record, then `error_log_records()`.
+    #[cfg(feature = "error-log")]
+    fn error_log_page() -> &'static [u8] {
+        let mut page = vec![0xFF; 1024];
+        let log = &mut page[errorlog::ERROR_LOG_OFFSET..];
+        log[..errorlog::ENTRY_LENGTH].fill(0);
+        let records = error_log_records();
+        log[errorlog::ENTRY_LENGTH..][..records.len()].copy_from_slice(&records);
+        Box::leak(page.into_boxed_slice())
+    }
+
+    fn error_log_records() -> Vec<u8> {
+        let reset = errorlog::Entry { kind: errorlog::RESET, detail: 0x20, boot: 3, uptime: 0 };
+        let panic = errorlog::Entry { kind: errorlog::PANIC, detail: 0, boot: 4, uptime: 61 };
+        [reset.to_bytes(), panic.to_bytes()].concat()
+    }
+
+    /// Firmware's own handler, which answers PING itself, adds a request, and leaves the rest to
+    /// the bridge.
+    #[derive(Default)]
+    struct Wrapped {
+        bridge: Bridge,
+        pinged: bool,
+    }
+
+    impl<B: UsbBus> VendorRequests<B> for Wrapped {
+        fn control_in(&mut self, port: &mut WebUsbClass<'_, B>, xfer: ControlIn<B>) {
+            match xfer.request().request {
+                request::PING => {
+                    self.pinged = true;
+                    xfer.accept_with(b"pong").ok();
+                }
+                _ => self.bridge.control_in(port, xfer),
+            }
+        }
+
+        fn control_out(&mut self, port: &mut WebUsbClass<'_, B>, xfer: &ControlOut<B>) -> bool {
+            match xfer.request().request {
+                0x7F => true,
+                _ => self.bridge.control_out(port, xfer),
+            }
+        }
+
+        fn reset(&mut self) {
+            VendorRequests::<B>::reset(&mut self.bridge);
+        }
+    }
+
+    #[test]
+    fn wrapped_requests() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUsbBuilder::new(&alloc).vendor_requests(Wrapped::default()).build();
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        let response = bus.control_read(&mut poll, 0xC1, request::PING, 0, 0, 64).unwrap();
+        assert_eq!(response, b"pong");
+        bus.control_write(&mut poll, 0x41, 0x7F, 3, 0, &[]).unwrap();
+        // The bridge's own requests still work.
+        let version = bus.control_read(&mut poll, 0xC1, request::GET_VERSION, 0, 0, 64).unwrap();
+        assert_eq!(Version::parse(&version), Some(VERSION));
+        bus.control_write(&mut poll, 0x41, request::SET_TIMESTAMPS, 1, 0, &[]).unwrap();
+
+        assert!(webusb.vendor_requests().pinged);
+        let events: Vec<_> = core::iter::from_fn(|| webusb.poll_event()).collect();
+        assert_eq!(
+            events,
+            [
+                WebUsbEvent::Vendor { request: 0x7F, value: 3 },
+                WebUsbEvent::Vendor { request: request::SET_TIMESTAMPS, value: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn vendor_events() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        bus.control_write(&mut poll, 0x41, request::SET_TIMESTAMPS, 1, 0, &[]).unwrap();
+        // Stalled, so nothing happened
+        let result = bus.control_write(&mut poll, 0x41, request::SET_TIMESTAMPS, 2, 0, &[]);
+        assert_eq!(result, Err(TransferError::Stall));
+
+        let events: Vec<_> = core::iter::from_fn(|| webusb.poll_event()).collect();
+        assert_eq!(events, [WebUsbEvent::Vendor { request: request::SET_TIMESTAMPS, value: 1 }]);
+    }
+
+    #[test]
+    fn config_requests() {
+        with_device(|bus, poll| {
+            let config = bus.control_read(poll, 0xC1, request::GET_CONFIG, 0, 0, 64).unwrap();
+            assert_eq!(config, Config::default().to_payload());
+
+            bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &[1, 10, 5, 2, 1]).unwrap();
+            let config = bus.control_read(poll, 0xC1, request::GET_CONFIG, 0, 0, 64).unwrap();
+            assert_eq!(config[..9], [1, 10, 5, 2, 1, 0, 0, 0, 1]);
+
+            let result = bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &[0x7F]);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            // The WebUSB platform capability's landing page goes with it.
+            let webusb_landing_page = |poll: &mut dyn FnMut()| {
+                let bos = bus.get_descriptor(poll, DESCRIPTOR_BOS, 0, 0xFF).unwrap();
+                let mut rest = &bos[bos[0] as usize..];
+                while rest[2] != 0x05 {
+                    rest = &rest[rest[0] as usize..];
+                }
+                rest[23]
+            };
+            assert_eq!(webusb_landing_page(poll), 1);
+            let mut payload = Config::default().to_payload();
+            payload[8] = 0;
+            bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &payload).unwrap();
+            assert_eq!(webusb_landing_page(poll), 0);
+        });
+    }
+
+    #[test]
+    fn identity_requests() {
+        with_device(|bus, poll| {
+            // Nothing to answer yet
+            let result = bus.control_read(poll, 0xC1, request::GET_IDENTITY, 0, 0, 64);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let result = bus.control_write(poll, 0x41, request::SET_CHALLENGE, 0, 0, &[0; 8]);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let challenge = [0x5A; CHALLENGE_LENGTH];
+            bus.control_write(poll, 0x41, request::SET_CHALLENGE, 0, 0, &challenge).unwrap();
+            let response = bus.control_read(poll, 0xC1, request::GET_IDENTITY, 0, 0, 64).unwrap();
+            assert_eq!(response[..request::IDENTITY_LENGTH], identity().respond(&challenge));
+        });
+    }
+
+    #[test]
+    fn error_log() {
+        with_device(|bus, poll| {
+            let log = bus.control_read(poll, 0xC1, request::GET_ERROR_LOG, 0, 0, 128);
+            if !cfg!(feature = "error-log") {
+                assert_eq!(log, Err(TransferError::Stall));
+                return;
+            }
+            assert_eq!(log, Ok(error_log_records()));
+
+            let key = request::ERROR_LOG_KEY;
+            let result = bus.control_write(poll, 0x41, request::CLEAR_ERROR_LOG, 0, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+            let result = bus.control_write(poll, 0x41, request::CLEAR_ERROR_LOG, key, 0, &[]);
+            assert_eq!(result, Ok(()));
+        });
+    }
+
+    #[test]
+    fn fixture_requests() {
+        with_device(|bus, poll| {
+            let key = request::FIXTURE_KEY;
+            let result = bus.control_write(poll, 0x41, request::FIXTURE_TEST, key, 0, &[]);
+            if !cfg!(feature = "fixture") {
+                assert_eq!(result, Err(TransferError::Stall));
+                return;
+            }
+            assert_eq!(result, Ok(()));
+
+            // The wrong key is refused, and there's no map until the main loop has walked the pins.
+            let result = bus.control_write(poll, 0x41, request::FIXTURE_TEST, 0, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+            let result = bus.control_read(poll, 0xC1, request::GET_FIXTURE, 0, 0, 128);
+            assert_eq!(result, Err(TransferError::Stall));
+        });
+    }
+
+    #[test]
+    fn unlock_requests() {
+        with_device(|bus, poll| {
+            if !cfg!(feature = "unlock") {
+                assert_eq!(unlock(bus, poll), Err(TransferError::Stall));
+                return;
+            }
+            let payload = Config::default().to_payload();
+            let result = bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &payload);
+            assert_eq!(result, Ok(()));
+
+            // A bus reset locks the guarded requests again.
+            bus.enumerate(poll).unwrap();
+            let result = bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &payload);
+            assert_eq!(result, Err(TransferError::Stall));
+            let result = bus.control_write(poll, 0x41, request::DETACH, 0, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+            let key = request::ERROR_LOG_KEY;
+            let result = bus.control_write(poll, 0x41, request::CLEAR_ERROR_LOG, key, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+            let result = bus.control_write(poll, 0x41, request::UNLOCK, 0, 0, &[0; 8]);
+            assert_eq!(result, Err(TransferError::Stall));
+            // Others still work.
+            assert!(bus.control_read(poll, 0xC1, request::GET_CONFIG, 0, 0, 64).is_ok());
+
+            // Each nonce is new, and only the last can be answered.
+            let nonce = bus.control_read(poll, 0xC1, request::GET_UNLOCK_NONCE, 0, 0, 8).unwrap();
+            let stale = identity().unlock_response(&nonce.clone().try_into().unwrap());
+            let next = bus.control_read(poll, 0xC1, request::GET_UNLOCK_NONCE, 0, 0, 8).unwrap();
+            assert_ne!(next, nonce);
+            let result = bus.control_write(poll, 0x41, request::UNLOCK, 0, 0, &stale);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let nonce = bus.control_read(poll, 0xC1, request::GET_UNLOCK_NONCE, 0, 0, 8).unwrap();
+            let response = identity().unlock_response(&nonce.try_into().unwrap());
+            bus.control_write(poll, 0x41, request::UNLOCK, 0, 0, &response).unwrap();
+            let result = bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &payload);
+            assert_eq!(result, Ok(()));
+
+            // As does sending nothing.
+            bus.control_write(poll, 0x41, request::UNLOCK, 0, 0, &[]).unwrap();
+            let result = bus.control_write(poll, 0x41, request::SET_CONFIG, 0, 0, &payload);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            // The same response again doesn't unlock them.
+            let result = bus.control_write(poll, 0x41, request::UNLOCK, 0, 0, &response);
+            assert_eq!(result, Err(TransferError::Stall));
+            assert_eq!(unlock(bus, poll), Ok(()));
+        });
+    }
+
+    #[test]
+    fn rate_limits() {
+        with_device(|bus, poll| {
+            let self_test = |poll: &mut dyn FnMut()| {
+                bus.control_write(poll, 0x41, request::SELF_TEST, 0, 0, &[])
+            };
+            assert_eq!(self_test(poll), Ok(()));
+            assert_eq!(self_test(poll), Ok(()));
+            if !cfg!(feature = "rate-limit") {
+                assert_eq!(self_test(poll), Ok(()));
+                return;
+            }
+            assert_eq!(self_test(poll), Err(TransferError::Stall));
+            // Others aren't held up.
+            assert!(bus.control_read(poll, 0xC1, request::GET_CONFIG, 0, 0, 64).is_ok());
+
+            // Only changes to the control lines count.
+            for value in (1..=32).map(|i| i & 1) {
+                bus.control_write(poll, 0x21, REQ_SET_CONTROL_LINE_STATE, value, 0, &[]).unwrap();
+            }
+            let result = bus.control_write(poll, 0x21, REQ_SET_CONTROL_LINE_STATE, 0, 0, &[]);
+            assert_eq!(result, Ok(()));
+            let result = bus.control_write(poll, 0x21, REQ_SET_CONTROL_LINE_STATE, 1, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+        });
+    }
+
+    #[test]
+    fn lock_request() {
+        with_device(|bus, poll| {
+            let result = bus.control_write(poll, 0x41, request::LOCK, request::LOCK_KEY, 0, &[]);
+            if cfg!(feature = "production-lock") {
+                assert_eq!(result, Ok(()));
+            } else {
+                assert_eq!(result, Err(TransferError::Stall));
+            }
+
+            // The wrong key is always refused.
+            let result = bus.control_write(poll, 0x41, request::LOCK, 0, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+        });
+    }
+
+    #[test]
+    fn strap_request() {
+        with_device(|bus, poll| {
+            let result = bus.control_write(poll, 0x41, request::SET_STRAP, 1, 0, &[]);
+            if cfg!(feature = "strap-pin") {
+                assert_eq!(result, Ok(()));
+            } else {
+                assert_eq!(result, Err(TransferError::Stall));
+            }
+
+            let result = bus.control_write(poll, 0x41, request::SET_STRAP, 3, 0, &[]);
+            assert_eq!(result, Err(TransferError::Stall));
+        });
+    }
+
+    #[test]
+    fn jtag_requests() {
+        with_device(|bus, poll| {
+            let result = bus.control_write(poll, 0x41, request::JTAG_SHIFT, 0, 0, &[0x04; 8]);
+            if cfg!(feature = "jtag") {
+                assert_eq!(result, Ok(()));
+                // Nothing has clocked the batch out.
+                let tdo = bus.control_read(poll, 0xC1, request::GET_JTAG_TDO, 0, 0, 8);
+                assert_eq!(tdo, Err(TransferError::Stall));
+            } else {
+                assert_eq!(result, Err(TransferError::Stall));
+            }
+        });
+    }
+
+    #[test]
+    fn spi_flash_requests() {
+        with_device(|bus, poll| {
+            // Only while the host has the flash
+            let result = bus.control_write(poll, 0x41, request::SPI_TRANSFER, 0, 0, &[0x9F; 4]);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let result = bus.control_write(poll, 0x41, request::SET_SPI_FLASH, 1, 0, &[]);
+            if cfg!(feature = "spi-flash") {
+                assert_eq!(result, Ok(()));
+                let result = bus.control_write(poll, 0x41, request::SPI_TRANSFER, 0, 0, &[0x9F; 4]);
+                assert_eq!(result, Ok(()));
+                // Nothing has clocked the transfer out.
+                let data = bus.control_read(poll, 0xC1, request::GET_SPI_RESULT, 0, 0, 4);
+                assert_eq!(data, Err(TransferError::Stall));
+            } else {
+                assert_eq!(result, Err(TransferError::Stall));
+            }
+        });
+    }
+
+    #[test]
+    fn flasher_requests() {
+        with_device(|bus, poll| {
+            // Only once the host has the UART
+            let sync = [0, 0x08, 4, 0, 0, 0, 0, 0, 0x07, 0x07, 0x12, 0x20];
+            let result = bus.control_write(poll, 0x41, request::ROM_COMMAND, 10, 0, &sync);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let result = bus.control_write(poll, 0x41, request::SET_FLASHER, 1, 0, &[]);
+            if cfg!(feature = "flasher") {
+                assert_eq!(result, Ok(()));
+                let result = bus.control_write(poll, 0x41, request::ROM_COMMAND, 10, 0, &sync);
+                assert_eq!(result, Ok(()));
+                // Nothing has sent it yet.
+                let response = bus.control_read(poll, 0xC1, request::GET_ROM_RESPONSE, 0, 0, 64);
+                assert_eq!(response.unwrap()[0], flasher::BUSY);
+            } else {
+                assert_eq!(result, Err(TransferError::Stall));
+            }
+        });
+    }
+
+    #[test]
+    fn status_request() {
+        with_device(|bus, poll| {
+            let status = bus.control_read(poll, 0xC1, request::GET_STATUS, 0, 0, 64).unwrap();
+            assert_eq!(status, [ResetCause::POWER_ON | ResetCause::PIN]);
+        });
+    }
+
+    #[test]
+    fn uart_queue_request() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        webusb.set_uart_queued(5);
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let queue = bus.control_read(&mut poll, 0xC1, request::GET_UART_QUEUE, 0, 0, 64).unwrap();
+        assert_eq!(queue, [5, 0, BACKLOG_SIZE as u8, 0]);
+    }
+
+    #[test]
+    fn ping_request() {
+        with_device(|bus, poll| {
+            let response = bus.control_read(poll, 0xC1, request::PING, 0x1234, 0, 64).unwrap();
+            assert_eq!(response, [0x34, 0x12, 0, 0, 0, 0]);
+        });
+    }
+
+    #[test]
+    fn version_request() {
+        with_device(|bus, poll| {
+            let host = Version::current(0).value();
+            let response = bus.control_read(poll, 0xC1, request::GET_VERSION, host, 0, 64).unwrap();
+            let version = Version::parse(&response).unwrap();
+            assert_eq!(version, VERSION);
+            assert!(Version::current(0).compatible_with(&version));
+        });
+    }
+
+    #[test]
+    fn build_info_request() {
+        with_device(|bus, poll| {
+            let info = bus.control_read(poll, 0xC1, request::GET_BUILD_INFO, 0, 0, 64).unwrap();
+            assert_eq!(info, BUILD_INFO);
+        });
+    }
+
+    #[test]
+    fn unsupported_line_coding() {
+        with_device(|bus, poll| {
+            let result =
+                bus.control_write(poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &[0, 0xC2, 1, 0, 0, 7, 8]);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let errors = bus.control_read(poll, 0xC1, request::GET_ERRORS, 0, 0, 64).unwrap();
+            assert_eq!(&errors[33..37], &[1, 0, 0, 0]);
+        });
+    }
+
+    #[test]
+    fn take_errors() {
+        with_device(|bus, poll| {
+            let result =
+                bus.control_write(poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &[0, 0xC2, 1, 0, 0, 7, 8]);
+            assert_eq!(result, Err(TransferError::Stall));
+
+            let errors = bus.control_read(poll, 0xC1, request::TAKE_ERRORS, 0, 0, 64).unwrap();
+            assert_eq!(&errors[33..37], &[1, 0, 0, 0]);
+            let errors = bus.control_read(poll, 0xC1, request::GET_ERRORS, 0, 0, 64).unwrap();
+            assert_eq!(errors, [0; request::ERRORS_LENGTH]);
+        });
+    }
+
+    #[test]
+    fn capture_request() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        webusb.record_uart_output(b"Guru Meditation Error: Core 0 panic'ed");
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        let first = bus.control_read(&mut poll, 0xC1, request::GET_CAPTURE, 0, 0, 16).unwrap();
+        assert_eq!(first, b"Guru Meditation ");
+        let rest = bus.control_read(&mut poll, 0xC1, request::GET_CAPTURE, 16, 0, 64).unwrap();
+        assert_eq!(rest, b"Error: Core 0 panic'ed");
+    }
+
+    #[test]
+    fn dropped_output() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        // The host isn't reading, so what doesn't fit in the write buffer is lost, and counted.
+        assert_eq!(webusb.write_uart(&[0x55; 200]).unwrap(), 128);
+        webusb.record_dropped(Port::Serial, 3);
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let errors = bus.control_read(&mut poll, 0xC1, request::GET_ERRORS, 0, 0, 64).unwrap();
+        assert_eq!(errors[37..], [3, 0, 0, 0, 72, 0, 0, 0]);
+    }
+
+    #[test]
+    fn esp_reset_notification() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        webusb.record_uart_output(b"rst:0xf (BROWNOUT_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\n");
+        usb_dev.poll(&mut [&mut webusb]);
+        let notification = [0xC1, event::ESP_RESET, 0, 0, 0, 0, 2, 0, 0x0F, 0x13];
+        assert_eq!(bus.take_in(1), [notification.to_vec()]);
+    }
+
+    #[test]
+    fn supply_notification() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        webusb.set_supply_low(false);
+        assert!(bus.take_in(1).is_empty());
+
+        webusb.set_supply_low(true);
+        webusb.set_supply_low(true);
+        assert!(webusb.supply_low());
+        assert_eq!(bus.take_in(1), [vec![0xC1, event::SUPPLY, 0, 0, 0, 0, 1, 0, 1]]);
+        usb_dev.poll(&mut [&mut webusb]);
+        webusb.set_supply_low(false);
+        usb_dev.poll(&mut [&mut webusb]);
+        assert_eq!(bus.take_in(1), [vec![0xC1, event::SUPPLY, 0, 0, 0, 0, 1, 0, 0]]);
+    }
+
+    #[test]
+    fn pattern_notification() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let pattern = b"Guru Meditation";
+        let result = bus.control_write(&mut poll, 0x41, request::SET_WATCH, 2, 0, pattern);
+        if !cfg!(feature = "pattern-watch") {
+            assert_eq!(result, Err(TransferError::Stall));
+            return;
+        }
+        assert_eq!(result, Ok(()));
+        let result = bus.control_write(&mut poll, 0x41, request::SET_WATCH, 4, 0, b"boot:");
+        assert_eq!(result, Err(TransferError::Stall));
+
+        webusb.record_uart_output(b"Guru Meditation Error: Core  0 panic'ed\r\n");
+        let mut notification = vec![0xC1, event::PATTERN, 0, 0, 0, 0, 8, 0, 2];
+        notification.extend_from_slice(b" Error:");
+        assert_eq!(bus.take_in(1), [notification]);
+    }
+
+    #[test]
+    fn throttle() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        // Two bytes a millisecond from the ESP32
+        let rate = 2000u32.to_le_bytes();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let result = bus.control_write(&mut poll, 0x41, request::SET_THROTTLE, 1, 0, &rate);
+        if !cfg!(feature = "throttle") {
+            assert_eq!(result, Err(TransferError::Stall));
+            assert!(webusb.pass_from_esp());
+            return;
+        }
+        assert_eq!(result, Ok(()));
+        assert!(webusb.pass_to_esp());
+        webusb.tick_uptime(1);
+        assert_eq!((0..3).filter(|_| webusb.pass_from_esp()).count(), 2);
+
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let errors = bus.control_read(&mut poll, 0xC1, request::GET_ERRORS, 0, 0, 64).unwrap();
+        assert_eq!(errors[37..], [1, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn faults() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        // Every byte towards the ESP32 doubled
+        let settings = [request::FAULTS_TO_ESP, 0, 255, 0];
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let result = bus.control_write(&mut poll, 0x41, request::SET_FAULTS, 0, 0, &settings);
+        assert_eq!(result, Err(TransferError::Stall));
+        let key = request::FAULTS_KEY;
+        let result = bus.control_write(&mut poll, 0x41, request::SET_FAULTS, key, 0, &settings);
+        let mut buf = [b'a', b'b', 0, 0];
+        if !cfg!(feature = "fault-inject") {
+            assert_eq!(result, Err(TransferError::Stall));
+            assert_eq!(webusb.inject_faults(Direction::ToEsp, &mut buf, 2), 2);
+            return;
+        }
+        assert_eq!(result, Ok(()));
+        assert_eq!(webusb.inject_faults(Direction::ToEsp, &mut buf, 2), 4);
+        assert_eq!(buf, *b"aabb");
+        assert_eq!(webusb.inject_faults(Direction::FromEsp, &mut buf, 2), 2);
+    }
+
+    // Only with the feature, as the builds without it have no `take_watch_reset`.
+    #[cfg(feature = "pattern-watch")]
+    #[test]
+    fn pattern_actions() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        // Reset into download mode on a panic, and there's no action 4.
+        let result = bus.control_write(&mut poll, 0x41, request::SET_WATCH, 0x0200, 0, b"panic");
+        assert_eq!(result, Ok(()));
+        let result = bus.control_write(&mut poll, 0x41, request::SET_WATCH, 0x0401, 0, b"rst:");
+        assert_eq!(result, Err(TransferError::Stall));
+
+        webusb.record_uart_output(b"Guru Meditation Error: Core  0 panic'ed\r\n");
+        assert_eq!(webusb.take_watch_reset(), Some(true));
+        assert_eq!(webusb.take_watch_reset(), None);
+    }
+
+    #[test]
+    fn esp_counts() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        webusb.set_esp_counts(EspCounts { boots: 9, crashes: 1 });
+        webusb.record_uart_output(b"Guru Meditation Error: Core  0 panic'ed\r\n");
+        webusb.record_uart_output(b"rst:0xc (SW_CPU_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\n");
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let report = bus.control_read(&mut poll, 0xC1, request::GET_TELEMETRY, 0, 0, 64).unwrap();
+        assert_eq!(report.len(), crate::telemetry::REPORT_LENGTH);
+        if !cfg!(feature = "esp-counters") {
+            return;
+        }
+        assert_eq!(report[36..44], [10, 0, 0, 0, 2, 0, 0, 0]);
+    }
+}
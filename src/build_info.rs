@@ -0,0 +1,37 @@
+//! Build metadata
+//!
+//! Generated by `build.rs`, so bug reports can say exactly which build of the bridge is running.
+
+pub use tilda_bridge_protocol::request::BUILD_INFO_LENGTH;
+use tilda_bridge_protocol::version::{self, Version};
+
+/// The build time in seconds since the Unix epoch (little-endian `u32`), 1 if the tree had
+/// uncommitted changes, then the 20 byte commit hash. The hash is all zeros if the firmware was
+/// built outside a git checkout.
+pub const BUILD_INFO: [u8; BUILD_INFO_LENGTH] =
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// The protocol version and the optional features this build has, for `GET_VERSION`.
+pub const VERSION: Version = Version::current(
+    if cfg!(feature = "vbus-sense") { version::FEATURE_VBUS_SENSE } else { 0 }
+        | if cfg!(feature = "battery-sense") { version::FEATURE_BATTERY_SENSE } else { 0 }
+        | if cfg!(feature = "production-lock") { version::FEATURE_PRODUCTION_LOCK } else { 0 }
+        | if cfg!(esp_chip = "esp32c3") { version::FEATURE_ESP32C3 } else { 0 }
+        | if cfg!(esp_chip = "esp32s3") { version::FEATURE_ESP32S3 } else { 0 }
+        | if cfg!(feature = "jtag") { version::FEATURE_JTAG } else { 0 }
+        | if cfg!(feature = "spi-flash") { version::FEATURE_SPI_FLASH } else { 0 }
+        | if cfg!(feature = "flasher") { version::FEATURE_FLASHER } else { 0 }
+        | if cfg!(feature = "strap-pin") { version::FEATURE_STRAP_PIN } else { 0 }
+        | if cfg!(feature = "pattern-watch") { version::FEATURE_PATTERN_WATCH } else { 0 }
+        | if cfg!(feature = "throttle") { version::FEATURE_THROTTLE } else { 0 }
+        | if cfg!(feature = "fault-inject") { version::FEATURE_FAULT_INJECT } else { 0 }
+        | if cfg!(feature = "esp-counters") { version::FEATURE_ESP_COUNTERS } else { 0 }
+        | if cfg!(feature = "line-buffer") { version::FEATURE_LINE_BUFFER } else { 0 }
+        | if cfg!(feature = "escapes") { version::FEATURE_ESCAPES } else { 0 }
+        | if cfg!(feature = "led-dim") { version::FEATURE_LED_DIM } else { 0 }
+        | if cfg!(feature = "fixture") { version::FEATURE_FIXTURE } else { 0 }
+        | if cfg!(feature = "unlock") { version::FEATURE_UNLOCK } else { 0 }
+        | if cfg!(feature = "rate-limit") { version::FEATURE_RATE_LIMIT } else { 0 }
+        | if cfg!(feature = "error-log") { version::FEATURE_ERROR_LOG } else { 0 }
+        | if cfg!(feature = "loop-load") { version::FEATURE_LOOP_LOAD } else { 0 },
+);
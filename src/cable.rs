@@ -0,0 +1,121 @@
+//! Cable detection from VBUS
+//!
+//! To the USB peripheral, a pulled cable looks just like the host suspending the bus. On boards
+//! built with `vbus-sense` the two are told apart by VBUS: while it's gone the D+ pull-up is
+//! dropped, so the battery doesn't back-power the host, and the ESP32 keeps running whatever the
+//! suspend policy says, as the bus power limits don't apply. VBUS falls over a few milliseconds, so
+//! a suspend only counts once VBUS has stayed up for `DEBOUNCE_MS`.
+
+/// VBUS above which the cable is plugged in.
+pub const PRESENT_MV: u16 = 4000;
+
+/// VBUS below which the cable has been pulled out.
+pub const ABSENT_MV: u16 = 3000;
+
+/// How long VBUS must stay past a threshold before the cable counts as plugged in or pulled out,
+/// and how long a suspend has to turn out to be a pulled cable.
+pub const DEBOUNCE_MS: u32 = 50;
+
+/// Follows the cable being plugged in and pulled out.
+pub struct CableMonitor {
+    present: bool,
+    /// Time VBUS has been past the threshold for the other state.
+    changing_ms: u32,
+    suspended_ms: u32,
+}
+
+impl Default for CableMonitor {
+    /// Starts out plugged in, as the pull-up is on from boot.
+    fn default() -> Self {
+        CableMonitor {
+            present: true,
+            changing_ms: 0,
+            suspended_ms: 0,
+        }
+    }
+}
+
+impl CableMonitor {
+    /// Advances the monitor with a VBUS reading and whether the bus looks suspended. Returns
+    /// whether the cable is now present, if that has changed.
+    pub fn tick(&mut self, vbus_mv: u16, suspended: bool, elapsed_ms: u32) -> Option<bool> {
+        self.suspended_ms = if suspended { self.suspended_ms.saturating_add(elapsed_ms) } else { 0 };
+        let crossed = if self.present { vbus_mv < ABSENT_MV } else { vbus_mv > PRESENT_MV };
+        if !crossed {
+            self.changing_ms = 0;
+            return None;
+        }
+
+        self.changing_ms += elapsed_ms;
+        if self.changing_ms < DEBOUNCE_MS {
+            return None;
+        }
+        self.changing_ms = 0;
+        self.present = !self.present;
+        Some(self.present)
+    }
+
+    /// Whether the cable is plugged in.
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    /// Whether the bus has been suspended long enough, with VBUS still up, to really be suspended.
+    pub fn suspended(&self) -> bool {
+        self.present && self.changing_ms == 0 && self.suspended_ms >= DEBOUNCE_MS
+    }
+
+    /// Whether the firmware can sleep until there's USB or UART traffic. Not while unplugged, or
+    /// VBUS coming back would go unnoticed, and not while a suspend might still be a pulled cable.
+    pub fn may_sleep(&self) -> bool {
+        self.present
+            && self.changing_ms == 0
+            && (self.suspended_ms == 0 || self.suspended_ms >= DEBOUNCE_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unplugged() {
+        let mut cable = CableMonitor::default();
+        assert!(cable.may_sleep());
+
+        // The host stops sending frames a little before VBUS falls.
+        assert_eq!(cable.tick(5000, true, 10), None);
+        assert!(!cable.suspended());
+        assert!(!cable.may_sleep());
+        for _ in 0..4 {
+            assert_eq!(cable.tick(2000, true, 10), None);
+        }
+        assert!(!cable.may_sleep());
+        assert_eq!(cable.tick(2000, true, 10), Some(false));
+        assert!(!cable.present());
+        assert!(!cable.suspended());
+        assert!(!cable.may_sleep());
+
+        // Between the thresholds isn't enough to count as plugged in.
+        assert_eq!(cable.tick(3500, true, 1000), None);
+        assert_eq!(cable.tick(5000, true, 40), None);
+        assert_eq!(cable.tick(5000, false, 10), Some(true));
+        assert!(cable.may_sleep());
+    }
+
+    #[test]
+    fn suspended() {
+        let mut cable = CableMonitor::default();
+        assert_eq!(cable.tick(5000, true, 40), None);
+        assert!(!cable.suspended());
+        // A dip doesn't unplug it.
+        assert_eq!(cable.tick(2000, true, 10), None);
+        assert_eq!(cable.tick(5000, true, 10), None);
+        assert!(cable.suspended());
+        assert!(cable.may_sleep());
+
+        assert_eq!(cable.tick(5000, false, 10), None);
+        assert!(!cable.suspended());
+        assert!(cable.may_sleep());
+    }
+}
@@ -0,0 +1,150 @@
+//! UART output capture
+//!
+//! The last `CAPTURE_LENGTH` bytes the ESP32 sent are kept in RAM whether or not anyone was
+//! listening, so the host can fetch a panic which happened before a terminal was opened. There's
+//! only 6K of RAM, so that's 1K - enough for a backtrace and the lines leading up to it.
+//!
+//! The host reads the capture in chunks (see `request::GET_CAPTURE`). Reading the first chunk
+//! freezes it, so the chunks line up, and it starts recording again once the host has read past
+//! the end.
+//!
+//! The buffer is passed in rather than kept in the bridge, so the firmware can keep it out of the
+//! main loop's stack frame, where everything past the first 1K costs extra instructions to reach.
+
+/// How much UART output is kept.
+pub const CAPTURE_LENGTH: usize = 1024;
+
+/// The most recent UART output. Until it has a buffer, nothing is kept.
+#[derive(Default)]
+pub struct Capture {
+    buf: Option<&'static mut [u8; CAPTURE_LENGTH]>,
+    /// Where the next byte goes.
+    head: usize,
+    len: usize,
+    frozen: bool,
+}
+
+impl Capture {
+    /// Keeps the output in `buf`.
+    #[inline(never)]
+    pub fn new(buf: &'static mut [u8; CAPTURE_LENGTH]) -> Self {
+        Capture { buf: Some(buf), ..Capture::default() }
+    }
+
+    /// Adds UART output, dropping the oldest if it's full. Does nothing while a host is reading.
+    pub fn record(&mut self, data: &[u8]) {
+        let Some(buf) = &mut self.buf else { return };
+        if self.frozen {
+            return;
+        }
+        for &byte in data {
+            buf[self.head % CAPTURE_LENGTH] = byte;
+            self.head = (self.head + 1) % CAPTURE_LENGTH;
+            self.len = (self.len + 1).min(CAPTURE_LENGTH);
+        }
+    }
+
+    /// How many bytes have been captured.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether nothing has been captured.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies the capture from `offset` bytes after the oldest into `out`, and returns how many
+    /// bytes were copied. Offset 0 freezes the capture, and copying less than `out` can hold
+    /// (because the end has been reached) unfreezes it.
+    pub fn read(&mut self, offset: usize, out: &mut [u8]) -> usize {
+        if offset == 0 {
+            self.frozen = true;
+        }
+
+        let oldest = (self.head + CAPTURE_LENGTH - self.len) % CAPTURE_LENGTH;
+        let count = self.len.saturating_sub(offset).min(out.len());
+        if let Some(buf) = &self.buf {
+            for (i, byte) in out[..count].iter_mut().enumerate() {
+                *byte = buf[(oldest + offset + i) % CAPTURE_LENGTH];
+            }
+        }
+
+        if count < out.len() {
+            self.frozen = false;
+        }
+        count
+    }
+
+    /// Stops recording until the host has read the capture, so it keeps what led up to now.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Starts recording again if a host stopped reading part way through.
+    pub fn thaw(&mut self) {
+        self.frozen = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture() -> Capture {
+        Capture::new(Box::leak(Box::new([0; CAPTURE_LENGTH])))
+    }
+
+    #[test]
+    fn keeps_the_latest() {
+        let mut capture = capture();
+        capture.record(b"boot\n");
+        assert_eq!(capture.len(), 5);
+
+        for _ in 0..CAPTURE_LENGTH / 4 {
+            capture.record(b"abcd");
+        }
+        capture.record(b"panic");
+        assert_eq!(capture.len(), CAPTURE_LENGTH);
+
+        let mut out = [0u8; 8];
+        assert_eq!(capture.read(CAPTURE_LENGTH - 8, &mut out), 8);
+        assert_eq!(&out, b"bcdpanic");
+    }
+
+    #[test]
+    fn frozen_while_reading() {
+        let mut capture = capture();
+        capture.record(b"0123456789");
+
+        let mut out = [0u8; 4];
+        assert_eq!(capture.read(0, &mut out), 4);
+        assert_eq!(&out, b"0123");
+
+        // Output while the host is reading doesn't move the chunks about.
+        capture.record(b"xyz");
+        assert_eq!(capture.read(4, &mut out), 4);
+        assert_eq!(&out, b"4567");
+        assert_eq!(capture.read(8, &mut out), 2);
+        assert_eq!(&out[..2], b"89");
+
+        capture.record(b"!");
+        assert_eq!(capture.len(), 11);
+
+        // Frozen until it's been read.
+        capture.freeze();
+        capture.record(b"?");
+        assert_eq!(capture.read(0, &mut out), 4);
+        assert_eq!(capture.read(8, &mut out), 3);
+        capture.record(b"?");
+        assert_eq!(capture.len(), 12);
+    }
+
+    #[test]
+    fn no_buffer() {
+        let mut capture = Capture::default();
+        capture.record(b"boot\n");
+        assert!(capture.is_empty());
+        assert_eq!(capture.read(0, &mut [0; 4]), 0);
+    }
+}
@@ -0,0 +1,263 @@
+//! Persistent configuration page
+//!
+//! The last 1K flash page is reserved for configuration (see `memory-app.x`). It holds a small header
+//! followed by the settings payload:
+//!
+//! | Offset | Size | Field                              |
+//! |--------|------|------------------------------------|
+//! | 0      | 4    | Magic (`CONFIG_MAGIC`)             |
+//! | 4      | 2    | Format version                     |
+//! | 6      | 2    | Payload length in bytes            |
+//! | 8      | 4    | CRC-32 of the payload              |
+//! | 12     | n    | Payload                            |
+//!
+//! A fully erased settings area (all `0xFF`) is a valid state and means "use the defaults".
+//!
+//! The payload is the one the host reads and writes, defined in `tilda_bridge_protocol::settings`.
+//!
+//! The error log follows at offset 128 (see `errorlog`), and the second half of the page holds the
+//! boot counters (see `counters`), which are both kept across settings changes.
+
+use crate::errorlog::ERROR_LOG_OFFSET;
+
+pub use tilda_bridge_protocol::settings::{
+    Config, ControlLinePolicy, EspSuspendPolicy, LineCodingPolicy, PortClosePolicy, FULL_BRIGHTNESS,
+    LINE_CR_TO_LF, LINE_ECHO, LINE_LF_TO_CRLF, NICKNAME_LENGTH, PAYLOAD_LENGTH,
+};
+
+/// Address of the configuration page in flash.
+pub const CONFIG_PAGE_ADDRESS: usize = 0x0800_7C00;
+
+/// Size of a flash page on the STM32F042.
+pub const PAGE_SIZE: usize = 1024;
+
+/// "TiLD" in little-endian.
+pub const CONFIG_MAGIC: u32 = 0x444c_6954;
+
+/// Current format version.
+pub const CONFIG_VERSION: u16 = 1;
+
+const HEADER_LENGTH: usize = 12;
+
+/// Length of a configuration page image written by this firmware.
+pub const IMAGE_LENGTH: usize = HEADER_LENGTH + PAYLOAD_LENGTH;
+
+/// USB product string, without a nickname.
+pub const PRODUCT: &str = "TiLDA MkV";
+
+/// Comes before the nickname in the product string.
+const NICKNAME_PREFIX: &str = "TiLDA MkV \u{2014} ";
+
+/// Longest product string, with a nickname.
+pub const PRODUCT_LENGTH: usize = NICKNAME_PREFIX.len() + NICKNAME_LENGTH;
+
+/// Buffer for `product_string`, starting with `NICKNAME_PREFIX` so only the nickname needs
+/// copying in.
+pub const PRODUCT_BUFFER: [u8; PRODUCT_LENGTH] = {
+    let mut buf = [0; PRODUCT_LENGTH];
+    let mut i = 0;
+    while i < NICKNAME_PREFIX.len() {
+        buf[i] = NICKNAME_PREFIX.as_bytes()[i];
+        i += 1;
+    }
+    buf
+};
+
+/// Result of checking the configuration page.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PageState {
+    /// Page is erased - the defaults are in use.
+    Erased,
+
+    /// Page holds a configuration with a valid checksum.
+    Valid,
+
+    /// Page holds something we don't understand, or the checksum doesn't match.
+    Corrupt,
+}
+
+/// Loads the settings from a configuration page, using the defaults if the page is erased or
+/// corrupt.
+pub fn load(page: &[u8]) -> Config {
+    payload(page).and_then(Config::parse).unwrap_or_default()
+}
+
+/// Encodes the start of a configuration page: the header followed by the settings payload. The
+/// rest of the page should be left erased.
+pub fn to_image(config: &Config) -> [u8; IMAGE_LENGTH] {
+    let payload = config.to_payload();
+    let mut image = [0u8; IMAGE_LENGTH];
+    image[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+    image[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+    image[6..8].copy_from_slice(&(PAYLOAD_LENGTH as u16).to_le_bytes());
+    image[8..12].copy_from_slice(&crc32(&payload).to_le_bytes());
+    image[HEADER_LENGTH..].copy_from_slice(&payload);
+    image
+}
+
+/// Builds the USB product string in `buf`, which starts as `PRODUCT_BUFFER`: `PRODUCT`, followed by
+/// the nickname if there is one.
+pub fn product_string<'a>(config: &Config, buf: &'a mut [u8; PRODUCT_LENGTH]) -> &'a str {
+    let nickname = config.nickname();
+    if nickname.is_empty() {
+        return PRODUCT;
+    }
+    let len = NICKNAME_PREFIX.len() + nickname.len();
+    buf[NICKNAME_PREFIX.len()..len].copy_from_slice(nickname.as_bytes());
+    // Safe as it's built from whole strings.
+    unsafe { core::str::from_utf8_unchecked(&buf[..len]) }
+}
+
+/// Checks the header and checksum of a configuration page. Only the settings count: the records
+/// which follow them can be there with the settings erased.
+pub fn verify_page(page: &[u8]) -> PageState {
+    let settings = &page[..page.len().min(ERROR_LOG_OFFSET)];
+    if settings.iter().all(|&b| b == 0xFF) {
+        return PageState::Erased;
+    }
+
+    match payload(page) {
+        Some(_) => PageState::Valid,
+        None => PageState::Corrupt,
+    }
+}
+
+/// Returns the payload of a valid configuration page.
+fn payload(page: &[u8]) -> Option<&[u8]> {
+    if page.len() < HEADER_LENGTH || read_u32(page, 0) != CONFIG_MAGIC {
+        return None;
+    }
+
+    let length = read_u16(page, 6) as usize;
+    if HEADER_LENGTH + length > page.len() {
+        return None;
+    }
+
+    let payload = &page[HEADER_LENGTH..HEADER_LENGTH + length];
+    if crc32(payload) != read_u32(page, 8) {
+        return None;
+    }
+
+    Some(payload)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to save flash.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counters::COUNTERS_OFFSET;
+
+    fn page_with(payload: &[u8]) -> [u8; 64] {
+        let mut page = [0xFFu8; 64];
+        page[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+        page[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+        page[6..8].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        page[8..12].copy_from_slice(&crc32(payload).to_le_bytes());
+        page[12..12 + payload.len()].copy_from_slice(payload);
+        page
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn erased() {
+        assert_eq!(verify_page(&[0xFF; 64]), PageState::Erased);
+    }
+
+    #[test]
+    fn erased_with_counters() {
+        let mut page = [0xFFu8; 1024];
+        page[COUNTERS_OFFSET..COUNTERS_OFFSET + 4].copy_from_slice(&[1, 0, 0, 0]);
+        assert_eq!(verify_page(&page), PageState::Erased);
+        page[ERROR_LOG_OFFSET] = 1;
+        assert_eq!(verify_page(&page), PageState::Erased);
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(verify_page(&page_with(&[1, 2, 3])), PageState::Valid);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut page = page_with(&[1, 2, 3]);
+        page[13] = 0;
+        assert_eq!(verify_page(&page), PageState::Corrupt);
+
+        let mut page = page_with(&[1, 2, 3]);
+        page[6] = 0xFF;
+        assert_eq!(verify_page(&page), PageState::Corrupt);
+
+        assert_eq!(verify_page(&[0u8; 64]), PageState::Corrupt);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = Config {
+            esp_suspend: EspSuspendPolicy::PowerDown,
+            idle_timeout: 0,
+            control_line_grace: 25,
+            port_close: PortClosePolicy::Strapping,
+            close_strapping: 0b10,
+            serial_line: 0,
+            webusb_line: LINE_ECHO,
+            control_lines: ControlLinePolicy::LastChanged,
+            landing_page: false,
+            nickname: *b"Ada\0\0\0\0\0\0\0\0\0\0\0\0\0",
+            battery_brightness: 0,
+            battery_cutoff: 36,
+            line_coding: LineCodingPolicy::Serial,
+        };
+
+        let mut page = [0xFFu8; 64];
+        page[..IMAGE_LENGTH].copy_from_slice(&to_image(&config));
+        assert_eq!(verify_page(&page), PageState::Valid);
+        assert_eq!(load(&page), config);
+    }
+
+    #[test]
+    fn config_defaults() {
+        assert_eq!(load(&[0xFF; 64]), Config::default());
+        assert_eq!(load(&[0u8; 64]), Config::default());
+
+        // Payload from older firmware, without any settings
+        assert_eq!(load(&page_with(&[])), Config::default());
+
+        // Invalid settings
+        assert_eq!(load(&page_with(&[2])), Config::default());
+    }
+
+    #[test]
+    fn product_with_nickname() {
+        let mut buf = PRODUCT_BUFFER;
+        let mut config = Config::default();
+        assert_eq!(product_string(&config, &mut buf), "TiLDA MkV");
+        config.set_nickname("Ada");
+        assert_eq!(product_string(&config, &mut buf), "TiLDA MkV \u{2014} Ada");
+        config.set_nickname("Augusta Ada King");
+        assert_eq!(product_string(&config, &mut buf), "TiLDA MkV \u{2014} Augusta Ada King");
+    }
+}
@@ -0,0 +1,295 @@
+//! Persistent boot counters
+//!
+//! The firmware counts how many times it has started, and how many of those weren't power-on
+//! resets (panics, restarts through the bootloader, the reset pin), so the reliability of a release
+//! can be tracked across badges. The counts live in the second half of the configuration page:
+//!
+//! | Offset | Size | Field                                                     |
+//! |--------|------|-----------------------------------------------------------|
+//! | 512    | 12   | Record: boots and resets (`u32`), CRC-32 of the counts    |
+//! | 524    | 488  | Boot log: one half-word per boot since the record         |
+//! | 1012   | 12   | ESP32 record: its boots and crashes, CRC-32 of the counts |
+//!
+//! Erasing the page for every boot would wear the flash out, so each boot just programs the next
+//! erased slot of the log with its `ResetCause`. The page is only rewritten, with the totals moved
+//! into the records, when the log fills up or the settings change.
+//!
+//! Builds with the `esp-counters` feature also count the ESP32's reset banners and crashes (see
+//! `crash`), in the same log. Its entries have `ESP_BOOTS` or `ESP_CRASHES` in the high byte, where
+//! a `ResetCause` has zero, and how many in the low byte, and at most one of each is logged a
+//! minute, so an ESP32 stuck in a boot loop doesn't wear the page out either.
+
+use crate::config::{crc32, PAGE_SIZE};
+use crate::stats::counts_to_bytes;
+use tilda_bridge_protocol::reset::ResetCause;
+use core::convert::TryInto;
+
+/// Offset of the counters in the configuration page. Everything before it belongs to the settings
+/// and the error log (see `errorlog`).
+pub const COUNTERS_OFFSET: usize = 512;
+
+/// Length of the record at the start of the counters.
+pub const RECORD_LENGTH: usize = 12;
+
+/// Offset of the boot log in the configuration page.
+pub const LOG_OFFSET: usize = COUNTERS_OFFSET + RECORD_LENGTH;
+
+/// Offset of the ESP32's record in the configuration page, which ends the boot log.
+pub const ESP_RECORD_OFFSET: usize = PAGE_SIZE - RECORD_LENGTH;
+
+/// High byte of a boot log entry counting ESP32 boots.
+const ESP_BOOTS: u8 = 0x01;
+
+/// High byte of a boot log entry counting ESP32 crashes. Must follow `ESP_BOOTS`.
+const ESP_CRASHES: u8 = ESP_BOOTS + 1;
+
+/// Value of a log slot which hasn't been used yet.
+const ERASED: u16 = 0xFFFF;
+
+/// Number of boots, and how many of them weren't from power-on.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct BootCounts {
+    pub boots: u32,
+    pub resets: u32,
+}
+
+impl BootCounts {
+    /// Reads the counts from a configuration page: the record plus the boot log. A missing or
+    /// corrupt record counts from zero.
+    pub fn load(page: &[u8]) -> BootCounts {
+        let [boots, resets] = read_record(page, COUNTERS_OFFSET);
+        let mut counts = BootCounts { boots, resets };
+        for slot in log(page).chunks_exact(2) {
+            if slot[1] == 0 {
+                counts.record(ResetCause::from_bits(slot[0]));
+            }
+        }
+        counts
+    }
+
+    /// Counts a boot.
+    pub fn record(&mut self, cause: ResetCause) {
+        self.boots = self.boots.wrapping_add(1);
+        if !cause.contains(ResetCause::POWER_ON) {
+            self.resets = self.resets.wrapping_add(1);
+        }
+    }
+
+    /// Encodes the counts for the host: boots then resets, as little-endian `u32`s.
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        counts_to_bytes(&[self.boots, self.resets], &mut buf);
+        buf
+    }
+
+    /// Encodes the record to program at `COUNTERS_OFFSET` in a freshly erased page.
+    pub fn to_record(self) -> [u8; RECORD_LENGTH] {
+        to_record(self.to_bytes())
+    }
+}
+
+/// Number of ESP32 boots and crashes seen on the UART.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct EspCounts {
+    pub boots: u32,
+    pub crashes: u32,
+}
+
+impl EspCounts {
+    /// Reads the counts from a configuration page: the ESP32's record plus its boot log entries. A
+    /// missing or corrupt record counts from zero.
+    pub fn load(page: &[u8]) -> EspCounts {
+        let [boots, crashes] = read_record(page, ESP_RECORD_OFFSET);
+        let mut counts = EspCounts { boots, crashes };
+        for slot in log(page).chunks_exact(2) {
+            let count = match slot[1] {
+                ESP_BOOTS => &mut counts.boots,
+                ESP_CRASHES => &mut counts.crashes,
+                _ => continue,
+            };
+            *count = count.wrapping_add(slot[0] as u32);
+        }
+        counts
+    }
+
+    /// Encodes the counts for the host: boots then crashes, as little-endian `u32`s.
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        counts_to_bytes(&[self.boots, self.crashes], &mut buf);
+        buf
+    }
+
+    /// Encodes the record to program at `ESP_RECORD_OFFSET` in a freshly erased page.
+    pub fn to_record(self) -> [u8; RECORD_LENGTH] {
+        to_record(self.to_bytes())
+    }
+}
+
+/// Counts the ESP32's boots and crashes, and keeps track of how many haven't been logged yet.
+#[derive(Default)]
+pub struct EspCounter {
+    counts: EspCounts,
+    /// Boots then crashes.
+    unlogged: [u8; 2],
+}
+
+impl EspCounter {
+    /// Starts from the counts in the configuration page.
+    pub fn new(counts: EspCounts) -> EspCounter {
+        EspCounter {
+            counts,
+            ..EspCounter::default()
+        }
+    }
+
+    /// The counts, including those not logged yet.
+    pub fn counts(&self) -> EspCounts {
+        self.counts
+    }
+
+    /// Counts a reset banner.
+    pub fn record_boot(&mut self) {
+        self.counts.boots = self.counts.boots.wrapping_add(1);
+        self.unlogged[0] = self.unlogged[0].saturating_add(1);
+    }
+
+    /// Counts a crash.
+    pub fn record_crash(&mut self) {
+        self.counts.crashes = self.counts.crashes.wrapping_add(1);
+        self.unlogged[1] = self.unlogged[1].saturating_add(1);
+    }
+
+    /// Takes a boot log entry for boots or crashes which haven't been logged yet, up to 255 of
+    /// them, or `None` once everything has been.
+    pub fn take_log_entry(&mut self) -> Option<[u8; 2]> {
+        let i = self.unlogged.iter().position(|&count| count != 0)?;
+        let entry = [self.unlogged[i], ESP_BOOTS + i as u8];
+        self.unlogged[i] = 0;
+        Some(entry)
+    }
+}
+
+/// Finds the offset in the configuration page of the next free boot log slot, or `None` if the log
+/// is full and the page needs rewriting.
+pub fn free_slot(page: &[u8]) -> Option<usize> {
+    let offset = LOG_OFFSET + log(page).len();
+    (offset < ESP_RECORD_OFFSET).then_some(offset)
+}
+
+/// Encodes the boot log entry for a boot.
+pub fn log_entry(cause: ResetCause) -> [u8; 2] {
+    (cause.bits() as u16).to_le_bytes()
+}
+
+/// The used part of the boot log, up to the first erased slot.
+fn log(page: &[u8]) -> &[u8] {
+    let log = &page[LOG_OFFSET..ESP_RECORD_OFFSET];
+    let mut len = 0;
+    while len < log.len() && u16::from_le_bytes([log[len], log[len + 1]]) != ERASED {
+        len += 2;
+    }
+    &log[..len]
+}
+
+/// Reads the pair of counts from the record at `offset`, or zeros if its CRC doesn't match.
+fn read_record(page: &[u8], offset: usize) -> [u32; 2] {
+    let (counts, crc) = page[offset..offset + RECORD_LENGTH].split_at(8);
+    if crc32(counts).to_le_bytes() != crc {
+        return [0; 2];
+    }
+    [
+        u32::from_le_bytes(counts[0..4].try_into().unwrap()),
+        u32::from_le_bytes(counts[4..8].try_into().unwrap()),
+    ]
+}
+
+fn to_record(counts: [u8; 8]) -> [u8; RECORD_LENGTH] {
+    let mut record = [0u8; RECORD_LENGTH];
+    record[..8].copy_from_slice(&counts);
+    record[8..].copy_from_slice(&crc32(&counts).to_le_bytes());
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POWER_ON: ResetCause = ResetCause::from_bits(ResetCause::POWER_ON | ResetCause::PIN);
+    const SOFTWARE: ResetCause = ResetCause::from_bits(ResetCause::SOFTWARE | ResetCause::PIN);
+
+    fn boot(page: &mut [u8], cause: ResetCause) {
+        let offset = free_slot(page).unwrap();
+        page[offset..offset + 2].copy_from_slice(&log_entry(cause));
+    }
+
+    #[test]
+    fn erased_page() {
+        let page = [0xFFu8; 1024];
+        assert_eq!(BootCounts::load(&page), BootCounts::default());
+        assert_eq!(free_slot(&page), Some(LOG_OFFSET));
+    }
+
+    #[test]
+    fn boot_log() {
+        let mut page = [0xFFu8; 1024];
+        boot(&mut page, POWER_ON);
+        boot(&mut page, SOFTWARE);
+        boot(&mut page, POWER_ON);
+        assert_eq!(BootCounts::load(&page), BootCounts { boots: 3, resets: 1 });
+        assert_eq!(free_slot(&page), Some(LOG_OFFSET + 6));
+    }
+
+    #[test]
+    fn full_log() {
+        let mut page = [0xFFu8; 1024];
+        while free_slot(&page).is_some() {
+            boot(&mut page, SOFTWARE);
+        }
+        let counts = BootCounts::load(&page);
+        assert_eq!(counts.boots as usize, (ESP_RECORD_OFFSET - LOG_OFFSET) / 2);
+
+        // Rewriting the page carries the counts over in the record
+        let mut page = [0xFFu8; 1024];
+        page[COUNTERS_OFFSET..LOG_OFFSET].copy_from_slice(&counts.to_record());
+        boot(&mut page, POWER_ON);
+        assert_eq!(BootCounts::load(&page).boots, counts.boots + 1);
+        assert_eq!(BootCounts::load(&page).resets, counts.resets);
+    }
+
+    #[test]
+    fn esp_counts() {
+        let mut page = [0xFFu8; 1024];
+        let mut counter = EspCounter::new(EspCounts::load(&page));
+        boot(&mut page, POWER_ON);
+        counter.record_boot();
+        counter.record_boot();
+        counter.record_crash();
+        while let Some(entry) = counter.take_log_entry() {
+            let offset = free_slot(&page).unwrap();
+            page[offset..offset + 2].copy_from_slice(&entry);
+        }
+        counter.record_boot();
+        assert_eq!(counter.counts(), EspCounts { boots: 3, crashes: 1 });
+
+        // The ESP32's entries don't count as boots of the bridge
+        assert_eq!(BootCounts::load(&page), BootCounts { boots: 1, resets: 0 });
+        assert_eq!(EspCounts::load(&page), EspCounts { boots: 2, crashes: 1 });
+
+        // Rewriting the page carries them over in their own record
+        let counts = EspCounts::load(&page);
+        let mut page = [0xFFu8; 1024];
+        page[ESP_RECORD_OFFSET..].copy_from_slice(&counts.to_record());
+        assert_eq!(EspCounts::load(&page), counts);
+        assert_eq!(free_slot(&page), Some(LOG_OFFSET));
+    }
+
+    #[test]
+    fn corrupt_record() {
+        let mut page = [0xFFu8; 1024];
+        let mut record = BootCounts { boots: 10, resets: 2 }.to_record();
+        record[0] = 11;
+        page[COUNTERS_OFFSET..LOG_OFFSET].copy_from_slice(&record);
+        boot(&mut page, POWER_ON);
+        assert_eq!(BootCounts::load(&page), BootCounts { boots: 1, resets: 0 });
+    }
+}
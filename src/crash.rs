@@ -0,0 +1,53 @@
+//! ESP32 crash detection
+//!
+//! Builds with the `esp-counters` feature count the ESP32's crashes (see `counters`), so badges with
+//! flaky firmware or hardware stand out. A crash is one of the messages ESP-IDF prints when it
+//! panics, aborts or browns out. As in `banner`, a byte which doesn't match starts the message
+//! again from that byte.
+
+/// Messages which mean the ESP32 has crashed.
+const SIGNATURES: [&[u8]; 3] = [b"Guru Meditation", b"abort() was called", b"Brownout detector"];
+
+/// Watches the ESP32's output for crashes.
+#[derive(Default)]
+pub struct CrashDetector {
+    matched: [u8; SIGNATURES.len()],
+}
+
+impl CrashDetector {
+    /// Feeds a received byte to the detector. Returns true at the end of a crash message.
+    pub fn feed(&mut self, byte: u8) -> bool {
+        let mut crashed = false;
+        for (signature, matched) in SIGNATURES.iter().zip(self.matched.iter_mut()) {
+            if byte == signature[*matched as usize] {
+                *matched += 1;
+            } else {
+                *matched = (byte == signature[0]) as u8;
+            }
+            if *matched as usize == signature.len() {
+                *matched = 0;
+                crashed = true;
+            }
+        }
+        crashed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crashes(rx: &[u8]) -> usize {
+        let mut detector = CrashDetector::default();
+        rx.iter().filter(|&&b| detector.feed(b)).count()
+    }
+
+    #[test]
+    fn crash_messages() {
+        let rx = b"Guru Meditation Error: Core  0 panic'ed (LoadProhibited)\r\n\
+                   abort() was called at PC 0x400d2d5b on core 0\r\n\
+                   Brownout detector was triggered\r\n\r\nets Jun  8 2016 00:22:57\r\n";
+        assert_eq!(crashes(rx), 3);
+        assert_eq!(crashes(b"I (312) app: abort() was not called\r\nGuru meditation\r\n"), 0);
+    }
+}
@@ -0,0 +1,472 @@
+//! DFU bootloader
+//!
+//! The bootloader lives in the first 8K of flash, with the bridge firmware above it. It runs the
+//! firmware unless the firmware has asked for an update (by leaving `DFU_REQUEST` in the boot flag
+//! word and resetting) or there's no valid firmware, in which case it enumerates as a DFU 1.1 device
+//! so the firmware can be replaced with `dfu-util`.
+//!
+//! The first block of an image is held back until the download is complete, so an interrupted
+//! update leaves no reset vector behind and the bootloader stays in DFU mode on the next boot.
+//!
+//! There's no room for a second image to roll back to, so a new image is instead booted on trial:
+//! if it resets `TRIAL_ATTEMPTS` times without the host configuring it, the bootloader goes back to
+//! DFU mode for another try. The trial is tracked in RAM, so it's forgotten on a power cycle.
+
+use crate::config::{CONFIG_PAGE_ADDRESS, PAGE_SIZE};
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+/// Start of the bridge firmware. `memory-app.x` must agree.
+pub const APP_ADDRESS: usize = 0x0800_2000;
+
+/// End of the bridge firmware, which is where the configuration page starts.
+pub const APP_END: usize = CONFIG_PAGE_ADDRESS;
+
+/// Length of the STM32F042's vector table: 16 exceptions and 32 interrupts.
+///
+/// The Cortex-M0 can't relocate its vector table, so the firmware copies its own to the start of
+/// RAM and maps RAM at address 0. The first 256 bytes of RAM are kept free for this and the boot
+/// flag.
+pub const VECTOR_TABLE_LENGTH: usize = 192;
+
+/// A word of RAM which survives a reset, used by the firmware to ask the bootloader to stay in DFU
+/// mode.
+pub const BOOT_FLAG_ADDRESS: usize = 0x2000_00C0;
+
+/// Boot flag value asking for DFU mode.
+pub const DFU_REQUEST: u32 = 0xB007_0DF0;
+
+/// Boot flag value while a new image is on trial. The low byte counts the attempts so far.
+pub const TRIAL_BOOT: u32 = 0xB007_7100;
+
+/// Number of times a new image may reset before it's given up on.
+pub const TRIAL_ATTEMPTS: u32 = 3;
+
+/// Largest DNLOAD block, limited by the USB control buffer.
+pub const TRANSFER_SIZE: usize = 256;
+
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2000_1800;
+
+const DFU_DETACH: u8 = 0x00;
+const DFU_DNLOAD: u8 = 0x01;
+const DFU_GETSTATUS: u8 = 0x03;
+const DFU_CLRSTATUS: u8 = 0x04;
+const DFU_GETSTATE: u8 = 0x05;
+const DFU_ABORT: u8 = 0x06;
+
+const DESCRIPTOR_DFU_FUNCTIONAL: u8 = 0x21;
+
+/// bitCanDnload | bitManifestationTolerant
+const DFU_ATTRIBUTES: u8 = 0x05;
+
+/// Whether `vector_table` (at least its first two words) looks like firmware which can be run: the
+/// initial stack pointer is in RAM, and the reset vector points into the firmware.
+pub fn is_valid_app(vector_table: &[u32]) -> bool {
+    let (stack, reset) = (vector_table[0], vector_table[1]);
+    (RAM_START..=RAM_END).contains(&stack) && (APP_ADDRESS as u32..APP_END as u32).contains(&reset)
+}
+
+/// What the bootloader does after a reset.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Boot {
+    /// Start the firmware.
+    App,
+
+    /// Stay in DFU mode.
+    Dfu,
+}
+
+/// Decides what to do after a reset, given the boot flag and whether there's valid firmware.
+/// Returns the decision and the flag to leave for next time.
+pub fn boot(flag: u32, app_valid: bool) -> (Boot, u32) {
+    if flag == DFU_REQUEST || !app_valid {
+        return (Boot::Dfu, 0);
+    }
+    if flag & !0xFF == TRIAL_BOOT {
+        let attempts = flag & 0xFF;
+        if attempts >= TRIAL_ATTEMPTS {
+            return (Boot::Dfu, 0);
+        }
+        return (Boot::App, TRIAL_BOOT | (attempts + 1));
+    }
+    (Boot::App, 0)
+}
+
+/// Flash operations needed to write an image.
+pub trait Flash {
+    /// Erases the page starting at `address`.
+    fn erase_page(&mut self, address: usize);
+
+    /// Programs `data` at `address`, which must already be erased.
+    fn program(&mut self, address: usize, data: &[u8]);
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum State {
+    Idle = 2,
+    DnloadSync = 3,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Error = 10,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Status {
+    Ok = 0x00,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrStalledPkt = 0x0F,
+}
+
+/// DFU 1.1 class which writes downloaded images above the bootloader.
+pub struct DfuClass<F: Flash> {
+    iface: InterfaceNumber,
+    flash: F,
+    state: State,
+    status: Status,
+    first_block: [u8; TRANSFER_SIZE],
+    first_block_length: usize,
+    manifested: bool,
+    reboot_requested: bool,
+}
+
+impl<F: Flash> DfuClass<F> {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, flash: F) -> Self {
+        DfuClass {
+            iface: alloc.interface(),
+            flash,
+            state: State::Idle,
+            status: Status::Ok,
+            first_block: [0; TRANSFER_SIZE],
+            first_block_length: 0,
+            manifested: false,
+            reboot_requested: false,
+        }
+    }
+
+    /// Returns true (once) if a new image has been written and the host has reset the bus, so the
+    /// new firmware should be started.
+    pub fn take_reboot_request(&mut self) -> bool {
+        core::mem::replace(&mut self.reboot_requested, false)
+    }
+
+    /// Writes a DNLOAD block.
+    fn download(&mut self, block: u16, data: &[u8]) -> core::result::Result<(), Status> {
+        let address = APP_ADDRESS + block as usize * TRANSFER_SIZE;
+        if data.len() > TRANSFER_SIZE || address + data.len() > APP_END {
+            return Err(Status::ErrAddress);
+        }
+
+        // Blocks never straddle pages, since TRANSFER_SIZE divides PAGE_SIZE.
+        if (address - APP_ADDRESS).is_multiple_of(PAGE_SIZE) {
+            self.flash.erase_page(address);
+        }
+
+        if block == 0 {
+            self.first_block[..data.len()].copy_from_slice(data);
+            self.first_block_length = data.len();
+        } else {
+            self.flash.program(address, data);
+        }
+        Ok(())
+    }
+
+    /// Finishes a download by writing the first block, which makes the image bootable.
+    fn manifest(&mut self) {
+        self.flash.program(APP_ADDRESS, &self.first_block[..self.first_block_length]);
+        self.first_block_length = 0;
+        self.manifested = true;
+    }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+}
+
+impl<B: UsbBus, F: Flash> UsbClass<B> for DfuClass<F> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.iface,
+            0xFE, // Application specific
+            0x01, // Device firmware upgrade
+            0x02, // DFU mode
+        )?;
+
+        let transfer_size = (TRANSFER_SIZE as u16).to_le_bytes();
+        writer.write(
+            DESCRIPTOR_DFU_FUNCTIONAL,
+            &[
+                DFU_ATTRIBUTES,
+                0xE8, 0x03, // wDetachTimeOut (1000 ms)
+                transfer_size[0], transfer_size[1], // wTransferSize
+                0x10, 0x01, // bcdDFUVersion (1.1)
+            ],
+        )
+    }
+
+    fn reset(&mut self) {
+        // dfu-util resets the bus once it's done.
+        if self.manifested {
+            self.reboot_requested = true;
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !(req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.iface) as u16)
+        {
+            return;
+        }
+
+        match req.request {
+            DFU_GETSTATUS => {
+                // Blocks are written as soon as they arrive, so there's never anything to wait for.
+                match self.state {
+                    State::DnloadSync => self.state = State::DnloadIdle,
+                    State::ManifestSync => {
+                        self.manifest();
+                        self.state = State::Idle;
+                    }
+                    _ => {}
+                }
+                let status = [
+                    self.status as u8,
+                    0, 0, 0, // bwPollTimeout
+                    self.state as u8,
+                    0, // iString
+                ];
+                xfer.accept_with(&status).ok();
+            }
+            DFU_GETSTATE => {
+                xfer.accept_with(&[self.state as u8]).ok();
+            }
+            _ => {
+                self.fail(Status::ErrStalledPkt);
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !(req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.iface) as u16)
+        {
+            return;
+        }
+
+        match (req.request, self.state) {
+            (DFU_DNLOAD, State::DnloadIdle) if xfer.data().is_empty() => {
+                self.state = State::ManifestSync;
+                xfer.accept().ok();
+            }
+            (DFU_DNLOAD, State::Idle) if xfer.data().is_empty() => {
+                self.fail(Status::ErrNotDone);
+                xfer.reject().ok();
+            }
+            (DFU_DNLOAD, State::Idle) | (DFU_DNLOAD, State::DnloadIdle) => {
+                match self.download(req.value, xfer.data()) {
+                    Ok(()) => {
+                        self.state = State::DnloadSync;
+                        xfer.accept().ok();
+                    }
+                    Err(status) => {
+                        self.fail(status);
+                        xfer.reject().ok();
+                    }
+                }
+            }
+            (DFU_CLRSTATUS, State::Error) => {
+                self.state = State::Idle;
+                self.status = Status::Ok;
+                xfer.accept().ok();
+            }
+            (DFU_ABORT, State::Idle) | (DFU_ABORT, State::DnloadIdle) => {
+                self.state = State::Idle;
+                self.first_block_length = 0;
+                xfer.accept().ok();
+            }
+            // We're already in DFU mode.
+            (DFU_DETACH, _) => {
+                xfer.accept().ok();
+            }
+            _ => {
+                self.fail(Status::ErrStalledPkt);
+                xfer.reject().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBus, TransferError};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use usb_device::prelude::*;
+
+    /// The firmware region of flash.
+    #[derive(Clone)]
+    struct MockFlash(Rc<RefCell<Vec<u8>>>);
+
+    impl MockFlash {
+        fn new() -> Self {
+            MockFlash(Rc::new(RefCell::new(vec![0; APP_END - APP_ADDRESS])))
+        }
+
+        fn read(&self, address: usize, length: usize) -> Vec<u8> {
+            let offset = address - APP_ADDRESS;
+            self.0.borrow()[offset..offset + length].to_vec()
+        }
+    }
+
+    impl Flash for MockFlash {
+        fn erase_page(&mut self, address: usize) {
+            assert_eq!(address % PAGE_SIZE, 0);
+            let offset = address - APP_ADDRESS;
+            self.0.borrow_mut()[offset..offset + PAGE_SIZE].fill(0xFF);
+        }
+
+        fn program(&mut self, address: usize, data: &[u8]) {
+            let offset = address - APP_ADDRESS;
+            let mut flash = self.0.borrow_mut();
+            let target = &mut flash[offset..offset + data.len()];
+            assert!(target.iter().all(|&b| b == 0xFF), "programming unerased flash");
+            target.copy_from_slice(data);
+        }
+    }
+
+    /// Runs `f` against an enumerated DFU device. The flag is set once the class asks to reboot.
+    fn with_device(f: impl FnOnce(&MockBus, &mut dyn FnMut(), &MockFlash, &Cell<bool>)) {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let flash = MockFlash::new();
+        let reboot = Cell::new(false);
+        let mut dfu = DfuClass::new(&alloc, flash.clone());
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x05dc)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut dfu]);
+            if dfu.take_reboot_request() {
+                reboot.set(true);
+            }
+        };
+
+        bus.enumerate(&mut poll).unwrap();
+        f(&bus, &mut poll, &flash, &reboot);
+    }
+
+    /// Returns bStatus and bState.
+    fn get_status(bus: &MockBus, poll: &mut dyn FnMut()) -> (u8, u8) {
+        let status = bus.control_read(poll, 0xA1, DFU_GETSTATUS, 0, 0, 6).unwrap();
+        (status[0], status[4])
+    }
+
+    fn download(
+        bus: &MockBus,
+        poll: &mut dyn FnMut(),
+        block: u16,
+        data: &[u8],
+    ) -> core::result::Result<(), TransferError> {
+        bus.control_write(poll, 0x21, DFU_DNLOAD, block, 0, data)
+    }
+
+    #[test]
+    fn functional_descriptor() {
+        with_device(|bus, poll, _, _| {
+            let config = bus.get_descriptor(poll, 2, 0, 64).unwrap();
+            let functional = config
+                .windows(2)
+                .position(|w| w == [9, DESCRIPTOR_DFU_FUNCTIONAL])
+                .unwrap();
+            assert_eq!(
+                &config[functional..functional + 9],
+                &[9, 0x21, 0x05, 0xE8, 0x03, 0x00, 0x01, 0x10, 0x01]
+            );
+        });
+    }
+
+    #[test]
+    fn download_image() {
+        with_device(|bus, poll, flash, reboot| {
+            let image: Vec<u8> = (0..600).map(|i| i as u8).collect();
+            for (block, chunk) in image.chunks(TRANSFER_SIZE).enumerate() {
+                download(bus, poll, block as u16, chunk).unwrap();
+                assert_eq!(get_status(bus, poll), (0, State::DnloadIdle as u8));
+            }
+
+            // Nothing's bootable until the download is finished.
+            assert_eq!(flash.read(APP_ADDRESS, 8), [0xFF; 8]);
+            assert_eq!(flash.read(APP_ADDRESS + 256, 344), &image[256..]);
+
+            download(bus, poll, 0, &[]).unwrap();
+            assert_eq!(get_status(bus, poll), (0, State::Idle as u8));
+            assert_eq!(flash.read(APP_ADDRESS, 600), image);
+
+            // dfu-util resets the bus to start the new firmware.
+            bus.bus_reset();
+            poll();
+            assert!(reboot.get());
+        });
+    }
+
+    #[test]
+    fn out_of_range() {
+        with_device(|bus, poll, _, _| {
+            let block = ((APP_END - APP_ADDRESS) / TRANSFER_SIZE) as u16;
+            assert_eq!(download(bus, poll, block, &[0; 4]), Err(TransferError::Stall));
+            assert_eq!(get_status(bus, poll), (Status::ErrAddress as u8, State::Error as u8));
+
+            // Nothing more is accepted until the error's cleared.
+            assert_eq!(download(bus, poll, 0, &[0; 4]), Err(TransferError::Stall));
+            bus.control_write(poll, 0x21, DFU_CLRSTATUS, 0, 0, &[]).unwrap();
+            assert_eq!(get_status(bus, poll), (0, State::Idle as u8));
+            download(bus, poll, 0, &[0; 4]).unwrap();
+        });
+    }
+
+    #[test]
+    fn abort() {
+        with_device(|bus, poll, flash, reboot| {
+            download(bus, poll, 0, &[0x55; 16]).unwrap();
+            get_status(bus, poll);
+            bus.control_write(poll, 0x21, DFU_ABORT, 0, 0, &[]).unwrap();
+
+            // There's nothing to manifest any more.
+            assert_eq!(download(bus, poll, 0, &[]), Err(TransferError::Stall));
+            assert_eq!(flash.read(APP_ADDRESS, 16), [0xFF; 16]);
+
+            bus.bus_reset();
+            poll();
+            assert!(!reboot.get());
+        });
+    }
+
+    #[test]
+    fn trial_boot() {
+        assert_eq!(boot(0, true), (Boot::App, 0));
+        assert_eq!(boot(0, false), (Boot::Dfu, 0));
+        assert_eq!(boot(DFU_REQUEST, true), (Boot::Dfu, 0));
+
+        // Each reset on trial counts, until the image is given up on.
+        let mut flag = TRIAL_BOOT;
+        for _ in 0..TRIAL_ATTEMPTS {
+            let (decision, next) = boot(flag, true);
+            assert_eq!(decision, Boot::App);
+            flag = next;
+        }
+        assert_eq!(boot(flag, true), (Boot::Dfu, 0));
+    }
+
+    #[test]
+    fn valid_app() {
+        assert!(is_valid_app(&[0x2000_1800, APP_ADDRESS as u32 + 0xC1]));
+        assert!(!is_valid_app(&[0xFFFF_FFFF, 0xFFFF_FFFF]));
+        assert!(!is_valid_app(&[0x2000_1800, 0x0800_0101]));
+    }
+}
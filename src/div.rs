@@ -0,0 +1,32 @@
+//! Smaller division
+//!
+//! The Cortex-M0 has no divide instruction, and compiler_builtins' division unrolls its loops for
+//! speed, which takes about 400 bytes of flash. Nothing here divides often enough for that to
+//! matter, so a bit at a time is plenty. As with `mem`, compiler_builtins' `__aeabi_*` functions
+//! are weak, so these are linked instead.
+
+/// Unsigned division. The compiler checks for division by zero before calling this.
+#[no_mangle]
+pub extern "C" fn __aeabi_uidiv(n: u32, d: u32) -> u32 {
+    let mut quotient = 0;
+    let mut remainder = 0u32;
+    for bit in (0..32).rev() {
+        remainder = (remainder << 1) | ((n >> bit) & 1);
+        if remainder >= d {
+            remainder -= d;
+            quotient |= 1 << bit;
+        }
+    }
+    quotient
+}
+
+/// Signed division, rounding towards zero.
+#[no_mangle]
+pub extern "C" fn __aeabi_idiv(n: i32, d: i32) -> i32 {
+    let quotient = __aeabi_uidiv(n.unsigned_abs(), d.unsigned_abs()) as i32;
+    if (n < 0) != (d < 0) {
+        quotient.wrapping_neg()
+    } else {
+        quotient
+    }
+}
@@ -0,0 +1,174 @@
+//! Persistent error log
+//!
+//! With the `error-log` feature, the errors in `tilda_bridge_protocol::errorlog` are logged in the
+//! configuration page, between the settings and the counters:
+//!
+//! | Offset | Size | Field                                                     |
+//! |--------|------|-----------------------------------------------------------|
+//! | 128    | 128  | Up to 16 records, oldest first, then erased slots         |
+//!
+//! Each record is programmed into the next erased slot. When the page is rewritten, for new
+//! settings or a full boot log, the records are carried over, and if the log is full the older half
+//! is dropped to make room. Clearing the log programs the first half-word of each record to zero,
+//! which the flash controller allows without an erase, and cleared records are dropped at the next
+//! rewrite.
+//!
+//! A panic restarts the bridge, as with panic-reset, after leaving `PANIC_FLAG` in RAM for the next
+//! boot to log, as the stack overflow flag is (see `stack`).
+
+pub use tilda_bridge_protocol::errorlog::{
+    Entry, ENTRIES, ENTRY_LENGTH, LOG_LENGTH, PANIC, REATTACH, RESET, UART_OVERRUN, USB_RECOVERY,
+};
+
+use crate::dfu;
+use tilda_bridge_protocol::reset::ResetCause;
+
+/// Offset of the error log in the configuration page. Everything before it belongs to the
+/// settings.
+pub const ERROR_LOG_OFFSET: usize = 128;
+
+/// A word of RAM which survives a reset, next to the stack overflow flag.
+pub const PANIC_FLAG_ADDRESS: usize = dfu::BOOT_FLAG_ADDRESS + 8;
+
+/// Value left at `PANIC_FLAG_ADDRESS` before restarting after a panic.
+pub const PANIC_FLAG: u32 = 0x9A41_C0DE;
+
+/// Resets worth logging: the others are the user, the host or the bootloader.
+const LOGGED_RESETS: u8 = ResetCause::INDEPENDENT_WATCHDOG
+    | ResetCause::WINDOW_WATCHDOG
+    | ResetCause::LOW_POWER
+    | ResetCause::STACK_OVERFLOW;
+
+/// First byte of an erased slot.
+const ERASED: u8 = 0xFF;
+
+/// First byte of a cleared record.
+const CLEARED: u8 = 0;
+
+/// Errors seen this boot, and the records for them which haven't been logged yet.
+#[derive(Default)]
+pub struct ErrorLog {
+    boot: u16,
+    /// Bit `kind` is set once an error of that kind has been seen.
+    seen: u8,
+    /// By kind, from `RESET`; a kind of 0 has been logged.
+    unlogged: [Entry; UART_OVERRUN as usize],
+}
+
+impl ErrorLog {
+    /// Sets the boot count, to go in this boot's records when they're taken.
+    pub fn set_boots(&mut self, boots: u32) {
+        self.boot = boots as u16;
+    }
+
+    /// Notes an error, `uptime` seconds into the boot, unless one of its kind already has been.
+    #[inline(never)]
+    pub fn record(&mut self, kind: u8, detail: u8, uptime: u32) {
+        if self.seen & 1 << kind != 0 {
+            return;
+        }
+        self.seen |= 1 << kind;
+        self.unlogged[kind as usize - 1] = Entry { kind, detail, boot: 0, uptime };
+    }
+
+    /// Notes an abnormal reset at startup.
+    pub fn record_reset(&mut self, cause: ResetCause) {
+        if cause.contains(LOGGED_RESETS) {
+            self.record(RESET, cause.bits(), 0);
+        }
+    }
+
+    /// Takes a record which hasn't been logged yet, or `None` once everything has been.
+    pub fn take_entry(&mut self) -> Option<[u8; ENTRY_LENGTH]> {
+        let entry = self.unlogged.iter_mut().find(|entry| entry.kind != 0)?;
+        let entry = Entry { boot: self.boot, ..core::mem::take(entry) };
+        Some(entry.to_bytes())
+    }
+}
+
+/// The log in a configuration page, and how many of its slots have been programmed.
+fn log(page: &[u8]) -> (&[u8], usize) {
+    let log = page.get(ERROR_LOG_OFFSET..ERROR_LOG_OFFSET + LOG_LENGTH).unwrap_or(&[]);
+    let used = log.chunks_exact(ENTRY_LENGTH).take_while(|slot| slot[0] != ERASED).count();
+    (log, used)
+}
+
+/// The records in a configuration page, oldest first, and the offset in the page of the first.
+/// Clearing the log clears every record in it, so the cleared ones all come before them.
+pub fn records(page: &[u8]) -> (usize, &[u8]) {
+    let (log, used) = log(page);
+    let used = &log[..used * ENTRY_LENGTH];
+    let cleared = used.chunks_exact(ENTRY_LENGTH).take_while(|slot| slot[0] == CLEARED).count();
+    let start = cleared * ENTRY_LENGTH;
+    (ERROR_LOG_OFFSET + start, &used[start..])
+}
+
+/// Finds the offset in the configuration page of the next free slot, or `None` if the log is full
+/// and the page needs rewriting.
+pub fn free_slot(page: &[u8]) -> Option<usize> {
+    let (_, used) = log(page);
+    (used < ENTRIES).then_some(ERROR_LOG_OFFSET + used * ENTRY_LENGTH)
+}
+
+/// The records to carry over when the page is rewritten: all of them, or the newer half if the
+/// log is full.
+pub fn kept(page: &[u8]) -> &[u8] {
+    let (_, records) = records(page);
+    let keep = if free_slot(page).is_none() { LOG_LENGTH / 2 } else { LOG_LENGTH };
+    &records[records.len().saturating_sub(keep)..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_with(records: &[[u8; ENTRY_LENGTH]]) -> [u8; 1024] {
+        let mut page = [0xFFu8; 1024];
+        for (i, record) in records.iter().enumerate() {
+            let offset = ERROR_LOG_OFFSET + i * ENTRY_LENGTH;
+            page[offset..offset + ENTRY_LENGTH].copy_from_slice(record);
+        }
+        page
+    }
+
+    #[test]
+    fn once_a_boot() {
+        let mut log = ErrorLog::default();
+        log.record_reset(ResetCause::from_bits(ResetCause::PIN | ResetCause::SOFTWARE));
+        assert_eq!(log.take_entry(), None);
+
+        // The reset cause is known before the boot count.
+        log.record_reset(ResetCause::from_bits(ResetCause::PIN | ResetCause::STACK_OVERFLOW));
+        log.set_boots(0x1_0007);
+        log.record(UART_OVERRUN, 0, 30);
+        log.record(UART_OVERRUN, 0, 31);
+        assert_eq!(log.take_entry(), Some([RESET, 0x82, 7, 0, 0, 0, 0, 0]));
+        assert_eq!(log.take_entry(), Some([UART_OVERRUN, 0, 7, 0, 30, 0, 0, 0]));
+        assert_eq!(log.take_entry(), None);
+        log.record(UART_OVERRUN, 0, 40);
+        assert_eq!(log.take_entry(), None);
+    }
+
+    #[test]
+    fn ring() {
+        let record = |kind, boot| Entry { kind, detail: 0, boot, uptime: 5 }.to_bytes();
+        let page = page_with(&[]);
+        assert_eq!(free_slot(&page), Some(ERROR_LOG_OFFSET));
+        assert_eq!(kept(&page), &[]);
+
+        // Cleared records are skipped, but their slots stay used until the page is rewritten.
+        let mut cleared = record(PANIC, 1);
+        cleared[..2].copy_from_slice(&[0, 0]);
+        let page = page_with(&[cleared, record(PANIC, 2), record(REATTACH, 2)]);
+        assert_eq!(free_slot(&page), Some(ERROR_LOG_OFFSET + 3 * ENTRY_LENGTH));
+        let live = [record(PANIC, 2), record(REATTACH, 2)].concat();
+        assert_eq!(records(&page), (ERROR_LOG_OFFSET + ENTRY_LENGTH, &live[..]));
+        assert_eq!(kept(&page), &live[..]);
+
+        // A full log keeps the newer half.
+        let full: Vec<_> = (0..ENTRIES as u16).map(|boot| record(PANIC, boot)).collect();
+        let page = page_with(&full);
+        assert_eq!(free_slot(&page), None);
+        assert_eq!(kept(&page), &full[ENTRIES / 2..].concat()[..]);
+    }
+}
@@ -0,0 +1,214 @@
+//! In-band escapes on the serial port
+//!
+//! A terminal on the CDC serial port can switch what it's talking to without the WebUSB control
+//! channel, with `~` and a key at the start of a line, as in ssh:
+//!
+//! * `~e` the ESP32's console, which is where it starts
+//! * `~c` the bridge's command console
+//! * `~s` a stats line every second instead of the ESP32's output
+//! * `~r` resets the ESP32, and `~b` resets it into its ROM bootloader
+//! * `~?` lists these
+//!
+//! `~~` sends a single `~`; any other key after a `~` goes on its own. In the command console and
+//! the stats view, every key is taken as if it followed a `~`, and nothing goes to the ESP32.
+
+use crate::telemetry::SupplyReadings;
+
+/// The escape character.
+const ESCAPE: u8 = b'~';
+
+/// Sent to the terminal for `~?`, and on entering the command console.
+pub const HELP: &[u8] = b"\r\n~e ESP32 ~c bridge ~s stats ~r reset ~b boot\r\n";
+
+/// Length of the line from `stats_line`.
+pub const STATS_LINE_LENGTH: usize = 63;
+
+/// What the terminal is talking to.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// The ESP32's console.
+    #[default]
+    Esp,
+
+    /// The bridge's command console.
+    Commands,
+
+    /// A stats line every second.
+    Stats,
+}
+
+/// Something for the bridge to do for the terminal.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// Resets the ESP32.
+    Reset,
+
+    /// Resets the ESP32 into its ROM bootloader.
+    Download,
+
+    /// Sends `HELP`.
+    Help,
+
+    /// Sends a line for the stats view.
+    Stats,
+}
+
+/// Picks escapes out of what the terminal sends.
+#[derive(Default)]
+pub struct Escapes {
+    mode: Mode,
+    /// Whether the last byte didn't end a line.
+    mid_line: bool,
+    /// Whether the last byte was an escape at the start of a line.
+    escaped: bool,
+    command: Option<Command>,
+    /// The low byte of the uptime when the last stats line was asked for.
+    stats_second: u8,
+}
+
+impl Escapes {
+    /// What the terminal is talking to.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Returns (once) what the terminal last asked the bridge to do.
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.command.take()
+    }
+
+    /// Asks for a stats line each time the uptime, in seconds, moves on in the stats view, unless
+    /// there's a command waiting.
+    pub fn tick(&mut self, uptime: u32) {
+        if uptime as u8 != self.stats_second && self.mode == Mode::Stats && self.command.is_none() {
+            self.stats_second = uptime as u8;
+            self.command = Some(Command::Stats);
+        }
+    }
+
+    /// Takes the escapes out of data from the terminal in place, and returns how much of it is
+    /// left for the ESP32. Only the last command is kept for `take_command`.
+    pub fn filter(&mut self, data: &mut [u8]) -> usize {
+        let mut len = 0;
+        for i in 0..data.len() {
+            let byte = data[i];
+            let line_start = !self.mid_line;
+            self.mid_line = byte != b'\r' && byte != b'\n';
+            if self.mode == Mode::Esp && !self.escaped {
+                if byte == ESCAPE && line_start {
+                    self.escaped = true;
+                } else {
+                    data[len] = byte;
+                    len += 1;
+                }
+                continue;
+            }
+            self.escaped = false;
+            match byte {
+                b'e' => self.mode = Mode::Esp,
+                b'c' => {
+                    self.mode = Mode::Commands;
+                    self.command = Some(Command::Help);
+                }
+                b's' => self.mode = Mode::Stats,
+                b'r' => self.command = Some(Command::Reset),
+                b'b' => self.command = Some(Command::Download),
+                b'?' => self.command = Some(Command::Help),
+                _ if self.mode == Mode::Esp => {
+                    data[len] = byte;
+                    len += 1;
+                }
+                _ => {}
+            }
+        }
+        len
+    }
+}
+
+/// The stats view's line, with the numbers right-aligned to end at `STATS_FIELDS`, so they line
+/// up from one line to the next.
+const STATS_TEMPLATE: &[u8; STATS_LINE_LENGTH] =
+    b"up           s  3V3      mV  VBUS      mV  dropped           \r\n";
+
+/// Where each number in `STATS_TEMPLATE` ends: the uptime, the two rails and the drops.
+const STATS_FIELDS: [usize; 4] = [13, 25, 39, 61];
+
+/// Formats the stats view's line: the uptime in seconds, the supply rails (`-` if one isn't wired
+/// to the ADC), and how much of the ESP32's output the serial port has dropped.
+pub fn stats_line(uptime: u32, supply: SupplyReadings, dropped: u32) -> [u8; STATS_LINE_LENGTH] {
+    let mut line = *STATS_TEMPLATE;
+    let rail = |mv: Option<u16>| mv.map(u32::from);
+    let values = [Some(uptime), rail(supply.vdd), rail(supply.vbus), Some(dropped)];
+    for (i, &value) in values.iter().enumerate() {
+        let mut end = STATS_FIELDS[i];
+        match value {
+            Some(mut value) => loop {
+                end -= 1;
+                line[end] = b'0' + (value % 10) as u8;
+                value /= 10;
+                if value == 0 {
+                    break;
+                }
+            },
+            None => line[end - 1] = b'-',
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(escapes: &mut Escapes, data: &[u8]) -> Vec<u8> {
+        let mut buf = data.to_vec();
+        let len = escapes.filter(&mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn escapes() {
+        let mut escapes = Escapes::default();
+        assert_eq!(filter(&mut escapes, b"~r"), b"");
+        assert_eq!(escapes.take_command(), Some(Command::Reset));
+        assert_eq!(escapes.take_command(), None);
+
+        // Only at the start of a line, and split across reads
+        assert_eq!(filter(&mut escapes, b"ls ~r\r"), b"ls ~r\r");
+        assert_eq!(filter(&mut escapes, b"~"), b"");
+        assert_eq!(filter(&mut escapes, b"~\n~x"), b"~\nx");
+        assert_eq!(escapes.take_command(), None);
+
+        // The command console takes every key, and sends nothing on.
+        assert_eq!(filter(&mut escapes, b"\r~c"), b"\r");
+        assert_eq!(escapes.mode(), Mode::Commands);
+        assert_eq!(escapes.take_command(), Some(Command::Help));
+        assert_eq!(filter(&mut escapes, b"xb"), b"");
+        assert_eq!(escapes.take_command(), Some(Command::Download));
+        assert_eq!(filter(&mut escapes, b"s"), b"");
+        assert_eq!(escapes.mode(), Mode::Stats);
+        escapes.tick(1);
+        assert_eq!(escapes.take_command(), Some(Command::Stats));
+        escapes.tick(1);
+        assert_eq!(escapes.take_command(), None);
+        assert_eq!(filter(&mut escapes, b"~e"), b"");
+        assert_eq!(escapes.mode(), Mode::Esp);
+        assert_eq!(filter(&mut escapes, b"ok"), b"ok");
+    }
+
+    #[test]
+    fn stats() {
+        let supply = SupplyReadings { vdd: Some(3301), vbus: None, battery: None };
+        assert_eq!(
+            &stats_line(120, supply, 0),
+            b"up        120s  3V3  3301mV  VBUS     -mV  dropped          0\r\n"
+        );
+
+        let supply = SupplyReadings { vdd: Some(65535), vbus: Some(65535), battery: None };
+        assert_eq!(
+            &stats_line(u32::MAX, supply, u32::MAX),
+            b"up 4294967295s  3V3 65535mV  VBUS 65535mV  dropped 4294967295\r\n"
+        );
+    }
+}
@@ -0,0 +1,606 @@
+//! ESP32 boot control
+
+use crate::config::ControlLinePolicy;
+use crate::timing::Timeout;
+use embedded_hal::digital::v2::OutputPin;
+
+#[cfg(all(feature = "esp32c3", feature = "esp32s3"))]
+compile_error!("only one of the `esp32c3` and `esp32s3` features can be enabled");
+
+/// How to drive a particular ESP32 variant into download mode and back.
+///
+/// PA4 goes to whichever pin the variant samples for download mode: IO0 on the ESP32 and S3, and
+/// GPIO9 on the C3. The S3 also needs GPIO46 low, which its internal pull-down sees to.
+///
+/// The timings are esptool's, which it uses for every variant so far. A module with a slower reset
+/// circuit can be given longer ones here rather than in the reset sequences.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ChipProfile {
+    /// How long EN is held low to reset the chip.
+    pub reset_ms: u32,
+
+    /// How long the download mode pin is held low after EN is released, so the chip has latched
+    /// it.
+    pub strap_hold_ms: u32,
+
+    /// What drives the second strapping pin until the host says otherwise.
+    pub strap: StrapMode,
+}
+
+/// The original ESP32, as fitted to the first badges.
+pub const ESP32: ChipProfile = ChipProfile {
+    reset_ms: 100,
+    strap_hold_ms: 50,
+    strap: StrapMode::FollowIo0,
+};
+
+/// The ESP32-C3.
+pub const ESP32_C3: ChipProfile = ChipProfile {
+    reset_ms: 100,
+    strap_hold_ms: 50,
+    strap: StrapMode::Released,
+};
+
+/// The ESP32-S3.
+pub const ESP32_S3: ChipProfile = ChipProfile {
+    reset_ms: 100,
+    strap_hold_ms: 50,
+    strap: StrapMode::FollowIo0,
+};
+
+/// The variant this build is for, from the board file unless the `esp32c3` or `esp32s3` feature
+/// says otherwise.
+pub const CHIP: ChipProfile = if cfg!(esp_chip = "esp32c3") {
+    ESP32_C3
+} else if cfg!(esp_chip = "esp32s3") {
+    ESP32_S3
+} else {
+    ESP32
+};
+
+/// What drives the second strapping pin, on boards built with the `strap-pin` feature.
+///
+/// Newer modules sample a second pin for the boot mode: GPIO2 on the ESP32 and GPIO46 on the S3
+/// have to be low for download mode, and GPIO8 on the C3 high. PB7 is open drain, so it can only
+/// pull the pin low; otherwise the module's own pull decides. It follows IO0 within a pass of the
+/// main loop, well inside the time the EN line's RC delay takes to release the chip.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StrapMode {
+    /// Pulled low whenever IO0 is, so download mode gets both.
+    FollowIo0 = 0,
+
+    /// Held low.
+    Low = 1,
+
+    /// Left to the module.
+    Released = 2,
+}
+
+impl StrapMode {
+    /// Converts a `SET_STRAP` `wValue`.
+    pub fn from_u16(value: u16) -> Option<StrapMode> {
+        match value {
+            0 => Some(StrapMode::FollowIo0),
+            1 => Some(StrapMode::Low),
+            2 => Some(StrapMode::Released),
+            _ => None,
+        }
+    }
+
+    /// Whether the pin should be pulled low, given IO0's level.
+    pub fn pulls_low(self, io0_high: bool) -> bool {
+        match self {
+            StrapMode::FollowIo0 => !io0_high,
+            StrapMode::Low => true,
+            StrapMode::Released => false,
+        }
+    }
+}
+
+/// Where `EspBootControl` has the ESP32.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BootState {
+    /// EN released, and IO0 wherever the host or the last sequence left it.
+    Running,
+
+    /// EN held low for a reset, with IO0 too if it's into download mode.
+    Resetting { download: bool },
+
+    /// EN released with IO0 still held low, until the chip has latched it.
+    DownloadEntry,
+
+    /// EN held low until something says otherwise.
+    PoweredOff,
+}
+
+/// Drives the ESP32's EN and IO0 lines.
+///
+/// The host can drive them directly with DTR and RTS, or the bridge can reset the ESP32 itself, in
+/// which case the lines are released in order as `tick` counts the time down. Driving them
+/// directly cuts a reset short.
+///
+/// The host's changes are paced, as the main loop may take a whole sequence of them from the
+/// ports' queues at once: EN stays low for at least `reset_ms` once it's pulled low, and IO0 low for
+/// `strap_hold_ms` after EN is released, with later changes waiting their turn.
+pub struct EspBootControl<P> {
+    en: P,
+    gpio0: P,
+    /// `ChipProfile::reset_ms`
+    reset_ms: u32,
+    /// `ChipProfile::strap_hold_ms`
+    strap_hold_ms: u32,
+    state: BootState,
+    /// Until a reset moves on to its next state, or the host's next change can be made.
+    timer: Timeout,
+    /// The host's changes waiting to be made.
+    pending: PendingLines,
+}
+
+impl<P: OutputPin> EspBootControl<P> {
+    /// Takes the pins, which are expected to be high, with the ESP32 running.
+    pub fn new(en: P, gpio0: P, profile: ChipProfile) -> Self {
+        EspBootControl {
+            en,
+            gpio0,
+            reset_ms: profile.reset_ms,
+            strap_hold_ms: profile.strap_hold_ms,
+            state: BootState::Running,
+            timer: Timeout::default(),
+            pending: PendingLines::default(),
+        }
+    }
+
+    pub fn state(&self) -> BootState {
+        self.state
+    }
+
+    /// The EN and IO0 pins, to check their levels.
+    pub fn pins(&self) -> (&P, &P) {
+        (&self.en, &self.gpio0)
+    }
+
+    /// Sets the pins from the serial port's DTR and RTS, as levels rather than as asserted.
+    ///
+    /// This emulates the transistor logic on ESP32 dev boards, which ignores DTR and RTS being
+    /// asserted together.
+    pub fn set_lines(&mut self, dtr: bool, rts: bool) {
+        // https://github.com/espressif/esptool/wiki/ESP32-Boot-Mode-Selection#automatic-bootloader
+        // DTR RTS| EN  IO0
+        // 1   1  |  1   1
+        // 0   0  |  1   1
+        // 1   0  |  0   1
+        // 0   1  |  1   0
+        let (en, gpio0) = if dtr == rts { (true, true) } else { (rts, dtr) };
+        match self.state {
+            BootState::Running | BootState::PoweredOff
+                if self.timer.active() || self.pending.len != 0 =>
+            {
+                self.pending.push(en, gpio0);
+            }
+            _ => self.apply(en, gpio0),
+        }
+    }
+
+    /// Makes one of the host's changes, and holds it as long as the chip needs.
+    fn apply(&mut self, en: bool, gpio0: bool) {
+        let en_was_high = matches!(self.state, BootState::Running | BootState::DownloadEntry);
+        self.set_pins(en, gpio0);
+        self.timer.start(match (en_was_high, en) {
+            (true, false) => self.reset_ms,
+            (false, true) if !gpio0 => self.strap_hold_ms,
+            _ => 0,
+        });
+    }
+
+    /// Sets EN and IO0 as given, dropping any of the host's changes still waiting. With EN low,
+    /// the ESP32 is held off until they're next set.
+    #[inline(never)]
+    pub fn drive(&mut self, en: bool, gpio0: bool) {
+        self.pending = PendingLines::default();
+        self.timer.cancel();
+        self.set_pins(en, gpio0);
+    }
+
+    fn set_pins(&mut self, en: bool, gpio0: bool) {
+        set(&mut self.gpio0, gpio0);
+        set(&mut self.en, en);
+        self.state = if en { BootState::Running } else { BootState::PoweredOff };
+    }
+
+    /// Holds the ESP32 in reset.
+    pub fn power_off(&mut self) {
+        self.drive(false, true);
+    }
+
+    /// Resets the ESP32, holding EN low for `reset_ms`, into its ROM bootloader if `download`.
+    pub fn reset(&mut self, download: bool, reset_ms: u32) {
+        self.drive(false, !download);
+        self.state = BootState::Resetting { download };
+        self.timer.start(reset_ms);
+    }
+
+    /// Advances a reset, or makes the host's next change once the last has been held long enough.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        if self.timer.active() && !self.timer.tick(elapsed_ms) {
+            return;
+        }
+        match self.state {
+            BootState::Resetting { download: true } => {
+                set(&mut self.en, true);
+                self.state = BootState::DownloadEntry;
+                self.timer.start(self.strap_hold_ms);
+            }
+            BootState::Running | BootState::PoweredOff => {
+                if let Some((en, gpio0)) = self.pending.pop() {
+                    self.apply(en, gpio0);
+                }
+            }
+            BootState::Resetting { .. } | BootState::DownloadEntry => self.drive(true, true),
+        }
+    }
+}
+
+/// The host's EN and IO0 levels waiting to be made, two bits each from the oldest up. When it's
+/// full, the oldest is dropped for a new one.
+#[derive(Default)]
+struct PendingLines {
+    bits: u16,
+    len: u8,
+}
+
+impl PendingLines {
+    const LENGTH: u8 = 8;
+
+    fn push(&mut self, en: bool, gpio0: bool) {
+        if self.len == Self::LENGTH {
+            self.bits >>= 2;
+            self.len -= 1;
+        }
+        self.bits |= (en as u16 | (gpio0 as u16) << 1) << (2 * self.len);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<(bool, bool)> {
+        if self.len == 0 {
+            return None;
+        }
+        let levels = (self.bits & 0b01 != 0, self.bits & 0b10 != 0);
+        self.bits >>= 2;
+        self.len -= 1;
+        Some(levels)
+    }
+}
+
+/// Sets a pin's level. The board's pins can't fail.
+#[inline(never)]
+fn set<P: OutputPin>(pin: &mut P, high: bool) {
+    let _ = if high { pin.set_high() } else { pin.set_low() };
+}
+
+/// How long DTR may be down with RTS still up before it's treated as a real control line change
+/// rather than the first half of the port being closed.
+pub const CLOSE_DEBOUNCE_MS: u32 = 20;
+
+/// What to do after a DTR/RTS change.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineAction {
+    /// Set the boot pins from the control lines.
+    Apply,
+
+    /// Nothing to do yet.
+    Wait,
+
+    /// The host has closed the port.
+    Closed,
+}
+
+/// Tells closing the port apart from other control line changes.
+///
+/// Some hosts drop DTR and RTS separately when closing the port, and DTR low with RTS high holds
+/// the ESP32 in reset. esptool uses the same state for its reset pulse, but holds it for 100ms, so
+/// the state is only applied once it's lasted `CLOSE_DEBOUNCE_MS`.
+///
+/// esptool's reset into the ROM loader ends with both lines down, straight from DTR up with RTS
+/// down, while the port is still open. That isn't a close, or the port closing policy would reset
+/// the ESP32 out of the loader, so it's applied as it is, and the port counts as closed from then
+/// on. A host which only raises DTR can't be told from esptool, so its close is applied the same
+/// way, which leaves the ESP32 running.
+#[derive(Default)]
+pub struct PortCloseFilter {
+    lines: (bool, bool),
+    open: bool,
+    /// Until DTR down with RTS up counts as a change of its own.
+    debounce: Timeout,
+}
+
+impl PortCloseFilter {
+    /// Updates the DTR and RTS state.
+    pub fn update(&mut self, dtr: bool, rts: bool) -> LineAction {
+        if (dtr, rts) == self.lines {
+            return LineAction::Wait;
+        }
+        let last = core::mem::replace(&mut self.lines, (dtr, rts));
+        self.debounce.cancel();
+
+        if dtr {
+            self.open = true;
+            LineAction::Apply
+        } else if !self.open {
+            LineAction::Apply
+        } else if rts {
+            self.debounce.start(CLOSE_DEBOUNCE_MS);
+            LineAction::Wait
+        } else {
+            self.open = false;
+            // The end of esptool's reset into the ROM loader
+            if last == (true, false) {
+                LineAction::Apply
+            } else {
+                LineAction::Closed
+            }
+        }
+    }
+
+    /// Advances the debounce timer. Returns true if the control lines should now be applied.
+    pub fn tick(&mut self, elapsed_ms: u32) -> bool {
+        self.debounce.tick(elapsed_ms)
+    }
+}
+
+/// Combines the two ports' DTR/RTS into the lines which drive the ESP32, as chosen by
+/// `Config::control_lines`.
+#[derive(Default)]
+pub struct ControlLineArbiter {
+    serial: (bool, bool),
+    webusb: (bool, bool),
+    /// Whether the WebUSB port's lines changed more recently than the serial port's.
+    webusb_last: bool,
+}
+
+impl ControlLineArbiter {
+    /// Takes each port's (DTR, RTS), and returns the combined lines.
+    pub fn update(
+        &mut self,
+        policy: ControlLinePolicy,
+        serial: (bool, bool),
+        webusb: (bool, bool),
+    ) -> (bool, bool) {
+        if serial != self.serial {
+            self.serial = serial;
+            self.webusb_last = false;
+        }
+        if webusb != self.webusb {
+            self.webusb = webusb;
+            self.webusb_last = true;
+        }
+
+        let asserted = |(dtr, rts): (bool, bool)| dtr || rts;
+        match policy {
+            ControlLinePolicy::Either => (serial.0 || webusb.0, serial.1 || webusb.1),
+            ControlLinePolicy::LastChanged if self.webusb_last => webusb,
+            ControlLinePolicy::LastChanged => serial,
+            ControlLinePolicy::SerialFirst if asserted(serial) => serial,
+            ControlLinePolicy::SerialFirst => webusb,
+            ControlLinePolicy::WebUsbFirst if asserted(webusb) => webusb,
+            ControlLinePolicy::WebUsbFirst => serial,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BootState, ControlLineArbiter, ControlLinePolicy, EspBootControl,
+        LineAction, PortCloseFilter, StrapMode, CLOSE_DEBOUNCE_MS, ESP32,
+    };
+    use core::convert::Infallible;
+    use embedded_hal::digital::v2::OutputPin;
+
+    struct Pin(bool);
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0 = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0 = true;
+            Ok(())
+        }
+    }
+
+    fn boot() -> EspBootControl<Pin> {
+        EspBootControl::new(Pin(true), Pin(true), ESP32)
+    }
+
+    fn levels(boot: &EspBootControl<Pin>) -> (bool, bool) {
+        let (en, gpio0) = boot.pins();
+        (en.0, gpio0.0)
+    }
+
+    fn pins(dtr: bool, rts: bool) -> (bool, bool) {
+        let mut boot = boot();
+        boot.set_lines(dtr, rts);
+        levels(&boot)
+    }
+
+    #[test]
+    fn truth_table() {
+        // (DTR, RTS) -> (EN, IO0)
+        assert_eq!(pins(true, true), (true, true));
+        assert_eq!(pins(false, false), (true, true));
+        assert_eq!(pins(true, false), (false, true));
+        assert_eq!(pins(false, true), (true, false));
+
+        let mut boot = boot();
+        boot.set_lines(true, false);
+        assert_eq!(boot.state(), BootState::PoweredOff);
+        boot.set_lines(false, false);
+        boot.tick(ESP32.reset_ms);
+        assert_eq!(boot.state(), BootState::Running);
+    }
+
+    #[test]
+    fn queued_esptool_edges() {
+        // esptool's classic reset into the ROM loader, taken from the queues all at once
+        let mut boot = boot();
+        boot.set_lines(true, false);
+        boot.set_lines(false, true);
+        boot.set_lines(false, false);
+
+        // EN is held low for the chip's reset time...
+        assert_eq!(levels(&boot), (false, true));
+        boot.tick(ESP32.reset_ms - 1);
+        assert_eq!(levels(&boot), (false, true));
+
+        // ...then IO0 low for as long as it takes to latch...
+        boot.tick(1);
+        assert_eq!(levels(&boot), (true, false));
+        boot.tick(ESP32.strap_hold_ms - 1);
+        assert_eq!(levels(&boot), (true, false));
+
+        // ...before the last change is made.
+        boot.tick(1);
+        assert_eq!(levels(&boot), (true, true));
+        assert_eq!(boot.state(), BootState::Running);
+
+        // Changes which come slowly enough aren't held up.
+        boot.set_lines(true, false);
+        boot.tick(ESP32.reset_ms);
+        boot.set_lines(false, false);
+        assert_eq!(levels(&boot), (true, true));
+
+        // The bridge driving the pins itself drops whatever's waiting.
+        boot.set_lines(true, false);
+        boot.set_lines(false, true);
+        boot.power_off();
+        boot.tick(ESP32.reset_ms);
+        assert_eq!(levels(&boot), (false, true));
+    }
+
+    #[test]
+    fn download_reset() {
+        let mut boot = boot();
+        boot.reset(true, ESP32.reset_ms);
+        assert_eq!(levels(&boot), (false, false));
+        boot.tick(ESP32.reset_ms - 1);
+        assert_eq!(boot.state(), BootState::Resetting { download: true });
+        assert_eq!(levels(&boot), (false, false));
+
+        // EN goes first, then IO0 once the chip has latched it.
+        boot.tick(1);
+        assert_eq!(boot.state(), BootState::DownloadEntry);
+        assert_eq!(levels(&boot), (true, false));
+        boot.tick(ESP32.strap_hold_ms);
+        assert_eq!(boot.state(), BootState::Running);
+        assert_eq!(levels(&boot), (true, true));
+    }
+
+    #[test]
+    fn normal_reset() {
+        let mut boot = boot();
+        boot.reset(false, 10);
+        assert_eq!(levels(&boot), (false, true));
+        assert_eq!(boot.state(), BootState::Resetting { download: false });
+        boot.tick(20);
+        assert_eq!(boot.state(), BootState::Running);
+        assert_eq!(levels(&boot), (true, true));
+
+        // The host's lines cut a reset short.
+        boot.reset(true, 10);
+        boot.set_lines(true, true);
+        boot.tick(10);
+        assert_eq!(boot.state(), BootState::Running);
+        assert_eq!(levels(&boot), (true, true));
+
+        boot.power_off();
+        boot.tick(1000);
+        assert_eq!(boot.state(), BootState::PoweredOff);
+        assert_eq!(levels(&boot), (false, true));
+    }
+
+    #[test]
+    fn port_close() {
+        let mut filter = PortCloseFilter::default();
+        assert_eq!(filter.update(true, true), LineAction::Apply);
+        assert_eq!(filter.update(true, true), LineAction::Wait);
+
+        // Closed in two steps
+        assert_eq!(filter.update(false, true), LineAction::Wait);
+        assert!(!filter.tick(CLOSE_DEBOUNCE_MS / 2));
+        assert_eq!(filter.update(false, false), LineAction::Closed);
+        assert!(!filter.tick(CLOSE_DEBOUNCE_MS));
+
+        // Closed in one step
+        assert_eq!(filter.update(true, true), LineAction::Apply);
+        assert_eq!(filter.update(false, false), LineAction::Closed);
+    }
+
+    #[test]
+    fn esptool_reset() {
+        let mut filter = PortCloseFilter::default();
+        filter.update(true, true);
+
+        // Reset
+        assert_eq!(filter.update(false, true), LineAction::Wait);
+        assert!(filter.tick(CLOSE_DEBOUNCE_MS));
+        assert!(!filter.tick(CLOSE_DEBOUNCE_MS));
+
+        // Boot into the ROM loader, and let go of IO0 with the port still open
+        assert_eq!(filter.update(true, false), LineAction::Apply);
+        assert_eq!(filter.update(false, false), LineAction::Apply);
+
+        // The hard reset once it's done goes straight through, as does closing the port after.
+        assert_eq!(filter.update(false, true), LineAction::Apply);
+        assert!(!filter.tick(CLOSE_DEBOUNCE_MS));
+        assert_eq!(filter.update(false, false), LineAction::Apply);
+    }
+
+    #[test]
+    fn never_opened() {
+        let mut filter = PortCloseFilter::default();
+        assert_eq!(filter.update(false, true), LineAction::Apply);
+        assert_eq!(filter.update(false, false), LineAction::Apply);
+    }
+
+    #[test]
+    fn combined_lines() {
+        let (open, reset, closed) = ((true, true), (false, true), (false, false));
+        let mut arbiter = ControlLineArbiter::default();
+        // A terminal left open on the serial port holds DTR, so esptool on WebUSB can't reset.
+        assert_eq!(arbiter.update(ControlLinePolicy::Either, open, reset), open);
+
+        for &(policy, lines) in &[
+            (ControlLinePolicy::LastChanged, reset),
+            (ControlLinePolicy::WebUsbFirst, reset),
+            (ControlLinePolicy::SerialFirst, open),
+        ] {
+            let mut arbiter = ControlLineArbiter::default();
+            arbiter.update(policy, open, closed);
+            assert_eq!(arbiter.update(policy, open, reset), lines);
+        }
+
+        // The serial port goes first once it's open, and the WebUSB port's lines are used again
+        // once it's closed.
+        let policy = ControlLinePolicy::SerialFirst;
+        let mut arbiter = ControlLineArbiter::default();
+        assert_eq!(arbiter.update(policy, closed, reset), reset);
+        assert_eq!(arbiter.update(policy, open, reset), open);
+        assert_eq!(arbiter.update(policy, closed, reset), reset);
+
+        let policy = ControlLinePolicy::LastChanged;
+        assert_eq!(arbiter.update(policy, open, reset), open);
+    }
+
+    #[test]
+    fn strap_modes() {
+        assert_eq!(StrapMode::from_u16(3), None);
+        let follow = StrapMode::from_u16(0).unwrap();
+        assert!(follow.pulls_low(false) && !follow.pulls_low(true));
+        assert!(StrapMode::from_u16(1).unwrap().pulls_low(true));
+        assert!(!StrapMode::from_u16(2).unwrap().pulls_low(false));
+    }
+}
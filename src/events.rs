@@ -0,0 +1,159 @@
+//! Control change events
+//!
+//! The USB classes only keep the DTR, RTS and line coding the host last set, so a change which is
+//! reversed before the main loop looks, as esptool's reset sequence does with a slow pass, would be
+//! lost. The classes queue each change as it's made instead, for the main loop to take in order.
+//!
+//! Each port has its own queue, so every event is stamped from a count they share, and the main
+//! loop takes whichever port's next event is older (see `first`). The ports' lines are then
+//! combined as they were at the time, rather than as they are by the time the loop looks.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// When an event was queued, relative to those in other queues.
+pub type Stamp = u16;
+
+/// The last stamp given out.
+static STAMP: AtomicU16 = AtomicU16::new(0);
+
+/// Gives out the next stamp.
+#[cfg(target_has_atomic = "16")]
+fn next_stamp() -> Stamp {
+    STAMP.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+}
+
+/// Gives out the next stamp. thumbv6m has no atomic read-modify-write, but events are only queued
+/// from the USB poll, which doesn't interrupt itself.
+#[cfg(not(target_has_atomic = "16"))]
+fn next_stamp() -> Stamp {
+    let stamp = STAMP.load(Ordering::Relaxed).wrapping_add(1);
+    STAMP.store(stamp, Ordering::Relaxed);
+    stamp
+}
+
+/// Which of two queues' next events (from `EventQueue::next_stamp`) was queued first: `Some(true)`
+/// for the first queue's, or `None` if both are empty. The queues are short enough that the stamps
+/// wrapping around doesn't matter.
+pub fn first(a: Option<Stamp>, b: Option<Stamp>) -> Option<bool> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some((a.wrapping_sub(b) as i16) < 0),
+        (a, b) => (a.is_some() || b.is_some()).then_some(a.is_some()),
+    }
+}
+
+/// Events queued before the oldest is dropped. esptool's reset sequence is four control line
+/// changes, and the main loop takes them on its next pass.
+pub const EVENT_QUEUE_LENGTH: usize = 8;
+
+/// A first in, first out queue of stamped events. When it's full, the oldest is dropped to make
+/// room, as the latest state matters most.
+pub struct EventQueue<E> {
+    events: [Option<(Stamp, E)>; EVENT_QUEUE_LENGTH],
+    /// Where the oldest event is.
+    head: usize,
+    len: usize,
+}
+
+impl<E: Copy> Default for EventQueue<E> {
+    fn default() -> Self {
+        EventQueue { events: [None; EVENT_QUEUE_LENGTH], head: 0, len: 0 }
+    }
+}
+
+impl<E: Copy> EventQueue<E> {
+    /// Queues an event, dropping the oldest if the queue is full.
+    pub fn push(&mut self, event: E) {
+        if self.len == EVENT_QUEUE_LENGTH {
+            self.head = (self.head + 1) % EVENT_QUEUE_LENGTH;
+            self.len -= 1;
+        }
+        self.events[(self.head + self.len) % EVENT_QUEUE_LENGTH] = Some((next_stamp(), event));
+        self.len += 1;
+    }
+
+    /// Takes the oldest event.
+    pub fn pop(&mut self) -> Option<E> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % EVENT_QUEUE_LENGTH;
+        self.len -= 1;
+        event.map(|(_, event)| event)
+    }
+
+    /// Whether there are no events waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// When the oldest event was queued.
+    pub fn next_stamp(&self) -> Option<Stamp> {
+        if self.len == 0 {
+            return None;
+        }
+        self.events[self.head].map(|(stamp, _)| stamp)
+    }
+
+    /// Drops every queued event.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut queue = EventQueue::default();
+        queue.push(1);
+        queue.push(2);
+        assert_eq!(queue.pop(), Some(1));
+        queue.push(3);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn full() {
+        let mut queue = EventQueue::default();
+        for i in 0..EVENT_QUEUE_LENGTH + 2 {
+            queue.push(i);
+        }
+        // The oldest two made way
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(core::iter::from_fn(|| queue.pop()).count(), EVENT_QUEUE_LENGTH - 1);
+
+        queue.push(7);
+        queue.clear();
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.next_stamp(), None);
+    }
+
+    #[test]
+    fn across_queues() {
+        let mut serial = EventQueue::default();
+        let mut webusb = EventQueue::default();
+        // esptool on the serial port, with a WebUSB change in the middle
+        serial.push('a');
+        serial.push('b');
+        webusb.push('c');
+        serial.push('d');
+        webusb.push('e');
+
+        let mut order = Vec::new();
+        while let Some(from_serial) = first(serial.next_stamp(), webusb.next_stamp()) {
+            let queue = if from_serial { &mut serial } else { &mut webusb };
+            order.push(queue.pop().unwrap());
+        }
+        assert_eq!(order, ['a', 'b', 'c', 'd', 'e']);
+
+        assert_eq!(first(Some(u16::MAX), Some(0)), Some(true));
+        assert_eq!(first(Some(3), Some(2)), Some(false));
+        assert_eq!(first(None, Some(2)), Some(false));
+        assert_eq!(first(Some(2), None), Some(true));
+    }
+}
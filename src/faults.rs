@@ -0,0 +1,142 @@
+//! Fault injection
+//!
+//! For testing a protocol's error handling end to end, the host can have the bridge drop,
+//! duplicate or flip a bit in a fraction of the bytes it passes on, in either direction (see
+//! `request::SET_FAULTS`). The request has to carry `request::FAULTS_KEY`, so nothing turns this on
+//! by accident.
+//!
+//! The chances are in 256ths of a byte, drawn from a xorshift generator which starts from the same
+//! seed whenever the faults are set, so a run which finds a bug can be repeated byte for byte.
+
+use crate::backlog::BACKLOG_SIZE;
+use crate::hexdump::Direction;
+use tilda_bridge_protocol::request::{FAULTS_FROM_ESP, FAULTS_LENGTH, FAULTS_TO_ESP};
+
+const SEED: u32 = 0x2545_F491;
+
+/// The host's fault settings.
+pub struct Faults {
+    directions: u8,
+    drop: u8,
+    duplicate: u8,
+    flip: u8,
+    state: u32,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            directions: 0,
+            drop: 0,
+            duplicate: 0,
+            flip: 0,
+            state: SEED,
+        }
+    }
+}
+
+impl Faults {
+    /// Takes the settings from a `SET_FAULTS` payload: the directions, then the chances of a drop,
+    /// a duplicate and a bit flip. Returns false, changing nothing, if a direction bit is unknown.
+    pub fn set(&mut self, settings: [u8; FAULTS_LENGTH]) -> bool {
+        let [directions, drop, duplicate, flip] = settings;
+        if directions & !(FAULTS_TO_ESP | FAULTS_FROM_ESP) != 0 {
+            return false;
+        }
+        *self = Faults {
+            directions,
+            drop,
+            duplicate,
+            flip,
+            state: SEED,
+        };
+        true
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    fn roll(&mut self, chance: u8) -> bool {
+        chance != 0 && (self.next() as u8) < chance
+    }
+
+    /// Injects faults into the first `count` bytes of `buf` going the given way, and returns how
+    /// many there are now. A duplicate which doesn't fit in `buf` is left out.
+    pub fn inject(&mut self, direction: Direction, buf: &mut [u8], count: usize) -> usize {
+        let bit = match direction {
+            Direction::ToEsp => FAULTS_TO_ESP,
+            Direction::FromEsp => FAULTS_FROM_ESP,
+        };
+        if self.directions & bit == 0 {
+            return count;
+        }
+
+        let mut input = [0; BACKLOG_SIZE];
+        let count = count.min(BACKLOG_SIZE);
+        input[..count].copy_from_slice(&buf[..count]);
+        let mut len = 0;
+        for &byte in &input[..count] {
+            if self.roll(self.drop) {
+                continue;
+            }
+            let byte = if self.roll(self.flip) { byte ^ 1 << (self.next() % 8) } else { byte };
+            let copies = if self.roll(self.duplicate) { 2 } else { 1 };
+            for _ in 0..copies {
+                if len < buf.len() {
+                    buf[len] = byte;
+                    len += 1;
+                }
+            }
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inject(faults: &mut Faults, direction: Direction, data: &[u8]) -> Vec<u8> {
+        let mut buf = [0; BACKLOG_SIZE];
+        buf[..data.len()].copy_from_slice(data);
+        let count = faults.inject(direction, &mut buf, data.len());
+        buf[..count].to_vec()
+    }
+
+    #[test]
+    fn faults() {
+        let data = [0x55; 32];
+        let mut faults = Faults::default();
+        assert_eq!(inject(&mut faults, Direction::ToEsp, &data), data);
+        assert!(!faults.set([4, 0, 0, 0]));
+
+        // Every byte dropped, only towards the ESP32
+        assert!(faults.set([FAULTS_TO_ESP, 255, 0, 0]));
+        assert!(inject(&mut faults, Direction::ToEsp, &data).len() < 4);
+        assert_eq!(inject(&mut faults, Direction::FromEsp, &data), data);
+
+        // Every byte doubled, with the duplicates past the end of the buffer left out
+        assert!(faults.set([FAULTS_FROM_ESP, 0, 255, 0]));
+        let doubled = inject(&mut faults, Direction::FromEsp, &[1, 2, 3]);
+        assert_eq!(doubled.len(), 6);
+        assert_eq!(doubled.iter().filter(|&&b| b == 2).count(), 2);
+        assert_eq!(inject(&mut faults, Direction::FromEsp, &[0; BACKLOG_SIZE]).len(), BACKLOG_SIZE);
+
+        // One bit flipped in each flipped byte
+        assert!(faults.set([FAULTS_TO_ESP | FAULTS_FROM_ESP, 0, 0, 128]));
+        let flipped = inject(&mut faults, Direction::ToEsp, &data);
+        assert!(flipped.iter().all(|b| (b ^ 0x55).count_ones() <= 1));
+        assert!(flipped.iter().any(|&b| b != 0x55));
+        assert!(flipped.contains(&0x55));
+
+        // The same again from the same settings
+        assert!(faults.set([FAULTS_TO_ESP | FAULTS_FROM_ESP, 0, 0, 128]));
+        assert_eq!(inject(&mut faults, Direction::ToEsp, &data), flipped);
+    }
+}
@@ -0,0 +1,62 @@
+//! Fixture pin walk
+//!
+//! With the `fixture` feature, `FIXTURE_TEST` walks the bridge's GPIOs for a bed-of-nails test
+//! fixture, so it can check the bridge section's connections without programming anything else.
+//! The board sets the pins up for each sample and reads them back (see `board::walk_pins`); this
+//! decides what to sample and what it means.
+
+pub use tilda_bridge_protocol::fixture::{pins, FixtureMap, PINS};
+
+/// Walks the pins, calling `sample` with whether to pull them all up or down and the pin, if any,
+/// to drive the other way. It returns the levels of all the pins, numbered as in `PINS`.
+pub fn walk(mut sample: impl FnMut(bool, Option<u32>) -> u32) -> FixtureMap {
+    let mut map = FixtureMap {
+        stuck_high: sample(false, None) & PINS,
+        stuck_low: !sample(true, None) & PINS,
+        ..FixtureMap::default()
+    };
+    for (link, pin) in map.links.iter_mut().zip(pins()) {
+        let high = sample(false, Some(pin));
+        let low = !sample(true, Some(pin));
+        *link = high & low & PINS;
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tilda_bridge_protocol::fixture::PIN_COUNT;
+
+    /// PA1 and PB4 wired together, PA3 shorted to ground and PB1 not driving.
+    fn board(pull_up: bool, driven: Option<u32>) -> u32 {
+        let mut levels = if pull_up { PINS } else { 0 };
+        if let Some(pin) = driven.filter(|&pin| pin != 17) {
+            let wired = if pin == 1 || pin == 20 { 1 << 1 | 1 << 20 } else { 1 << pin };
+            levels = if pull_up { levels & !wired } else { levels | wired };
+        }
+        levels & !(1 << 3)
+    }
+
+    fn link(map: &FixtureMap, pin: u32) -> u32 {
+        map.links[pins().position(|p| p == pin).unwrap()]
+    }
+
+    #[test]
+    fn connectivity() {
+        let map = walk(board);
+        assert_eq!(map.stuck_high, 0);
+        assert_eq!(map.stuck_low, 1 << 3);
+        assert_eq!(link(&map, 0), 1 << 0);
+        assert_eq!(link(&map, 1), 1 << 1 | 1 << 20);
+        assert_eq!(link(&map, 20), 1 << 1 | 1 << 20);
+        assert_eq!(link(&map, 3), 0);
+        assert_eq!(link(&map, 17), 0);
+        assert_eq!(map.links.len(), PIN_COUNT);
+
+        // Nothing outside the walked pins is reported, whatever the board reads back
+        let map = walk(|pull_up, driven| board(pull_up, driven) | 1 << 11);
+        assert_eq!(map.stuck_high, 0);
+        assert_eq!(link(&map, 0), 1 << 0);
+    }
+}
@@ -0,0 +1,60 @@
+//! Flash programming
+//!
+//! Shared by the firmware (for the configuration page) and the bootloader.
+
+use stm32f0xx_hal::stm32;
+use tilda_stm::{config::PAGE_SIZE, dfu};
+
+pub const KEY1: u32 = 0x4567_0123;
+pub const KEY2: u32 = 0xCDEF_89AB;
+
+/// The STM32's own flash.
+///
+/// The CPU stalls while the flash is busy, so erasing a page takes a few tens of milliseconds
+/// during which nothing else runs.
+pub struct Flash;
+
+impl dfu::Flash for Flash {
+    fn erase_page(&mut self, address: usize) {
+        debug_assert_eq!(address % PAGE_SIZE, 0);
+        let flash = unlock();
+
+        flash.cr.modify(|_, w| w.per().set_bit());
+        flash.ar.write(|w| w.far().bits(address as u32));
+        flash.cr.modify(|_, w| w.strt().set_bit());
+        wait_idle();
+        flash.cr.modify(|_, w| w.per().clear_bit().lock().set_bit());
+    }
+
+    fn program(&mut self, address: usize, data: &[u8]) {
+        let flash = unlock();
+
+        // Flash is programmed a half-word at a time; pad an odd length with the erased value.
+        flash.cr.modify(|_, w| w.pg().set_bit());
+        for (i, chunk) in data.chunks(2).enumerate() {
+            let half_word = u16::from_le_bytes([chunk[0], *chunk.get(1).unwrap_or(&0xFF)]);
+            unsafe { core::ptr::write_volatile((address + i * 2) as *mut u16, half_word) };
+            wait_idle();
+        }
+        flash.cr.modify(|_, w| w.pg().clear_bit().lock().set_bit());
+    }
+}
+
+/// Unlocks the flash controller for programming.
+pub fn unlock() -> &'static stm32::flash::RegisterBlock {
+    let flash = unsafe { &*stm32::FLASH::ptr() };
+
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| w.fkeyr().bits(KEY1));
+        flash.keyr.write(|w| w.fkeyr().bits(KEY2));
+    }
+    wait_idle();
+    flash
+}
+
+/// Waits for the flash controller to finish, and clears its status flags.
+pub fn wait_idle() {
+    let flash = unsafe { &*stm32::FLASH::ptr() };
+    while flash.sr.read().bsy().bit_is_set() {}
+    flash.sr.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+}
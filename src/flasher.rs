@@ -0,0 +1,238 @@
+//! ESP32 ROM loader commands
+//!
+//! Web flashing has the browser speak esptool's serial protocol, and every command and response
+//! waits on USB and the browser's timers, which is where most failed flashes come from. With the
+//! `flasher` feature the bridge does the timing-sensitive part instead: the host hands it one ROM
+//! command packet at a time, and the bridge SLIP frames it, waits for the response, and sends it
+//! again if the ROM doesn't answer in time (see `tilda_bridge_protocol::flasher`). Building the
+//! packets, and checking the MD5 at the end, is left to the host, as there isn't room for it here.
+
+use embedded_hal::serial;
+use tilda_bridge_protocol::flasher::{
+    ATTEMPTS, BUSY, DONE, IDLE, MAX_COMMAND_LENGTH, RESPONSE_LENGTH, TIMEOUT, TIMEOUT_UNIT_MS,
+};
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// Sends the host's ROM commands, and collects the responses.
+pub struct Flasher {
+    /// Whether the host has taken over the UART.
+    active: bool,
+    /// The command packet, unframed.
+    packet: [u8; MAX_COMMAND_LENGTH],
+    len: usize,
+    timeout_ms: u32,
+    /// When the command was last sent, or `None` if it's yet to be.
+    sent_ms: Option<u32>,
+    attempts: u8,
+    /// The state, then the response packet. While the command is `BUSY`, packets from the ROM are
+    /// received into it.
+    response: [u8; RESPONSE_LENGTH],
+    rx_len: usize,
+    escape: bool,
+}
+
+impl Default for Flasher {
+    fn default() -> Self {
+        Flasher {
+            active: false,
+            packet: [0; MAX_COMMAND_LENGTH],
+            len: 0,
+            timeout_ms: 0,
+            sent_ms: None,
+            attempts: 0,
+            response: [IDLE; RESPONSE_LENGTH],
+            rx_len: 0,
+            escape: false,
+        }
+    }
+}
+
+impl Flasher {
+    /// Whether the host has taken over the UART.
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Takes over the UART for the host, or gives it back.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+        self.response[0] = IDLE;
+    }
+
+    /// Takes a `ROM_COMMAND` from the host. Returns false if the UART hasn't been taken over, the
+    /// last command hasn't been answered, or the packet is too long.
+    #[inline(never)]
+    pub fn load(&mut self, timeout: u16, packet: &[u8]) -> bool {
+        if !self.active || self.response[0] == BUSY || packet.len() > MAX_COMMAND_LENGTH {
+            return false;
+        }
+        self.packet[..packet.len()].copy_from_slice(packet);
+        self.len = packet.len();
+        self.timeout_ms = timeout as u32 * TIMEOUT_UNIT_MS;
+        self.sent_ms = None;
+        self.attempts = 0;
+        self.response[0] = BUSY;
+        true
+    }
+
+    /// The `GET_ROM_RESPONSE` response.
+    pub fn response(&self) -> &[u8] {
+        &self.response
+    }
+
+    /// Sends the command, or sends it again if the ROM hasn't answered in time.
+    #[inline(never)]
+    pub fn poll<W: serial::Write<u8>>(&mut self, now_ms: u32, uart: &mut W) {
+        if self.response[0] != BUSY {
+            return;
+        }
+        match self.sent_ms {
+            Some(sent_ms) if now_ms.wrapping_sub(sent_ms) < self.timeout_ms => return,
+            Some(_) if self.attempts == ATTEMPTS => {
+                self.response[0] = TIMEOUT;
+                return;
+            }
+            _ => {}
+        }
+        self.sent_ms = Some(now_ms);
+        self.attempts += 1;
+
+        let mut write = |byte| {
+            let _ = nb::block!(uart.write(byte));
+        };
+        write(SLIP_END);
+        for &byte in &self.packet[..self.len] {
+            match byte {
+                SLIP_END => {
+                    write(SLIP_ESC);
+                    write(SLIP_ESC_END);
+                }
+                SLIP_ESC => {
+                    write(SLIP_ESC);
+                    write(SLIP_ESC_ESC);
+                }
+                _ => write(byte),
+            }
+        }
+        write(SLIP_END);
+    }
+
+    /// Feeds a byte from the ESP32 to the flasher, looking for the response to the command.
+    #[inline(never)]
+    pub fn feed(&mut self, byte: u8) {
+        if self.response[0] != BUSY {
+            return;
+        }
+        let byte = match byte {
+            SLIP_END => {
+                if (8..RESPONSE_LENGTH).contains(&self.rx_len)
+                    && self.response[1..3] == [1, self.packet[1]]
+                {
+                    self.response[0] = DONE;
+                }
+                self.rx_len = 0;
+                return;
+            }
+            SLIP_ESC => {
+                self.escape = true;
+                return;
+            }
+            SLIP_ESC_END if self.escape => SLIP_END,
+            SLIP_ESC_ESC if self.escape => SLIP_ESC,
+            _ => byte,
+        };
+        self.escape = false;
+        if let Some(slot) = self.response.get_mut(1 + self.rx_len) {
+            *slot = byte;
+        }
+        self.rx_len = self.rx_len.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Uart {
+        sent: Vec<u8>,
+    }
+
+    impl serial::Write<u8> for Uart {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            self.sent.push(byte);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn feed(flasher: &mut Flasher, rx: &[u8]) {
+        for &byte in rx {
+            flasher.feed(byte);
+        }
+    }
+
+    const SYNC: [u8; 12] = [0, 0x08, 4, 0, 0, 0, 0, 0, 0x07, 0x07, 0x12, 0x20];
+
+    fn active() -> Flasher {
+        let mut flasher = Flasher::default();
+        assert!(!flasher.load(10, &SYNC));
+        flasher.set_active(true);
+        flasher
+    }
+
+    #[test]
+    fn framing() {
+        let mut flasher = active();
+        let mut uart = Uart::default();
+        let packet = [0, 0x03, 4, 0, 0xEF, 0, 0, 0, 0xC0, 0xDB, 0x01, 0x02];
+        assert!(!flasher.load(300, &[0; MAX_COMMAND_LENGTH + 1]));
+        assert!(flasher.load(300, &packet));
+        assert!(!flasher.load(300, &packet));
+        assert_eq!(flasher.response()[0], BUSY);
+
+        flasher.poll(0, &mut uart);
+        let mut frame = vec![0xC0];
+        frame.extend_from_slice(&packet[..8]);
+        frame.extend_from_slice(&[0xDB, 0xDC, 0xDB, 0xDD, 0x01, 0x02, 0xC0]);
+        assert_eq!(uart.sent, frame);
+        // Nothing more until it times out
+        flasher.poll(2999, &mut uart);
+        assert_eq!(uart.sent.len(), frame.len());
+
+        // Our own echo, a response to something else, and then the one we want
+        feed(&mut flasher, &frame);
+        feed(&mut flasher, b"\xC0\x01\x08\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\xC0");
+        assert_eq!(flasher.response()[0], BUSY);
+        feed(&mut flasher, b"\xC0\x01\x03\x04\x00\x12\x34\x00\x00\x00\x00\xDB\xDC\x00\xC0");
+        let response = b"\x02\x01\x03\x04\x00\x12\x34\x00\x00\x00\x00\xC0\x00";
+        assert_eq!(flasher.response()[..response.len()], response[..]);
+    }
+
+    #[test]
+    fn retries() {
+        let mut flasher = active();
+        let mut uart = Uart::default();
+        assert!(flasher.load(10, &SYNC));
+        for attempt in 0..ATTEMPTS as u32 {
+            flasher.poll(attempt * 100, &mut uart);
+        }
+        assert_eq!(uart.sent.len(), 14 * ATTEMPTS as usize);
+        assert_eq!(flasher.response()[0], BUSY);
+        flasher.poll(ATTEMPTS as u32 * 100, &mut uart);
+        assert_eq!(flasher.response()[0], TIMEOUT);
+
+        flasher.set_active(false);
+        assert!(!flasher.active());
+        assert_eq!(flasher.response()[0], IDLE);
+    }
+}
@@ -0,0 +1,131 @@
+//! Hex dump of the serial bridge's traffic
+//!
+//! In hex dump mode (see `request::SET_MONITOR`) the WebUSB port gets a rendering of everything
+//! passing through the CDC serial port, for debugging esptool or MicroPython protocols without a
+//! logic analyser. Each line has up to 16 bytes going one way:
+//!
+//! `< 0d 0a 3e 3e 3e                                  |..>>>|`
+//!
+//! `>` is the host to the ESP32, and `<` the ESP32 to the host.
+
+/// Bytes per line.
+pub const LINE_BYTES: usize = 16;
+
+/// Longest rendered line: direction, hex, ASCII and CR LF.
+pub const LINE_LENGTH: usize = 2 + LINE_BYTES * 3 + 1 + LINE_BYTES + 3;
+
+/// Hex digits, by value.
+pub const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// Which way traffic is going.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// From the host to the ESP32.
+    ToEsp,
+
+    /// From the ESP32 to the host.
+    FromEsp,
+}
+
+/// Collects traffic into lines.
+pub struct HexDump {
+    buf: [u8; LINE_BYTES],
+    len: usize,
+    direction: Direction,
+    /// Whether anything has been added since the last `tick`.
+    fresh: bool,
+}
+
+impl Default for HexDump {
+    fn default() -> Self {
+        HexDump {
+            buf: [0; LINE_BYTES],
+            len: 0,
+            direction: Direction::ToEsp,
+            fresh: false,
+        }
+    }
+}
+
+impl HexDump {
+    /// Adds traffic, passing each line it completes to `line`.
+    #[inline(never)]
+    pub fn push(&mut self, direction: Direction, data: &[u8], mut line: impl FnMut(&[u8])) {
+        for &byte in data {
+            if self.len == LINE_BYTES || (self.len > 0 && direction != self.direction) {
+                let mut out = [b' '; LINE_LENGTH];
+                let len = self.render(&mut out);
+                line(&out[..len]);
+            }
+            self.direction = direction;
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        self.fresh = true;
+    }
+
+    /// Called every millisecond. Passes a part-filled line to `line` once the traffic pauses.
+    pub fn tick(&mut self, mut line: impl FnMut(&[u8])) {
+        if !core::mem::replace(&mut self.fresh, false) && self.len > 0 {
+            let mut out = [b' '; LINE_LENGTH];
+            let len = self.render(&mut out);
+            line(&out[..len]);
+        }
+    }
+
+    /// Renders the line so far into `out`, which starts out blank, and returns its length.
+    fn render(&mut self, out: &mut [u8; LINE_LENGTH]) -> usize {
+        out[0] = match self.direction {
+            Direction::ToEsp => b'>',
+            Direction::FromEsp => b'<',
+        };
+        let ascii = 2 + LINE_BYTES * 3;
+        out[ascii] = b'|';
+        for (i, &byte) in self.buf[..self.len].iter().enumerate() {
+            out[2 + i * 3] = HEX[(byte >> 4) as usize];
+            out[3 + i * 3] = HEX[(byte & 0xF) as usize];
+            out[ascii + 1 + i] = if (0x20..0x7F).contains(&byte) { byte } else { b'.' };
+        }
+        let end = ascii + 1 + self.len;
+        out[end..end + 3].copy_from_slice(b"|\r\n");
+        self.len = 0;
+        end + 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(dump: &mut HexDump, direction: Direction, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        dump.push(direction, data, |line| lines.push(String::from_utf8(line.to_vec()).unwrap()));
+        lines
+    }
+
+    #[test]
+    fn full_lines() {
+        let mut dump = HexDump::default();
+        let sync = b"\xc0\x00\x08\x24\x00\x00\x00\x00\x00\x07\x07\x12\x20UUU";
+        assert!(lines(&mut dump, Direction::ToEsp, sync).is_empty());
+        let out = lines(&mut dump, Direction::ToEsp, b"U");
+        assert_eq!(
+            out,
+            ["> c0 00 08 24 00 00 00 00 00 07 07 12 20 55 55 55 |...$........ UUU|\r\n"]
+        );
+    }
+
+    #[test]
+    fn direction_change_and_pause() {
+        let mut dump = HexDump::default();
+        lines(&mut dump, Direction::ToEsp, b"\r\n");
+        let out = lines(&mut dump, Direction::FromEsp, b">>>");
+        assert_eq!(out, [format!("> 0d 0a{:43}|..|\r\n", "")]);
+
+        let mut out = Vec::new();
+        dump.tick(|line| out.push(line.to_vec()));
+        assert!(out.is_empty());
+        dump.tick(|line| out.push(line.to_vec()));
+        assert_eq!(out, [format!("< 3e 3e 3e{:40}|>>>|\r\n", "").into_bytes()]);
+    }
+}
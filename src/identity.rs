@@ -0,0 +1,72 @@
+//! Badge identity challenge-response
+//!
+//! The host sends a random challenge, and the badge answers with its 96-bit unique ID and a
+//! SipHash-2-4 MAC over the challenge and the ID. The key is built into the firmware (see
+//! `build.rs`) and shared with the EMF web services, which can then tell a genuine badge from
+//! something replaying another badge's ID. SipHash is small and fast on a Cortex-M0, and this only
+//! needs to keep honest people honest - anyone with a debugger can read the key back out.
+
+pub use tilda_bridge_protocol::request::{
+    CHALLENGE_LENGTH, IDENTITY_LENGTH as RESPONSE_LENGTH, UID_LENGTH,
+};
+pub use tilda_bridge_protocol::siphash::siphash;
+use tilda_bridge_protocol::unlock::{self, NONCE_LENGTH, RESPONSE_LENGTH as UNLOCK_LENGTH};
+
+/// The badge's identity.
+#[derive(Copy, Clone)]
+pub struct Identity {
+    uid: [u8; UID_LENGTH],
+    key: [u8; 16],
+}
+
+impl Identity {
+    pub fn new(uid: [u8; UID_LENGTH], key: [u8; 16]) -> Self {
+        Identity { uid, key }
+    }
+
+    /// Answers a challenge from the host.
+    pub fn respond(&self, challenge: &[u8; CHALLENGE_LENGTH]) -> [u8; RESPONSE_LENGTH] {
+        let mut message = [0u8; CHALLENGE_LENGTH + UID_LENGTH];
+        message[..CHALLENGE_LENGTH].copy_from_slice(challenge);
+        message[CHALLENGE_LENGTH..].copy_from_slice(&self.uid);
+
+        let mut response = [0u8; RESPONSE_LENGTH];
+        response[..UID_LENGTH].copy_from_slice(&self.uid);
+        response[UID_LENGTH..].copy_from_slice(&self.mac(&message).to_le_bytes());
+        response
+    }
+
+    /// The SipHash-2-4 MAC of a message under the identity key.
+    pub fn mac(&self, message: &[u8]) -> u64 {
+        siphash(&self.key, message)
+    }
+
+    /// The response which unlocks the guarded requests for this boot's nonce (see `unlock`).
+    pub fn unlock_response(&self, nonce: &[u8; NONCE_LENGTH]) -> [u8; UNLOCK_LENGTH] {
+        unlock::response(&self.key, &self.uid, nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    #[test]
+    fn response() {
+        let uid = [0xAA; UID_LENGTH];
+        let identity = Identity::new(uid, KEY);
+        let challenge = [0x55; CHALLENGE_LENGTH];
+
+        let response = identity.respond(&challenge);
+        assert_eq!(&response[..UID_LENGTH], &uid);
+
+        let mut message = [0x55; CHALLENGE_LENGTH + UID_LENGTH];
+        message[CHALLENGE_LENGTH..].copy_from_slice(&uid);
+        assert_eq!(&response[UID_LENGTH..], &siphash(&KEY, &message).to_le_bytes());
+
+        // A different challenge gets a different answer.
+        assert_ne!(identity.respond(&[0; CHALLENGE_LENGTH]), response);
+    }
+}
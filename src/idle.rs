@@ -0,0 +1,64 @@
+//! Inactivity tracking
+//!
+//! The firmware drops into a low power mode once there's been no USB or UART traffic for a while.
+
+/// Counts the time since the last USB or UART traffic.
+pub struct IdleTimer {
+    timeout_ms: u32,
+    idle_ms: u32,
+}
+
+impl IdleTimer {
+    /// Creates a timer which goes idle after `timeout_ms`. A timeout of 0 disables it.
+    pub fn new(timeout_ms: u32) -> Self {
+        IdleTimer {
+            timeout_ms,
+            idle_ms: 0,
+        }
+    }
+
+    /// Changes the timeout. A timeout of 0 disables the timer.
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Records some traffic, restarting the timer.
+    pub fn activity(&mut self) {
+        self.idle_ms = 0;
+    }
+
+    /// Advances the timer.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.idle_ms = self.idle_ms.saturating_add(elapsed_ms);
+    }
+
+    /// Whether the timeout has passed without any traffic.
+    pub fn is_idle(&self) -> bool {
+        self.timeout_ms != 0 && self.idle_ms >= self.timeout_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout() {
+        let mut timer = IdleTimer::new(1000);
+        timer.tick(900);
+        assert!(!timer.is_idle());
+        timer.tick(100);
+        assert!(timer.is_idle());
+
+        timer.activity();
+        assert!(!timer.is_idle());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut timer = IdleTimer::new(0);
+        timer.tick(u32::MAX);
+        timer.tick(1);
+        assert!(!timer.is_idle());
+    }
+}
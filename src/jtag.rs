@@ -0,0 +1,160 @@
+//! JTAG bit-banging
+//!
+//! Lets OpenOCD debug the ESP32 over the same USB connection, on boards which wire its JTAG pins to
+//! the STM32 (see the `jtag` feature). The host sends batches of pin states with `JTAG_SHIFT`, and
+//! reads back what TDO did with `GET_JTAG_TDO`; `tilda-bridge jtag` turns that into OpenOCD's
+//! `remote_bitbang` protocol. The batch is clocked out from the main loop, as the pins belong to it.
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use tilda_bridge_protocol::jtag::{tdo_length, MAX_BATCH_LENGTH, TCK, TDI, TMS};
+
+/// The ESP32's JTAG pins. Each has its own type so the pins needn't be downgraded, which would make
+/// every edge a dynamic call.
+pub struct JtagPins<TCK, TMS, TDI, TDO> {
+    pub tck: TCK,
+    pub tms: TMS,
+    pub tdi: TDI,
+    pub tdo: TDO,
+}
+
+/// A batch from the host, and then its TDO samples.
+pub struct JtagBatch {
+    buf: [u8; MAX_BATCH_LENGTH],
+    len: usize,
+    pending: bool,
+}
+
+impl Default for JtagBatch {
+    fn default() -> Self {
+        JtagBatch {
+            buf: [0; MAX_BATCH_LENGTH],
+            len: 0,
+            pending: false,
+        }
+    }
+}
+
+impl JtagBatch {
+    /// Takes a batch from the host. Returns false if it's too long.
+    pub fn load(&mut self, data: &[u8]) -> bool {
+        if data.len() > MAX_BATCH_LENGTH {
+            return false;
+        }
+        self.buf[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+        self.pending = true;
+        true
+    }
+
+    /// The TDO samples from the last batch, or `None` if it hasn't been clocked out yet.
+    pub fn tdo(&self) -> Option<&[u8]> {
+        if self.pending {
+            return None;
+        }
+        Some(&self.buf[..tdo_length(self.len)])
+    }
+
+    /// Clocks out a waiting batch, replacing it with the TDO samples.
+    #[inline(never)]
+    pub fn shift<TCK, TMS, TDI, TDO>(&mut self, pins: &mut JtagPins<TCK, TMS, TDI, TDO>)
+    where
+        TCK: OutputPin,
+        TMS: OutputPin,
+        TDI: OutputPin,
+        TDO: InputPin,
+    {
+        if !self.pending {
+            return;
+        }
+
+        let mut tdo = [0u8; tdo_length(MAX_BATCH_LENGTH)];
+        for (i, &state) in self.buf[..self.len].iter().enumerate() {
+            // TCK last, so TMS and TDI are set up before the rising edge.
+            let _ = set(&mut pins.tms, state & TMS != 0);
+            let _ = set(&mut pins.tdi, state & TDI != 0);
+            let _ = set(&mut pins.tck, state & TCK != 0);
+            if pins.tdo.is_high().unwrap_or(false) {
+                tdo[i / 8] |= 1 << (i % 8);
+            }
+        }
+        self.buf[..tdo.len()].copy_from_slice(&tdo);
+        self.pending = false;
+    }
+}
+
+fn set<O: OutputPin>(pin: &mut O, high: bool) -> Result<(), O::Error> {
+    if high {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A pin which can be read back, standing in for both ends of a wire.
+    #[derive(Clone, Default)]
+    struct Wire(Rc<Cell<bool>>);
+
+    impl OutputPin for Wire {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.set(true);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Wire {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(!self.0.get())
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        // TDI wired straight to TDO
+        let tdi = Wire::default();
+        let mut pins = JtagPins {
+            tck: Wire::default(),
+            tms: Wire::default(),
+            tdi: tdi.clone(),
+            tdo: tdi,
+        };
+
+        let mut batch = JtagBatch::default();
+        let mut states = [0u8; 10];
+        for (i, state) in states.iter_mut().enumerate() {
+            *state = if i % 3 == 0 { TDI } else { TMS } | if i % 2 == 1 { TCK } else { 0 };
+        }
+        assert!(batch.load(&states));
+        assert_eq!(batch.tdo(), None);
+
+        batch.shift(&mut pins);
+        assert_eq!(batch.tdo(), Some(&[0b0100_1001, 0b10][..]));
+        assert!(pins.tck.0.get());
+    }
+
+    #[test]
+    fn batch_too_long() {
+        let mut batch = JtagBatch::default();
+        assert!(!batch.load(&[0; MAX_BATCH_LENGTH + 1]));
+        assert_eq!(batch.tdo(), Some(&[][..]));
+    }
+}
@@ -0,0 +1,96 @@
+//! LED dimming on battery
+//!
+//! With the `led-dim` feature the LED is dimmed while the badge runs from its battery, to the
+//! `battery_brightness` setting, and turned off altogether once the battery drops below
+//! `battery_cutoff`. The HAL has no PWM driver, and the board file can put the LED on a pin without
+//! a timer channel, so the dimming is done in software: a lit LED is only on for part of every
+//! `PERIOD_MS`, stepped from SysTick.
+
+use crate::config::{Config, FULL_BRIGHTNESS};
+
+/// Length of a dimming cycle. At one step a millisecond that's 125 Hz, too fast to see flicker.
+pub const PERIOD_MS: u8 = FULL_BRIGHTNESS;
+
+/// Switches a lit LED on and off to dim it.
+pub struct Dimmer {
+    brightness: u8,
+    phase: u8,
+}
+
+impl Default for Dimmer {
+    /// Starts out at full brightness.
+    fn default() -> Self {
+        Dimmer {
+            brightness: FULL_BRIGHTNESS,
+            phase: 0,
+        }
+    }
+}
+
+impl Dimmer {
+    /// Sets the brightness, in eighths.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    /// Advances the cycle by a millisecond. Returns whether a lit LED should now be on.
+    pub fn tick(&mut self) -> bool {
+        self.phase = (self.phase + 1) % PERIOD_MS;
+        self.on()
+    }
+
+    /// Whether a lit LED should be on at this point in the cycle.
+    pub fn on(&self) -> bool {
+        self.phase < self.brightness
+    }
+}
+
+/// The LED's brightness in eighths: full off battery, `battery_brightness` on it, and off once the
+/// battery reading is below `battery_cutoff`.
+pub fn brightness(config: &Config, on_battery: bool, battery_mv: Option<u16>) -> u8 {
+    if !on_battery {
+        return FULL_BRIGHTNESS;
+    }
+    match battery_mv {
+        Some(mv) if mv < u16::from(config.battery_cutoff) * 100 => 0,
+        _ => config.battery_brightness,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle(dimmer: &mut Dimmer) -> usize {
+        (0..PERIOD_MS).filter(|_| dimmer.tick()).count()
+    }
+
+    #[test]
+    fn duty_cycle() {
+        let mut dimmer = Dimmer::default();
+        assert!(dimmer.on());
+        assert_eq!(cycle(&mut dimmer), 8);
+
+        dimmer.set_brightness(2);
+        assert_eq!(cycle(&mut dimmer), 2);
+        dimmer.set_brightness(0);
+        assert_eq!(cycle(&mut dimmer), 0);
+    }
+
+    #[test]
+    fn battery() {
+        let mut config = Config {
+            battery_brightness: 3,
+            ..Config::default()
+        };
+        assert_eq!(brightness(&config, false, Some(3000)), FULL_BRIGHTNESS);
+        assert_eq!(brightness(&config, true, Some(3000)), 3);
+        assert_eq!(brightness(&config, true, None), 3);
+
+        config.battery_cutoff = 34;
+        assert_eq!(brightness(&config, true, Some(3400)), 3);
+        assert_eq!(brightness(&config, true, Some(3390)), 0);
+        assert_eq!(brightness(&config, true, None), 3);
+        assert_eq!(brightness(&config, false, Some(3390)), FULL_BRIGHTNESS);
+    }
+}
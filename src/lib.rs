@@ -0,0 +1,52 @@
+//! Platform-independent parts of the TiLDA MkV bridge firmware.
+//!
+//! Everything in here builds for both the STM32 and the host. The `std` feature drops the
+//! `no_std` attribute so the descriptor and protocol code can be unit-tested on a development
+//! machine:
+//!
+//! `cargo test --lib --features std --target x86_64-unknown-linux-gnu`
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod backlog;
+pub mod banner;
+pub mod baud;
+pub mod bridge;
+pub mod build_info;
+pub mod cable;
+pub mod capture;
+pub mod config;
+pub mod counters;
+pub mod crash;
+pub mod dfu;
+pub mod errorlog;
+pub mod esp;
+pub mod escape;
+pub mod events;
+pub mod faults;
+pub mod fixture;
+pub mod flasher;
+pub mod hexdump;
+pub mod identity;
+pub mod jtag;
+pub mod led;
+pub mod load;
+pub mod idle;
+#[cfg(any(test, feature = "std"))]
+pub mod mock;
+pub mod newline;
+pub mod ratelimit;
+pub mod recovery;
+pub mod selftest;
+pub mod serial;
+pub mod speed;
+pub mod stack;
+pub mod spiflash;
+pub mod stats;
+pub mod telemetry;
+pub mod throttle;
+pub mod throughput;
+pub mod timing;
+pub mod unlock;
+pub mod watch;
+pub mod webusb;
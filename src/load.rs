@@ -0,0 +1,79 @@
+//! Poll loop load
+//!
+//! With the `loop-load` feature, the main loop times each pass with SysTick and counts the ones
+//! which had something to do, USB traffic or bytes from the ESP32, as busy. The rest is spinning
+//! or asleep. The busy time over each second goes in the telemetry report, as does the busiest
+//! second since the previous report, so changes to the loop can be measured on a badge rather than
+//! guessed at.
+
+/// How long each figure is measured over.
+pub const WINDOW_MS: u32 = 1000;
+
+/// Busy time in the current window, and the figures for the last one and the busiest since the
+/// previous report, in thousandths of the time.
+#[derive(Default)]
+pub struct LoopLoad {
+    busy_us: u32,
+    window_ms: u32,
+    latest: u16,
+    highest: u16,
+}
+
+impl LoopLoad {
+    /// Counts a busy pass of the loop, from `board::tick_micros` at its start and end. A pass
+    /// which took more than a tick is short by the whole ticks.
+    pub fn record_busy(&mut self, start_us: u32, end_us: u32) {
+        let us = if end_us >= start_us { end_us - start_us } else { end_us + 1000 - start_us };
+        self.busy_us = self.busy_us.saturating_add(us);
+    }
+
+    /// Advances the window, working out the figures at its end.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.window_ms += elapsed_ms;
+        if self.window_ms >= WINDOW_MS {
+            // Microseconds a millisecond is thousandths.
+            self.latest = (self.busy_us / self.window_ms).min(1000) as u16;
+            self.highest = self.highest.max(self.latest);
+            self.busy_us = 0;
+            self.window_ms = 0;
+        }
+    }
+
+    /// Encodes the figures for the telemetry report and starts tracking the busiest second again.
+    pub fn report(&mut self) -> [u8; 4] {
+        let mut buf = [0u8; 4];
+        buf[..2].copy_from_slice(&self.latest.to_le_bytes());
+        buf[2..].copy_from_slice(&self.highest.to_le_bytes());
+        self.highest = self.latest;
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_thousandths() {
+        let mut load = LoopLoad::default();
+        assert_eq!(load.report(), [0; 4]);
+
+        // 250µs busy each millisecond, one of them across a SysTick wrap.
+        load.record_busy(900, 150);
+        load.tick(1);
+        for _ in 1..WINDOW_MS {
+            load.record_busy(100, 350);
+            load.tick(1);
+        }
+        assert_eq!(load.report(), [250, 0, 250, 0]);
+
+        // Flat out for a second, then idle.
+        for _ in 0..WINDOW_MS {
+            load.record_busy(0, 999);
+            load.tick(1);
+        }
+        load.tick(WINDOW_MS);
+        assert_eq!(load.report(), [0, 0, 0xE7, 0x03]);
+        assert_eq!(load.report(), [0; 4]);
+    }
+}
@@ -1,158 +1,925 @@
 #![no_std]
 #![no_main]
 
-mod webusb;
+// Stops a release build whose features can't fit in flash (see build.rs).
+include!(concat!(env!("OUT_DIR"), "/flash_check.rs"));
 
+// With the `error-log` feature, `board` has a panic handler which logs the panic first.
+#[cfg(not(feature = "error-log"))]
 extern crate panic_reset;
 
-use crate::webusb::WebUSB;
-use core::convert::Infallible;
+mod board;
+mod div;
+mod flash;
+mod mem;
+mod power;
+#[cfg(feature = "production-lock")]
+mod protection;
+mod sensors;
+
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
 use cortex_m_rt::entry;
-use stm32_device_signature::device_id_hex;
-use stm32_usbd::UsbBus;
+use stm32_device_signature::device_id;
+use stm32_usbd::UsbBusType;
+use embedded_hal::serial;
 use stm32f0xx_hal::{
-    gpio::{Output, Pin, PushPull},
     prelude::*,
-    serial::Serial,
+    serial::{self as uart, Event},
     stm32,
 };
+use tilda_bridge_protocol::{request, reset::ResetCause};
+#[cfg(feature = "vbus-sense")]
+use tilda_stm::cable::CableMonitor;
+#[cfg(feature = "error-log")]
+use tilda_stm::errorlog;
+#[cfg(feature = "escapes")]
+use tilda_stm::escape::{self, Command};
+#[cfg(feature = "led-dim")]
+use tilda_stm::led;
+#[cfg(feature = "unlock")]
+use tilda_stm::unlock::Unlock;
+#[cfg(feature = "sof-timing")]
+use tilda_stm::timing::Ticks;
+use tilda_stm::{
+    backlog::UartBacklog,
+    baud::BaudSwitch,
+    bridge::{Bridge, BridgePort},
+    capture::CAPTURE_LENGTH,
+    config::{self, Config, EspSuspendPolicy, PageState, PortClosePolicy},
+    counters::{self, BootCounts, COUNTERS_OFFSET},
+    events,
+    dfu::Flash as _,
+    esp::{self, ControlLineArbiter, EspBootControl, LineAction, PortCloseFilter},
+    hexdump::{self, Direction},
+    identity::{Identity, UID_LENGTH},
+    idle::IdleTimer,
+    newline,
+    recovery::{ClockAction, ClockSync, ClockWatchdog, EnumerationWatchdog},
+    selftest::{SelfTestReport, SyncDetector, ESP_SYNC_FRAME},
+    serial::{SerialEvent, SerialPort},
+    speed::{SpeedGovernor, CONSOLE_BAUD},
+    stats::{Port, UartError},
+    timing::Timeout,
+    webusb::{WebUsbBuilder, WebUsbEvent},
+};
 use usb_device::prelude::*;
-use usbd_serial::SerialPort;
 
 #[entry]
 fn main() -> ! {
-    let mut dp = stm32::Peripherals::take().unwrap();
-
-    dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
-    dp.SYSCFG.cfgr1.modify(|_, w| w.pa11_pa12_rmp().remapped());
-
-    let mut rcc = dp
-        .RCC
-        .configure()
-        .hsi48()
-        .enable_crs(dp.CRS)
-        .sysclk(48.mhz())
-        .pclk(24.mhz())
-        .freeze(&mut dp.FLASH);
-
-    let gpioa = dp.GPIOA.split(&mut rcc);
-    let gpiob = dp.GPIOB.split(&mut rcc);
-
-    let (usb_dm, usb_dp, uart_tx, uart_rx, mut esp_en, mut esp_gpio0, mut led) =
-        cortex_m::interrupt::free(|cs| {
-            (
-                gpioa.pa11,
-                gpioa.pa12,
-                gpioa.pa2.into_alternate_af1(cs),
-                gpioa.pa3.into_alternate_af1(cs),
-                gpioa.pa1.into_push_pull_output(cs).downgrade(),
-                gpioa.pa4.into_push_pull_output(cs).downgrade(),
-                gpiob.pb1.into_push_pull_output(cs).downgrade(),
-            )
-        });
-
-    let _ = esp_en.set_high();
-    let _ = esp_gpio0.set_high();
-    let _ = led.set_high();
-
-    let usb_bus = UsbBus::new(dp.USB, (usb_dm, usb_dp));
+    board::paint_stack();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+    let board::Board {
+        usb_bus,
+        mut uart,
+        esp: mut boot,
+        mut led,
+        mut usb_state,
+        mut sensors,
+        #[cfg(feature = "jtag")]
+        mut jtag,
+        #[cfg(feature = "spi-flash")]
+        mut spi_flash,
+        #[cfg(feature = "strap-pin")]
+        mut esp_strap,
+        reset_cause,
+        #[cfg(feature = "error-log")]
+        panicked,
+    } = board::Board::new(stm32::Peripherals::take().unwrap());
+
+    power::init(&mut cp.SCB);
+    // Only so received bytes can wake us from sleep - the interrupt itself is never enabled.
+    uart.listen(Event::Rxne);
 
+    // Its interface protocol is 0 rather than AT commands, so ModemManager's strict filter leaves it
+    // alone; `udev/70-tilda-bridge.rules` covers the rest.
     let mut usb_serial = SerialPort::new(&usb_bus);
-    let mut webusb = WebUSB::new(&usb_bus);
+    let mut webusb = WebUsbBuilder::new(&usb_bus).vendor_requests(Bridge::default()).build();
+    // Out of the way of the rest of the stack frame (see `capture`).
+    static mut CAPTURE: [u8; CAPTURE_LENGTH] = [0; CAPTURE_LENGTH];
+    // Safe as nothing else uses the buffer, and this is only run once.
+    webusb.set_capture_buffer(unsafe { &mut *core::ptr::addr_of_mut!(CAPTURE) });
+    webusb.set_config(config::load(config_page()));
+    webusb.set_identity(Identity::new(*device_id(), IDENTITY_KEY));
+    webusb.set_reset_cause(reset_cause);
+    webusb.set_tick_clock(board::tick_micros);
+    let boot_counts = record_boot(reset_cause);
+    webusb.set_boot_counts(boot_counts);
+    #[cfg(feature = "unlock")]
+    webusb.set_unlock(Unlock::new(boot_counts.boots));
+    #[cfg(feature = "unlock")]
+    webusb.set_entropy(board::entropy);
+    #[cfg(feature = "esp-counters")]
+    webusb.set_esp_counts(counters::EspCounts::load(config_page()));
+    #[cfg(feature = "error-log")]
+    {
+        webusb.set_config_page(config_page());
+        if panicked {
+            webusb.record_error(errorlog::PANIC, 0);
+        }
+    }
+
+    let mut idle = IdleTimer::new(idle_timeout_ms(webusb.config()));
 
-    let mut uart = Serial::usart2(dp.USART2, (uart_tx, uart_rx), 115_200.bps(), &mut rcc);
+    let mut syst = cp.SYST;
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(TICK_MS * board::cycles_per_ms() - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
 
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
         .manufacturer("Electromagnetic Field")
-        .product("TiLDA MkV")
-        .serial_number(device_id_hex())
+        .product(product_string(webusb.config()))
+        // Miscellaneous/interface association, for the WebUSB port's name
+        .device_class(0xEF)
+        .device_sub_class(0x02)
+        .device_protocol(0x01)
+        .serial_number(serial_number())
         .max_power(500)
         .build();
 
+    let mut backlog = UartBacklog::default();
+    let mut baud = BaudSwitch::default();
+    let mut watchdog = EnumerationWatchdog::default();
+    let mut clock = ClockWatchdog::default();
+    let mut speed = SpeedGovernor::default();
+    #[cfg(feature = "vbus-sense")]
+    let mut cable = CableMonitor::default();
+    // DTR/RTS changes are ignored for a while after the host configures the device, as some OSes
+    // wiggle them while probing a new serial port, which would otherwise reset the ESP32 every
+    // time the badge is plugged in. It ends early once the host sends any data, since by then
+    // something is really using the port.
+    let mut grace = Timeout::default();
+    let mut close_filter = PortCloseFilter::default();
+    let mut arbiter = ControlLineArbiter::default();
+    // Both ports' lines as of the last change the loop has taken.
+    let mut replayed: PortLines = Default::default();
+    // Whether the grace period has run out, and the changes made during it are to be dropped.
+    let mut expired = false;
+    let mut sample_ms = 0;
+    #[cfg(feature = "esp-counters")]
+    let mut esp_log_ms = 0;
+    let mut was_configured = false;
+    let mut was_suspended = false;
+    #[cfg(feature = "sof-timing")]
+    let mut ticks = Ticks::default();
+
     loop {
+        // Whether this pass has anything to do, for the loop load.
+        #[cfg(feature = "loop-load")]
+        let (pass_start, mut busy) = (board::tick_micros(), false);
+
         if usb_dev.poll(&mut [&mut usb_serial, &mut webusb]) {
+            #[cfg(feature = "loop-load")]
+            {
+                busy = true;
+            }
+            idle.activity();
             led.set_low().unwrap();
-            let mut buf = [0u8; 64];
-            match usb_serial.read(&mut buf) {
-                Ok(count) if count > 0 => {
-                    let mut offset = 0;
-                    while offset < count {
-                        if let Ok(()) = uart.write(buf[offset]) {
-                            offset += 1;
-                        }
+
+            // Resuming from suspend isn't a new enumeration.
+            match usb_dev.state() {
+                UsbDeviceState::Suspend => {}
+                UsbDeviceState::Configured => {
+                    if !was_configured {
+                        board::confirm_boot();
+                        // A bus reset clears DTR/RTS, but that's not the host asking for anything,
+                        // so the ESP32 carries on as it was until the lines change from here.
+                        close_filter = PortCloseFilter::default();
+                        grace.start(webusb.config().control_line_grace as u32 * 100);
                     }
+                    was_configured = true;
                 }
-                _ => {}
+                _ => was_configured = false,
             }
 
-            match webusb.read(&mut buf) {
-                Ok(count) if count > 0 => {
-                    let mut offset = 0;
-                    while offset < count {
-                        if let Ok(()) = uart.write(buf[offset]) {
-                            offset += 1;
-                        }
+            if webusb.take_self_test_request() {
+                let report = self_test(&mut uart, &mut boot, &mut led);
+                webusb.set_self_test_report(report);
+            }
+
+            #[cfg(feature = "fixture")]
+            if webusb.take_fixture_request() {
+                webusb.set_fixture_map(board::walk_pins());
+                // The walk has had EN and IO0 too, so start the ESP32 afresh.
+                boot.reset(false, esp::CHIP.reset_ms);
+            }
+
+            if webusb.take_detach_request() {
+                // Give the host time to see the request complete.
+                delay_ms(50);
+                board::reboot_to_bootloader();
+            }
+
+            if webusb.take_reattach_request() {
+                delay_ms(50);
+                board::usb_reattach();
+            }
+
+            #[cfg(feature = "jtag")]
+            webusb.shift_jtag(&mut jtag);
+
+            #[cfg(feature = "spi-flash")]
+            {
+                if let Some(taken) = webusb.take_spi_flash_change() {
+                    board::drive_flash_pins(taken);
+                    if taken {
+                        boot.power_off();
+                    } else {
+                        apply_control_lines(&mut arbiter, port_lines(&usb_serial, &webusb), &webusb, &mut boot);
                     }
                 }
-                _ => {}
-            }
-
-            // Set the ESP32 boot pins based on the RTS/DTR pins.
-            // These are inverted because the USB flags are true when asserted where as the serial
-            // lines are low when asserted.
-            let _ = set_pins(
-                !(usb_serial.dtr() || webusb.dtr()),
-                !(usb_serial.rts() || webusb.rts()),
-                &mut esp_en,
-                &mut esp_gpio0,
-            );
+                webusb.run_spi(&mut spi_flash);
+            }
+
+            #[cfg(feature = "production-lock")]
+            if !webusb.supply_low() && webusb.take_lock_request() {
+                delay_ms(50);
+                protection::lock();
+            }
+
             led.set_high().unwrap();
         }
 
-        if usb_dev.state() == UsbDeviceState::Configured {
-            // USB device is active.
-            while let Ok(byte) = uart.read() {
-                led.set_low().unwrap();
-                // Write input from UART to both USB endpoints, ignoring errors.
-                let _ = usb_serial.write(&[byte]);
-                let _ = webusb.write(&[byte]);
+        // The control lines only mean anything once the host has configured the device. Both
+        // ports' changes are taken in the order the host made them, so none is lost, each with the
+        // other port's lines as they were then. A port with none waiting is as it is now, which
+        // also covers a bus reset clearing its lines. During the grace period they wait, to be
+        // taken if the port's used, or dropped if the period runs out.
+        if !grace.active() {
+            let mut lines = port_lines(&usb_serial, &webusb);
+            if usb_serial.next_event_stamp().is_some() {
+                lines.0 = replayed.0;
             }
-            led.set_high().unwrap();
+            if webusb.next_event_stamp().is_some() {
+                lines.1 = replayed.1;
+            }
+            while let Some(from_serial) =
+                events::first(usb_serial.next_event_stamp(), webusb.next_event_stamp())
+            {
+                if from_serial {
+                    match usb_serial.poll_event() {
+                        Some(SerialEvent::ControlLines { dtr, rts }) => lines.0 = (dtr, rts),
+                        // BaudSwitch follows both ports' rates itself.
+                        _ => continue,
+                    }
+                } else {
+                    match webusb.poll_event() {
+                        Some(WebUsbEvent::ControlLines { dtr, rts }) => lines.1 = (dtr, rts),
+                        // Which port may drive the ESP32, and how the ports' lines are combined
+                        Some(WebUsbEvent::Vendor {
+                            request: request::CLAIM | request::SET_CONFIG, ..
+                        }) => {}
+                        _ => continue,
+                    }
+                }
+                if was_configured && !expired {
+                    update_control_lines(
+                        &mut close_filter,
+                        &mut arbiter,
+                        lines,
+                        &webusb,
+                        &mut boot,
+                    );
+                }
+            }
+            if core::mem::take(&mut expired) {
+                update_control_lines(&mut close_filter, &mut arbiter, lines, &webusb, &mut boot);
+            }
+            replayed = lines;
         }
-    }
-}
 
-/// Set the ESP boot control pins based on serial DTR and RTS pins.
-/// This emulates the transistor logic implemented on ESP32 dev boards to ignore DTR and RTS being
-/// asserted simultaneously.
-fn set_pins(
-    dtr: bool,
-    rts: bool,
-    esp_en: &mut Pin<Output<PushPull>>,
-    esp_gpio0: &mut Pin<Output<PushPull>>,
-) -> Result<(), Infallible> {
-    // https://github.com/espressif/esptool/wiki/ESP32-Boot-Mode-Selection#automatic-bootloader
-    // DTR RTS| EN  IO0
-    // 1   1  |  1   1
-    // 0   0  |  1   1
-    // 1   0  |  0   1
-    // 0   1  |  1   0
-
-    if !rts && !dtr {
-        esp_gpio0.set_high()?;
-        esp_en.set_high()?;
-    } else {
-        if dtr {
-            esp_gpio0.set_high()?;
+        let configured = usb_dev.state() == UsbDeviceState::Configured;
+        let rates = if configured {
+            Some((usb_serial.line_coding().data_rate(), webusb.line_coding().data_rate()))
         } else {
-            esp_gpio0.set_low()?;
+            None
+        };
+        baud.update(rates, webusb.config().line_coding);
+        // The serial port has no way to be told, and keeps what its host set.
+        if baud.take_overruled() == Some(Port::WebUsb) {
+            webusb.overrule_line_coding(Port::Serial);
         }
 
-        if rts {
-            esp_en.set_high()?;
+        // The host has the UART to itself while it talks to the ROM loader.
+        let flashing = webusb.flashing();
+        if configured {
+            // USB device is active. Only read more from USB once the UART has taken the last lot
+            // and any baud rate change has been made; until then the host is NAKed.
+            backlog.drain(&mut uart, || webusb.pass_to_esp());
+            let config = *webusb.config();
+            let serial_drives = webusb.may_drive(Port::Serial);
+            if backlog.is_empty() && !baud.is_pending() && !flashing {
+                // There's no way to get at the CDC-ACM class's endpoints, so errors there are only
+                // counted.
+                let result = backlog.fill(|buf| {
+                    let count = usb_serial.read(buf)?;
+                    if !serial_drives {
+                        // The WebUSB port has claimed the UART.
+                        return Ok(0);
+                    }
+                    #[cfg(feature = "escapes")]
+                    let count = webusb.filter_escapes(&mut buf[..count]);
+                    newline::from_host(config.serial_line, &mut buf[..count], |echo| {
+                        write_serial(&mut usb_serial, &mut webusb, echo)
+                    });
+                    webusb.dump(Direction::ToEsp, &buf[..count]);
+                    Ok(webusb.inject_faults(Direction::ToEsp, buf, count))
+                });
+                webusb.record_read(Port::Serial, &result);
+            }
+            #[cfg(feature = "escapes")]
+            run_escape(&mut usb_serial, &mut webusb, &mut boot);
+            if backlog.is_empty() && !baud.is_pending() && !flashing {
+                let result = backlog.fill(|buf| {
+                    let count = webusb.read(buf)?;
+                    newline::from_host(config.webusb_line, &mut buf[..count], |echo| {
+                        write_webusb(&mut webusb, echo)
+                    });
+                    Ok(webusb.inject_faults(Direction::ToEsp, buf, count))
+                });
+                if webusb.record_read(Port::WebUsb, &result) {
+                    webusb.recover_read_ep();
+                }
+            }
+            if !backlog.is_empty() && grace.active() {
+                // Someone's really using the port, so the changes they made during the grace
+                // period are taken on the next pass.
+                grace.cancel();
+            }
+            backlog.drain(&mut uart, || webusb.pass_to_esp());
+            webusb.set_uart_queued(backlog.len());
+        }
+
+        // However IO0 got where it is, the strapping pin goes along with it.
+        #[cfg(feature = "strap-pin")]
+        {
+            let (_, io0) = board::esp_pin_levels();
+            let _ = if webusb.strap().pulls_low(io0) {
+                esp_strap.set_low()
+            } else {
+                esp_strap.set_high()
+            };
+        }
+
+        // The UART is read even with no host, so the capture has whatever the ESP32 said before
+        // one turned up.
+        loop {
+            match uart.read() {
+                Ok(byte) => {
+                    #[cfg(feature = "loop-load")]
+                    {
+                        busy = true;
+                    }
+                    idle.activity();
+                    led.set_low().unwrap();
+                    webusb.record_uart_output(&[byte]);
+                    if configured && !flashing && webusb.pass_from_esp() {
+                        // Write input from UART to both USB ports. Each has its own queue, so one
+                        // whose host isn't reading only loses its own copy. Injected faults can
+                        // make it none or two bytes.
+                        let config = *webusb.config();
+                        let mut bytes = [byte, 0];
+                        let count = webusb.inject_faults(Direction::FromEsp, &mut bytes, 1);
+                        for byte in &bytes[..count] {
+                            let serial_data = newline::to_host(config.serial_line, byte);
+                            // Nothing while the serial port's terminal has escaped to the bridge.
+                            let serial_data =
+                                if webusb.serial_shows_esp() { serial_data } else { &[] };
+                            write_serial(&mut usb_serial, &mut webusb, serial_data);
+                            webusb.dump(Direction::FromEsp, serial_data);
+                            write_webusb(&mut webusb, newline::to_host(config.webusb_line, byte));
+                        }
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                // The HAL has already cleared the error flag, so reception carries on.
+                Err(nb::Error::Other(err)) => webusb.record_uart_error(uart_error(err)),
+            }
+        }
+        led.set_high().unwrap();
+
+        #[cfg(feature = "flasher")]
+        webusb.run_flasher(&mut uart);
+
+        #[cfg(feature = "pattern-watch")]
+        if let Some(download) = webusb.take_watch_reset() {
+            reset_esp(download, &mut boot);
+        }
+
+        // Straight after reading, so a byte arriving at the old rate isn't lost.
+        if backlog.is_empty() && uart.flush().is_ok() {
+            if let Some(rate) = baud.take() {
+                let achieved = board::set_uart_baud(rate);
+                webusb.set_uart_rate(rate, achieved);
+                // Whatever's on the WebUSB port didn't ask for it, so tell it.
+                if baud.source() == Some(Port::Serial) {
+                    webusb.overrule_line_coding(Port::Serial);
+                }
+            }
+        }
+
+        // A tick at a time, so after a long pass the ones it took are caught up on over the next.
+        if board::take_tick() {
+            // The host's frames keep time while there are any (see `timing`).
+            #[cfg(feature = "sof-timing")]
+            let elapsed_ms = {
+                let configured = usb_dev.state() == UsbDeviceState::Configured;
+                ticks.elapsed(board::usb_frame().filter(|_| configured), TICK_MS)
+            };
+            #[cfg(not(feature = "sof-timing"))]
+            let elapsed_ms = TICK_MS;
+
+            if watchdog.tick(usb_dev.state(), TICK_MS) || webusb.read_errors().is_stuck() {
+                board::usb_reattach();
+                webusb.record_reattach();
+            }
+
+            check_clock(&mut clock, usb_dev.state());
+            webusb.set_supply_low(power::supply_low());
+
+            check_speed(&mut speed, &baud, &webusb, &mut syst);
+
+            #[cfg(feature = "vbus-sense")]
+            check_cable(&mut cable, &mut sensors, usb_dev.state());
+
+            if grace.tick(elapsed_ms) {
+                // Nobody used the port, so nothing the host did with its lines counts, only where
+                // they've been left.
+                expired = true;
+            }
+            if close_filter.tick(elapsed_ms) {
+                apply_control_lines(&mut arbiter, port_lines(&usb_serial, &webusb), &webusb, &mut boot);
+            }
+            boot.tick(elapsed_ms);
+
+            idle.tick(TICK_MS);
+            webusb.tick_uptime(elapsed_ms);
+            sample_ms += TICK_MS;
+            if sample_ms >= SAMPLE_PERIOD_MS && !idle.is_idle() {
+                sample_ms = 0;
+                sample(&mut webusb, &mut sensors);
+                #[cfg(feature = "led-dim")]
+                {
+                    #[cfg(feature = "vbus-sense")]
+                    let on_battery = !cable.present();
+                    #[cfg(not(feature = "vbus-sense"))]
+                    let on_battery = true;
+                    let battery = webusb.latest_supply().battery;
+                    led.set_brightness(led::brightness(webusb.config(), on_battery, battery));
+                }
+            }
+            #[cfg(feature = "led-dim")]
+            led.tick();
+            #[cfg(feature = "esp-counters")]
+            {
+                esp_log_ms += TICK_MS;
+                if esp_log_ms >= ESP_LOG_PERIOD_MS && !webusb.supply_low() {
+                    esp_log_ms = 0;
+                    log_esp_counts(&mut webusb, boot_counts);
+                }
+            }
+            #[cfg(feature = "error-log")]
+            if !webusb.supply_low() {
+                log_errors(&mut webusb, boot_counts);
+            }
+        }
+
+        // Not while the supply is sagging, in case the page is left half written. The settings are
+        // saved once it recovers.
+        if !webusb.supply_low() && webusb.take_config_changed() {
+            write_config_page(webusb.config(), boot_counts);
+            idle.set_timeout(idle_timeout_ms(webusb.config()));
+        }
+
+        #[cfg(feature = "vbus-sense")]
+        let (suspended, may_sleep) = (cable.suspended(), cable.may_sleep());
+        #[cfg(not(feature = "vbus-sense"))]
+        let (suspended, may_sleep) = (usb_dev.state() == UsbDeviceState::Suspend, true);
+        if suspended && !was_suspended {
+            if webusb.config().esp_suspend == EspSuspendPolicy::PowerDown {
+                boot.power_off();
+            }
+        } else if !suspended
+            && was_suspended
+            && webusb.config().esp_suspend == EspSuspendPolicy::PowerDown
+        {
+            apply_control_lines(&mut arbiter, port_lines(&usb_serial, &webusb), &webusb, &mut boot);
+        }
+        was_suspended = suspended;
+
+        #[cfg(feature = "loop-load")]
+        if busy {
+            webusb.record_busy(pass_start, board::tick_micros());
+        }
+
+        // After everything deep this pass called, and before it sleeps (see `stack`).
+        board::check_stack();
+
+        // Let the ESP32 know whether anyone's listening.
+        let _ = if usb_dev.state() == UsbDeviceState::Configured {
+            usb_state.set_high()
         } else {
-            esp_en.set_low()?;
+            usb_state.set_low()
+        };
+
+        if let Some(on) = clock.led() {
+            // Off the bus, so nothing else will wake us up to retry.
+            let _ = if on { led.set_high() } else { led.set_low() };
+        } else if !may_sleep {
+            // Keep sampling VBUS until it's clear whether the cable is there.
+        } else if suspended {
+            // Nothing to do until the host wakes us up. UART data from the ESP32 is lost while
+            // we're stopped, but there's nowhere to send it anyway.
+            power::stop_until_usb_wakeup(&mut cp.SCB);
+        } else if idle.is_idle() && backlog.is_empty() {
+            // Nobody's talking to us - turn the LED off and wait until they do.
+            let _ = led.set_low();
+            power::sleep_until_traffic();
         }
     }
-    Ok(())
+}
+
+/// Key for the identity challenge-response, from `TILDA_IDENTITY_KEY` at build time.
+const IDENTITY_KEY: [u8; 16] = include!(concat!(env!("OUT_DIR"), "/identity_key.rs"));
+
+/// SysTick period.
+const TICK_MS: u32 = 1;
+
+/// How often the ADC is sampled.
+const SAMPLE_PERIOD_MS: u32 = 100;
+
+/// How often the ESP32's boots and crashes are logged in the configuration page, at most.
+#[cfg(feature = "esp-counters")]
+const ESP_LOG_PERIOD_MS: u32 = 60_000;
+
+/// How long EN is held low to reset the ESP32.
+const RESET_PULSE_MS: u32 = 10;
+
+/// The ESP32's EN and IO0 lines.
+type Boot = EspBootControl<board::OutputPin>;
+
+#[inline(never)]
+fn delay_ms(ms: u32) {
+    cortex_m::asm::delay(ms * board::cycles_per_ms());
+}
+
+/// Writes to the CDC serial port, dropping and counting whatever doesn't fit.
+fn write_serial(
+    usb_serial: &mut SerialPort<UsbBusType>,
+    webusb: &mut BridgePort<UsbBusType>,
+    data: &[u8],
+) {
+    let written = usb_serial.write(data).unwrap_or(0);
+    webusb.record_dropped(Port::Serial, data.len() - written);
+}
+
+/// Does what the terminal on the serial port has asked for with an escape (see `escape`).
+#[cfg(feature = "escapes")]
+#[inline(never)]
+fn run_escape(
+    usb_serial: &mut SerialPort<UsbBusType>,
+    webusb: &mut BridgePort<UsbBusType>,
+    boot: &mut Boot,
+) {
+    match webusb.take_escape_command() {
+        Some(Command::Reset) => reset_esp(false, boot),
+        Some(Command::Download) => reset_esp(true, boot),
+        Some(Command::Help) => write_serial(usb_serial, webusb, escape::HELP),
+        Some(Command::Stats) => {
+            let line = webusb.stats_line();
+            write_serial(usb_serial, webusb, &line);
+        }
+        None => {}
+    }
+}
+
+/// Writes the ESP32's output (or an echo) to the WebUSB port, dropping and counting whatever
+/// doesn't fit.
+fn write_webusb(webusb: &mut BridgePort<UsbBusType>, data: &[u8]) {
+    let _ = webusb.write_uart(data);
+}
+
+/// Takes the readings for the telemetry report. The stack's high-water mark is one of them, and
+/// if it has reached the guard this restarts. See `stack`.
+#[inline(never)]
+fn sample(webusb: &mut BridgePort<UsbBusType>, sensors: &mut sensors::Sensors) {
+    webusb.record_supply(sensors.read_supply());
+    webusb.record_chip(sensors.read_chip());
+    let (used, size) = board::stack_usage();
+    webusb.record_stack(used as u16, size as u16);
+}
+
+/// Keeps an eye on the USB clock.
+fn check_clock(clock: &mut ClockWatchdog, state: UsbDeviceState) {
+    // Start of frame packets only come once a host has found us, and stop in suspend.
+    let sync = board::crs_sync();
+    let sync = match state {
+        UsbDeviceState::Addressed | UsbDeviceState::Configured => sync,
+        _ => ClockSync::None,
+    };
+    match clock.tick(sync, TICK_MS) {
+        ClockAction::None => {}
+        ClockAction::Relock => board::crs_relock(),
+        ClockAction::Fail => board::usb_detach(),
+        ClockAction::Retry => {
+            board::crs_relock();
+            board::usb_reattach();
+        }
+    }
+}
+
+/// Scales the core clock to the work there is.
+fn check_speed(
+    speed: &mut SpeedGovernor,
+    baud: &BaudSwitch,
+    webusb: &BridgePort<UsbBusType>,
+    syst: &mut SYST,
+) {
+    // Holding IO0 low means the ROM loader, and so flashing, is on its way.
+    let (_, io0) = board::esp_pin_levels();
+    let busy = baud.rate() > CONSOLE_BAUD || !io0 || webusb.flashing() || webusb.throughput();
+    if let Some(full) = speed.tick(busy, TICK_MS) {
+        board::set_full_speed(full);
+        // SysTick counts core cycles.
+        syst.set_reload(TICK_MS * board::cycles_per_ms() - 1);
+    }
+}
+
+/// Follows the cable being plugged in and pulled out.
+#[cfg(feature = "vbus-sense")]
+fn check_cable(cable: &mut CableMonitor, sensors: &mut sensors::Sensors, state: UsbDeviceState) {
+    let suspended = state == UsbDeviceState::Suspend;
+    match cable.tick(sensors.read_vbus(), suspended, TICK_MS) {
+        Some(true) => board::usb_attach(),
+        // Without VBUS, the pull-up would be powering the host's D+.
+        Some(false) => board::usb_detach(),
+        None => {}
+    }
+}
+
+/// DTR and RTS on the serial port, then on the WebUSB port.
+type PortLines = ((bool, bool), (bool, bool));
+
+/// Both ports' DTR and RTS as they are now.
+#[inline(never)]
+fn port_lines(usb_serial: &SerialPort<UsbBusType>, webusb: &BridgePort<UsbBusType>) -> PortLines {
+    ((usb_serial.dtr(), usb_serial.rts()), (webusb.dtr(), webusb.rts()))
+}
+
+/// DTR and RTS, combined from both ports' `lines` as configured.
+fn control_lines(
+    arbiter: &mut ControlLineArbiter,
+    (serial, own): PortLines,
+    webusb: &BridgePort<UsbBusType>,
+) -> (bool, bool) {
+    // A port which the host hasn't given the ESP32 to has its lines treated as released.
+    let lines = |port, (dtr, rts)| if webusb.may_drive(port) { (dtr, rts) } else { (false, false) };
+    let serial = lines(Port::Serial, serial);
+    let own = lines(Port::WebUsb, own);
+    arbiter.update(webusb.config().control_lines, serial, own)
+}
+
+/// Sets the ESP32 boot pins from DTR/RTS.
+fn apply_control_lines(
+    arbiter: &mut ControlLineArbiter,
+    lines: PortLines,
+    webusb: &BridgePort<UsbBusType>,
+    boot: &mut Boot,
+) {
+    // The ESP32 stays in reset while the host has its flash.
+    if webusb.spi_flash() {
+        return;
+    }
+    let (dtr, rts) = control_lines(arbiter, lines, webusb);
+    // These are inverted because the USB flags are true when asserted where as the serial lines
+    // are low when asserted.
+    boot.set_lines(!dtr, !rts);
+}
+
+/// Passes DTR/RTS changes on to the ESP32 boot pins, with both ports' at `lines`. Only changes
+/// since the device was configured count, so the ESP32 is left alone by bus resets.
+fn update_control_lines(
+    close_filter: &mut PortCloseFilter,
+    arbiter: &mut ControlLineArbiter,
+    lines: PortLines,
+    webusb: &BridgePort<UsbBusType>,
+    boot: &mut Boot,
+) {
+    let (dtr, rts) = control_lines(arbiter, lines, webusb);
+    match close_filter.update(dtr, rts) {
+        // The ESP32 stays in reset while the host has its flash.
+        _ if webusb.spi_flash() => {}
+        LineAction::Apply => boot.set_lines(!dtr, !rts),
+        LineAction::Wait => {}
+        LineAction::Closed => port_closed(webusb.config(), boot),
+    }
+}
+
+/// Resets the ESP32 for a pattern watch or a serial port escape, into its ROM bootloader if
+/// `download`. The reset carries on as `boot` ticks, and the ESP32 then runs whatever the control
+/// lines say, until they next change.
+#[cfg(any(feature = "pattern-watch", feature = "escapes"))]
+fn reset_esp(download: bool, boot: &mut Boot) {
+    boot.reset(download, esp::CHIP.reset_ms);
+}
+
+/// Sets the ESP32 boot pins as configured for when the host closes the port.
+fn port_closed(config: &Config, boot: &mut Boot) {
+    // Bit 1 is IO0 and bit 0 EN, as in `close_strapping`; keeping it running is both high.
+    let strapping = match config.port_close {
+        PortClosePolicy::Reset => return boot.reset(false, RESET_PULSE_MS),
+        PortClosePolicy::KeepRunning => 0b11,
+        PortClosePolicy::Strapping => config.close_strapping,
+    };
+    boot.drive(strapping & 0b01 != 0, strapping & 0b10 != 0);
+}
+
+fn uart_error(err: uart::Error) -> UartError {
+    match err {
+        uart::Error::Framing => UartError::Framing,
+        uart::Error::Noise => UartError::Noise,
+        uart::Error::Parity => UartError::Parity,
+        _ => UartError::Overrun,
+    }
+}
+
+fn idle_timeout_ms(config: &Config) -> u32 {
+    config.idle_timeout as u32 * 1000
+}
+
+/// Rewrites the configuration page, with an empty boot log. The ESP32's counts are carried over
+/// into their record and the error log as `errorlog::kept` says.
+fn write_config_page(config: &Config, counts: BootCounts) {
+    #[cfg(feature = "esp-counters")]
+    let esp_counts = counters::EspCounts::load(config_page());
+    #[cfg(feature = "error-log")]
+    let (error_log, error_log_length) = {
+        let mut log = [0u8; errorlog::LOG_LENGTH];
+        let kept = errorlog::kept(config_page());
+        log[..kept.len()].copy_from_slice(kept);
+        (log, kept.len())
+    };
+    flash::Flash.erase_page(config::CONFIG_PAGE_ADDRESS);
+    flash::Flash.program(config::CONFIG_PAGE_ADDRESS, &config::to_image(config));
+    #[cfg(feature = "error-log")]
+    flash::Flash.program(
+        config::CONFIG_PAGE_ADDRESS + errorlog::ERROR_LOG_OFFSET,
+        &error_log[..error_log_length],
+    );
+    flash::Flash.program(config::CONFIG_PAGE_ADDRESS + COUNTERS_OFFSET, &counts.to_record());
+    #[cfg(feature = "esp-counters")]
+    flash::Flash.program(
+        config::CONFIG_PAGE_ADDRESS + counters::ESP_RECORD_OFFSET,
+        &esp_counts.to_record(),
+    );
+}
+
+/// Logs the ESP32's boots and crashes since last time in the configuration page, rewriting it if
+/// the log is full.
+#[cfg(feature = "esp-counters")]
+fn log_esp_counts(webusb: &mut BridgePort<UsbBusType>, boot_counts: BootCounts) {
+    while let Some(entry) = webusb.take_esp_log_entry() {
+        let offset = match counters::free_slot(config_page()) {
+            Some(offset) => offset,
+            None => {
+                write_config_page(webusb.config(), boot_counts);
+                counters::LOG_OFFSET
+            }
+        };
+        flash::Flash.program(config::CONFIG_PAGE_ADDRESS + offset, &entry);
+    }
+}
+
+/// Saves the errors noted since last time in the configuration page's error log, rewriting the
+/// page if the log is full, and clears the log if the host has asked.
+#[cfg(feature = "error-log")]
+fn log_errors(webusb: &mut BridgePort<UsbBusType>, boot_counts: BootCounts) {
+    if webusb.take_error_log_cleared() {
+        // Programming a half-word to zero is the one write the flash allows without an erase.
+        let (first, records) = errorlog::records(config_page());
+        for offset in (first..first + records.len()).step_by(errorlog::ENTRY_LENGTH) {
+            flash::Flash.program(config::CONFIG_PAGE_ADDRESS + offset, &[0, 0]);
+        }
+    }
+    while let Some(entry) = webusb.take_error_log_entry() {
+        if errorlog::free_slot(config_page()).is_none() {
+            write_config_page(webusb.config(), boot_counts);
+        }
+        if let Some(offset) = errorlog::free_slot(config_page()) {
+            flash::Flash.program(config::CONFIG_PAGE_ADDRESS + offset, &entry);
+        }
+    }
+}
+
+/// Counts this boot in the configuration page, returning the new counts.
+fn record_boot(cause: ResetCause) -> BootCounts {
+    let page = config_page();
+    let mut counts = BootCounts::load(page);
+    counts.record(cause);
+    match counters::free_slot(page) {
+        Some(offset) => {
+            flash::Flash.program(config::CONFIG_PAGE_ADDRESS + offset, &counters::log_entry(cause))
+        }
+        None => write_config_page(&config::load(page), counts),
+    }
+    counts
+}
+
+/// The configuration page, read directly from flash.
+fn config_page() -> &'static [u8] {
+    unsafe {
+        core::slice::from_raw_parts(config::CONFIG_PAGE_ADDRESS as *const u8, config::PAGE_SIZE)
+    }
+}
+
+/// The USB product string, with the nickname from the settings. Only called once, at startup.
+#[inline(never)]
+fn product_string(config: &Config) -> &'static str {
+    static mut PRODUCT: [u8; config::PRODUCT_LENGTH] = config::PRODUCT_BUFFER;
+    // Safe as nothing else uses the buffer, and this is only called once.
+    config::product_string(config, unsafe { &mut *core::ptr::addr_of_mut!(PRODUCT) })
+}
+
+/// The USB serial number: the unique ID in hex, as the bootloader gives it. Only called once, at
+/// startup.
+fn serial_number() -> &'static str {
+    static mut SERIAL: [u8; 2 * UID_LENGTH] = [0; 2 * UID_LENGTH];
+    // Safe as nothing else uses the buffer, and this is only called once.
+    let serial = unsafe { &mut *core::ptr::addr_of_mut!(SERIAL) };
+    for (hex, &byte) in serial.chunks_exact_mut(2).zip(device_id()) {
+        hex[0] = hexdump::HEX[(byte >> 4) as usize];
+        hex[1] = hexdump::HEX[(byte & 0xF) as usize];
+    }
+    // Safe as it's all hex digits.
+    unsafe { core::str::from_utf8_unchecked(serial) }
+}
+
+/// Runs the end-of-line self-test. This takes around a second, during which USB isn't serviced.
+///
+/// The ESP32 is put into download mode and sent a SYNC over the UART, then reset back into normal
+/// boot. The LED flashes three times for the operator to check.
+fn self_test<U>(uart: &mut U, boot: &mut Boot, led: &mut board::Led) -> SelfTestReport
+where
+    U: serial::Read<u8> + serial::Write<u8>,
+{
+    let mut report = SelfTestReport::default();
+
+    // Hold the ESP in reset with IO0 strapped low, then release it into the ROM loader.
+    boot.reset(true, esp::CHIP.reset_ms);
+    delay_ms(1);
+    let (en_low, gpio0_low) = board::esp_pin_levels();
+
+    wait(boot, esp::CHIP.reset_ms);
+    wait(boot, esp::CHIP.strap_hold_ms);
+    let (en_high, _) = board::esp_pin_levels();
+
+    delay_ms(1);
+    let (_, gpio0_high) = board::esp_pin_levels();
+
+    report.esp_en = !en_low && en_high;
+    report.esp_gpio0 = !gpio0_low && gpio0_high;
+
+    // Discard the ROM boot banner, then try to sync a few times like esptool does.
+    while uart.read().is_ok() {}
+    let mut detector = SyncDetector::default();
+    for _ in 0..5 {
+        for &byte in ESP_SYNC_FRAME.iter() {
+            let _ = nb::block!(uart.write(byte));
+        }
+
+        for _ in 0..100_000 {
+            if let Ok(byte) = uart.read() {
+                detector.feed(byte);
+            }
+            // About a microsecond
+            cortex_m::asm::delay(board::cycles_per_ms() >> 10);
+        }
+
+        if detector.found() {
+            break;
+        }
+    }
+    report.uart_sync = detector.found();
+
+    // Reset the ESP into normal boot.
+    boot.reset(false, esp::CHIP.reset_ms);
+    wait(boot, esp::CHIP.reset_ms);
+
+    for _ in 0..3 {
+        let _ = led.set_low();
+        delay_ms(150);
+        let _ = led.set_high();
+        delay_ms(150);
+    }
+
+    report.config = config::verify_page(config_page()) != PageState::Corrupt;
+
+    report
+}
+
+/// Waits while the ESP32's reset carries on, for the self-test.
+fn wait(boot: &mut Boot, ms: u32) {
+    delay_ms(ms);
+    boot.tick(ms);
 }
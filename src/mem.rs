@@ -0,0 +1,102 @@
+//! Smaller `memmove`, `memcpy` and `memset`
+//!
+//! compiler_builtins' `memmove`, `memcpy` and `memset` work a word at a time where they can, which
+//! takes about two kilobytes of flash between them. The biggest copies are usbd-serial moving
+//! unread data to the front of its 128 byte buffers, and the biggest fills are zeroing buffers of a
+//! similar size, so a byte loop is plenty. compiler_builtins' `__aeabi_*` functions are weak, so
+//! these are linked instead, and the big versions behind them go.
+
+/// # Safety
+///
+/// `src` and `dest` must be valid for `n` bytes. They may overlap.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memmove(dest: *mut u8, src: *const u8, n: usize) {
+    // Volatile, or LLVM turns the loops back into a memmove call.
+    if (dest as usize) < (src as usize) {
+        for i in 0..n {
+            dest.add(i).write_volatile(src.add(i).read());
+        }
+    } else {
+        for i in (0..n).rev() {
+            dest.add(i).write_volatile(src.add(i).read());
+        }
+    }
+}
+
+/// # Safety
+///
+/// `src` and `dest` must be valid for `n` bytes, and mustn't overlap.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memcpy(dest: *mut u8, src: *const u8, n: usize) {
+    // Volatile, as above.
+    for i in 0..n {
+        dest.add(i).write_volatile(src.add(i).read());
+    }
+}
+
+/// # Safety
+///
+/// As `__aeabi_memcpy`. The alignment isn't needed.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memcpy4(dest: *mut u8, src: *const u8, n: usize) {
+    __aeabi_memcpy(dest, src, n)
+}
+
+/// # Safety
+///
+/// As `__aeabi_memcpy`. The alignment isn't needed.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memcpy8(dest: *mut u8, src: *const u8, n: usize) {
+    __aeabi_memcpy(dest, src, n)
+}
+
+/// # Safety
+///
+/// `dest` must be valid for `n` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memset(dest: *mut u8, n: usize, c: i32) {
+    // Volatile, or LLVM turns the loop back into a memset call.
+    for i in 0..n {
+        dest.add(i).write_volatile(c as u8);
+    }
+}
+
+/// # Safety
+///
+/// As `__aeabi_memset`. The alignment isn't needed.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memset4(dest: *mut u8, n: usize, c: i32) {
+    __aeabi_memset(dest, n, c)
+}
+
+/// # Safety
+///
+/// As `__aeabi_memset`. The alignment isn't needed.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memset8(dest: *mut u8, n: usize, c: i32) {
+    __aeabi_memset(dest, n, c)
+}
+
+/// # Safety
+///
+/// As `__aeabi_memset`.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memclr(dest: *mut u8, n: usize) {
+    __aeabi_memset(dest, n, 0)
+}
+
+/// # Safety
+///
+/// As `__aeabi_memset`. The alignment isn't needed.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memclr4(dest: *mut u8, n: usize) {
+    __aeabi_memset(dest, n, 0)
+}
+
+/// # Safety
+///
+/// As `__aeabi_memset`. The alignment isn't needed.
+#[no_mangle]
+pub unsafe extern "C" fn __aeabi_memclr8(dest: *mut u8, n: usize) {
+    __aeabi_memset(dest, n, 0)
+}
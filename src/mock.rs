@@ -0,0 +1,410 @@
+//! Simulated USB peripheral for running the USB stack on a development machine.
+//!
+//! `MockBus` implements `UsbBus` and plays the part of both the peripheral and the host. Hold on to
+//! a clone of it after handing it to `UsbBusAllocator`, and use it to push packets at the device
+//! and collect whatever the device sends back. The control transfer helpers drive the device
+//! through a `poll` closure, which should call `UsbDevice::poll` with the classes under test:
+//!
+//! ```ignore
+//! let bus = MockBus::new();
+//! let alloc = UsbBusAllocator::new(bus.clone());
+//! let mut webusb = WebUSB::new(&alloc);
+//! let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+//! let mut poll = || { usb_dev.poll(&mut [&mut webusb]); };
+//!
+//! bus.enumerate(&mut poll).unwrap();
+//! let device = bus.get_descriptor(&mut poll, 1, 0, 18).unwrap();
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec::Vec;
+use usb_device::bus::{PollResult, UsbBus};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+const MAX_ENDPOINTS: usize = 8;
+
+/// How many times the device is polled while waiting for it to respond to a transfer.
+const MAX_POLLS: usize = 100;
+
+/// Ways a simulated control transfer can fail.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TransferError {
+    /// The device stalled the transfer.
+    Stall,
+
+    /// The device didn't finish the transfer within `MAX_POLLS` polls.
+    NoResponse,
+}
+
+pub type TransferResult<T> = core::result::Result<T, TransferError>;
+
+#[derive(Default)]
+struct Endpoint {
+    allocated: bool,
+    max_packet_size: u16,
+    /// Packets from the host waiting to be read, and whether each one is a SETUP packet.
+    out: VecDeque<(bool, Vec<u8>)>,
+    /// Packets the device has written which the host hasn't collected yet.
+    in_packets: VecDeque<Vec<u8>>,
+    in_complete: bool,
+    stalled: bool,
+}
+
+#[derive(Default)]
+struct State {
+    reset: bool,
+    suspend: Option<bool>,
+    address: u8,
+    out_eps: [Endpoint; MAX_ENDPOINTS],
+    in_eps: [Endpoint; MAX_ENDPOINTS],
+}
+
+/// A simulated USB peripheral. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct MockBus(Arc<Mutex<State>>);
+
+impl MockBus {
+    pub fn new() -> MockBus {
+        MockBus::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.lock().unwrap()
+    }
+
+    /// Signals a bus reset on the next poll.
+    pub fn bus_reset(&self) {
+        self.state().reset = true;
+    }
+
+    /// Signals a bus suspend (or resume, if `suspended` is false) on the next poll.
+    pub fn set_suspended(&self, suspended: bool) {
+        self.state().suspend = Some(suspended);
+    }
+
+    /// The address assigned to the device by SET_ADDRESS.
+    pub fn address(&self) -> u8 {
+        self.state().address
+    }
+
+    /// Queues an OUT packet from the host. Packets for unallocated endpoints are dropped.
+    pub fn push_out(&self, ep: usize, data: &[u8]) {
+        self.push(ep, false, data);
+    }
+
+    /// Queues a SETUP packet from the host. This clears any stall on endpoint 0.
+    pub fn push_setup(&self, setup: &[u8; 8]) {
+        {
+            let mut state = self.state();
+            state.out_eps[0].stalled = false;
+            state.in_eps[0].stalled = false;
+            state.in_eps[0].in_packets.clear();
+        }
+        self.push(0, true, setup);
+    }
+
+    fn push(&self, ep: usize, setup: bool, data: &[u8]) {
+        let mut state = self.state();
+        if let Some(ep) = state.out_eps.get_mut(ep).filter(|ep| ep.allocated) {
+            ep.out.push_back((setup, data.to_vec()));
+        }
+    }
+
+    /// Takes the packets the device has written to an IN endpoint.
+    pub fn take_in(&self, ep: usize) -> Vec<Vec<u8>> {
+        let mut state = self.state();
+        match state.in_eps.get_mut(ep) {
+            Some(ep) => ep.in_packets.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Takes the data the device has written to an IN endpoint, joining packets together.
+    pub fn take_in_data(&self, ep: usize) -> Vec<u8> {
+        self.take_in(ep).concat()
+    }
+
+    /// Whether the given endpoint is currently stalled.
+    pub fn stalled(&self, ep: EndpointAddress) -> bool {
+        self.is_stalled(ep)
+    }
+
+    /// Performs a control transfer with an IN data stage and returns the data.
+    pub fn control_read(
+        &self,
+        poll: &mut dyn FnMut(),
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> TransferResult<Vec<u8>> {
+        self.push_setup(&setup_packet(request_type | 0x80, request, value, index, length));
+
+        // The data stage ends with a short packet (usb-device always sends a ZLP after a final full
+        // packet, even if wLength has been reached).
+        let max_packet_size = self.state().in_eps[0].max_packet_size as usize;
+        let mut data = Vec::new();
+        let mut done = false;
+        for _ in 0..MAX_POLLS {
+            poll();
+            self.check_ep0_stall()?;
+
+            for packet in self.take_in(0) {
+                done |= packet.len() < max_packet_size;
+                data.extend_from_slice(&packet);
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        if !done {
+            return Err(TransferError::NoResponse);
+        }
+
+        // Let the device see the last packet complete before the status stage.
+        poll();
+        self.push_out(0, &[]);
+        poll();
+        self.check_ep0_stall()?;
+
+        Ok(data)
+    }
+
+    /// Performs a control transfer with an optional OUT data stage.
+    pub fn control_write(
+        &self,
+        poll: &mut dyn FnMut(),
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> TransferResult<()> {
+        self.push_setup(&setup_packet(request_type & 0x7F, request, value, index, data.len() as u16));
+        poll();
+        self.check_ep0_stall()?;
+
+        let max_packet_size = self.state().out_eps[0].max_packet_size as usize;
+        for chunk in data.chunks(max_packet_size) {
+            self.push_out(0, chunk);
+            poll();
+            self.check_ep0_stall()?;
+        }
+
+        // Wait for the status stage ZLP.
+        for _ in 0..MAX_POLLS {
+            if !self.take_in(0).is_empty() {
+                // Let the device see the status stage complete before the next transfer.
+                poll();
+                return Ok(());
+            }
+            self.check_ep0_stall()?;
+            poll();
+        }
+
+        Err(TransferError::NoResponse)
+    }
+
+    /// Standard GET_DESCRIPTOR request.
+    pub fn get_descriptor(
+        &self,
+        poll: &mut dyn FnMut(),
+        descriptor_type: u8,
+        index: u8,
+        length: u16,
+    ) -> TransferResult<Vec<u8>> {
+        let value = (descriptor_type as u16) << 8 | index as u16;
+        self.control_read(poll, 0x00, 0x06, value, 0, length)
+    }
+
+    /// Resets the device and takes it through SET_ADDRESS and SET_CONFIGURATION.
+    pub fn enumerate(&self, poll: &mut dyn FnMut()) -> TransferResult<()> {
+        self.bus_reset();
+        poll();
+        self.control_write(poll, 0x00, 0x05, 1, 0, &[])?;
+        poll();
+        self.control_write(poll, 0x00, 0x09, 1, 0, &[])
+    }
+
+    fn check_ep0_stall(&self) -> TransferResult<()> {
+        let state = self.state();
+        if state.in_eps[0].stalled || state.out_eps[0].stalled {
+            Err(TransferError::Stall)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Builds an 8 byte SETUP packet.
+pub fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let mut setup = [0u8; 8];
+    setup[0] = request_type;
+    setup[1] = request;
+    setup[2..4].copy_from_slice(&value.to_le_bytes());
+    setup[4..6].copy_from_slice(&index.to_le_bytes());
+    setup[6..8].copy_from_slice(&length.to_le_bytes());
+    setup
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let mut state = self.state();
+        let eps = match ep_dir {
+            UsbDirection::Out => &mut state.out_eps,
+            UsbDirection::In => &mut state.in_eps,
+        };
+
+        let index = match ep_addr {
+            Some(addr) => addr.index(),
+            None => (1..MAX_ENDPOINTS)
+                .find(|&i| !eps[i].allocated)
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+
+        let ep = eps.get_mut(index).ok_or(UsbError::EndpointOverflow)?;
+        if ep.allocated {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        ep.allocated = true;
+        ep.max_packet_size = max_packet_size;
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {
+        self.state().reset = true;
+    }
+
+    fn reset(&self) {
+        let state = &mut *self.state();
+        state.address = 0;
+        for ep in state.out_eps.iter_mut().chain(state.in_eps.iter_mut()) {
+            ep.out.clear();
+            ep.in_packets.clear();
+            ep.in_complete = false;
+            ep.stalled = false;
+        }
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state();
+        let ep = state
+            .in_eps
+            .get_mut(ep_addr.index())
+            .filter(|ep| ep.allocated)
+            .ok_or(UsbError::InvalidEndpoint)?;
+
+        if buf.len() > ep.max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+        if ep.in_complete {
+            // The previous packet hasn't been reported as sent yet.
+            return Err(UsbError::WouldBlock);
+        }
+
+        ep.in_packets.push_back(buf.to_vec());
+        ep.in_complete = true;
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state();
+        let ep = state
+            .out_eps
+            .get_mut(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+
+        let len = match ep.out.front() {
+            Some((_, data)) if data.len() > buf.len() => return Err(UsbError::BufferOverflow),
+            Some((_, data)) => data.len(),
+            None => return Err(UsbError::WouldBlock),
+        };
+
+        let (_, data) = ep.out.pop_front().unwrap();
+        buf[..len].copy_from_slice(&data);
+        Ok(len)
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut state = self.state();
+        let eps = match ep_addr.direction() {
+            UsbDirection::Out => &mut state.out_eps,
+            UsbDirection::In => &mut state.in_eps,
+        };
+
+        // The host can name any endpoint in CLEAR_FEATURE/SET_FEATURE
+        if let Some(ep) = eps.get_mut(ep_addr.index()) {
+            ep.stalled = stalled;
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let state = self.state();
+        let eps = match ep_addr.direction() {
+            UsbDirection::Out => &state.out_eps,
+            UsbDirection::In => &state.in_eps,
+        };
+
+        eps.get(ep_addr.index()).is_some_and(|ep| ep.stalled)
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state();
+
+        if state.reset {
+            state.reset = false;
+            return PollResult::Reset;
+        }
+
+        match state.suspend.take() {
+            Some(true) => return PollResult::Suspend,
+            Some(false) => return PollResult::Resume,
+            None => {}
+        }
+
+        let (mut ep_out, mut ep_in_complete, mut ep_setup) = (0u16, 0u16, 0u16);
+        for i in 0..MAX_ENDPOINTS {
+            match state.out_eps[i].out.front() {
+                Some((true, _)) => ep_setup |= 1 << i,
+                Some((false, _)) => ep_out |= 1 << i,
+                None => {}
+            }
+
+            if state.in_eps[i].in_complete {
+                state.in_eps[i].in_complete = false;
+                ep_in_complete |= 1 << i;
+            }
+        }
+
+        if ep_out | ep_in_complete | ep_setup == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
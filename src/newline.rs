@@ -0,0 +1,66 @@
+//! Line ending translation and local echo
+//!
+//! Browser terminals send CR for Enter and expect CR LF back, while the ESP32 console sends bare LF
+//! and some ESP32 programs only act on LF. Each port has its own `LINE_*` settings bits, so either
+//! end can be fixed up here rather than in every host tool.
+
+use crate::config::{LINE_CR_TO_LF, LINE_ECHO, LINE_LF_TO_CRLF};
+
+/// Translates data from the host in place before it goes to the ESP32, and echoes it back with
+/// `echo` if that's turned on.
+pub fn from_host(options: u8, data: &mut [u8], mut echo: impl FnMut(&[u8])) {
+    for byte in data.iter_mut() {
+        if *byte == b'\r' && options & LINE_CR_TO_LF != 0 {
+            *byte = b'\n';
+        }
+        if options & LINE_ECHO != 0 {
+            echo(to_host(options, byte));
+        }
+    }
+}
+
+/// Translates a byte from the ESP32 for the host.
+#[inline(never)]
+pub fn to_host(options: u8, byte: &u8) -> &[u8] {
+    if *byte == b'\n' && options & LINE_LF_TO_CRLF != 0 {
+        b"\r\n"
+    } else {
+        core::slice::from_ref(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_output(options: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &byte in data {
+            out.extend_from_slice(to_host(options, &byte));
+        }
+        out
+    }
+
+    #[test]
+    fn translation() {
+        assert_eq!(host_output(0, b"ok\n"), b"ok\n");
+        assert_eq!(host_output(LINE_LF_TO_CRLF, b"ok\n"), b"ok\r\n");
+
+        let mut data = *b"ls\r";
+        from_host(0, &mut data, |_| panic!("echo is off"));
+        assert_eq!(&data, b"ls\r");
+        from_host(LINE_CR_TO_LF, &mut data, |_| panic!("echo is off"));
+        assert_eq!(&data, b"ls\n");
+    }
+
+    #[test]
+    fn echo() {
+        let mut echoed = Vec::new();
+        let mut data = *b"ls\r";
+        from_host(LINE_CR_TO_LF | LINE_LF_TO_CRLF | LINE_ECHO, &mut data, |bytes| {
+            echoed.extend_from_slice(bytes)
+        });
+        assert_eq!(&data, b"ls\n");
+        assert_eq!(echoed, b"ls\r\n");
+    }
+}
@@ -0,0 +1,89 @@
+//! Low power modes
+
+use crate::board;
+use cortex_m::peripheral::{NVIC, SCB};
+use stm32f0xx_hal::stm32::{self, Interrupt};
+
+const SCB_SCR_SEVONPEND: u32 = 1 << 4;
+
+const EXTI_LINE_USB_WAKEUP: u32 = 1 << 18;
+
+/// The programmable voltage detector's highest threshold, around 2.8V as VDD falls, to give the
+/// most warning before the 2.0V flash needs.
+const PVD_LEVEL: u8 = 7;
+
+/// USB ISTR flags (CTR, WKUP, RESET) which would wake us from STOP mode.
+const USB_ISTR_WAKEUP_EVENTS: u32 = (1 << 15) | (1 << 12) | (1 << 10);
+
+/// Enables the power controller and the brown-out warning, and routes the USB wakeup event to the
+/// core.
+pub fn init(scb: &mut SCB) {
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    let exti = unsafe { &*stm32::EXTI::ptr() };
+    let pwr = unsafe { &*stm32::PWR::ptr() };
+
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|_, w| unsafe { w.pls().bits(PVD_LEVEL).pvde().set_bit() });
+    exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_LINE_USB_WAKEUP) });
+
+    // The USB interrupt is never enabled, but with SEVONPEND it becoming pending is still an event
+    // which will wake the core from WFE.
+    unsafe { scb.scr.modify(|scr| scr | SCB_SCR_SEVONPEND) };
+}
+
+/// Whether VDD is below the brown-out warning threshold. Flash erased or written then might not
+/// hold what was written.
+pub fn supply_low() -> bool {
+    let pwr = unsafe { &*stm32::PWR::ptr() };
+    pwr.csr.read().pvdo().bit_is_set()
+}
+
+/// Enters STOP mode until the USB peripheral sees bus activity, then restores the 48 MHz clock.
+///
+/// The USB peripheral should already be suspended. This may return early if some other event is
+/// pending, so call it again if the bus is still suspended.
+pub fn stop_until_usb_wakeup(scb: &mut SCB) {
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+    let pwr = unsafe { &*stm32::PWR::ptr() };
+    let usb = unsafe { &*stm32::USB::ptr() };
+
+    // Anything already flagged would wake us straight away - leave it for the next poll.
+    if usb.istr.read().bits() & USB_ISTR_WAKEUP_EVENTS != 0 {
+        return;
+    }
+    NVIC::unpend(Interrupt::USB);
+
+    // STOP rather than STANDBY, with the regulator in low power mode.
+    pwr.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit());
+    scb.set_sleepdeep();
+    cortex_m::asm::dsb();
+    cortex_m::asm::wfe();
+    scb.clear_sleepdeep();
+
+    // We wake up running from the 8 MHz HSI. The CRS keeps its configuration through STOP mode and
+    // will start trimming HSI48 again once SOFs arrive.
+    rcc.cr2.modify(|_, w| w.hsi48on().set_bit());
+    while rcc.cr2.read().hsi48rdy().bit_is_clear() {}
+    rcc.cfgr.modify(|_, w| w.sw().hsi48());
+    while !rcc.cfgr.read().sws().is_hsi48() {}
+}
+
+/// Sleeps until the USB peripheral or the UART has something for us. The ADC and its clock are
+/// switched off while we wait.
+///
+/// The UART's RXNE interrupt must be enabled (though not in the NVIC) for received bytes to wake
+/// us up. The SysTick exception wakes us every tick too, so the loop's timers keep running.
+pub fn sleep_until_traffic() {
+    let rcc = unsafe { &*stm32::RCC::ptr() };
+
+    rcc.apb2enr.modify(|_, w| w.adcen().clear_bit());
+    rcc.cr2.modify(|_, w| w.hsi14on().clear_bit());
+
+    NVIC::unpend(Interrupt::USB);
+    NVIC::unpend(board::ESP_UART_INTERRUPT);
+    cortex_m::asm::wfe();
+
+    rcc.cr2.modify(|_, w| w.hsi14on().set_bit());
+    while rcc.cr2.read().hsi14rdy().bit_is_clear() {}
+    rcc.apb2enr.modify(|_, w| w.adcen().set_bit());
+}
@@ -0,0 +1,49 @@
+//! Flash readout protection for production badges
+//!
+//! Sets readout protection level 1, which stops a debugger reading or writing the flash. The
+//! firmware and bootloader can still write it, so updates and settings keep working. Level 2 would
+//! also disable the debug port for good, but then there'd be no way back for a badge which needs
+//! reworking.
+
+use crate::flash::{self, KEY1, KEY2};
+
+/// The option bytes, each stored as a half-word with its complement: RDP, USER, DATA0, DATA1,
+/// WRP0 and WRP1.
+const OPTION_BYTES: usize = 0x1FFF_F800;
+const OPTION_BYTE_COUNT: usize = 6;
+
+/// Any RDP value other than 0xAA (level 0) or 0xCC (level 2) means level 1.
+const RDP_LEVEL_1: u8 = 0xBB;
+
+/// Turns on readout protection. The option bytes only take effect after they're reloaded, which
+/// resets the chip.
+pub fn lock() -> ! {
+    // Erasing the option bytes clears all of them, so the rest are put back as they were.
+    let mut option_bytes = [0u8; OPTION_BYTE_COUNT];
+    for (i, byte) in option_bytes.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile((OPTION_BYTES + i * 2) as *const u8) };
+    }
+    option_bytes[0] = RDP_LEVEL_1;
+
+    let flash = flash::unlock();
+    flash.optkeyr.write(|w| w.optkeyr().bits(KEY1));
+    flash.optkeyr.write(|w| w.optkeyr().bits(KEY2));
+
+    flash.cr.modify(|_, w| w.opter().set_bit());
+    flash.cr.modify(|_, w| w.strt().set_bit());
+    flash::wait_idle();
+    flash.cr.modify(|_, w| w.opter().clear_bit());
+
+    flash.cr.modify(|_, w| w.optpg().set_bit());
+    for (i, &byte) in option_bytes.iter().enumerate() {
+        let half_word = u16::from_le_bytes([byte, !byte]);
+        unsafe { core::ptr::write_volatile((OPTION_BYTES + i * 2) as *mut u16, half_word) };
+        flash::wait_idle();
+    }
+    flash.cr.modify(|_, w| w.optpg().clear_bit());
+
+    flash.cr.modify(|_, w| w.force_optload().set_bit());
+    loop {
+        cortex_m::asm::nop();
+    }
+}
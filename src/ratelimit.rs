@@ -0,0 +1,171 @@
+//! Vendor request rate limits
+//!
+//! With the `rate-limit` feature, a host application stuck in a loop can't keep resetting the
+//! ESP32, wear out the configuration page or keep the bridge so busy with control requests that
+//! the UART falls behind. Every vendor request spends from a shared budget, and the ones in
+//! `LIMITS`, which reset something or write the flash, from their own as well, as do the WebUSB
+//! port's control line changes, which reset the ESP32 or put it in download mode. Each budget
+//! fills at a steady rate up to a burst, so a host behaving itself never notices; a request over
+//! either budget is stalled, and doesn't spend from the other.
+
+use tilda_bridge_protocol::request;
+
+/// A budget of requests: it fills at `per_minute`, up to `burst`.
+#[derive(Copy, Clone)]
+struct Limit {
+    per_minute: u32,
+    burst: u32,
+}
+
+/// What one request costs, in units of the budgets: a request for every minute's worth of
+/// milliseconds, so a budget fills by `per_minute` every millisecond.
+const COST: u32 = 60_000;
+
+/// Every vendor request: enough for a tool reading the SPI flash flat out, a request every
+/// millisecond, with the rest of the loop's time left for the UART.
+const ALL: Limit = Limit { per_minute: 60_000, burst: 200 };
+
+/// The WebUSB port's control line changes. esptool takes 3 or 4 for each reset, and retries a few
+/// times if the ESP32 doesn't answer.
+const CONTROL_LINES: Limit = Limit { per_minute: 120, burst: 32 };
+
+/// Requests with their own budget. The self-test and fixture walk reset the ESP32 and stop the
+/// bridge servicing USB, a profile change drops the bridge off the bus, so a host needs a few to
+/// try one out and change back, and the settings and cleared error log are written to flash.
+const LIMITS: [(u8, Limit); 5] = [
+    (request::SELF_TEST, Limit { per_minute: 6, burst: 2 }),
+    (request::FIXTURE_TEST, Limit { per_minute: 6, burst: 2 }),
+    (request::SET_PROFILE, Limit { per_minute: 6, burst: 4 }),
+    (request::SET_CONFIG, Limit { per_minute: 10, burst: 5 }),
+    (request::CLEAR_ERROR_LOG, Limit { per_minute: 10, burst: 5 }),
+];
+
+/// What's left of a budget, in `COST`s.
+#[derive(Copy, Clone)]
+struct Budget(u32);
+
+impl Budget {
+    const fn full(limit: Limit) -> Self {
+        Budget(limit.burst * COST)
+    }
+
+    fn fill(&mut self, limit: Limit, elapsed_ms: u32) {
+        self.0 = self.0.saturating_add(limit.per_minute.saturating_mul(elapsed_ms));
+        self.0 = self.0.min(limit.burst * COST);
+    }
+
+    fn has_one(&self) -> bool {
+        self.0 >= COST
+    }
+
+    fn spend(&mut self) {
+        self.0 -= COST;
+    }
+}
+
+/// The bridge's budgets, which start full.
+pub struct RateLimits {
+    all: Budget,
+    control_lines: Budget,
+    requests: [Budget; LIMITS.len()],
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let mut requests = [Budget(0); LIMITS.len()];
+        for (budget, (_, limit)) in requests.iter_mut().zip(LIMITS.iter()) {
+            *budget = Budget::full(*limit);
+        }
+        RateLimits {
+            all: Budget::full(ALL),
+            control_lines: Budget::full(CONTROL_LINES),
+            requests,
+        }
+    }
+}
+
+impl RateLimits {
+    /// Fills the budgets.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.all.fill(ALL, elapsed_ms);
+        self.control_lines.fill(CONTROL_LINES, elapsed_ms);
+        for (budget, (_, limit)) in self.requests.iter_mut().zip(LIMITS.iter()) {
+            budget.fill(*limit, elapsed_ms);
+        }
+    }
+
+    /// Spends from the budgets for a vendor request. Returns false, spending nothing, if it's over
+    /// either.
+    pub fn vendor(&mut self, request: u8) -> bool {
+        let requests = &mut self.requests;
+        let own = LIMITS.iter().position(|(limited, _)| *limited == request);
+        let own = own.map(|i| &mut requests[i]);
+        if !self.all.has_one() || own.as_ref().is_some_and(|own| !own.has_one()) {
+            return false;
+        }
+        self.all.spend();
+        if let Some(own) = own {
+            own.spend();
+        }
+        true
+    }
+
+    /// Spends from the budget for control line changes. Returns false if it's empty.
+    pub fn control_lines(&mut self) -> bool {
+        if !self.control_lines.has_one() {
+            return false;
+        }
+        self.control_lines.spend();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_request() {
+        let mut limits = RateLimits::default();
+        assert!(limits.vendor(request::SELF_TEST));
+        assert!(limits.vendor(request::SELF_TEST));
+        assert!(!limits.vendor(request::SELF_TEST));
+        // Others aren't held up
+        assert!(limits.vendor(request::FIXTURE_TEST));
+        assert!(limits.vendor(request::GET_TELEMETRY));
+
+        // 6 a minute
+        limits.tick(9_999);
+        assert!(!limits.vendor(request::SELF_TEST));
+        limits.tick(1);
+        assert!(limits.vendor(request::SELF_TEST));
+        assert!(!limits.vendor(request::SELF_TEST));
+
+        // Only the burst saves up.
+        limits.tick(3_600_000);
+        assert_eq!((0..10).filter(|_| limits.vendor(request::SET_CONFIG)).count(), 5);
+        assert_eq!((0..10).filter(|_| limits.vendor(request::CLEAR_ERROR_LOG)).count(), 5);
+    }
+
+    #[test]
+    fn shared() {
+        let mut limits = RateLimits::default();
+        assert_eq!((0..1000).filter(|_| limits.vendor(request::GET_TELEMETRY)).count(), 200);
+        // Over the shared budget, limited requests don't spend their own.
+        assert!(!limits.vendor(request::SET_CONFIG));
+        limits.tick(1);
+        assert!(limits.vendor(request::PING));
+        assert!(!limits.vendor(request::PING));
+        for _ in 0..5 {
+            limits.tick(1);
+            assert!(limits.vendor(request::SET_CONFIG));
+        }
+        limits.tick(1);
+        assert!(!limits.vendor(request::SET_CONFIG));
+
+        assert_eq!((0..100).filter(|_| limits.control_lines()).count(), 32);
+        limits.tick(500);
+        assert!(limits.control_lines());
+        assert!(!limits.control_lines());
+    }
+}
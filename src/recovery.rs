@@ -0,0 +1,202 @@
+//! USB enumeration and clock watchdogs
+//!
+//! If the host addresses us but never gets as far as configuring the device, something has gone
+//! wrong with enumeration. The firmware recovers by briefly disconnecting from the bus, which makes
+//! the host start again from scratch.
+//!
+//! The USB clock is the HSI48, which the CRS trims to the host's start of frame packets. On a
+//! marginal board it can lose them, and USB then fails in odd ways as the clock drifts. The CRS is
+//! restarted from the factory trim a few times, and if it still can't sync the bridge gets off the
+//! bus, blinks its LED quickly and tries again now and then. The UART keeps running throughout.
+
+use usb_device::device::UsbDeviceState;
+
+/// How long the device may sit addressed but unconfigured before it's re-attached.
+pub const ENUMERATION_TIMEOUT_MS: u32 = 5000;
+
+/// Watches for enumeration getting stuck.
+#[derive(Default)]
+pub struct EnumerationWatchdog {
+    addressed_ms: u32,
+}
+
+impl EnumerationWatchdog {
+    /// Advances the watchdog. Returns true (once per timeout) if the device has been addressed but
+    /// not configured for `ENUMERATION_TIMEOUT_MS`.
+    pub fn tick(&mut self, state: UsbDeviceState, elapsed_ms: u32) -> bool {
+        if state != UsbDeviceState::Addressed {
+            self.addressed_ms = 0;
+            return false;
+        }
+
+        self.addressed_ms = self.addressed_ms.saturating_add(elapsed_ms);
+        if self.addressed_ms >= ENUMERATION_TIMEOUT_MS {
+            self.addressed_ms = 0;
+            return true;
+        }
+        false
+    }
+}
+
+/// How long the CRS can go without syncing to the host before it's restarted.
+pub const CLOCK_RELOCK_MS: u32 = 200;
+
+/// Restarts in a row after which the clock is given up on.
+pub const CLOCK_RELOCK_ATTEMPTS: u8 = 3;
+
+/// How often a failed clock is tried again.
+pub const CLOCK_RETRY_MS: u32 = 5000;
+
+/// Half the period of the LED's blink while the clock has failed.
+pub const CLOCK_BLINK_MS: u32 = 100;
+
+/// What the CRS made of the host's start of frame packets since the last tick.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ClockSync {
+    /// None arrived, or none were expected.
+    None,
+
+    /// The HSI48 is in step with the host.
+    Ok,
+
+    /// The CRS missed one or couldn't trim to it, or the HSI48 has stopped.
+    Error,
+}
+
+/// What to do about the clock.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ClockAction {
+    None,
+
+    /// Restart the CRS.
+    Relock,
+
+    /// Get off the bus.
+    Fail,
+
+    /// Restart the CRS and re-attach to the bus.
+    Retry,
+}
+
+/// Watches the CRS for the USB clock losing the host.
+#[derive(Default)]
+pub struct ClockWatchdog {
+    error_ms: u32,
+    relocks: u8,
+    /// Time since the clock failed or was last retried.
+    failed_ms: Option<u32>,
+}
+
+impl ClockWatchdog {
+    /// Advances the watchdog.
+    pub fn tick(&mut self, sync: ClockSync, elapsed_ms: u32) -> ClockAction {
+        match sync {
+            ClockSync::None => {}
+            ClockSync::Ok => {
+                *self = ClockWatchdog::default();
+                return ClockAction::None;
+            }
+            ClockSync::Error => self.error_ms += elapsed_ms,
+        }
+
+        if let Some(failed_ms) = &mut self.failed_ms {
+            *failed_ms += elapsed_ms;
+            if *failed_ms >= CLOCK_RETRY_MS {
+                *failed_ms = 0;
+                self.error_ms = 0;
+                return ClockAction::Retry;
+            }
+        }
+        if self.error_ms < CLOCK_RELOCK_MS {
+            return ClockAction::None;
+        }
+
+        self.error_ms = 0;
+        if self.relocks < CLOCK_RELOCK_ATTEMPTS {
+            self.relocks += 1;
+            return ClockAction::Relock;
+        }
+        self.failed_ms.get_or_insert(0);
+        ClockAction::Fail
+    }
+
+    /// Whether the clock has been given up on.
+    pub fn failed(&self) -> bool {
+        self.failed_ms.is_some()
+    }
+
+    /// The LED's state while the clock has failed: a quick blink, so it isn't mistaken for
+    /// traffic.
+    pub fn led(&self) -> Option<bool> {
+        self.failed_ms.map(|ms| (ms / CLOCK_BLINK_MS).is_multiple_of(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stuck_enumeration() {
+        let mut watchdog = EnumerationWatchdog::default();
+        for _ in 0..49 {
+            assert!(!watchdog.tick(UsbDeviceState::Addressed, 100));
+        }
+        assert!(watchdog.tick(UsbDeviceState::Addressed, 100));
+
+        // Starts timing again after firing
+        assert!(!watchdog.tick(UsbDeviceState::Addressed, 100));
+    }
+
+    #[test]
+    fn other_states() {
+        let mut watchdog = EnumerationWatchdog::default();
+        watchdog.tick(UsbDeviceState::Addressed, 4900);
+        assert!(!watchdog.tick(UsbDeviceState::Configured, 100));
+        assert!(!watchdog.tick(UsbDeviceState::Addressed, 100));
+
+        // Sitting unenumerated without a host is fine
+        assert!(!watchdog.tick(UsbDeviceState::Default, u32::MAX));
+        assert!(!watchdog.tick(UsbDeviceState::Suspend, u32::MAX));
+    }
+
+    #[test]
+    fn clock_relocks_then_fails() {
+        let mut watchdog = ClockWatchdog::default();
+        for _ in 0..CLOCK_RELOCK_ATTEMPTS {
+            assert_eq!(watchdog.tick(ClockSync::Error, 100), ClockAction::None);
+            assert_eq!(watchdog.tick(ClockSync::Error, 100), ClockAction::Relock);
+        }
+        // Frames not arriving isn't evidence either way.
+        assert_eq!(watchdog.tick(ClockSync::None, 1000), ClockAction::None);
+        assert_eq!(watchdog.tick(ClockSync::Error, 200), ClockAction::Fail);
+        assert!(watchdog.failed());
+        assert_eq!(watchdog.led(), Some(true));
+        assert_eq!(watchdog.tick(ClockSync::None, CLOCK_BLINK_MS), ClockAction::None);
+        assert_eq!(watchdog.led(), Some(false));
+
+        assert_eq!(watchdog.tick(ClockSync::None, CLOCK_RETRY_MS), ClockAction::Retry);
+        // Still no good after the retry
+        assert_eq!(watchdog.tick(ClockSync::Error, 200), ClockAction::Fail);
+        assert!(watchdog.failed());
+
+        assert_eq!(watchdog.tick(ClockSync::Ok, 1), ClockAction::None);
+        assert!(!watchdog.failed());
+        assert_eq!(watchdog.led(), None);
+    }
+
+    #[test]
+    fn clock_sync_resets() {
+        let mut watchdog = ClockWatchdog::default();
+        assert_eq!(watchdog.tick(ClockSync::Error, 100), ClockAction::None);
+        assert_eq!(watchdog.tick(ClockSync::Error, 100), ClockAction::Relock);
+        assert_eq!(watchdog.tick(ClockSync::Error, 199), ClockAction::None);
+        assert_eq!(watchdog.tick(ClockSync::Ok, 1), ClockAction::None);
+        assert_eq!(watchdog.tick(ClockSync::Error, 199), ClockAction::None);
+
+        // The count of relocks starts again too.
+        for _ in 0..CLOCK_RELOCK_ATTEMPTS {
+            assert_eq!(watchdog.tick(ClockSync::Error, 200), ClockAction::Relock);
+        }
+    }
+}
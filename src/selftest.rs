@@ -0,0 +1,73 @@
+//! End-of-line self-test
+//!
+//! Used on the badge assembly line: the host triggers the test with a vendor request, the
+//! firmware exercises the bridge hardware, and the host reads back a `SelfTestReport`.
+
+pub use tilda_bridge_protocol::selftest::SelfTestReport;
+
+/// esptool SYNC command, SLIP framed. Sent to the ESP32 ROM loader to check the UART path.
+pub const ESP_SYNC_FRAME: [u8; 46] = [
+    0xC0, // SLIP frame start
+    0x00, // Request
+    0x08, // SYNC
+    0x24, 0x00, // Data length (36)
+    0x00, 0x00, 0x00, 0x00, // Checksum (unused)
+    0x07, 0x07, 0x12, 0x20, // Sync pattern
+    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+    0xC0, // SLIP frame end
+];
+
+/// Watches UART RX for the start of a response to `ESP_SYNC_FRAME`.
+#[derive(Default)]
+pub struct SyncDetector {
+    matched: usize,
+}
+
+impl SyncDetector {
+    const RESPONSE: [u8; 3] = [0xC0, 0x01, 0x08];
+
+    /// Feeds a received byte to the detector. Returns true once a SYNC response has been seen.
+    pub fn feed(&mut self, byte: u8) -> bool {
+        if self.matched < Self::RESPONSE.len() {
+            if byte == Self::RESPONSE[self.matched] {
+                self.matched += 1;
+            } else if byte == Self::RESPONSE[0] {
+                // SLIP frames are back-to-back, so an end marker can be followed by a start
+                self.matched = 1;
+            } else {
+                self.matched = 0;
+            }
+        }
+        self.found()
+    }
+
+    /// Whether a SYNC response has been seen.
+    pub fn found(&self) -> bool {
+        self.matched == Self::RESPONSE.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_response() {
+        let mut detector = SyncDetector::default();
+        let rx = b"waiting for download\r\n\xC0\xC0\x01\x08\x04\x00";
+
+        let found = rx.iter().any(|&b| detector.feed(b));
+        assert!(found);
+        assert!(detector.found());
+    }
+
+    #[test]
+    fn no_sync_response() {
+        let mut detector = SyncDetector::default();
+        // Our own request echoed back doesn't count
+        assert!(!ESP_SYNC_FRAME.iter().any(|&b| detector.feed(b)));
+    }
+}
@@ -0,0 +1,84 @@
+//! ADC sampling of the supply rails and internal sensors
+
+use embedded_hal::adc::OneShot;
+use stm32f0xx_hal::{
+    adc::{Adc, AdcSampleTime, VRef, VTemp},
+    gpio::{
+        gpioa::{PA0, PA5},
+        Analog,
+    },
+};
+use tilda_stm::telemetry::{ChipReadings, SupplyReadings};
+
+/// VBUS and the battery are wired to the ADC through 1:2 dividers.
+const DIVIDER_RATIO: u16 = 2;
+
+/// Factory calibration values in system memory.
+const TS_CAL1: *const u16 = 0x1FFF_F7B8 as *const u16;
+const VREFINT_CAL: *const u16 = 0x1FFF_F7BA as *const u16;
+
+/// Temperature sensor and VREFINT start-up time (10 µs).
+const SENSOR_STARTUP_CYCLES: u32 = 480;
+
+/// Samples the supply rails and internal sensors with the ADC.
+///
+/// VDD is always measured against the internal reference. VBUS (PA0) and the battery (PA5) are
+/// only measured if the board has them wired up, as selected by the `vbus-sense` and
+/// `battery-sense` features.
+pub struct Sensors {
+    adc: Adc,
+    vbus: PA0<Analog>,
+    battery: PA5<Analog>,
+}
+
+impl Sensors {
+    pub fn new(mut adc: Adc, vbus: PA0<Analog>, battery: PA5<Analog>) -> Self {
+        // The internal sensors need at least 17 µs of sampling time.
+        adc.set_sample_time(AdcSampleTime::T_239);
+        Sensors { adc, vbus, battery }
+    }
+
+    /// Takes a set of supply readings. This takes a few tens of microseconds per rail.
+    pub fn read_supply(&mut self) -> SupplyReadings {
+        SupplyReadings {
+            vdd: Some(VRef::read_vdda(&mut self.adc)),
+            vbus: if cfg!(feature = "vbus-sense") {
+                Some(self.read_vbus())
+            } else {
+                None
+            },
+            battery: if cfg!(feature = "battery-sense") {
+                Some(self.adc.read_abs_mv(&mut self.battery).saturating_mul(DIVIDER_RATIO))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Reads VBUS, which only means anything with the `vbus-sense` feature.
+    pub fn read_vbus(&mut self) -> u16 {
+        self.adc.read_abs_mv(&mut self.vbus).saturating_mul(DIVIDER_RATIO)
+    }
+
+    /// Reads the temperature sensor and internal reference.
+    pub fn read_chip(&mut self) -> ChipReadings {
+        let mut vtemp = VTemp::new();
+        let mut vref = VRef::new();
+        vtemp.enable(&mut self.adc);
+        vref.enable(&mut self.adc);
+        cortex_m::asm::delay(SENSOR_STARTUP_CYCLES);
+
+        let temperature = OneShot::<Adc, u16, _>::read(&mut self.adc, &mut vtemp).unwrap_or(0);
+        let vrefint = OneShot::<Adc, u16, _>::read(&mut self.adc, &mut vref).unwrap_or(0);
+
+        vtemp.disable(&mut self.adc);
+        vref.disable(&mut self.adc);
+
+        ChipReadings {
+            temperature,
+            temperature_cal: unsafe { core::ptr::read(TS_CAL1) },
+            vrefint,
+            vrefint_cal: unsafe { core::ptr::read(VREFINT_CAL) },
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! CDC serial port with change events
+//!
+//! Wraps usbd-serial's `SerialPort`, which only has the DTR, RTS and line coding the host last
+//! set, and queues an event for each change as the host makes it (see `events`). A bus reset
+//! clears them without the host asking for anything, so it's not an event.
+
+use crate::events::{EventQueue, Stamp};
+use usb_device::class_prelude::*;
+use usb_device::Result;
+use usbd_serial::LineCoding;
+
+/// A change the host has made to the serial port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SerialEvent {
+    /// SET_CONTROL_LINE_STATE changed DTR or RTS to these levels (true when asserted).
+    ControlLines { dtr: bool, rts: bool },
+
+    /// SET_LINE_CODING changed the baud rate.
+    LineCoding(u32),
+}
+
+/// usbd-serial's `SerialPort`, with events for the host's changes.
+pub struct SerialPort<'a, B: UsbBus> {
+    inner: usbd_serial::SerialPort<'a, B>,
+    events: EventQueue<SerialEvent>,
+}
+
+impl<B: UsbBus> SerialPort<'_, B> {
+    /// Creates a new serial port with usbd-serial's default buffers.
+    pub fn new(alloc: &UsbBusAllocator<B>) -> SerialPort<'_, B> {
+        SerialPort { inner: usbd_serial::SerialPort::new(alloc), events: EventQueue::default() }
+    }
+
+    /// Gets the current line coding.
+    pub fn line_coding(&self) -> &LineCoding { self.inner.line_coding() }
+
+    /// Gets the DTR (data terminal ready) state
+    pub fn dtr(&self) -> bool { self.inner.dtr() }
+
+    /// Gets the RTS (ready to send) state
+    pub fn rts(&self) -> bool { self.inner.rts() }
+
+    /// Writes bytes from `data` into the port and returns the number of bytes written.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> { self.inner.write(data) }
+
+    /// Reads bytes from the port into `data` and returns the number of bytes read.
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize> { self.inner.read(data) }
+
+    /// Sends as much as possible of the current write buffer.
+    pub fn flush(&mut self) -> Result<()> { self.inner.flush() }
+
+    /// Takes the oldest change the host has made, if there's one waiting.
+    pub fn poll_event(&mut self) -> Option<SerialEvent> {
+        self.events.pop()
+    }
+
+    /// When the oldest waiting change was made, to put it in order with the other port's.
+    pub fn next_event_stamp(&self) -> Option<Stamp> {
+        self.events.next_stamp()
+    }
+
+    /// The state events are made from: DTR, RTS and the baud rate.
+    #[inline(never)]
+    fn state(&self) -> (bool, bool, u32) {
+        (self.dtr(), self.rts(), self.line_coding().data_rate())
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for SerialPort<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        self.inner.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.events.clear();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        self.inner.endpoint_in_complete(addr);
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.inner.control_in(xfer);
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let (dtr, rts, rate) = self.state();
+        self.inner.control_out(xfer);
+        let now = self.state();
+        if (now.0, now.1) != (dtr, rts) {
+            self.events.push(SerialEvent::ControlLines { dtr: now.0, rts: now.1 });
+        }
+        if now.2 != rate {
+            self.events.push(SerialEvent::LineCoding(now.2));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBus;
+    use usb_device::prelude::*;
+
+    const REQ_SET_LINE_CODING: u8 = 0x20;
+    const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+    #[test]
+    fn events() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut serial = SerialPort::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut serial]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        // esptool's reset into the ROM loader, then its switch to a faster rate
+        for lines in [1, 3, 2, 0, 0] {
+            bus.control_write(&mut poll, 0x21, REQ_SET_CONTROL_LINE_STATE, lines, 0, &[]).unwrap();
+        }
+        let coding = [0x00, 0x10, 0x0E, 0x00, 0, 0, 8];
+        bus.control_write(&mut poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &coding).unwrap();
+
+        let events: Vec<_> = core::iter::from_fn(|| serial.poll_event()).collect();
+        assert_eq!(
+            events,
+            [
+                SerialEvent::ControlLines { dtr: true, rts: false },
+                SerialEvent::ControlLines { dtr: true, rts: true },
+                SerialEvent::ControlLines { dtr: false, rts: true },
+                SerialEvent::ControlLines { dtr: false, rts: false },
+                SerialEvent::LineCoding(921_600),
+            ]
+        );
+
+        // A bus reset clears the lines, but isn't an event.
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut serial]);
+        };
+        bus.control_write(&mut poll, 0x21, REQ_SET_CONTROL_LINE_STATE, 1, 0, &[]).unwrap();
+        bus.bus_reset();
+        poll();
+        assert_eq!(serial.poll_event(), None);
+        assert!(!serial.dtr());
+    }
+}
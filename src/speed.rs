@@ -0,0 +1,78 @@
+//! Core clock scaling
+//!
+//! Idling as a USB device and passing on console output, the core spends its time polling, so it
+//! runs at 24 MHz to save the battery. Only the AHB prescaler changes: the HSI48 keeps running for
+//! USB, and the APB prescaler goes from 2 to 1 so the UART's clock, and so its baud rate, stays put.
+//! Anything more demanding, like a faster baud rate or a flash being written, brings back 48 MHz
+//! straight away, and the core only slows down again once it's been quiet for `HOLD_MS`.
+
+/// The fastest baud rate which counts as console traffic.
+pub const CONSOLE_BAUD: u32 = 115_200;
+
+/// How long the core stays at full speed after the last demand for it.
+pub const HOLD_MS: u32 = 1000;
+
+/// Decides when the core runs at full speed.
+pub struct SpeedGovernor {
+    full: bool,
+    quiet_ms: u32,
+}
+
+impl Default for SpeedGovernor {
+    /// Starts at full speed, as the clocks are set up that way.
+    fn default() -> Self {
+        SpeedGovernor {
+            full: true,
+            quiet_ms: 0,
+        }
+    }
+}
+
+impl SpeedGovernor {
+    /// Advances the governor with whether anything needs the full clock. Returns whether the core
+    /// should now run at full speed, if that has changed.
+    pub fn tick(&mut self, busy: bool, elapsed_ms: u32) -> Option<bool> {
+        if busy {
+            self.quiet_ms = 0;
+            return if self.full { None } else { self.change() };
+        }
+        self.quiet_ms = self.quiet_ms.saturating_add(elapsed_ms);
+        if self.full && self.quiet_ms >= HOLD_MS {
+            return self.change();
+        }
+        None
+    }
+
+    /// Whether the core is at full speed.
+    pub fn full(&self) -> bool {
+        self.full
+    }
+
+    fn change(&mut self) -> Option<bool> {
+        self.full = !self.full;
+        Some(self.full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slows_down_when_quiet() {
+        let mut speed = SpeedGovernor::default();
+        assert!(speed.full());
+        assert_eq!(speed.tick(false, HOLD_MS - 1), None);
+        assert_eq!(speed.tick(false, 1), Some(false));
+        assert_eq!(speed.tick(false, u32::MAX), None);
+        assert!(!speed.full());
+
+        assert_eq!(speed.tick(true, 1), Some(true));
+        assert_eq!(speed.tick(true, 1), None);
+        // The hold starts again from the last demand.
+        assert_eq!(speed.tick(false, HOLD_MS - 1), None);
+        assert_eq!(speed.tick(true, 1), None);
+        assert_eq!(speed.tick(false, HOLD_MS - 1), None);
+        assert_eq!(speed.tick(false, 1), Some(false));
+    }
+}
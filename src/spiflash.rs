@@ -0,0 +1,182 @@
+//! Direct SPI flash access
+//!
+//! Recovers modules whose ROM loader can't be reached, on boards which wire the ESP32's SPI flash
+//! to the STM32 (see the `spi-flash` feature). While the host has the flash, the ESP32 is held in
+//! reset and the bridge bit-bangs the host's `SPI_TRANSFER`s; the host tool does the flash
+//! commands. Like JTAG batches, transfers are clocked out from the main loop, as the pins belong
+//! to it.
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use tilda_bridge_protocol::spiflash::MAX_TRANSFER_LENGTH;
+
+/// The ESP32's SPI flash pins.
+pub struct SpiPins<CLK, MOSI, CS, MISO> {
+    pub clk: CLK,
+    pub mosi: MOSI,
+    pub cs: CS,
+    pub miso: MISO,
+}
+
+/// A transfer from the host, and then the data clocked in.
+pub struct SpiTransfer {
+    buf: [u8; MAX_TRANSFER_LENGTH],
+    len: usize,
+    keep_cs: bool,
+    pending: bool,
+}
+
+impl Default for SpiTransfer {
+    fn default() -> Self {
+        SpiTransfer {
+            buf: [0; MAX_TRANSFER_LENGTH],
+            len: 0,
+            keep_cs: false,
+            pending: false,
+        }
+    }
+}
+
+impl SpiTransfer {
+    /// Takes a transfer from the host. Returns false if it's too long.
+    pub fn load(&mut self, data: &[u8], keep_cs: bool) -> bool {
+        if data.len() > MAX_TRANSFER_LENGTH {
+            return false;
+        }
+        self.buf[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+        self.keep_cs = keep_cs;
+        self.pending = true;
+        true
+    }
+
+    /// The data clocked in during the last transfer, or `None` if it hasn't been done yet.
+    #[inline(never)]
+    pub fn result(&self) -> Option<&[u8]> {
+        if self.pending {
+            return None;
+        }
+        Some(&self.buf[..self.len])
+    }
+
+    /// Clocks out a waiting transfer in SPI mode 0, replacing it with the data clocked in.
+    #[inline(never)]
+    pub fn run<CLK, MOSI, CS, MISO>(&mut self, pins: &mut SpiPins<CLK, MOSI, CS, MISO>)
+    where
+        CLK: OutputPin,
+        MOSI: OutputPin,
+        CS: OutputPin,
+        MISO: InputPin,
+    {
+        if !self.pending {
+            return;
+        }
+
+        let _ = pins.cs.set_low();
+        for byte in self.buf[..self.len].iter_mut() {
+            let mut received = 0;
+            for bit in (0..8).rev() {
+                let _ = if *byte & 1 << bit != 0 {
+                    pins.mosi.set_high()
+                } else {
+                    pins.mosi.set_low()
+                };
+                let _ = pins.clk.set_high();
+                received = received << 1 | pins.miso.is_high().unwrap_or(false) as u8;
+                let _ = pins.clk.set_low();
+            }
+            *byte = received;
+        }
+        if !self.keep_cs {
+            let _ = pins.cs.set_high();
+        }
+        self.pending = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// The flash end of the bus: shifts MOSI in on each rising CLK edge, and shifts a reply out.
+    #[derive(Default)]
+    struct Flash {
+        cs: bool,
+        clk: bool,
+        mosi: bool,
+        received: Vec<bool>,
+        reply: Vec<bool>,
+    }
+
+    #[derive(Clone, Default)]
+    struct Bus(Rc<RefCell<Flash>>);
+
+    struct Pin(Bus, fn(&mut Flash, bool));
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            (self.1)(&mut self.0 .0.borrow_mut(), false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            (self.1)(&mut self.0 .0.borrow_mut(), true);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Bus {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            let flash = self.0.borrow();
+            Ok(flash.reply.get(flash.received.len().wrapping_sub(1)) == Some(&true))
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    fn bits(bytes: &[u8]) -> Vec<bool> {
+        bytes.iter().flat_map(|&byte| (0..8).rev().map(move |bit| byte & 1 << bit != 0)).collect()
+    }
+
+    #[test]
+    fn mode_0() {
+        let bus = Bus::default();
+        bus.0.borrow_mut().cs = true;
+        bus.0.borrow_mut().reply = bits(&[0xFF, 0xEF, 0x40]);
+        let mut pins = SpiPins {
+            clk: Pin(bus.clone(), |flash, high| {
+                if high && !flash.clk {
+                    assert!(!flash.cs);
+                    let mosi = flash.mosi;
+                    flash.received.push(mosi);
+                }
+                flash.clk = high;
+            }),
+            mosi: Pin(bus.clone(), |flash, high| flash.mosi = high),
+            cs: Pin(bus.clone(), |flash, high| flash.cs = high),
+            miso: bus.clone(),
+        };
+
+        // JEDEC ID, split across two transfers
+        let mut transfer = SpiTransfer::default();
+        assert!(transfer.load(&[0x9F], true));
+        assert_eq!(transfer.result(), None);
+        transfer.run(&mut pins);
+        assert_eq!(transfer.result(), Some(&[0xFF][..]));
+        assert!(!bus.0.borrow().cs);
+
+        assert!(transfer.load(&[0, 0], false));
+        transfer.run(&mut pins);
+        assert_eq!(transfer.result(), Some(&[0xEF, 0x40][..]));
+        assert!(bus.0.borrow().cs);
+        assert_eq!(bus.0.borrow().received, bits(&[0x9F, 0, 0]));
+    }
+}
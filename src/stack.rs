@@ -0,0 +1,69 @@
+//! Stack usage
+//!
+//! There's only 6K of RAM, and the stack gets whatever the statics leave, so it's worth knowing how
+//! close it comes to running out. At startup the unused stack is painted with `PAINT`, and each
+//! time the sensors are sampled the firmware counts how much of it is still painted from the bottom
+//! up. The most used since startup goes in the telemetry report.
+//!
+//! The bottom `GUARD_BYTES` of the stack are a guard. The firmware checks it at the end of every
+//! pass of the main loop, after anything deep the pass called, such as the USB control handlers,
+//! the self-test and writing the settings page. Once anything has written over it, the stack has
+//! come within `GUARD_BYTES` of the statics, so rather than carry on the firmware leaves
+//! `OVERFLOW_FLAG` in RAM and restarts. The next boot reports it as a `STACK_OVERFLOW` reset.
+//!
+//! That's only a best effort. The stack grows down towards the end of `.bss`, and nothing faults
+//! when it goes past, so a call which goes further than the guard in one go has already written
+//! over the last statics by the time the check sees it, and the restart only stops things getting
+//! worse. Catching that as it happens would need the stack below the statics (as flip-link puts
+//! it), which this build doesn't do.
+
+use crate::dfu;
+
+/// Value the unused stack is painted with.
+pub const PAINT: u32 = 0x5AC5_5AC5;
+
+/// Size of the guard at the bottom of the stack.
+pub const GUARD_BYTES: usize = 32;
+
+/// A word of RAM which survives a reset, next to the boot flag.
+pub const OVERFLOW_FLAG_ADDRESS: usize = dfu::BOOT_FLAG_ADDRESS + 4;
+
+/// Value left at `OVERFLOW_FLAG_ADDRESS` before restarting after a stack overflow.
+pub const OVERFLOW_FLAG: u32 = 0x57AC_0F10;
+
+/// Bytes used of a stack, given its words from the bottom up.
+pub fn used(stack: &[u32]) -> usize {
+    let unused = stack.iter().take_while(|&&word| word == PAINT).count();
+    (stack.len() - unused) * 4
+}
+
+/// Whether the guard at the bottom of a stack, given its words from the bottom up, has been
+/// written over.
+pub fn overflowed(stack: &[u32]) -> bool {
+    stack.iter().take(GUARD_BYTES / 4).any(|&word| word != PAINT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_water_mark() {
+        let mut stack = [PAINT; 64];
+        assert_eq!(used(&stack), 0);
+
+        // Painted words higher up, like locals which happen to match, don't count.
+        stack[40] = 0;
+        stack[50] = PAINT;
+        assert_eq!(used(&stack), 24 * 4);
+        assert!(!overflowed(&stack));
+
+        stack[8] = 0;
+        assert_eq!(used(&stack), 256 - GUARD_BYTES);
+        assert!(!overflowed(&stack));
+        // Only the guard is looked at, and a word below the deepest use is enough.
+        stack[8] = PAINT;
+        stack[7] = 0;
+        assert!(overflowed(&stack));
+    }
+}
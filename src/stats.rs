@@ -0,0 +1,259 @@
+//! USB and UART error counters
+//!
+//! `WouldBlock` from a read just means there's no data, but anything else is a genuine endpoint
+//! error. These are counted, along with UART receive errors, so a bridge which has silently stopped
+//! passing data (or dropped some of it) can be diagnosed.
+//!
+//! Each port has its own queue for the ESP32's output, and they're drained independently, so a
+//! port whose host isn't reading only loses its own copy. What it loses is counted in
+//! `OutputDrops`.
+
+use usb_device::UsbError;
+
+/// Consecutive errors from one port after which it's considered stuck.
+pub const STUCK_THRESHOLD: u8 = 16;
+
+/// Length of the report returned by `ReadErrors::to_bytes`.
+pub const REPORT_LENGTH: usize = 17;
+
+/// The USB interfaces which are read from.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Port {
+    /// The CDC-ACM serial port.
+    Serial = 0,
+
+    /// The WebUSB port.
+    WebUsb = 1,
+}
+
+/// Read error counters for both ports.
+#[derive(Default)]
+pub struct ReadErrors {
+    counts: [u32; 2],
+    consecutive: [u8; 2],
+    recoveries: u32,
+    last_error: u8,
+    reattaches: u32,
+}
+
+impl ReadErrors {
+    /// Records the result of reading from `port`. Returns true if it was a genuine error, which
+    /// the caller should try to recover from.
+    pub fn record<T>(&mut self, port: Port, result: &Result<T, UsbError>) -> bool {
+        let port = port as usize;
+        match result {
+            Ok(_) | Err(UsbError::WouldBlock) => {
+                self.consecutive[port] = 0;
+                false
+            }
+            Err(err) => {
+                self.counts[port] = self.counts[port].wrapping_add(1);
+                self.consecutive[port] = self.consecutive[port].saturating_add(1);
+                self.last_error = error_code(err);
+                true
+            }
+        }
+    }
+
+    /// Records an attempt to recover an endpoint.
+    pub fn recovered(&mut self) {
+        self.recoveries = self.recoveries.wrapping_add(1);
+    }
+
+    /// Records the device being re-attached to the bus, which starts the ports afresh.
+    pub fn reattached(&mut self) {
+        self.reattaches = self.reattaches.wrapping_add(1);
+        self.consecutive = [0; 2];
+    }
+
+    /// Clears the counters and the last error. The consecutive errors are kept, so a stuck port is
+    /// still spotted.
+    pub fn clear(&mut self) {
+        *self = ReadErrors {
+            consecutive: self.consecutive,
+            ..ReadErrors::default()
+        };
+    }
+
+    /// Whether either port has been failing for `STUCK_THRESHOLD` reads in a row.
+    pub fn is_stuck(&self) -> bool {
+        self.consecutive.iter().any(|&count| count >= STUCK_THRESHOLD)
+    }
+
+    /// Encodes the counters for the host: serial and WebUSB error counts and the number of
+    /// recovery attempts as little-endian `u32`s, the last error (`error_code`), then the number of
+    /// times the device has re-attached to the bus.
+    pub fn to_bytes(&self) -> [u8; REPORT_LENGTH] {
+        let mut buf = [0u8; REPORT_LENGTH];
+        counts_to_bytes(&[self.counts[0], self.counts[1], self.recoveries], &mut buf);
+        buf[12] = self.last_error;
+        buf[13..17].copy_from_slice(&self.reattaches.to_le_bytes());
+        buf
+    }
+}
+
+/// UART receive errors. The bytes involved are lost.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UartError {
+    /// A byte arrived before the previous one was read.
+    Overrun = 0,
+
+    /// Missing stop bit.
+    Framing = 1,
+
+    /// Noise detected while sampling.
+    Noise = 2,
+
+    /// Parity check failed.
+    Parity = 3,
+}
+
+/// UART receive error counters.
+#[derive(Default)]
+pub struct UartErrors {
+    counts: [u32; 4],
+}
+
+impl UartErrors {
+    /// Records a receive error.
+    pub fn record(&mut self, error: UartError) {
+        let count = &mut self.counts[error as usize];
+        *count = count.wrapping_add(1);
+    }
+
+    /// Encodes the counters for the host: overrun, framing, noise and parity error counts as
+    /// little-endian `u32`s.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        counts_to_bytes(&self.counts, &mut buf);
+        buf
+    }
+}
+
+/// Bytes of the ESP32's output dropped because a port's queue was full.
+#[derive(Default)]
+pub struct OutputDrops {
+    counts: [u32; 2],
+}
+
+impl OutputDrops {
+    /// Records `count` bytes dropped from `port`.
+    pub fn record(&mut self, port: Port, count: usize) {
+        if let Some(total) = self.counts.get_mut(port as usize) {
+            *total = total.wrapping_add(count as u32);
+        }
+    }
+
+    /// Bytes dropped from `port`.
+    pub fn count(&self, port: Port) -> u32 {
+        self.counts[port as usize]
+    }
+
+    /// Encodes the counters for the host: serial and WebUSB drops as little-endian `u32`s.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        counts_to_bytes(&self.counts, &mut buf);
+        buf
+    }
+}
+
+/// Encodes counters as little-endian `u32`s.
+pub fn counts_to_bytes(counts: &[u32], buf: &mut [u8]) {
+    for (chunk, count) in buf.chunks_mut(4).zip(counts.iter()) {
+        chunk.copy_from_slice(&count.to_le_bytes());
+    }
+}
+
+/// Numbers a `UsbError` for reporting to the host. 0 means no error.
+pub fn error_code(err: &UsbError) -> u8 {
+    match err {
+        UsbError::WouldBlock => 1,
+        UsbError::ParseError => 2,
+        UsbError::BufferOverflow => 3,
+        UsbError::EndpointOverflow => 4,
+        UsbError::EndpointMemoryOverflow => 5,
+        UsbError::InvalidEndpoint => 6,
+        UsbError::Unsupported => 7,
+        UsbError::InvalidState => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn would_block_is_not_an_error() {
+        let mut errors = ReadErrors::default();
+        assert!(!errors.record::<usize>(Port::Serial, &Err(UsbError::WouldBlock)));
+        assert!(!errors.record(Port::Serial, &Ok(0)));
+        assert_eq!(errors.to_bytes(), [0; REPORT_LENGTH]);
+    }
+
+    #[test]
+    fn counts() {
+        let mut errors = ReadErrors::default();
+        assert!(errors.record::<usize>(Port::WebUsb, &Err(UsbError::BufferOverflow)));
+        assert!(errors.record::<usize>(Port::WebUsb, &Err(UsbError::InvalidEndpoint)));
+        errors.recovered();
+
+        errors.reattached();
+
+        assert_eq!(
+            errors.to_bytes(),
+            [0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 6, 1, 0, 0, 0]
+        );
+
+        errors.clear();
+        assert_eq!(errors.to_bytes(), [0; REPORT_LENGTH]);
+    }
+
+    #[test]
+    fn stuck() {
+        let mut errors = ReadErrors::default();
+        for _ in 0..STUCK_THRESHOLD - 1 {
+            errors.record::<usize>(Port::Serial, &Err(UsbError::InvalidState));
+        }
+        assert!(!errors.is_stuck());
+
+        // A good read from the other port doesn't help
+        errors.record(Port::WebUsb, &Ok(1));
+        errors.record::<usize>(Port::Serial, &Err(UsbError::InvalidState));
+        assert!(errors.is_stuck());
+        errors.clear();
+        assert!(errors.is_stuck());
+
+        errors.record(Port::Serial, &Ok(1));
+        assert!(!errors.is_stuck());
+
+        errors.record::<usize>(Port::Serial, &Err(UsbError::InvalidState));
+        errors.reattached();
+        for _ in 0..STUCK_THRESHOLD - 1 {
+            errors.record::<usize>(Port::Serial, &Err(UsbError::InvalidState));
+        }
+        assert!(!errors.is_stuck());
+    }
+
+    #[test]
+    fn uart_errors() {
+        let mut errors = UartErrors::default();
+        errors.record(UartError::Overrun);
+        errors.record(UartError::Overrun);
+        errors.record(UartError::Parity);
+
+        assert_eq!(
+            errors.to_bytes(),
+            [2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn output_drops() {
+        let mut drops = OutputDrops::default();
+        drops.record(Port::WebUsb, 0);
+        drops.record(Port::WebUsb, 3);
+        drops.record(Port::Serial, 1);
+        drops.record(Port::WebUsb, 2);
+        assert_eq!(drops.to_bytes(), [1, 0, 0, 0, 5, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,336 @@
+//! Supply rail and chip telemetry
+//!
+//! The firmware samples the supply rails and the STM32's internal sensors periodically, and the
+//! host reads them back with a vendor request. As well as the latest readings, the report includes
+//! the lowest supply voltages and highest temperature seen since the previous report, so the host
+//! can spot a USB port which sags under load, or a badge cooking in the sun.
+//!
+//! The report also carries the uptime, the persistent boot counters (see `counters`), how much
+//! of the stack has been used (see `stack`), in builds with the `esp-counters` feature, the
+//! ESP32's boot and crash counts, and in builds with the `loop-load` feature, how busy the main
+//! loop is (see `load`).
+
+use crate::counters::{BootCounts, EspCounter, EspCounts};
+#[cfg(feature = "loop-load")]
+use crate::load::LoopLoad;
+
+pub use tilda_bridge_protocol::telemetry::{
+    ESP_COUNTS_OFFSET, LOOP_LOAD_OFFSET, NOT_AVAILABLE, TEMPERATURE_NOT_AVAILABLE,
+};
+
+/// Length of the report returned by `Telemetry::report`, which only has the ESP32's counts in
+/// builds with the `esp-counters` or `loop-load` features, and the loop load in builds with
+/// `loop-load`.
+pub const REPORT_LENGTH: usize = if cfg!(feature = "loop-load") {
+    tilda_bridge_protocol::request::TELEMETRY_LENGTH
+} else if cfg!(feature = "esp-counters") {
+    LOOP_LOAD_OFFSET
+} else {
+    ESP_COUNTS_OFFSET
+};
+
+/// VDDA the factory calibration values were taken at, in millivolts.
+const CALIBRATION_VDDA: u32 = 3300;
+
+/// Typical temperature sensor slope, in microvolts per degree (datasheet Avg_Slope).
+const TEMPERATURE_SLOPE: u32 = 4300;
+
+/// Supply rail readings in millivolts. Rails which aren't wired to the ADC are `None`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct SupplyReadings {
+    /// The STM32's own supply, measured against the internal reference.
+    pub vdd: Option<u16>,
+
+    /// USB VBUS.
+    pub vbus: Option<u16>,
+
+    /// Battery.
+    pub battery: Option<u16>,
+}
+
+impl SupplyReadings {
+    /// Takes the lowest of each rail from two sets of readings.
+    fn min(self, other: SupplyReadings) -> SupplyReadings {
+        fn min(a: Option<u16>, b: Option<u16>) -> Option<u16> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                _ => a.or(b),
+            }
+        }
+
+        SupplyReadings {
+            vdd: min(self.vdd, other.vdd),
+            vbus: min(self.vbus, other.vbus),
+            battery: min(self.battery, other.battery),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 6] {
+        let mut buf = [0u8; 6];
+        let rails = [self.vdd, self.vbus, self.battery];
+        for (chunk, rail) in buf.chunks_mut(2).zip(rails.iter()) {
+            chunk.copy_from_slice(&rail.unwrap_or(NOT_AVAILABLE).to_le_bytes());
+        }
+        buf
+    }
+}
+
+/// Raw ADC readings of the STM32's internal sensors, along with their factory calibration values.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ChipReadings {
+    /// Temperature sensor.
+    pub temperature: u16,
+
+    /// Temperature sensor calibration at 30°C and 3.3V (TS_CAL1).
+    pub temperature_cal: u16,
+
+    /// Internal voltage reference.
+    pub vrefint: u16,
+
+    /// Internal voltage reference calibration at 3.3V (VREFINT_CAL).
+    pub vrefint_cal: u16,
+}
+
+impl ChipReadings {
+    /// Converts the temperature sensor reading to tenths of a degree Celsius.
+    ///
+    /// Not every STM32F0 has a second calibration point, so this uses the typical slope from the
+    /// datasheet. Expect to be a few degrees out.
+    #[inline(never)]
+    pub fn temperature_decidegrees(&self) -> i16 {
+        if self.vrefint == 0 {
+            return TEMPERATURE_NOT_AVAILABLE;
+        }
+
+        // Scale the reading to what it would have been at the calibration VDDA, which is
+        // VREFINT_CAL / VREFINT times the actual VDDA.
+        let scaled = self.temperature as i32 * self.vrefint_cal as i32 / self.vrefint as i32;
+        let microvolts = (self.temperature_cal as i32 - scaled) * (CALIBRATION_VDDA * 1000) as i32
+            / 4095;
+        (300 + microvolts * 10 / TEMPERATURE_SLOPE as i32) as i16
+    }
+}
+
+/// Tracks the latest readings, the lowest supply voltages and the highest temperature since the
+/// last report.
+#[derive(Default)]
+pub struct Telemetry {
+    latest: SupplyReadings,
+    lowest: SupplyReadings,
+    chip: Option<ChipReadings>,
+    highest_temperature: Option<i16>,
+    uptime_ms: u32,
+    uptime: u32,
+    boot_counts: BootCounts,
+    stack_used: u16,
+    stack_size: u16,
+    esp_counter: EspCounter,
+    #[cfg(feature = "loop-load")]
+    load: LoopLoad,
+}
+
+impl Telemetry {
+    /// Records a new set of supply readings.
+    pub fn record_supply(&mut self, readings: SupplyReadings) {
+        self.latest = readings;
+        self.lowest = self.lowest.min(readings);
+    }
+
+    /// Records a new set of internal sensor readings.
+    pub fn record_chip(&mut self, readings: ChipReadings) {
+        let temperature = readings.temperature_decidegrees();
+        self.chip = Some(readings);
+        self.highest_temperature = Some(match self.highest_temperature {
+            Some(highest) => highest.max(temperature),
+            None => temperature,
+        });
+    }
+
+    /// Advances the uptime.
+    #[inline(never)]
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        #[cfg(feature = "loop-load")]
+        self.load.tick(elapsed_ms);
+        self.uptime_ms += elapsed_ms;
+        while self.uptime_ms >= 1000 {
+            self.uptime_ms -= 1000;
+            self.uptime = self.uptime.wrapping_add(1);
+        }
+    }
+
+    /// Milliseconds since startup. Wraps after 49 days.
+    pub fn millis(&self) -> u32 {
+        self.uptime.wrapping_mul(1000).wrapping_add(self.uptime_ms)
+    }
+
+    /// Seconds since startup.
+    pub fn uptime(&self) -> u32 {
+        self.uptime
+    }
+
+    /// The latest supply readings.
+    pub fn latest(&self) -> SupplyReadings {
+        self.latest
+    }
+
+    /// Sets the boot counters, which only change at startup.
+    pub fn set_boot_counts(&mut self, counts: BootCounts) {
+        self.boot_counts = counts;
+    }
+
+    /// Records the most stack used so far, and the stack's size, in bytes.
+    pub fn record_stack(&mut self, used: u16, size: u16) {
+        self.stack_used = used;
+        self.stack_size = size;
+    }
+
+    /// Sets the ESP32's counts from the configuration page, which only happens at startup.
+    pub fn set_esp_counts(&mut self, counts: EspCounts) {
+        self.esp_counter = EspCounter::new(counts);
+    }
+
+    /// Counts a busy pass of the main loop, from `board::tick_micros` at its start and end.
+    #[cfg(feature = "loop-load")]
+    pub fn record_busy(&mut self, start_us: u32, end_us: u32) {
+        self.load.record_busy(start_us, end_us);
+    }
+
+    /// Counts the ESP32's boots and crashes.
+    pub fn esp_counter(&mut self) -> &mut EspCounter {
+        &mut self.esp_counter
+    }
+
+    /// Encodes the report for the host and starts tracking new extremes. The layout is described
+    /// in `tilda_bridge_protocol::telemetry`.
+    pub fn report(&mut self) -> [u8; REPORT_LENGTH] {
+        let mut buf = [0u8; REPORT_LENGTH];
+        buf[..6].copy_from_slice(&self.latest.to_bytes());
+        buf[6..12].copy_from_slice(&self.lowest.to_bytes());
+
+        let temperature = self.chip.map(|chip| chip.temperature_decidegrees());
+        let highest = self.highest_temperature;
+        let (vrefint, vrefint_cal) = match self.chip {
+            Some(chip) => (chip.vrefint, chip.vrefint_cal),
+            None => (NOT_AVAILABLE, NOT_AVAILABLE),
+        };
+        buf[12..14].copy_from_slice(&temperature.unwrap_or(TEMPERATURE_NOT_AVAILABLE).to_le_bytes());
+        buf[14..16].copy_from_slice(&highest.unwrap_or(TEMPERATURE_NOT_AVAILABLE).to_le_bytes());
+        buf[16..18].copy_from_slice(&vrefint.to_le_bytes());
+        buf[18..20].copy_from_slice(&vrefint_cal.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.uptime.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.boot_counts.to_bytes());
+        buf[32..34].copy_from_slice(&self.stack_used.to_le_bytes());
+        buf[34..36].copy_from_slice(&self.stack_size.to_le_bytes());
+        #[cfg(feature = "esp-counters")]
+        buf[36..44].copy_from_slice(&self.esp_counter.counts().to_bytes());
+        #[cfg(feature = "loop-load")]
+        buf[44..48].copy_from_slice(&self.load.report());
+
+        self.lowest = self.latest;
+        self.highest_temperature = temperature;
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(vdd: u16, vbus: u16) -> SupplyReadings {
+        SupplyReadings {
+            vdd: Some(vdd),
+            vbus: Some(vbus),
+            battery: None,
+        }
+    }
+
+    fn chip(temperature: u16) -> ChipReadings {
+        ChipReadings {
+            temperature,
+            temperature_cal: 1750,
+            vrefint: 1520,
+            vrefint_cal: 1520,
+        }
+    }
+
+    #[test]
+    fn empty_report() {
+        let mut telemetry = Telemetry::default();
+        let report = telemetry.report();
+        assert_eq!(report[..12], [0xFF; 12]);
+        assert_eq!(report[12..16], [0x00, 0x80, 0x00, 0x80]);
+        assert_eq!(report[16..20], [0xFF; 4]);
+        assert_eq!(report[20..36], [0; 16]);
+    }
+
+    #[test]
+    fn uptime_boot_counts_and_stack() {
+        let mut telemetry = Telemetry::default();
+        telemetry.set_boot_counts(BootCounts { boots: 7, resets: 2 });
+        telemetry.record_stack(1200, 3600);
+        telemetry.set_esp_counts(EspCounts { boots: 40, crashes: 3 });
+        telemetry.esp_counter().record_crash();
+        for _ in 0..2500 {
+            telemetry.tick(1);
+        }
+
+        let report = telemetry.report();
+        assert_eq!(report[20..24], [2, 0, 0, 0]);
+        assert_eq!(report[24..32], [7, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(report[32..36], [0xB0, 0x04, 0x10, 0x0E]);
+        #[cfg(feature = "esp-counters")]
+        assert_eq!(report[36..44], [40, 0, 0, 0, 4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn low_water_mark() {
+        let mut telemetry = Telemetry::default();
+        telemetry.record_supply(readings(3300, 5000));
+        telemetry.record_supply(readings(3280, 4400));
+        telemetry.record_supply(readings(3310, 4950));
+
+        let report = telemetry.report();
+        assert_eq!(
+            report[..12],
+            [
+                0xEE, 0x0C, 0x56, 0x13, 0xFF, 0xFF, // Latest: 3310, 4950, n/a
+                0xD0, 0x0C, 0x30, 0x11, 0xFF, 0xFF, // Lowest: 3280, 4400, n/a
+            ]
+        );
+
+        // The low-water mark restarts from the latest readings.
+        assert_eq!(telemetry.report()[6..12], report[..6]);
+    }
+
+    #[test]
+    fn temperature_conversion() {
+        // At the calibration point
+        assert_eq!(chip(1750).temperature_decidegrees(), 300);
+
+        // The sensor voltage falls as it warms up: 53 counts is ~42.7mV, or ~10°C
+        assert_eq!(chip(1697).temperature_decidegrees(), 399);
+
+        // Same sensor voltage at a lower VDDA gives a higher reading, which VREFINT corrects for
+        let low_vdd = ChipReadings {
+            temperature: 1803,
+            vrefint: 1566,
+            ..chip(0)
+        };
+        assert_eq!(low_vdd.temperature_decidegrees(), 300);
+    }
+
+    #[test]
+    fn highest_temperature() {
+        let mut telemetry = Telemetry::default();
+        telemetry.record_chip(chip(1697));
+        telemetry.record_chip(chip(1750));
+
+        let report = telemetry.report();
+        assert_eq!(i16::from_le_bytes([report[12], report[13]]), 300);
+        assert_eq!(i16::from_le_bytes([report[14], report[15]]), 399);
+        assert_eq!(u16::from_le_bytes([report[16], report[17]]), 1520);
+
+        let report = telemetry.report();
+        assert_eq!(i16::from_le_bytes([report[14], report[15]]), 300);
+    }
+}
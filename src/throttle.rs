@@ -0,0 +1,74 @@
+//! Slow link emulation
+//!
+//! The host can limit either direction to a rate of its choosing (see `request::SET_THROTTLE`), to
+//! check the ESP32's firmware copes with a slow link on the same hardware it ships with. Each
+//! direction has a budget which fills at the rate every millisecond tick, up to one packet's worth,
+//! and every byte through spends from it.
+//!
+//! Towards the ESP32 the UART backlog is drained no faster than the budget, so the host is NAKed
+//! as it would be by a slower UART. The ESP32 can't be held up, so what it sends beyond the budget
+//! is dropped, and counted as if the host weren't reading.
+
+/// The most a budget can save up, in thousandths of a byte.
+const BURST: u32 = 64 * 1000;
+
+/// A rate limit on one direction.
+#[derive(Default)]
+pub struct Throttle {
+    /// Bytes per second, or 0 for no limit.
+    rate: u32,
+    /// Thousandths of a byte.
+    budget: u32,
+}
+
+impl Throttle {
+    /// Sets the limit in bytes per second, or 0 to lift it, starting with an empty budget.
+    pub fn set(&mut self, rate: u32) {
+        self.rate = rate;
+        self.budget = 0;
+    }
+
+    /// Fills the budget.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.budget = self.budget.saturating_add(self.rate.saturating_mul(elapsed_ms)).min(BURST);
+    }
+
+    /// Spends a byte from the budget. Returns false if there isn't one to spend.
+    pub fn take(&mut self) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        if self.budget < 1000 {
+            return false;
+        }
+        self.budget -= 1000;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate() {
+        let mut throttle = Throttle::default();
+        assert!((0..1000).all(|_| throttle.take()));
+
+        // 9600 baud, or 960 bytes a second
+        throttle.set(960);
+        assert!(!throttle.take());
+        let mut sent = 0;
+        for _ in 0..1000 {
+            throttle.tick(1);
+            while throttle.take() {
+                sent += 1;
+            }
+        }
+        assert_eq!(sent, 960);
+
+        // Only a packet's worth saves up.
+        throttle.tick(1000);
+        assert_eq!((0..100).filter(|_| throttle.take()).count(), 64);
+    }
+}
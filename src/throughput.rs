@@ -0,0 +1,71 @@
+//! USB throughput test
+//!
+//! Measures the USB path on its own, without the UART's baud rate getting in the way. While the
+//! test runs, the WebUSB port sends the counter pattern from `tilda_bridge_protocol::throughput`
+//! in full packets whenever the IN endpoint is free, and checks what the host sends against it. A
+//! byte which doesn't follow the one before is counted, and the check carries on from it, so a
+//! lost packet only counts once.
+
+use crate::stats::counts_to_bytes;
+use tilda_bridge_protocol::request::THROUGHPUT_LENGTH;
+use tilda_bridge_protocol::throughput;
+
+/// The throughput test's counts.
+#[derive(Default)]
+pub struct ThroughputTest {
+    sent: u32,
+    received: u32,
+    errors: u32,
+    expected: u8,
+}
+
+impl ThroughputTest {
+    /// Fills `buf` with the next packet's worth of the pattern. Nothing counts as sent until
+    /// `sent` is called, so a packet which couldn't be written goes again.
+    pub fn next_packet(&self, buf: &mut [u8]) {
+        throughput::fill(self.sent, buf);
+    }
+
+    /// Counts a packet as sent.
+    pub fn sent(&mut self, len: usize) {
+        self.sent = self.sent.wrapping_add(len as u32);
+    }
+
+    /// Checks a packet from the host.
+    pub fn receive(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte != self.expected {
+                self.errors = self.errors.wrapping_add(1);
+            }
+            self.expected = byte.wrapping_add(1);
+        }
+        self.received = self.received.wrapping_add(data.len() as u32);
+    }
+
+    /// Encodes the counts for `GET_THROUGHPUT`.
+    pub fn report(&self) -> [u8; THROUGHPUT_LENGTH] {
+        let mut buf = [0u8; THROUGHPUT_LENGTH];
+        counts_to_bytes(&[self.sent, self.received, self.errors], &mut buf);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let mut test = ThroughputTest::default();
+        let mut packet = [0u8; 64];
+        test.next_packet(&mut packet);
+        test.sent(64);
+        test.next_packet(&mut packet);
+        assert_eq!(packet[..2], [64, 65]);
+
+        test.receive(&[0, 1, 2]);
+        // A lost byte is one error, not one for every byte after it.
+        test.receive(&[4, 5, 6]);
+        assert_eq!(test.report(), [64, 0, 0, 0, 6, 0, 0, 0, 1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Millisecond timing
+//!
+//! The main loop's timers all count milliseconds from SysTick, whose reload follows the core clock
+//! as the speed governor changes it (see `speed`). Its exception counts the periods, so none are
+//! lost while the loop sleeps or a pass runs long; the loop takes one a pass until it's caught up.
+//! While the device is configured, the host sends a start of frame packet every millisecond, and
+//! the USB peripheral counts them, so with the `sof-timing` feature the ESP32's reset sequencing,
+//! the control line timers and the flushing of the USB ports tick with the host's frames instead,
+//! falling back to SysTick while there aren't any. `Ticks` turns either into elapsed milliseconds,
+//! and the loop's timeouts, such as the control line grace period and the ESP32's reset sequencing,
+//! are `Timeout`s counting them down.
+
+/// Frame numbers are 11 bits, and wrap every 2048ms.
+const FRAME_MASK: u16 = 0x7FF;
+
+/// Milliseconds from the USB frame number, or SysTick when there are no frames.
+#[derive(Default)]
+pub struct Ticks {
+    last_frame: Option<u16>,
+}
+
+impl Ticks {
+    /// Returns the milliseconds since the last call, which is made for each SysTick period. `frame`
+    /// is the USB peripheral's frame number while the device is configured and it's locked to the
+    /// host's frames, otherwise `None`, and then SysTick's `tick_ms` is taken instead.
+    pub fn elapsed(&mut self, frame: Option<u16>, tick_ms: u32) -> u32 {
+        match (core::mem::replace(&mut self.last_frame, frame), frame) {
+            (Some(last), Some(frame)) => (frame.wrapping_sub(last) & FRAME_MASK) as u32,
+            _ => tick_ms,
+        }
+    }
+}
+
+/// A one-shot timer, for delays and timeouts.
+#[derive(Default)]
+pub struct Timeout {
+    remaining_ms: u32,
+}
+
+impl Timeout {
+    /// Starts the timer, or starts it again, to expire in `ms`.
+    pub fn start(&mut self, ms: u32) {
+        self.remaining_ms = ms;
+    }
+
+    /// Stops the timer without it expiring.
+    pub fn cancel(&mut self) {
+        self.remaining_ms = 0;
+    }
+
+    /// Advances the timer. Returns true if it has just expired.
+    pub fn tick(&mut self, elapsed_ms: u32) -> bool {
+        if self.remaining_ms == 0 {
+            return false;
+        }
+        self.remaining_ms = self.remaining_ms.saturating_sub(elapsed_ms);
+        self.remaining_ms == 0
+    }
+
+    /// Whether the timer is running.
+    pub fn active(&self) -> bool {
+        self.remaining_ms != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_or_systick() {
+        let mut ticks = Ticks::default();
+        assert_eq!(ticks.elapsed(None, 1), 1);
+        // The first frame only gives a starting point.
+        assert_eq!(ticks.elapsed(Some(2046), 1), 1);
+        assert_eq!(ticks.elapsed(Some(2047), 1), 1);
+        assert_eq!(ticks.elapsed(Some(1), 1), 2);
+        assert_eq!(ticks.elapsed(Some(1), 1), 0);
+        // Back to SysTick while suspended, and starting afresh after.
+        assert_eq!(ticks.elapsed(None, 1), 1);
+        assert_eq!(ticks.elapsed(Some(500), 1), 1);
+        assert_eq!(ticks.elapsed(Some(503), 1), 3);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut timeout = Timeout::default();
+        assert!(!timeout.tick(1));
+        timeout.start(3);
+        assert!(timeout.active());
+        assert!(!timeout.tick(2));
+        assert!(timeout.tick(2));
+        assert!(!timeout.active());
+        assert!(!timeout.tick(1));
+
+        timeout.start(3);
+        timeout.cancel();
+        assert!(!timeout.tick(3));
+    }
+}
@@ -0,0 +1,129 @@
+//! Unlock handshake
+//!
+//! With the `unlock` feature, the requests in `GUARDED` are refused until the host has answered a
+//! nonce (see `tilda_bridge_protocol::unlock`), so a page granted WebUSB access can't rewrite the
+//! settings, restart into the bootloader or lock the flash without the identity key.
+//!
+//! Each nonce is new, and only good for one answer, so a response seen on the bus can't be sent
+//! again. They're the identity's MAC over the boot count, a count of nonces given out this boot and
+//! whatever noise the bridge has to hand: the ADC's readings, and the HSI48's error against the
+//! host's start of frame packets and the SysTick's count when the nonce is asked for. That's 12
+//! bytes, which can't be mistaken for an unlock or identity response's message.
+
+pub use tilda_bridge_protocol::unlock::{GUARDED, NONCE_LENGTH, RESPONSE_LENGTH};
+
+use crate::identity::Identity;
+
+/// The boot count, the noise and nonces so far, the response the last nonce expects and whether
+/// it's had it.
+#[derive(Default)]
+pub struct Unlock {
+    boots: u32,
+    noise: u32,
+    nonces: u32,
+    expected: Option<[u8; RESPONSE_LENGTH]>,
+    unlocked: bool,
+}
+
+impl Unlock {
+    /// Sets up the handshake for this boot, from the boot count.
+    pub fn new(boots: u32) -> Self {
+        Unlock { boots, ..Unlock::default() }
+    }
+
+    /// Mixes in a noisy sample.
+    pub fn stir(&mut self, sample: u32) {
+        self.noise = self.noise.rotate_left(7) ^ sample;
+    }
+
+    /// Stirs in a last sample and makes a new nonce for the host to answer, which replaces any
+    /// earlier one.
+    pub fn nonce(&mut self, identity: &Identity, sample: u32) -> [u8; NONCE_LENGTH] {
+        self.stir(sample);
+        self.nonces = self.nonces.wrapping_add(1);
+        let mut message = [0u8; 12];
+        message[..4].copy_from_slice(&self.boots.to_le_bytes());
+        message[4..8].copy_from_slice(&self.nonces.to_le_bytes());
+        message[8..].copy_from_slice(&self.noise.to_le_bytes());
+        let nonce = (identity.mac(&message) as u32).to_le_bytes();
+        self.expected = Some(identity.unlock_response(&nonce));
+        nonce
+    }
+
+    /// Whether the host may send the guarded requests.
+    pub fn unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    /// Takes the host's response, or nothing to lock again. Returns false, leaving it locked, if
+    /// the response is wrong. Either way the nonce is used up.
+    pub fn answer(&mut self, response: &[u8]) -> bool {
+        let expected = self.expected.take();
+        self.unlocked = expected.is_some_and(|expected| expected[..] == *response);
+        self.unlocked || response.is_empty()
+    }
+
+    /// Locks the guarded requests again and drops the nonce, as at a bus reset.
+    pub fn lock(&mut self) {
+        self.unlocked = false;
+        self.expected = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        let identity = Identity::new([0xAA; 12], [0x11; 16]);
+        let mut unlock = Unlock::new(7);
+        // Nothing to answer until there's a nonce
+        assert!(!unlock.answer(&identity.unlock_response(&[7, 0, 0, 0])));
+
+        let nonce = unlock.nonce(&identity, 0);
+        let response = identity.unlock_response(&nonce);
+        assert!(!unlock.unlocked());
+        assert!(!unlock.answer(&response[1..]));
+        // The wrong answer used the nonce up.
+        assert!(!unlock.answer(&response));
+        assert!(!unlock.unlocked());
+
+        let nonce = unlock.nonce(&identity, 0);
+        let response = identity.unlock_response(&nonce);
+        assert!(unlock.answer(&response));
+        assert!(unlock.unlocked());
+        assert!(unlock.answer(&[]));
+        assert!(!unlock.unlocked());
+        // A response only works once.
+        assert!(!unlock.answer(&response));
+    }
+
+    #[test]
+    fn fresh_nonces() {
+        let identity = Identity::new([0xAA; 12], [0x11; 16]);
+        let mut unlock = Unlock::new(7);
+        let first = unlock.nonce(&identity, 0);
+        // The same sample still makes a new one.
+        assert_ne!(unlock.nonce(&identity, 0), first);
+
+        // Another boot with the same noise gets different nonces.
+        let mut next_boot = Unlock::new(8);
+        assert_ne!(next_boot.nonce(&identity, 0), first);
+
+        // As does the same boot with different noise.
+        let mut noisy = Unlock::new(7);
+        noisy.stir(0x1234);
+        assert_ne!(noisy.nonce(&identity, 0), first);
+
+        // Nor does another identity's.
+        let other = Identity::new([0xAA; 12], [0x22; 16]);
+        assert_ne!(Unlock::new(7).nonce(&other, 0), first);
+
+        // Only the last nonce can be answered.
+        let mut unlock = Unlock::new(7);
+        let response = identity.unlock_response(&unlock.nonce(&identity, 0));
+        unlock.nonce(&identity, 1);
+        assert!(!unlock.answer(&response));
+    }
+}
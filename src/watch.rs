@@ -0,0 +1,200 @@
+//! Pattern watching
+//!
+//! The host can give the bridge a few byte patterns to look for in the ESP32's output, like
+//! `Guru Meditation` or `boot:`, and be told when one turns up (see
+//! `tilda_bridge_protocol::event::PATTERN`) without streaming the whole console. What follows a
+//! match, up to the end of the line or `PATTERN_SNIPPET_LENGTH` bytes, goes with it. Nothing is
+//! matched while a snippet is being collected.
+//!
+//! As in `banner`, a byte which doesn't match starts the pattern again from that byte, so a
+//! pattern which repeats its own start, like `aab` in `aaab`, can be missed.
+//!
+//! Each pattern can also have the bridge act on a match itself (see `Action`), so an installation
+//! recovers from a crash even if its host is slow to notice.
+
+use tilda_bridge_protocol::event::PATTERN_SNIPPET_LENGTH;
+use tilda_bridge_protocol::request::{WATCH_PATTERN_LENGTH, WATCH_SLOTS};
+
+/// What the bridge does itself when a pattern matches, from the high byte of `SET_WATCH`'s
+/// `wValue`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Only the notification.
+    #[default]
+    Notify = 0,
+
+    /// Resets the ESP32.
+    Reset = 1,
+
+    /// Resets the ESP32 into its ROM bootloader.
+    Download = 2,
+
+    /// Freezes the capture until the host has read it.
+    Snapshot = 3,
+}
+
+impl Action {
+    /// Converts the high byte of `SET_WATCH`'s `wValue`.
+    pub fn from_u8(value: u8) -> Option<Action> {
+        match value {
+            0 => Some(Action::Notify),
+            1 => Some(Action::Reset),
+            2 => Some(Action::Download),
+            3 => Some(Action::Snapshot),
+            _ => None,
+        }
+    }
+}
+
+/// A match, and what followed it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Snippet {
+    /// Length of the data.
+    len: u8,
+    /// The slot, then the data, as a `PATTERN` notification carries them.
+    payload: [u8; 1 + PATTERN_SNIPPET_LENGTH],
+}
+
+impl Snippet {
+    /// The slot whose pattern matched.
+    pub fn slot(&self) -> u8 {
+        self.payload[0]
+    }
+
+    /// What followed the match.
+    pub fn data(&self) -> &[u8] {
+        &self.payload[1..1 + self.len as usize]
+    }
+
+    /// The slot and data for a `PATTERN` notification. Bytes past the data are zero.
+    pub fn payload(&self) -> (usize, [u8; 1 + PATTERN_SNIPPET_LENGTH]) {
+        (1 + self.len as usize, self.payload)
+    }
+}
+
+/// Looks for the host's patterns in the ESP32's output.
+#[derive(Default)]
+pub struct PatternWatch {
+    patterns: [[u8; WATCH_PATTERN_LENGTH]; WATCH_SLOTS],
+    lengths: [u8; WATCH_SLOTS],
+    matched: [u8; WATCH_SLOTS],
+    actions: [Action; WATCH_SLOTS],
+    /// The snippet being collected after a match.
+    snippet: Option<Snippet>,
+    /// The last complete snippet, until it's been sent.
+    found: Option<Snippet>,
+}
+
+impl PatternWatch {
+    /// Sets the pattern in a slot and what to do when it matches, or clears the slot if `pattern`
+    /// is empty. Returns false if there's no such slot, or the pattern is too long.
+    pub fn set(&mut self, slot: usize, action: Action, pattern: &[u8]) -> bool {
+        if slot >= WATCH_SLOTS || pattern.len() > WATCH_PATTERN_LENGTH {
+            return false;
+        }
+        self.patterns[slot][..pattern.len()].copy_from_slice(pattern);
+        self.lengths[slot] = pattern.len() as u8;
+        self.matched[slot] = 0;
+        self.actions[slot] = action;
+        true
+    }
+
+    /// The last complete snippet, which replaces any before it that hasn't been sent.
+    pub fn found(&self) -> Option<&Snippet> {
+        self.found.as_ref()
+    }
+
+    /// Forgets the last snippet once it's been sent.
+    pub fn sent(&mut self) {
+        self.found = None;
+    }
+
+    /// Feeds a byte from the ESP32 to the watch. Returns the matching slot's action when a pattern
+    /// matches.
+    pub fn feed(&mut self, byte: u8) -> Option<Action> {
+        if let Some(snippet) = &mut self.snippet {
+            if byte != b'\r' && byte != b'\n' {
+                snippet.len += 1;
+                snippet.payload[snippet.len as usize] = byte;
+                if (snippet.len as usize) < PATTERN_SNIPPET_LENGTH {
+                    return None;
+                }
+            }
+            self.found = self.snippet.take();
+            return None;
+        }
+
+        for slot in 0..WATCH_SLOTS {
+            let pattern = &self.patterns[slot][..self.lengths[slot] as usize];
+            let matched = &mut self.matched[slot];
+            if pattern.is_empty() {
+                continue;
+            }
+            if byte == pattern[*matched as usize] {
+                *matched += 1;
+            } else {
+                *matched = (byte == pattern[0]) as u8;
+            }
+            if *matched as usize == pattern.len() {
+                *matched = 0;
+                let mut payload = [0; 1 + PATTERN_SNIPPET_LENGTH];
+                payload[0] = slot as u8;
+                self.snippet = Some(Snippet { len: 0, payload });
+                return Some(self.actions[slot]);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(watch: &mut PatternWatch, rx: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut found = Vec::new();
+        for &byte in rx {
+            watch.feed(byte);
+            if let Some(snippet) = watch.found() {
+                found.push((snippet.slot(), snippet.data().to_vec()));
+                watch.sent();
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn patterns() {
+        let mut watch = PatternWatch::default();
+        assert!(watch.set(0, Action::Notify, b"boot:"));
+        assert!(watch.set(3, Action::Notify, b"Guru Meditation"));
+        assert!(!watch.set(4, Action::Notify, b"rst:"));
+        assert!(!watch.set(1, Action::Notify, &[b'x'; WATCH_PATTERN_LENGTH + 1]));
+
+        let rx = b"rst:0xc (SW_CPU_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\n\
+                   Guru Meditation Error: Core  0 panic'ed\r\nbboot:\r\n";
+        assert_eq!(
+            matches(&mut watch, rx),
+            [
+                (0, b"0x13 (S".to_vec()),
+                (3, b" Error:".to_vec()),
+                (0, Vec::new()),
+            ]
+        );
+
+        // A cleared slot matches nothing.
+        assert!(watch.set(0, Action::Notify, b""));
+        assert!(matches(&mut watch, b"boot:0x13\r\n").is_empty());
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(Action::from_u8(2), Some(Action::Download));
+        assert_eq!(Action::from_u8(4), None);
+
+        let mut watch = PatternWatch::default();
+        assert!(watch.set(1, Action::Reset, b"Guru"));
+        let actions: Vec<_> = b"Guru Meditation".iter().filter_map(|&b| watch.feed(b)).collect();
+        assert_eq!(actions, [Action::Reset]);
+    }
+}
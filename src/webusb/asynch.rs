@@ -0,0 +1,99 @@
+//! Async adapters for `WebUsbClass`
+//!
+//! usb-device is poll-driven, so async firmware still needs a task which calls `UsbDevice::poll`
+//! (e.g. whenever the USB interrupt fires). The class wakes tasks waiting to read or write from
+//! inside that poll, once a packet has arrived or the previous one has been sent.
+//!
+//! The class is shared between the polling task and its users, so these take it in a `RefCell`.
+//! Firmware which shares it some other way can build the same futures from
+//! `WebUsbClass::poll_read_packet` and `poll_write_packet` with `core::future::poll_fn`.
+
+use crate::webusb::WebUsbClass;
+use core::cell::RefCell;
+use core::future::poll_fn;
+use usb_device::bus::UsbBus;
+use usb_device::Result;
+
+/// Waits for a packet from the OUT endpoint and reads it into `data`, which must be at least the
+/// maximum packet size.
+pub async fn read_packet<B: UsbBus>(
+    class: &RefCell<WebUsbClass<'_, B>>,
+    data: &mut [u8],
+) -> Result<usize> {
+    poll_fn(|cx| class.borrow_mut().poll_read_packet(cx, data)).await
+}
+
+/// Waits for the IN endpoint to be free and writes a packet of no more than the maximum packet
+/// size.
+pub async fn write_packet<B: UsbBus>(
+    class: &RefCell<WebUsbClass<'_, B>>,
+    data: &[u8],
+) -> Result<usize> {
+    poll_fn(|cx| class.borrow_mut().poll_write_packet(cx, data)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBus;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use usb_device::prelude::*;
+
+    /// Bulk OUT and IN endpoint numbers.
+    const BULK_OUT: usize = 1;
+    const BULK_IN: usize = 2;
+
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl Flag {
+        fn take(&self) -> bool {
+            self.0.swap(false, Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn read_and_write() {
+        let bus = MockBus::new();
+        let alloc = usb_device::bus::UsbBusAllocator::new(bus.clone());
+        let class = RefCell::new(WebUsbClass::new(&alloc, 64));
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut *class.borrow_mut()]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Nothing to read until the host sends a packet, which wakes the reader.
+        let mut buf = [0u8; 64];
+        let mut read = pin!(read_packet(&class, &mut buf));
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+        bus.push_out(BULK_OUT, b"hello");
+        poll();
+        assert!(flag.take());
+        assert!(matches!(read.as_mut().poll(&mut cx), Poll::Ready(Ok(5))));
+
+        // The second write has to wait for the first packet to be collected.
+        let mut write = pin!(write_packet(&class, b"one"));
+        assert!(matches!(write.as_mut().poll(&mut cx), Poll::Ready(Ok(3))));
+        let mut write = pin!(write_packet(&class, b"two"));
+        assert!(write.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(bus.take_in_data(BULK_IN), b"one");
+        poll();
+        assert!(flag.take());
+        assert!(matches!(write.as_mut().poll(&mut cx), Poll::Ready(Ok(3))));
+    }
+}
@@ -55,7 +55,7 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
             return 0;
         }
 
-        &self.store.borrow_mut()[self.wpos..self.wpos+count].copy_from_slice(&data[..count]);
+        self.store.borrow_mut()[self.wpos..self.wpos+count].copy_from_slice(&data[..count]);
 
         self.wpos += count;
         count
@@ -80,9 +80,8 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
 
         assert!(self.available_write_without_discard() >= max_count);
 
-        f(&mut self.store.borrow_mut()[self.wpos..self.wpos+max_count]).map(|count| {
+        f(&mut self.store.borrow_mut()[self.wpos..self.wpos+max_count]).inspect(|count| {
             self.wpos += count;
-            count
         })
     }
 
@@ -95,9 +94,8 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
     {
         let count = cmp::min(max_count, self.available_read());
 
-        f(&self.store.borrow()[self.rpos..self.rpos+count]).map(|count| {
+        f(&self.store.borrow()[self.rpos..self.rpos+count]).inspect(|count| {
             self.rpos += count;
-            count
         })
     }
 
@@ -120,6 +118,12 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
 /// Default backing store for the mediocre buffer
 pub struct DefaultBufferStore([u8; 128]);
 
+impl Default for DefaultBufferStore {
+    fn default() -> Self {
+        DefaultBufferStore([0; 128])
+    }
+}
+
 impl Borrow<[u8]> for DefaultBufferStore {
     fn borrow(&self) -> &[u8] {
         &self.0
@@ -134,15 +138,13 @@ impl BorrowMut<[u8]> for DefaultBufferStore {
 
 #[cfg(test)]
 mod tests {
-    extern crate std;
-
     const DATA: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
     const LEN: usize = 5;
-    type Buf = crate::buffer::Buffer<generic_array::typenum::consts::U5>;
+    type Buf = super::Buffer<[u8; LEN]>;
 
     #[test]
     fn write() {
-        let mut b = Buf::new();
+        let mut b = Buf::new([0; LEN]);
 
         assert_eq!(b.write(&DATA[0..2]), 2);
         assert_eq!(b.available_write(), LEN - 2);
@@ -155,24 +157,27 @@ mod tests {
 
     #[test]
     fn read() {
-        let mut b = Buf::new();
+        let mut b = Buf::new([0; LEN]);
 
         assert_eq!(b.write(&DATA[0..4]), 4);
 
         b.read(3, |data| {
             assert_eq!(data, &DATA[0..3]);
-        });
+            Ok::<_, ()>(data.len())
+        }).unwrap();
         b.read(1, |data| {
             assert_eq!(data, &DATA[3..4]);
-        });
+            Ok::<_, ()>(data.len())
+        }).unwrap();
         b.read(1, |data| {
             assert_eq!(data, &[]);
-        });
+            Ok::<_, ()>(data.len())
+        }).unwrap();
     }
 
     #[test]
     fn clear() {
-        let mut b = Buf::new();
+        let mut b = Buf::new([0; LEN]);
 
         b.write(&DATA[0..2]);
         b.clear();
@@ -183,17 +188,19 @@ mod tests {
 
     #[test]
     fn discard() {
-        let mut b = Buf::new();
+        let mut b = Buf::new([0; LEN]);
 
         assert_eq!(b.write(&DATA[0..4]), 4);
         b.read(2, |data| {
             assert_eq!(data, &DATA[0..2]);
-        });
+            Ok::<_, ()>(data.len())
+        }).unwrap();
 
         assert_eq!(b.write(&DATA[4..7]), 3);
         b.read(5, |data| {
             assert_eq!(data, &DATA[2..7]);
-        });
+            Ok::<_, ()>(data.len())
+        }).unwrap();
 
         assert_eq!(b.available_read(), 0);
     }
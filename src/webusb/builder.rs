@@ -1,35 +1,55 @@
-
-
+/// Writes a descriptor into a buffer, little-endian. Its methods are `const`, so fixed descriptors
+/// can be built at compile time (see `msos`).
 pub struct DescriptorBuilder<'a> {
     buf: &'a mut [u8],
     position: usize
 }
 
+/// A length field which `DescriptorBuilder::end` fills in, once what it counts has been written.
+/// Lengths can nest, as long as the inner one ends first.
+#[must_use]
+pub struct Length {
+    /// Where what's counted starts.
+    start: usize,
+    /// Where the field is.
+    field: usize,
+    /// Whether the field is a u16 rather than a u8.
+    wide: bool,
+}
+
 impl DescriptorBuilder<'_> {
-    pub fn new(buf: &mut [u8]) -> DescriptorBuilder<'_> {
+    pub const fn new(buf: &mut [u8]) -> DescriptorBuilder<'_> {
         DescriptorBuilder{
             buf,
             position: 0
         }
     }
 
-    pub fn position(&self) -> usize {
+    pub const fn position(&self) -> usize {
         self.position
     }
 
-    pub fn write(&mut self, bytes: &[u8]) {
-        let length = bytes.len();
-        self.buf[self.position..self.position + length].copy_from_slice(bytes);
-        self.position += length;
+    pub const fn write(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            self.buf[self.position + i] = bytes[i];
+            i += 1;
+        }
+        self.position += bytes.len();
+    }
+
+    pub const fn write_u8(&mut self, val: u8) {
+        self.buf[self.position] = val;
+        self.position += 1
     }
 
-    pub fn write_u16(&mut self, val: u16) {
+    pub const fn write_u16(&mut self, val: u16) {
         self.buf[self.position] = (val & 0xFF) as u8;
         self.buf[self.position + 1] = (val >> 8) as u8;
         self.position += 2
     }
 
-    pub fn write_u32(&mut self, val: u32) {
+    pub const fn write_u32(&mut self, val: u32) {
         self.buf[self.position] = (val & 0xFF) as u8;
         self.buf[self.position + 1] = (val >> 8) as u8;
         self.buf[self.position + 2] = (val >> 16) as u8;
@@ -37,13 +57,193 @@ impl DescriptorBuilder<'_> {
         self.position += 4
     }
 
-    pub fn write_utf16(&mut self, val: &str) {
-        for cp in val.encode_utf16() {
-            self.write_u16(cp);
+    pub const fn write_utf16(&mut self, val: &str) {
+        let mut units = [0; 2];
+        let mut chars = Utf16::new(val);
+        while let Some(len) = chars.next(&mut units) {
+            self.write_u16(units[0]);
+            if len == 2 {
+                self.write_u16(units[1]);
+            }
+        }
+    }
+
+    /// Writes an ASCII string as it is, with no length or terminator.
+    pub const fn write_ascii(&mut self, val: &str) {
+        assert!(val.is_ascii());
+        self.write(val.as_bytes());
+    }
+
+    /// Writes a UUID as a GUID structure, as in platform capability descriptors: the first three
+    /// groups little-endian, and the last two as they're written.
+    pub const fn write_uuid(&mut self, uuid: u128) {
+        self.write_u32((uuid >> 96) as u32);
+        self.write_u16((uuid >> 80) as u16);
+        self.write_u16((uuid >> 64) as u16);
+        self.write(&(uuid as u64).to_be_bytes());
+    }
+
+    /// Starts a standard descriptor: bLength, which `end` fills in, then bDescriptorType.
+    pub const fn begin_descriptor(&mut self, descriptor_type: u8) -> Length {
+        let length = self.length_u8(self.position);
+        self.write_u8(descriptor_type);
+        length
+    }
+
+    /// Leaves a u8 length field here, of what's written from `start` until `end`.
+    pub const fn length_u8(&mut self, start: usize) -> Length {
+        let length = Length { start, field: self.position, wide: false };
+        self.write_u8(0);
+        length
+    }
+
+    /// Leaves a u16 length field here, of what's written from `start` until `end`.
+    pub const fn length_u16(&mut self, start: usize) -> Length {
+        let length = Length { start, field: self.position, wide: true };
+        self.write_u16(0);
+        length
+    }
+
+    /// Fills in a length field with how much has been written since its start.
+    pub const fn end(&mut self, length: Length) {
+        let len = self.position - length.start;
+        self.buf[length.field] = len as u8;
+        if length.wide {
+            assert!(len <= u16::MAX as usize);
+            self.buf[length.field + 1] = (len >> 8) as u8;
+        } else {
+            assert!(len <= u8::MAX as usize);
+        }
+    }
+
+    pub const fn buf(&self) -> &[u8] {
+        self.buf.split_at(self.position).0
+    }
+}
+
+/// How many bytes `write_utf16` writes for a string.
+pub const fn utf16_len(val: &str) -> usize {
+    let mut units = [0; 2];
+    let mut chars = Utf16::new(val);
+    let mut len = 0;
+    while let Some(count) = chars.next(&mut units) {
+        len += count * 2;
+    }
+    len
+}
+
+/// Walks a string's characters as UTF-16, as `str::encode_utf16` does but in a `const fn`.
+struct Utf16<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Utf16<'_> {
+    const fn new(val: &str) -> Utf16<'_> {
+        Utf16 { bytes: val.as_bytes(), position: 0 }
+    }
+
+    /// Puts the next character's code units in `units`, and returns how many there are.
+    const fn next(&mut self, units: &mut [u16; 2]) -> Option<usize> {
+        if self.position == self.bytes.len() {
+            return None;
+        }
+        // The string is valid UTF-8, so the lead byte gives the length.
+        let lead = self.bytes[self.position] as u32;
+        let (len, mut c) = match lead {
+            0x00..=0x7F => (1, lead),
+            0xC0..=0xDF => (2, lead & 0x1F),
+            0xE0..=0xEF => (3, lead & 0x0F),
+            _ => (4, lead & 0x07),
+        };
+        let mut i = 1;
+        while i < len {
+            c = c << 6 | (self.bytes[self.position + i] as u32 & 0x3F);
+            i += 1;
+        }
+        self.position += len;
+
+        if c < 0x1_0000 {
+            units[0] = c as u16;
+            Some(1)
+        } else {
+            let c = c - 0x1_0000;
+            units[0] = 0xD800 | (c >> 10) as u16;
+            units[1] = 0xDC00 | (c & 0x3FF) as u16;
+            Some(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{utf16_len, DescriptorBuilder};
+
+    #[test]
+    fn little_endian() {
+        let mut buf = [0u8; 8];
+        let mut db = DescriptorBuilder::new(&mut buf);
+        db.write_u16(0x1234);
+        db.write_u32(0x0603_0000);
+
+        assert_eq!(db.position(), 6);
+        assert_eq!(db.buf(), &[0x34, 0x12, 0x00, 0x00, 0x03, 0x06]);
+    }
+
+    #[test]
+    fn utf16() {
+        let mut buf = [0u8; 8];
+        let mut db = DescriptorBuilder::new(&mut buf);
+        db.write_utf16("A\0");
+
+        assert_eq!(db.buf(), &[0x41, 0x00, 0x00, 0x00]);
+
+        for s in ["TiLDA", "Mk\u{e9}", "\u{20ac}5", "\u{1f600}"] {
+            let expected: Vec<u8> = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+            let mut buf = [0u8; 16];
+            let mut db = DescriptorBuilder::new(&mut buf);
+            db.write_utf16(s);
+            assert_eq!(db.buf(), &expected[..]);
+            assert_eq!(utf16_len(s), expected.len());
         }
     }
 
-    pub fn buf(&self) -> &[u8] {
-        &self.buf[0..self.position]
+    #[test]
+    fn uuid() {
+        let mut buf = [0u8; 16];
+        let mut db = DescriptorBuilder::new(&mut buf);
+        db.write_uuid(0x3408b638_09a9_47a0_8bfd_a0768815b665);
+
+        assert_eq!(
+            db.buf(),
+            &[
+                0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47, 0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15,
+                0xB6, 0x65,
+            ]
+        );
+    }
+
+    #[test]
+    fn lengths() {
+        let mut buf = [0u8; 16];
+        let mut db = DescriptorBuilder::new(&mut buf);
+        let outer = db.begin_descriptor(0x03);
+        db.write_ascii("ab");
+        let inner = db.length_u16(db.position());
+        db.write_u32(0);
+        db.end(inner);
+        db.end(outer);
+
+        assert_eq!(db.buf(), &[10, 0x03, b'a', b'b', 6, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn length_too_long() {
+        let mut buf = [0u8; 300];
+        let mut db = DescriptorBuilder::new(&mut buf);
+        let length = db.begin_descriptor(0x03);
+        db.write(&[0; 254]);
+        db.end(length);
     }
 }
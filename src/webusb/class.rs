@@ -1,6 +1,16 @@
+use crate::baud::{self, DEFAULT_BAUD};
+use crate::events::{EventQueue, Stamp};
+use crate::stats::{OutputDrops, Port};
+use crate::throughput::ThroughputTest;
 use crate::webusb::builder::DescriptorBuilder;
-use core::convert::TryInto;
-use core::mem;
+use crate::webusb::msos::{
+    self, CompatibleId, ConfigurationSubset, DescriptorSet, FunctionSubset, RegistryProperty,
+};
+use crate::webusb::vendor::{NoVendorRequests, VendorRequests};
+use core::convert::TryFrom;
+use core::task::{Context, Poll, Waker};
+use tilda_bridge_protocol::event;
+use tilda_bridge_protocol::request::THROUGHPUT_LENGTH;
 use usb_device::class_prelude::*;
 use usb_device::Result;
 
@@ -10,23 +20,124 @@ const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
 const CDC_TYPE_ACM: u8 = 0x02;
 const CDC_TYPE_UNION: u8 = 0x06;
 
+const INTERFACE_ASSOCIATION: u8 = 0x0B;
+
+/// What the host calls the WebUSB interfaces, from the interface association descriptor's
+/// iFunction. usb-device doesn't let a class set iInterface.
+const FUNCTION_NAME: &str = "TiLDA MkV bridge";
+
 const WEBUSB_VENDOR_CODE: u8 = 0x42;
 const WEBUSB_GET_URL: u16 = 0x02;
 const WEBUSB_DESCRIPTOR_URL: u8 = 0x03;
 const WEBUSB_SCHEME_HTTPS: u8 = 0x01;
+const WEBUSB_PLATFORM_UUID: u128 = 0x3408b638_09a9_47a0_8bfd_a0768815b665;
+const WEBUSB_URL: &str = "tide.emfcamp.org";
 
 const MS_VENDOR_CODE: u8 = 0x43;
 const MS_GET_DESCRIPTOR_SET: u16 = 0x07;
+const MS_OS_20_PLATFORM_UUID: u128 = 0xd8dd60df_4589_4cc7_9cd2_659d9e648a9f;
+
+const MS_DEVICE_UUID: &str = "{f37ccce8-a70f-492a-acfb-cf2b2dab56a3}\0\0";
+
+/// The legacy Microsoft OS 1.0 string descriptor, which Windows 7 and 8.0 read to find the
+/// Microsoft vendor code: "MSFT100" then the vendor code, whose UTF-16 high byte is the pad.
+#[cfg(feature = "ms-os-10")]
+const MS_OS_10_STRING_INDEX: u8 = 0xEE;
+#[cfg(feature = "ms-os-10")]
+const MS_OS_10_SIGNATURE: &[u8; 7] = b"MSFT100";
+const MS_GET_COMPAT_ID: u16 = 0x04;
+const MS_GET_EXTENDED_PROPERTIES: u16 = 0x05;
+const MS_EXTENDED_PROPERTIES_LENGTH: usize = 146;
+
+/// The Microsoft OS 1.0 extended compat ID descriptor, which binds WinUSB to the WebUSB function.
+static MS_COMPAT_ID: [u8; 40] = [
+    40, 0x00, 0x00, 0x00, // dwLength
+    0x00, 0x01, // bcdVersion
+    0x04, 0x00, // wIndex
+    0x01, // bCount
+    0, 0, 0, 0, 0, 0, 0, // reserved
+    0x02, // bFirstInterfaceNumber. NOTE: hardcoded, as in the 2.0 set
+    0x01, // reserved
+    b'W', b'I', b'N', b'U', b'S', b'B', 0, 0, // compatibleID
+    0, 0, 0, 0, 0, 0, 0, 0, // subCompatibleID
+    0, 0, 0, 0, 0, 0, // reserved
+];
+
+/// The Microsoft OS 1.0 extended properties descriptor, with the same DeviceInterfaceGUIDs as the
+/// 2.0 set.
+static MS_EXTENDED_PROPERTIES: [u8; MS_EXTENDED_PROPERTIES_LENGTH] = ms_extended_properties();
+
+/// The Microsoft OS 2.0 descriptor set: WinUSB and the DeviceInterfaceGUIDs for the WebUSB
+/// function. NOTE: its interface number is hardcoded, as in the 1.0 descriptors.
+const MS_OS_20: DescriptorSet = DescriptorSet {
+    windows_version: msos::WINDOWS_8_1,
+    configurations: &[ConfigurationSubset {
+        configuration: 0,
+        functions: &[FunctionSubset {
+            first_interface: 0x02,
+            compatible_id: Some(CompatibleId::WINUSB),
+            properties: &[RegistryProperty {
+                data_type: msos::REG_MULTI_SZ,
+                name: "DeviceInterfaceGUIDs\0",
+                data: MS_DEVICE_UUID,
+            }],
+        }],
+    }],
+};
+const MS_OS_20_SET_LENGTH: u16 = MS_OS_20.length();
+
+/// The Microsoft OS 2.0 descriptor set. It never changes, so it's built at compile time rather than
+/// on every request, which saves a few hundred bytes of flash.
+static MS_OS_20_SET: [u8; MS_OS_20_SET_LENGTH as usize] = MS_OS_20.render();
+
+/// The WebUSB and Microsoft OS 2.0 platform capability descriptors. Copies from flash are smaller
+/// than the code to build them at run time. The vendor codes, and whether there's a landing page,
+/// are filled in when they're sent.
+static WEBUSB_CAPABILITY: [u8; 21] = webusb_capability();
+const WEBUSB_CAPABILITY_VENDOR_CODE: usize = 19;
+const WEBUSB_CAPABILITY_LANDING_PAGE: usize = 20;
+static MS_OS_20_CAPABILITY: [u8; 25] = ms_os_20_capability();
+const MS_OS_20_CAPABILITY_VENDOR_CODE: usize = 23;
+
+/// The WebUSB URL descriptor for the landing page.
+static WEBUSB_URL_DESCRIPTOR: [u8; 3 + WEBUSB_URL.len()] = webusb_url(WEBUSB_URL);
+
+/// The `bRequest` codes the host uses to fetch the WebUSB and Microsoft OS descriptors, which it
+/// learns from the BOS descriptor (and the Microsoft OS 1.0 string). They're vendor requests to the
+/// device, and in a composite device they mustn't clash with another class's, or with each other.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct VendorCodes {
+    /// bVendorCode of the WebUSB platform capability.
+    pub webusb: u8,
+
+    /// bMS_VendorCode of the Microsoft OS 2.0 platform capability, also used for the Microsoft OS
+    /// 1.0 descriptors.
+    pub ms: u8,
+}
 
-static MS_DEVICE_UUID: &str = "{f37ccce8-a70f-492a-acfb-cf2b2dab56a3}\0\0";
+impl Default for VendorCodes {
+    fn default() -> Self {
+        VendorCodes {
+            webusb: WEBUSB_VENDOR_CODE,
+            ms: MS_VENDOR_CODE,
+        }
+    }
+}
 
 const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
 #[allow(unused)]
 const REQ_GET_ENCAPSULATED_COMMAND: u8 = 0x01;
+const REQ_SET_COMM_FEATURE: u8 = 0x02;
+const REQ_GET_COMM_FEATURE: u8 = 0x03;
+const REQ_CLEAR_COMM_FEATURE: u8 = 0x04;
 const REQ_SET_LINE_CODING: u8 = 0x20;
 const REQ_GET_LINE_CODING: u8 = 0x21;
 const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
 
+/// Comm feature selectors, from wValue of the comm feature requests.
+const ABSTRACT_STATE: u16 = 0x01;
+const COUNTRY_SETTING: u16 = 0x02;
+
 /// Packet level implementation of a CDC-ACM serial port.
 ///
 /// This class can be used directly and it has the least overhead due to directly reading and
@@ -41,24 +152,86 @@ const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
 ///   host operating system until a subsequent shorter packet is sent. A zero-length packet (ZLP)
 ///   can be sent if there is no other data to send. This is because USB bulk transactions must be
 ///   terminated with a short packet, even if the bulk endpoint is used for stream-like data.
+///
+/// The class answers the CDC requests and the host's requests for its WebUSB and Microsoft OS
+/// descriptors. Vendor requests to the interface go to a `VendorRequests` handler (see `vendor`).
 pub struct WebUsbClass<'a, B: UsbBus> {
+    name: StringIndex,
     comm_if: InterfaceNumber,
-    comm_ep: EndpointIn<'a, B>,
+    /// `None` if the class was built without its interrupt endpoint.
+    comm_ep: Option<EndpointIn<'a, B>>,
     data_if: InterfaceNumber,
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
     line_coding: LineCoding,
+    /// The rate the UART was last set to, and the one it really runs at.
+    uart_rate: (u32, u32),
+    /// Whether the UART ignored the host's line coding, following the other port's.
+    line_coding_overruled: bool,
+    /// The port the UART follows, for a UART_RATE notification waiting to go.
+    pending_uart_rate: Option<Port>,
+    comm_features: CommFeatures,
     dtr: bool,
     rts: bool,
+    /// The host's changes, for `poll_event`.
+    events: EventQueue<WebUsbEvent>,
+    monitor: bool,
+    hexdump: bool,
+    owner: Option<Port>,
+    timestamps: bool,
+    throughput: ThroughputTest,
+    throughput_running: bool,
+    compatibility: bool,
+    landing_page: bool,
+    /// The longest to hold a partial line of the ESP32's output, in ms, or 0 not to.
+    #[cfg(feature = "line-buffer")]
+    line_buffer_ms: u16,
+    line_coding_rejects: u32,
+    output_drops: OutputDrops,
+    /// Milliseconds since startup, from `tick`.
+    millis: u32,
+    pending_serial_state: u16,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    webusb_vendor_code: u8,
+    ms_vendor_code: u8,
+    /// From `ms_os_10_string`.
+    #[cfg(feature = "ms-os-10")]
+    ms_os_10_string: [u8; 9],
 }
 
 impl<B: UsbBus> WebUsbClass<'_, B> {
     /// Creates a new WebUsbClass with the provided UsbBus and max_packet_size in bytes. For
     /// full-speed devices, max_packet_size has to be one of 8, 16, 32 or 64.
     pub fn new(alloc: &UsbBusAllocator<B>, max_packet_size: u16) -> WebUsbClass<'_, B> {
+        WebUsbClass::new_with_interval(alloc, max_packet_size, Some(255))
+    }
+
+    /// Creates a new WebUsbClass whose interrupt endpoint is polled every `interval` ms, or which
+    /// has no interrupt endpoint if `interval` is `None`. Without one, SERIAL_STATE and ESP_RESET
+    /// notifications are dropped.
+    pub fn new_with_interval(
+        alloc: &UsbBusAllocator<B>,
+        max_packet_size: u16,
+        interval: Option<u8>,
+    ) -> WebUsbClass<'_, B> {
+        WebUsbClass::new_with_vendor_codes(alloc, max_packet_size, interval, VendorCodes::default())
+    }
+
+    /// Creates a new WebUsbClass as `new_with_interval` does, which has the host fetch its WebUSB
+    /// and Microsoft OS descriptors with `vendor_codes`.
+    #[inline(never)]
+    pub fn new_with_vendor_codes(
+        alloc: &UsbBusAllocator<B>,
+        max_packet_size: u16,
+        interval: Option<u8>,
+        vendor_codes: VendorCodes,
+    ) -> WebUsbClass<'_, B> {
         WebUsbClass {
+            name: alloc.string(),
             comm_if: alloc.interface(),
-            comm_ep: alloc.interrupt(8, 255),
+            // Big enough for a SERIAL_STATE notification in one packet
+            comm_ep: interval.map(|interval| alloc.interrupt(16, interval)),
             data_if: alloc.interface(),
             read_ep: alloc.bulk(max_packet_size),
             write_ep: alloc.bulk(max_packet_size),
@@ -68,8 +241,33 @@ impl<B: UsbBus> WebUsbClass<'_, B> {
                 parity_type: ParityType::None,
                 data_rate: 8_000,
             },
+            uart_rate: (DEFAULT_BAUD, baud::achieved(DEFAULT_BAUD)),
+            line_coding_overruled: false,
+            pending_uart_rate: None,
+            comm_features: CommFeatures::default(),
             dtr: false,
             rts: false,
+            events: EventQueue::default(),
+            monitor: false,
+            hexdump: false,
+            owner: None,
+            timestamps: false,
+            throughput: ThroughputTest::default(),
+            throughput_running: false,
+            compatibility: false,
+            landing_page: true,
+            #[cfg(feature = "line-buffer")]
+            line_buffer_ms: 0,
+            line_coding_rejects: 0,
+            output_drops: OutputDrops::default(),
+            millis: 0,
+            pending_serial_state: 0,
+            read_waker: None,
+            write_waker: None,
+            webusb_vendor_code: vendor_codes.webusb,
+            ms_vendor_code: vendor_codes.ms,
+            #[cfg(feature = "ms-os-10")]
+            ms_os_10_string: ms_os_10_string(vendor_codes.ms),
         }
     }
 
@@ -85,6 +283,39 @@ impl<B: UsbBus> WebUsbClass<'_, B> {
         &self.line_coding
     }
 
+    /// Records that the UART has been set to `rate`, which it really runs at as `achieved`.
+    pub fn set_uart_rate(&mut self, rate: u32, achieved: u32) {
+        self.uart_rate = (rate, achieved);
+    }
+
+    /// Records that the UART doesn't follow the host's line coding on this port but the `source`
+    /// port's, having ignored the host's or changed to the other port's, and lets the host know
+    /// with a UART_RATE notification. GET_LINE_CODING returns what the UART does until the host
+    /// sets another.
+    pub fn overrule_line_coding(&mut self, source: Port) {
+        self.line_coding_overruled = true;
+        self.pending_uart_rate = Some(source);
+        self.send_uart_rate();
+    }
+
+    /// The line coding for GET_LINE_CODING. Once the host's has been applied, or overruled, that's
+    /// what the UART really does: the rate the divisor gives, and always 8N1. Until then, it's the
+    /// host's own.
+    fn applied_line_coding(&self) -> LineCoding {
+        let (rate, achieved) = self.uart_rate;
+        if self.line_coding_overruled || rate == self.line_coding.data_rate {
+            LineCoding { data_rate: achieved, ..LineCoding::default() }
+        } else {
+            self.line_coding
+        }
+    }
+
+    /// Gets the comm features set by the host. Like the line coding, they're only kept so the
+    /// host can read them back.
+    pub fn comm_features(&self) -> &CommFeatures {
+        &self.comm_features
+    }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool {
         self.dtr
@@ -95,24 +326,433 @@ impl<B: UsbBus> WebUsbClass<'_, B> {
         self.rts
     }
 
+    /// Takes the oldest change the host has made, if there's one waiting. The getters only have
+    /// the latest state, so a change which was reversed before they're next read would be lost.
+    pub fn poll_event(&mut self) -> Option<WebUsbEvent> {
+        self.events.pop()
+    }
+
+    /// When the oldest waiting change was made, to put it in order with the other port's.
+    #[inline(never)]
+    pub fn next_event_stamp(&self) -> Option<Stamp> {
+        self.events.next_stamp()
+    }
+
+    /// Whether the port is a read-only monitor: it gets the ESP32's output, but data and control
+    /// line changes from the host are thrown away.
+    pub fn monitor(&self) -> bool {
+        self.monitor
+    }
+
+    /// Whether the port is a monitor which gets a hex dump of the serial port's traffic (see
+    /// `hexdump`) rather than the ESP32's output.
+    pub fn hexdump(&self) -> bool {
+        self.hexdump
+    }
+
+    /// Makes the port a monitor, with a hex dump or not, or an ordinary port again. A monitor lets
+    /// go of the ESP32's pins, in case the port had them held.
+    pub fn set_monitor(&mut self, monitor: bool, hexdump: bool) {
+        self.monitor = monitor;
+        self.hexdump = monitor && hexdump;
+        if self.monitor && (self.dtr || self.rts) {
+            self.dtr = false;
+            self.rts = false;
+            self.events.push(WebUsbEvent::ControlLines { dtr: false, rts: false });
+        }
+    }
+
+    /// Whether `port`'s data and control lines should reach the ESP32: unless the host has claimed
+    /// them for the other port, both ports share them.
+    pub fn may_drive(&self, port: Port) -> bool {
+        self.owner.is_none_or(|owner| owner == port)
+    }
+
+    /// Gives one port the ESP32's data and control lines, or with `None`, has both share them.
+    pub fn set_owner(&mut self, owner: Option<Port>) {
+        self.owner = owner;
+    }
+
+    /// Leaves the WebUSB and Microsoft OS 2.0 capabilities out of the BOS descriptor, for hosts
+    /// which trip over them, or puts them back. The host only sees it once the device has dropped
+    /// off the bus and come back.
+    pub fn set_compatibility(&mut self, compatibility: bool) {
+        self.compatibility = compatibility;
+    }
+
+    /// Sets whether the WebUSB capability offers the landing page.
+    pub fn set_landing_page(&mut self, landing_page: bool) {
+        self.landing_page = landing_page;
+    }
+
+    /// Whether the host wants the ESP32's output framed with timestamps (see
+    /// `tilda_bridge_protocol::frame`).
+    pub fn timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    /// Frames the ESP32's output with timestamps, or stops.
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    /// The longest the host wants a partial line of the ESP32's output held, in ms, or 0 if it
+    /// wants it as it comes.
+    #[cfg(feature = "line-buffer")]
+    pub fn line_buffer_ms(&self) -> u16 {
+        self.line_buffer_ms
+    }
+
+    /// Sets the longest to hold a partial line of the ESP32's output, in ms, or 0 not to.
+    #[cfg(feature = "line-buffer")]
+    pub fn set_line_buffer_ms(&mut self, line_buffer_ms: u16) {
+        self.line_buffer_ms = line_buffer_ms;
+    }
+
+    /// Whether the throughput test has the data interface (see `throughput`).
+    pub fn throughput(&self) -> bool {
+        self.throughput_running
+    }
+
+    /// Starts the throughput test afresh, or stops it, keeping its counts for the host.
+    pub fn set_throughput(&mut self, running: bool) {
+        if running {
+            self.throughput = ThroughputTest::default();
+        }
+        self.throughput_running = running;
+    }
+
+    /// The throughput test's counts, for `GET_THROUGHPUT`.
+    pub fn throughput_report(&self) -> [u8; THROUGHPUT_LENGTH] {
+        self.throughput.report()
+    }
+
+    /// Milliseconds since startup, for timestamps.
+    pub fn millis(&self) -> u32 {
+        self.millis
+    }
+
+    /// Advances the clock timestamps are taken from.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.millis = self.millis.wrapping_add(elapsed_ms);
+    }
+
+    /// Records bytes of the ESP32's output dropped from a port because its queue was full.
+    pub fn record_dropped(&mut self, port: Port, count: usize) {
+        self.output_drops.record(port, count);
+    }
+
+    /// Gets the counts of the ESP32's output dropped from each port.
+    pub fn output_drops(&self) -> &OutputDrops {
+        &self.output_drops
+    }
+
+    /// How many SET_LINE_CODING requests have been rejected.
+    pub fn line_coding_rejects(&self) -> u32 {
+        self.line_coding_rejects
+    }
+
+    /// Clears the dropped output and line coding counts.
+    pub fn clear_errors(&mut self) {
+        self.line_coding_rejects = 0;
+        self.output_drops = OutputDrops::default();
+    }
+
+    /// Re-arms the OUT endpoint after an error, discarding any packet it was holding.
+    pub fn recover_read_ep(&mut self) {
+        self.read_ep.stall();
+        self.read_ep.unstall();
+    }
+
+    /// Adds `bits` (the `event::SERIAL_STATE_*` flags) to the SERIAL_STATE notification for the
+    /// host, and sends it.
+    pub fn notify_serial_state(&mut self, bits: u16) {
+        self.pending_serial_state |= bits;
+        self.send_serial_state();
+    }
+
+    /// Sends any pending SERIAL_STATE notification. If the endpoint is busy it's left pending for
+    /// the next poll, with any further errors merged in.
+    fn send_serial_state(&mut self) {
+        if self.pending_serial_state == 0 {
+            return;
+        }
+
+        // bmRequestType: class, interface, device to host
+        let state = self.pending_serial_state.to_le_bytes();
+        if self.send_notification(0xA1, event::SERIAL_STATE, &state) {
+            self.pending_serial_state = 0;
+        }
+    }
+
+    /// Tells the host the rate the UART runs at, and the port it follows. If the endpoint is busy
+    /// it's left pending for the next poll.
+    fn send_uart_rate(&mut self) {
+        let source = match self.pending_uart_rate {
+            Some(source) => source,
+            None => return,
+        };
+
+        let rate = self.uart_rate.1.to_le_bytes();
+        if self.notify(event::UART_RATE, &[rate[0], rate[1], rate[2], rate[3], source as u8]) {
+            self.pending_uart_rate = None;
+        }
+    }
+
+    /// Sends a vendor notification (see `tilda_bridge_protocol::event`) with up to 8 bytes of
+    /// `data`. Returns false if the endpoint is busy, for the caller to try again on a later poll.
+    /// Without the endpoint, the notification is dropped.
+    pub fn notify(&self, request: u8, data: &[u8]) -> bool {
+        // bmRequestType: vendor, interface, device to host
+        self.send_notification(0xC1, request, data)
+    }
+
+    /// Writes a notification to the interrupt endpoint, returning false if it's busy.
+    fn send_notification(&self, request_type: u8, request: u8, data: &[u8]) -> bool {
+        let mut notification = [0u8; 16];
+        notification[..8].copy_from_slice(&[
+            request_type,
+            request,
+            0x00, 0x00, // wValue
+            u8::from(self.comm_if), 0x00, // wIndex
+            data.len() as u8, 0x00, // wLength
+        ]);
+        notification[8..8 + data.len()].copy_from_slice(data);
+        let notification = &notification[..8 + data.len()];
+        self.comm_ep.as_ref().is_none_or(|ep| ep.write(notification).is_ok())
+    }
+
+    /// Sends the throughput test's next packet if the IN endpoint is free, and checks any packet
+    /// from the host. Runs whenever the device is polled, which includes every time a packet has
+    /// gone or arrived.
+    #[inline(never)]
+    fn run_throughput(&mut self) {
+        if !self.throughput_running {
+            return;
+        }
+        let mut packet = [0u8; 64];
+        let len = self.write_ep.max_packet_size() as usize;
+        self.throughput.next_packet(&mut packet[..len]);
+        if self.write_ep.write(&packet[..len]).is_ok() {
+            self.throughput.sent(len);
+        }
+        if let Ok(count) = self.read_ep.read(&mut packet) {
+            self.throughput.receive(&packet[..count]);
+        }
+    }
+
     /// Writes a single packet into the IN endpoint.
     pub fn write_packet(&mut self, data: &[u8]) -> Result<usize> {
         self.write_ep.write(data)
     }
 
-    /// Reads a single packet from the OUT endpoint.
+    /// Reads a single packet from the OUT endpoint. In monitor mode, or while the serial port has
+    /// claimed the UART, packets are thrown away, so there's never anything to read. While the
+    /// throughput test runs, they're left for it.
     pub fn read_packet(&mut self, data: &mut [u8]) -> Result<usize> {
-        self.read_ep.read(data)
+        if self.throughput_running {
+            return Err(UsbError::WouldBlock);
+        }
+        let count = self.read_ep.read(data)?;
+        if self.monitor || !self.may_drive(Port::WebUsb) {
+            return Err(UsbError::WouldBlock);
+        }
+        Ok(count)
+    }
+
+    /// Reads a single packet from the OUT endpoint, or if there isn't one, arranges for the task to
+    /// be woken when one arrives. See `webusb::asynch`.
+    pub fn poll_read_packet(&mut self, cx: &mut Context<'_>, data: &mut [u8]) -> Poll<Result<usize>> {
+        match self.read_packet(data) {
+            Err(UsbError::WouldBlock) => {
+                register(&mut self.read_waker, cx.waker());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Writes a single packet into the IN endpoint, or if the previous one hasn't been sent yet,
+    /// arranges for the task to be woken when it has. See `webusb::asynch`.
+    pub fn poll_write_packet(&mut self, cx: &mut Context<'_>, data: &[u8]) -> Poll<Result<usize>> {
+        match self.write_ep.write(data) {
+            Err(UsbError::WouldBlock) => {
+                register(&mut self.write_waker, cx.waker());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
     }
 
     /// Gets the address of the IN endpoint.
     pub(crate) fn write_ep_address(&self) -> EndpointAddress {
         self.write_ep.address()
     }
+
+    /// Whether a request is a vendor request addressed to the communication interface, which go
+    /// to the `VendorRequests` handler.
+    fn is_vendor_request(&self, req: &control::Request) -> bool {
+        req.request_type == control::RequestType::Vendor
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.comm_if) as u16
+    }
+
+    /// Handles a control IN request as `UsbClass::control_in` does, passing the vendor requests
+    /// to the interface to `vendor`.
+    #[inline(never)]
+    pub fn control_in_with<V: VendorRequests<B>>(&mut self, xfer: ControlIn<B>, vendor: &mut V) {
+        let req = *xfer.request();
+
+        if self.is_vendor_request(&req) {
+            vendor.control_in(self, xfer);
+            return;
+        }
+
+        // Ignore control messages not directed at this interface, except for WebUSB
+        if !(req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.comm_if) as u16)
+            && req.request != self.webusb_vendor_code
+            && req.request != self.ms_vendor_code
+        {
+            return;
+        }
+
+        match req.request {
+            // REQ_GET_ENCAPSULATED_COMMAND is not really supported - it will be rejected below.
+            REQ_GET_LINE_CODING if req.length == 7 => {
+                xfer.accept_with(&self.applied_line_coding().to_bytes()).ok();
+            }
+            REQ_GET_COMM_FEATURE if req.length == 2 => match self.comm_features.get(req.value) {
+                Some(value) => {
+                    xfer.accept_with(&value.to_le_bytes()).ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            },
+            // The landing page is the only URL, and iLandingPage is 1. Any other index has to stall.
+            code if code == self.webusb_vendor_code
+                && req.index == WEBUSB_GET_URL
+                && req.value == 1 =>
+            {
+                xfer.accept_with(&WEBUSB_URL_DESCRIPTOR).ok();
+            }
+            code if code == self.ms_vendor_code && req.index == MS_GET_DESCRIPTOR_SET => {
+                xfer.accept_with(&MS_OS_20_SET).ok();
+            }
+            code if code == self.ms_vendor_code
+                && cfg!(feature = "ms-os-10")
+                && req.index == MS_GET_COMPAT_ID =>
+            {
+                xfer.accept_with(&MS_COMPAT_ID).ok();
+            }
+            code if code == self.ms_vendor_code
+                && cfg!(feature = "ms-os-10")
+                && req.index == MS_GET_EXTENDED_PROPERTIES =>
+            {
+                xfer.accept_with(&MS_EXTENDED_PROPERTIES).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    /// Handles a control OUT request as `UsbClass::control_out` does, passing the vendor requests
+    /// to the interface to `vendor`. Those it accepts are queued as `WebUsbEvent::Vendor` events.
+    #[inline(never)]
+    pub fn control_out_with<V: VendorRequests<B>>(&mut self, xfer: ControlOut<B>, vendor: &mut V) {
+        let req = *xfer.request();
+
+        if self.is_vendor_request(&req) {
+            if vendor.control_out(self, &xfer) {
+                self.events.push(WebUsbEvent::Vendor { request: req.request, value: req.value });
+                xfer.accept().ok();
+            } else {
+                xfer.reject().ok();
+            }
+            return;
+        }
+
+        if !(req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.comm_if) as u16)
+        {
+            return;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                // We don't actually support encapsulated commands but pretend we do for standards
+                // compatibility.
+                xfer.accept().ok();
+            }
+            REQ_SET_LINE_CODING if xfer.data().len() >= 7 => match LineCoding::parse(xfer.data()) {
+                Some(line_coding) => {
+                    if line_coding.data_rate != self.line_coding.data_rate {
+                        self.events.push(WebUsbEvent::LineCoding(line_coding.data_rate));
+                    }
+                    self.line_coding = line_coding;
+                    self.line_coding_overruled = false;
+                    xfer.accept().ok();
+                }
+                None => {
+                    self.line_coding_rejects = self.line_coding_rejects.wrapping_add(1);
+                    xfer.reject().ok();
+                }
+            },
+            REQ_SET_COMM_FEATURE => match *xfer.data() {
+                [lo, hi, ..] if self.comm_features.set(req.value, u16::from_le_bytes([lo, hi])) => {
+                    xfer.accept().ok();
+                }
+                _ => {
+                    xfer.reject().ok();
+                }
+            },
+            REQ_CLEAR_COMM_FEATURE if self.comm_features.set(req.value, 0) => {
+                xfer.accept().ok();
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                let dtr = (req.value & 0x0001) != 0;
+                let rts = (req.value & 0x0002) != 0;
+                // A monitor mustn't reset the ESP32 out from under whoever owns the serial port.
+                if !self.monitor && (dtr, rts) != (self.dtr, self.rts) {
+                    // Each change can reset the ESP32 or put it in download mode.
+                    if !vendor.allow_control_lines() {
+                        xfer.reject().ok();
+                        return;
+                    }
+                    self.dtr = dtr;
+                    self.rts = rts;
+                    self.events.push(WebUsbEvent::ControlLines { dtr, rts });
+                }
+
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        };
+    }
 }
 
 impl<B: UsbBus> UsbClass<B> for WebUsbClass<'_, B> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // Groups the two interfaces under one name. This needs the device class to be 0xEF/0x02/0x01
+        // (miscellaneous, interface association).
+        writer.write(
+            INTERFACE_ASSOCIATION,
+            &[
+                self.comm_if.into(), // bFirstInterface
+                0x02,                // bInterfaceCount
+                0xFF,                // bFunctionClass: Vendor specific
+                0x00,                // bFunctionSubClass
+                0x00,                // bFunctionProtocol
+                self.name.into(),    // iFunction
+            ],
+        )?;
+
         writer.interface(
             self.comm_if,
             0xFF, // Interface class: Vendor specific
@@ -133,7 +773,7 @@ impl<B: UsbBus> UsbClass<B> for WebUsbClass<'_, B> {
             CS_INTERFACE,
             &[
                 CDC_TYPE_ACM, // bDescriptorSubtype
-                0x00,         // bmCapabilities
+                0x01,         // bmCapabilities: comm feature requests
             ],
         )?;
 
@@ -155,7 +795,9 @@ impl<B: UsbBus> UsbClass<B> for WebUsbClass<'_, B> {
             ],
         )?;
 
-        writer.endpoint(&self.comm_ep)?;
+        if let Some(comm_ep) = &self.comm_ep {
+            writer.endpoint(comm_ep)?;
+        }
 
         writer.interface(
             self.data_if,
@@ -169,201 +811,198 @@ impl<B: UsbBus> UsbClass<B> for WebUsbClass<'_, B> {
         Ok(())
     }
 
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        #[cfg(feature = "ms-os-10")]
+        if u8::from(index) == MS_OS_10_STRING_INDEX {
+            // Windows asks for it with no language.
+            let len = MS_OS_10_SIGNATURE.len() + char::from(self.ms_vendor_code).len_utf8();
+            // Safe as it's the signature and a whole character.
+            return Some(unsafe { core::str::from_utf8_unchecked(&self.ms_os_10_string[..len]) });
+        }
+        if index == self.name && lang_id == usb_device::descriptor::lang_id::ENGLISH_US {
+            Some(FUNCTION_NAME)
+        } else {
+            None
+        }
+    }
+
     fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        if self.compatibility {
+            return Ok(());
+        }
+
         // WebUSB BOS descriptor
-        writer.capability(
-            0x05,
-            &[
-                0x00, // bReserved
-                // UUID
-                0x38,
-                0xB6,
-                0x08,
-                0x34,
-                0xA9,
-                0x09,
-                0xA0,
-                0x47,
-                0x8B,
-                0xFD,
-                0xA0,
-                0x76,
-                0x88,
-                0x15,
-                0xB6,
-                0x65,
-                0x00,               // bcdVersion (LSB)
-                0x01,               // bcdVersion (MSB)
-                WEBUSB_VENDOR_CODE, // bVendorCode
-                0x01,               // iLandingPage
-            ],
-        )?;
+        let mut webusb = WEBUSB_CAPABILITY;
+        webusb[WEBUSB_CAPABILITY_VENDOR_CODE] = self.webusb_vendor_code;
+        webusb[WEBUSB_CAPABILITY_LANDING_PAGE] = self.landing_page as u8;
+        writer.capability(0x05, &webusb)?;
         // Microsoft OS 2.0 platform capability descriptor
-        writer.capability(
-            0x05,
-            &[
-                0x00, // bReserved
-                //UUID
-                0xDF,
-                0x60,
-                0xDD,
-                0xD8,
-                0x89,
-                0x45,
-                0xC7,
-                0x4C,
-                0x9C,
-                0xD2,
-                0x65,
-                0x9D,
-                0x9E,
-                0x64,
-                0x8A,
-                0x9F,
-                0x00,           // dwWindowsVersion
-                0x00,           // ..
-                0x03,           // ..
-                0x06,           // ..
-                0xB2,           // wMSOSDescriptorSetTotalLength
-                0x00,           // ..
-                MS_VENDOR_CODE, // bMS_VendorCode
-                0x00,           // bAltEnumCode
-            ],
-        )?;
+        let mut ms_os = MS_OS_20_CAPABILITY;
+        ms_os[MS_OS_20_CAPABILITY_VENDOR_CODE] = self.ms_vendor_code;
+        writer.capability(0x05, &ms_os)?;
 
         Ok(())
     }
 
     fn reset(&mut self) {
         self.line_coding = LineCoding::default();
+        self.line_coding_overruled = false;
+        self.pending_uart_rate = None;
+        #[cfg(feature = "line-buffer")]
+        {
+            self.line_buffer_ms = 0;
+        }
+        self.comm_features = CommFeatures::default();
         self.dtr = false;
         self.rts = false;
+        // A bus reset clears the lines without the host asking for anything.
+        self.events.clear();
+        self.monitor = false;
+        self.hexdump = false;
+        self.owner = None;
+        self.timestamps = false;
+        self.throughput_running = false;
+        self.pending_serial_state = 0;
+
+        // Let any waiting tasks see the endpoints have been reset.
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
     }
 
-    fn control_in(&mut self, xfer: ControlIn<B>) {
-        let req = xfer.request();
+    fn poll(&mut self) {
+        self.send_serial_state();
+        self.send_uart_rate();
+        self.run_throughput();
+    }
 
-        // Ignore control messages not directed at this interface, except for WebUSB
-        if !(req.request_type == control::RequestType::Class
-            && req.recipient == control::Recipient::Interface
-            && req.index == u8::from(self.comm_if) as u16)
-            && req.request != WEBUSB_VENDOR_CODE
-            && req.request != MS_VENDOR_CODE
-        {
-            return;
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.read_ep.address() {
+            wake(&mut self.read_waker);
         }
+    }
 
-        match req.request {
-            // REQ_GET_ENCAPSULATED_COMMAND is not really supported - it will be rejected below.
-            REQ_GET_LINE_CODING if req.length == 7 => {
-                xfer.accept(|data| {
-                    data[0..4].copy_from_slice(&self.line_coding.data_rate.to_le_bytes());
-                    data[4] = self.line_coding.stop_bits as u8;
-                    data[5] = self.line_coding.parity_type as u8;
-                    data[6] = self.line_coding.data_bits;
-
-                    Ok(7)
-                })
-                .ok();
-            }
-            WEBUSB_VENDOR_CODE if req.index == WEBUSB_GET_URL => {
-                // WebUSB URL descriptor (spec section 4.3)
-                let url = b"tide.emfcamp.org\0"; // Does this need a null-terminator or am I off-by-one somewhere?
-                xfer.accept(|data| {
-                    let length = url.len() + 2;
-                    data[0] = length as u8;
-                    data[1] = WEBUSB_DESCRIPTOR_URL;
-                    data[2] = WEBUSB_SCHEME_HTTPS;
-                    data[3..3 + url.len()].copy_from_slice(url);
-                    Ok(length)
-                })
-                .ok();
-            }
-            MS_VENDOR_CODE if req.index == MS_GET_DESCRIPTOR_SET => {
-                let mut buf: [u8; 180] = [0; 180];
-                let mut db = DescriptorBuilder::new(&mut buf);
-
-                // Microsoft OS 2.0 descriptor set header
-                db.write_u16(0x000A); // wLength
-                db.write_u16(0x0000); // wDescriptorType
-                db.write_u32(0x0603_0000); // dwWindowsVersion
-                db.write_u16(0x00B2); // wTotalLength
-
-                // Microsoft OS 2.0 configuration subset header
-                db.write_u16(0x0008); // wLength
-                db.write_u16(0x0001); // wDescriptorType
-                db.write(&[0x00, 0x00]); // NOTE: hardcoded configuration 1 here
-                db.write_u16(0x00A8);
-
-                // Microsoft OS 2.0 function subset header
-                db.write_u16(0x0008);
-                db.write_u16(0x0002);
-                db.write(&[0x02, 0x00]);
-                db.write_u16(0x00A0);
-
-                // Microsoft OS 2.0 compatible ID descriptor
-                db.write_u16(0x0014);
-                db.write_u16(0x0003);
-                db.write(b"WINUSB\0\0");
-                db.write(b"\0\0\0\0\0\0\0\0");
-
-                // Microsoft OS 2.0 registry property descriptor
-                db.write_u16(0x0084);
-                db.write_u16(0x0004);
-                db.write_u16(0x0007);
-                db.write_u16(0x002A);
-                db.write_utf16("DeviceInterfaceGUIDs\0");
-                db.write_u16(0x0050);
-                db.write_utf16(MS_DEVICE_UUID);
-
-                xfer.accept_with(db.buf()).ok();
-            }
-            _ => {
-                xfer.reject().ok();
-            }
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.write_ep.address() {
+            wake(&mut self.write_waker);
         }
     }
 
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.control_in_with(xfer, &mut NoVendorRequests);
+    }
+
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        let req = xfer.request();
+        self.control_out_with(xfer, &mut NoVendorRequests);
+    }
+}
 
-        if !(req.request_type == control::RequestType::Class
-            && req.recipient == control::Recipient::Interface
-            && req.index == u8::from(self.comm_if) as u16)
-        {
-            return;
-        }
+/// Builds the Microsoft OS 1.0 string for a vendor code, UTF-8 encoded: `MS_OS_10_SIGNATURE` then
+/// the code as a character, padded with zeroes.
+#[cfg(feature = "ms-os-10")]
+fn ms_os_10_string(vendor_code: u8) -> [u8; 9] {
+    let mut string = [0; 9];
+    string[..MS_OS_10_SIGNATURE.len()].copy_from_slice(MS_OS_10_SIGNATURE);
+    char::from(vendor_code).encode_utf8(&mut string[MS_OS_10_SIGNATURE.len()..]);
+    string
+}
 
-        match req.request {
-            REQ_SEND_ENCAPSULATED_COMMAND => {
-                // We don't actually support encapsulated commands but pretend we do for standards
-                // compatibility.
-                xfer.accept().ok();
-            }
-            REQ_SET_LINE_CODING if xfer.data().len() >= 7 => {
-                self.line_coding.data_rate =
-                    u32::from_le_bytes(xfer.data()[0..4].try_into().unwrap());
-                self.line_coding.stop_bits = xfer.data()[4].into();
-                self.line_coding.parity_type = xfer.data()[5].into();
-                self.line_coding.data_bits = xfer.data()[6];
+const fn webusb_capability() -> [u8; 21] {
+    let mut capability = [0; 21];
+    let mut db = DescriptorBuilder::new(&mut capability);
+    db.write_u8(0x00); // bReserved
+    db.write_uuid(WEBUSB_PLATFORM_UUID);
+    db.write_u16(0x0100); // bcdVersion
+    db.write_u8(WEBUSB_VENDOR_CODE); // bVendorCode
+    db.write_u8(0x01); // iLandingPage: 0 for none
+    capability
+}
 
-                xfer.accept().ok();
-            }
-            REQ_SET_CONTROL_LINE_STATE => {
-                self.dtr = (req.value & 0x0001) != 0;
-                self.rts = (req.value & 0x0002) != 0;
+const fn ms_os_20_capability() -> [u8; 25] {
+    let mut capability = [0; 25];
+    let mut db = DescriptorBuilder::new(&mut capability);
+    db.write_u8(0x00); // bReserved
+    db.write_uuid(MS_OS_20_PLATFORM_UUID);
+    db.write_u32(msos::WINDOWS_8_1); // dwWindowsVersion
+    db.write_u16(MS_OS_20_SET_LENGTH); // wMSOSDescriptorSetTotalLength
+    db.write_u8(MS_VENDOR_CODE); // bMS_VendorCode
+    db.write_u8(0x00); // bAltEnumCode
+    capability
+}
 
-                xfer.accept().ok();
-            }
-            _ => {
-                xfer.reject().ok();
-            }
-        };
+/// Builds the WebUSB URL descriptor (spec section 4.3) for an https:// URL.
+const fn webusb_url<const N: usize>(url: &str) -> [u8; N] {
+    let mut descriptor = [0; N];
+    let mut db = DescriptorBuilder::new(&mut descriptor);
+    let length = db.begin_descriptor(WEBUSB_DESCRIPTOR_URL);
+    db.write_u8(WEBUSB_SCHEME_HTTPS); // bScheme
+    db.write_ascii(url); // URL
+    db.end(length);
+    assert!(db.position() == N);
+    descriptor
+}
+
+const fn ms_extended_properties() -> [u8; MS_EXTENDED_PROPERTIES_LENGTH] {
+    let headers = [
+        MS_EXTENDED_PROPERTIES_LENGTH as u8, 0x00, 0x00, 0x00, // dwLength
+        0x00, 0x01, // bcdVersion
+        0x05, 0x00, // wIndex
+        0x01, 0x00, // wCount
+
+        // Custom property section
+        0x88, 0x00, 0x00, 0x00, // dwSize
+        0x07, 0x00, 0x00, 0x00, // dwPropertyDataType: REG_MULTI_SZ
+        0x2A, 0x00, // wPropertyNameLength
+    ];
+
+    let (set, pos) = put([0; MS_EXTENDED_PROPERTIES_LENGTH], 0, &headers);
+    let (set, pos) = put_utf16(set, pos, "DeviceInterfaceGUIDs\0");
+    let (set, pos) = put(set, pos, &0x0000_0050u32.to_le_bytes());
+    let (set, pos) = put_utf16(set, pos, MS_DEVICE_UUID);
+    assert!(pos == MS_EXTENDED_PROPERTIES_LENGTH);
+    set
+}
+
+/// Copies `bytes` into `set` at `pos`, returning the set and the position after them.
+const fn put<const N: usize>(mut set: [u8; N], mut pos: usize, bytes: &[u8]) -> ([u8; N], usize) {
+    let mut i = 0;
+    while i < bytes.len() {
+        set[pos] = bytes[i];
+        pos += 1;
+        i += 1;
     }
+    (set, pos)
+}
+
+/// Copies an ASCII string into `set` at `pos` as UTF-16.
+const fn put_utf16<const N: usize>(mut set: [u8; N], mut pos: usize, ascii: &str) -> ([u8; N], usize) {
+    let bytes = ascii.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        set[pos] = bytes[i];
+        set[pos + 1] = 0;
+        pos += 2;
+        i += 1;
+    }
+    (set, pos)
+}
+
+/// A change the host has made to the WebUSB port, from `WebUsbClass::poll_event`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WebUsbEvent {
+    /// SET_CONTROL_LINE_STATE, or the port becoming a monitor and letting go of the lines, changed
+    /// DTR or RTS to these levels (true when asserted).
+    ControlLines { dtr: bool, rts: bool },
+
+    /// SET_LINE_CODING changed the baud rate.
+    LineCoding(u32),
+
+    /// The `VendorRequests` handler accepted a vendor request from the host, with this `bRequest`
+    /// and `wValue`.
+    Vendor { request: u8, value: u16 },
 }
 
 /// Number of stop bits for LineCoding
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum StopBits {
     /// 1 stop bit
     One = 0,
@@ -375,18 +1014,22 @@ pub enum StopBits {
     Two = 2,
 }
 
-impl From<u8> for StopBits {
-    fn from(value: u8) -> Self {
-        if value <= 2 {
-            unsafe { mem::transmute(value) }
-        } else {
-            StopBits::One
+impl TryFrom<u8> for StopBits {
+    type Error = u8;
+
+    /// Converts a `bCharFormat` value, returning it back if it's not valid.
+    fn try_from(value: u8) -> core::result::Result<Self, u8> {
+        match value {
+            0 => Ok(StopBits::One),
+            1 => Ok(StopBits::OnePointFive),
+            2 => Ok(StopBits::Two),
+            _ => Err(value),
         }
     }
 }
 
 /// Parity for LineCoding
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum ParityType {
     None = 0,
     Odd = 1,
@@ -395,12 +1038,18 @@ pub enum ParityType {
     Space = 4,
 }
 
-impl From<u8> for ParityType {
-    fn from(value: u8) -> Self {
-        if value <= 4 {
-            unsafe { mem::transmute(value) }
-        } else {
-            ParityType::None
+impl TryFrom<u8> for ParityType {
+    type Error = u8;
+
+    /// Converts a `bParityType` value, returning it back if it's not valid.
+    fn try_from(value: u8) -> core::result::Result<Self, u8> {
+        match value {
+            0 => Ok(ParityType::None),
+            1 => Ok(ParityType::Odd),
+            2 => Ok(ParityType::Event),
+            3 => Ok(ParityType::Mark),
+            4 => Ok(ParityType::Space),
+            _ => Err(value),
         }
     }
 }
@@ -409,6 +1058,7 @@ impl From<u8> for ParityType {
 ///
 /// This is provided by the host for specifying the standard UART parameters such as baud rate. Can
 /// be ignored if you don't plan to interface with a physical UART.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct LineCoding {
     stop_bits: StopBits,
     data_bits: u8,
@@ -417,6 +1067,35 @@ pub struct LineCoding {
 }
 
 impl LineCoding {
+    /// Parses the 7 byte line coding structure sent with SET_LINE_CODING. Returns `None` if it's
+    /// too short or any of the fields aren't valid CDC values.
+    pub(crate) fn parse(data: &[u8]) -> Option<LineCoding> {
+        let [r0, r1, r2, r3, stop_bits, parity_type, data_bits, ..] = *data else {
+            return None;
+        };
+        let data_rate = u32::from_le_bytes([r0, r1, r2, r3]);
+        if data_rate == 0 || !matches!(data_bits, 5..=8 | 16) {
+            return None;
+        }
+
+        Some(LineCoding {
+            data_rate,
+            stop_bits: StopBits::try_from(stop_bits).ok()?,
+            parity_type: ParityType::try_from(parity_type).ok()?,
+            data_bits,
+        })
+    }
+
+    /// Encodes the line coding structure as returned by GET_LINE_CODING.
+    pub(crate) fn to_bytes(self) -> [u8; 7] {
+        let mut data = [0u8; 7];
+        data[0..4].copy_from_slice(&self.data_rate.to_le_bytes());
+        data[4] = self.stop_bits as u8;
+        data[5] = self.parity_type as u8;
+        data[6] = self.data_bits;
+        data
+    }
+
     /// Gets the number of stop bits for UART communication.
     pub fn stop_bits(&self) -> StopBits {
         self.stop_bits
@@ -448,3 +1127,449 @@ impl Default for LineCoding {
         }
     }
 }
+
+/// Communication features (CDC PSTN spec section 6.3.2)
+///
+/// Neither means anything to a UART, but hosts which set them get them back from
+/// GET_COMM_FEATURE rather than a stall.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct CommFeatures {
+    abstract_state: u16,
+    country_setting: u16,
+}
+
+impl CommFeatures {
+    /// The value of the feature with the given selector, or `None` if there's no such feature.
+    fn get(&self, selector: u16) -> Option<u16> {
+        match selector {
+            ABSTRACT_STATE => Some(self.abstract_state),
+            COUNTRY_SETTING => Some(self.country_setting),
+            _ => None,
+        }
+    }
+
+    /// Sets the feature with the given selector. Returns false if there's no such feature.
+    fn set(&mut self, selector: u16, value: u16) -> bool {
+        match selector {
+            ABSTRACT_STATE => self.abstract_state = value,
+            COUNTRY_SETTING => self.country_setting = value,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Gets the abstract state: bit 0 is the idle setting, and bit 1 the data multiplexed state.
+    pub fn abstract_state(&self) -> u16 {
+        self.abstract_state
+    }
+
+    /// Gets the country setting, an ISO 3166 country code.
+    pub fn country_setting(&self) -> u16 {
+        self.country_setting
+    }
+}
+
+/// Stores a waker, unless it would wake the same task as the one already stored.
+fn register(slot: &mut Option<Waker>, waker: &Waker) {
+    if !slot.as_ref().is_some_and(|stored| stored.will_wake(waker)) {
+        *slot = Some(waker.clone());
+    }
+}
+
+#[inline(never)]
+fn wake(slot: &mut Option<Waker>) {
+    if let Some(waker) = slot.take() {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBus, TransferError};
+    use crate::webusb::{WebUSB, WebUsbBuilder};
+    use usb_device::prelude::*;
+
+    const DESCRIPTOR_BOS: u8 = 0x0F;
+
+    /// Runs `f` against an enumerated device with just the WebUSB class on a simulated bus.
+    fn with_device(f: impl FnOnce(&MockBus, &mut dyn FnMut())) {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+
+        bus.enumerate(&mut poll).unwrap();
+        f(&bus, &mut poll);
+    }
+
+    /// Splits a BOS descriptor into its device capabilities.
+    fn capabilities(bos: &[u8]) -> Vec<&[u8]> {
+        let mut caps = Vec::new();
+        let mut rest = &bos[bos[0] as usize..];
+        while !rest.is_empty() {
+            let (cap, next) = rest.split_at(rest[0] as usize);
+            caps.push(cap);
+            rest = next;
+        }
+        caps
+    }
+
+    #[test]
+    fn bos_descriptor() {
+        with_device(|bus, poll| {
+            let bos = bus.get_descriptor(poll, DESCRIPTOR_BOS, 0, 0xFF).unwrap();
+            assert_eq!(u16::from_le_bytes([bos[2], bos[3]]) as usize, bos.len());
+
+            // usb-device adds a USB 2.0 extension capability ahead of our two platform ones
+            let caps = capabilities(&bos);
+            assert_eq!(bos[4] as usize, caps.len());
+            let platform: Vec<_> = caps.into_iter().filter(|cap| cap[2] == 0x05).collect();
+            assert_eq!(platform.len(), 2);
+
+            let webusb = platform[0];
+            assert_eq!(webusb.len(), 24);
+            assert_eq!(webusb[22], WEBUSB_VENDOR_CODE);
+            assert_eq!(webusb[23], 1);
+
+            let ms_os = platform[1];
+            assert_eq!(ms_os.len(), 28);
+            assert_eq!(u16::from_le_bytes([ms_os[24], ms_os[25]]), MS_OS_20_SET_LENGTH);
+            assert_eq!(ms_os[26], MS_VENDOR_CODE);
+        });
+    }
+
+    #[test]
+    fn function_name() {
+        with_device(|bus, poll| {
+            let config = bus.get_descriptor(poll, 0x02, 0, 0xFF).unwrap();
+            let iad = &config[config[0] as usize..];
+            assert_eq!(iad[..7], [8, INTERFACE_ASSOCIATION, 0, 2, 0xFF, 0, 0]);
+
+            let value = 0x0300 | iad[7] as u16;
+            let name = bus.control_read(poll, 0x00, 0x06, value, 0x0409, 0xFF).unwrap();
+            let name: Vec<u16> =
+                name[2..].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            assert_eq!(String::from_utf16(&name).unwrap(), FUNCTION_NAME);
+        });
+    }
+
+    /// The descriptor set as it used to be written out at run time, to check the compile time
+    /// version against.
+    fn write_ms_os_20_descriptor_set(db: &mut DescriptorBuilder) {
+        // Microsoft OS 2.0 descriptor set header
+        db.write_u16(0x000A); // wLength
+        db.write_u16(0x0000); // wDescriptorType
+        db.write_u32(0x0603_0000); // dwWindowsVersion
+        db.write_u16(MS_OS_20_SET_LENGTH); // wTotalLength
+
+        // Microsoft OS 2.0 configuration subset header
+        db.write_u16(0x0008); // wLength
+        db.write_u16(0x0001); // wDescriptorType
+        db.write(&[0x00, 0x00]); // NOTE: hardcoded configuration 1 here
+        db.write_u16(0x00A8);
+
+        // Microsoft OS 2.0 function subset header
+        db.write_u16(0x0008);
+        db.write_u16(0x0002);
+        db.write(&[0x02, 0x00]);
+        db.write_u16(0x00A0);
+
+        // Microsoft OS 2.0 compatible ID descriptor
+        db.write_u16(0x0014);
+        db.write_u16(0x0003);
+        db.write(b"WINUSB\0\0");
+        db.write(b"\0\0\0\0\0\0\0\0");
+
+        // Microsoft OS 2.0 registry property descriptor
+        db.write_u16(0x0084);
+        db.write_u16(0x0004);
+        db.write_u16(0x0007);
+        db.write_u16(0x002A);
+        db.write_utf16("DeviceInterfaceGUIDs\0");
+        db.write_u16(0x0050);
+        db.write_utf16(MS_DEVICE_UUID);
+    }
+
+    #[test]
+    fn ms_os_10_descriptors() {
+        with_device(|bus, poll| {
+            let string = bus.control_read(poll, 0x00, 0x06, 0x03EE, 0, 0xFF);
+            let compat_id = bus.control_read(poll, 0x40, MS_VENDOR_CODE, 0, MS_GET_COMPAT_ID, 0xFF);
+            let properties = bus.control_read(
+                poll,
+                0x41,
+                MS_VENDOR_CODE,
+                2,
+                MS_GET_EXTENDED_PROPERTIES,
+                0xFF,
+            );
+            if !cfg!(feature = "ms-os-10") {
+                assert_eq!(string, Err(TransferError::Stall));
+                assert_eq!(compat_id, Err(TransferError::Stall));
+                assert_eq!(properties, Err(TransferError::Stall));
+                return;
+            }
+
+            let string = string.unwrap();
+            assert_eq!(string[..2], [18, 0x03]);
+            let signature: Vec<u8> = b"MSFT100".iter().flat_map(|&c| [c, 0]).collect();
+            assert_eq!(string[2..16], signature[..]);
+            assert_eq!(string[16..], [MS_VENDOR_CODE, 0]);
+
+            let compat_id = compat_id.unwrap();
+            assert_eq!(compat_id.len(), compat_id[0] as usize);
+            assert_eq!(compat_id[18..24], *b"WINUSB");
+
+            let properties = properties.unwrap();
+            assert_eq!(properties.len(), properties[0] as usize);
+            let section = &properties[10..];
+            assert_eq!(section.len(), section[0] as usize);
+            let name_length = section[8] as usize;
+            let data = &section[10 + name_length + 4..];
+            assert_eq!(data.len(), section[10 + name_length] as usize);
+            assert_eq!(data[..2], *b"{\0");
+        });
+    }
+
+    #[test]
+    fn ms_os_20_descriptor_request() {
+        with_device(|bus, poll| {
+            let set = bus
+                .control_read(poll, 0xC0, MS_VENDOR_CODE, 0, MS_GET_DESCRIPTOR_SET, 0xFFFF)
+                .unwrap();
+
+            assert_eq!(set, &MS_OS_20_SET[..]);
+
+            let mut expected = [0u8; MS_OS_20_SET_LENGTH as usize];
+            write_ms_os_20_descriptor_set(&mut DescriptorBuilder::new(&mut expected));
+            assert_eq!(set, &expected[..]);
+        });
+    }
+
+    #[test]
+    fn webusb_url_request() {
+        with_device(|bus, poll| {
+            let url = bus
+                .control_read(poll, 0xC0, WEBUSB_VENDOR_CODE, 1, WEBUSB_GET_URL, 0xFF)
+                .unwrap();
+
+            assert_eq!(url[0] as usize, url.len());
+            assert_eq!(url[1], WEBUSB_DESCRIPTOR_URL);
+            assert_eq!(url[2], WEBUSB_SCHEME_HTTPS);
+            assert_eq!(&url[3..], b"tide.emfcamp.org");
+
+            // There's no URL at any other index.
+            for index in [0, 2] {
+                let url =
+                    bus.control_read(poll, 0xC0, WEBUSB_VENDOR_CODE, index, WEBUSB_GET_URL, 0xFF);
+                assert_eq!(url, Err(TransferError::Stall));
+            }
+        });
+    }
+
+    #[test]
+    fn vendor_codes() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let codes = VendorCodes { webusb: 0x50, ms: 0xA1 };
+        let mut webusb = WebUsbBuilder::new(&alloc).vendor_codes(codes).build();
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        let bos = bus.get_descriptor(&mut poll, DESCRIPTOR_BOS, 0, 0xFF).unwrap();
+        let platform: Vec<_> =
+            capabilities(&bos).into_iter().filter(|cap| cap[2] == 0x05).collect();
+        assert_eq!(platform[0][22], 0x50);
+        assert_eq!(platform[1][26], 0xA1);
+
+        assert!(bus.control_read(&mut poll, 0xC0, 0x50, 1, WEBUSB_GET_URL, 0xFF).is_ok());
+        let set = bus.control_read(&mut poll, 0xC0, 0xA1, 0, MS_GET_DESCRIPTOR_SET, 0xFFFF);
+        assert_eq!(set.unwrap(), &MS_OS_20_SET[..]);
+        let url = bus.control_read(&mut poll, 0xC0, WEBUSB_VENDOR_CODE, 1, WEBUSB_GET_URL, 0xFF);
+        assert_eq!(url, Err(TransferError::Stall));
+
+        if cfg!(feature = "ms-os-10") {
+            let string = bus.control_read(&mut poll, 0x00, 0x06, 0x03EE, 0, 0xFF).unwrap();
+            assert_eq!(string[0] as usize, string.len());
+            assert_eq!(string[16..], [0xA1, 0]);
+        }
+    }
+
+    #[test]
+    fn unknown_vendor_request_stalls() {
+        with_device(|bus, poll| {
+            let result = bus.control_read(poll, 0xC1, 0x7F, 0, 0, 8);
+            assert_eq!(result, Err(TransferError::Stall));
+        });
+    }
+
+    #[test]
+    fn applied_line_coding() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        // 921600 baud, 2 stop bits, odd parity, 7 data bits
+        let coding = [0x00, 0x10, 0x0E, 0x00, 2, 1, 7];
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.control_write(&mut poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &coding).unwrap();
+        // Not applied yet, so the host gets its own back
+        let read = bus.control_read(&mut poll, 0xA1, REQ_GET_LINE_CODING, 0, 0, 7).unwrap();
+        assert_eq!(read, coding);
+
+        webusb.set_uart_rate(921_600, 923_076);
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let read = bus.control_read(&mut poll, 0xA1, REQ_GET_LINE_CODING, 0, 0, 7).unwrap();
+        assert_eq!(read, [0xC4, 0x15, 0x0E, 0x00, 0, 0, 8]);
+    }
+
+    #[test]
+    fn overruled_line_coding() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        let coding = [0x00, 0x10, 0x0E, 0x00, 0, 0, 8];
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.control_write(&mut poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &coding).unwrap();
+
+        // The UART stays at 115200, following the serial port
+        webusb.overrule_line_coding(Port::Serial);
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        let read = bus.control_read(&mut poll, 0xA1, REQ_GET_LINE_CODING, 0, 0, 7).unwrap();
+        assert_eq!(read, [0xB8, 0xC2, 0x01, 0x00, 0, 0, 8]);
+
+        // Until the host sets another
+        bus.control_write(&mut poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &coding).unwrap();
+        let read = bus.control_read(&mut poll, 0xA1, REQ_GET_LINE_CODING, 0, 0, 7).unwrap();
+        assert_eq!(read, coding);
+    }
+
+    #[test]
+    fn control_events() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        // The same lines again aren't a change
+        for lines in [1, 1, 0] {
+            bus.control_write(&mut poll, 0x21, REQ_SET_CONTROL_LINE_STATE, lines, 0, &[]).unwrap();
+        }
+        let coding = [0x00, 0x10, 0x0E, 0x00, 0, 0, 8];
+        bus.control_write(&mut poll, 0x21, REQ_SET_LINE_CODING, 0, 0, &coding).unwrap();
+
+        let events: Vec<_> = core::iter::from_fn(|| webusb.poll_event()).collect();
+        assert_eq!(
+            events,
+            [
+                WebUsbEvent::ControlLines { dtr: true, rts: false },
+                WebUsbEvent::ControlLines { dtr: false, rts: false },
+                WebUsbEvent::LineCoding(921_600),
+            ]
+        );
+    }
+
+    #[test]
+    fn comm_feature_requests() {
+        with_device(|bus, poll| {
+            let feature = bus.control_read(poll, 0xA1, REQ_GET_COMM_FEATURE, COUNTRY_SETTING, 0, 2);
+            assert_eq!(feature.unwrap(), [0, 0]);
+
+            let state = [0x03, 0x00];
+            bus.control_write(poll, 0x21, REQ_SET_COMM_FEATURE, ABSTRACT_STATE, 0, &state).unwrap();
+            let feature = bus.control_read(poll, 0xA1, REQ_GET_COMM_FEATURE, ABSTRACT_STATE, 0, 2);
+            assert_eq!(feature.unwrap(), state);
+
+            bus.control_write(poll, 0x21, REQ_CLEAR_COMM_FEATURE, ABSTRACT_STATE, 0, &[]).unwrap();
+            let feature = bus.control_read(poll, 0xA1, REQ_GET_COMM_FEATURE, ABSTRACT_STATE, 0, 2);
+            assert_eq!(feature.unwrap(), [0, 0]);
+
+            // No such feature
+            let result = bus.control_write(poll, 0x21, REQ_SET_COMM_FEATURE, 3, 0, &state);
+            assert_eq!(result, Err(TransferError::Stall));
+        });
+    }
+
+    #[test]
+    fn ms_os_20_descriptor_set_length() {
+        // The whole buffer must be used, and wTotalLength must agree with it.
+        let mut buf = [0u8; MS_OS_20_SET_LENGTH as usize];
+        let mut db = DescriptorBuilder::new(&mut buf);
+        write_ms_os_20_descriptor_set(&mut db);
+        assert_eq!(db.position(), MS_OS_20_SET_LENGTH as usize);
+        let set = &MS_OS_20_SET;
+        assert_eq!(&set[0..4], &[0x0A, 0x00, 0x00, 0x00]);
+        assert_eq!(&set[8..10], &MS_OS_20_SET_LENGTH.to_le_bytes());
+    }
+
+    #[test]
+    fn ms_os_20_subset_lengths() {
+        let set = &MS_OS_20_SET;
+
+        // Each subset header's wTotalLength covers everything up to the end of the set.
+        let config_len = u16::from_le_bytes([set[16], set[17]]) as usize;
+        assert_eq!(config_len, set.len() - 10);
+        let function_len = u16::from_le_bytes([set[24], set[25]]) as usize;
+        assert_eq!(function_len, set.len() - 18);
+
+        // Registry property descriptor length
+        let reg = &set[46..];
+        assert_eq!(u16::from_le_bytes([reg[0], reg[1]]) as usize, reg.len());
+    }
+
+    #[test]
+    fn line_coding_round_trip() {
+        let bytes = [0x00, 0x10, 0x0E, 0x00, 2, 1, 7];
+        let coding = LineCoding::parse(&bytes).unwrap();
+
+        assert_eq!(coding.data_rate(), 921_600);
+        assert_eq!(coding.stop_bits(), StopBits::Two);
+        assert_eq!(coding.parity_type(), ParityType::Odd);
+        assert_eq!(coding.data_bits(), 7);
+        assert_eq!(coding.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn line_coding_invalid_values() {
+        assert_eq!(LineCoding::parse(&[0x00, 0xC2, 0x01, 0x00, 3, 0, 8]), None);
+        assert_eq!(LineCoding::parse(&[0x00, 0xC2, 0x01, 0x00, 0, 5, 8]), None);
+        assert_eq!(LineCoding::parse(&[0x00, 0xC2, 0x01, 0x00, 0, 0, 9]), None);
+        assert_eq!(LineCoding::parse(&[0x00, 0x00, 0x00, 0x00, 0, 0, 8]), None);
+
+        assert_eq!(StopBits::try_from(9), Err(9));
+        assert_eq!(ParityType::try_from(4), Ok(ParityType::Space));
+    }
+}
@@ -1,23 +1,38 @@
 use core::borrow::BorrowMut;
-use core::mem;
+use core::fmt;
 use core::slice;
 use usb_device::class_prelude::*;
 use usb_device::Result;
+use crate::hexdump::{Direction, HexDump};
+use tilda_bridge_protocol::frame;
+use crate::events::Stamp;
+use crate::stats::Port;
 use crate::webusb::class::*;
 use crate::webusb::buffer::{Buffer, DefaultBufferStore};
+use crate::webusb::vendor::{NoVendorRequests, VendorRequests};
 
 /// The RS and WS type arguments specify the storage for the read/write buffers, respectively. By
 /// default an internal 128 byte buffer is used for both directions.
-pub struct WebUSB<'a, B, RS=DefaultBufferStore, WS=DefaultBufferStore>
+pub struct WebUSB<'a, B, RS=DefaultBufferStore, WS=DefaultBufferStore, V=NoVendorRequests>
 where
     B: UsbBus,
     RS: BorrowMut<[u8]>,
     WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
 {
     inner: WebUsbClass<'a, B>,
+    vendor: V,
     read_buf: Buffer<RS>,
     write_buf: Buffer<WS>,
     write_state: WriteState,
+    /// UART output waiting to go out as a timestamped frame, header first. Empty if `frame_len` is 0.
+    frame: [u8; frame::HEADER_LENGTH + frame::MAX_DATA_LENGTH],
+    frame_len: usize,
+    hexdump: HexDump,
+    /// How long the start of a line has been held in line-buffered mode, in ms, or `None` if
+    /// nothing is held.
+    #[cfg(feature = "line-buffer")]
+    held_ms: Option<u16>,
 }
 
 /// If this many full size packets have been sent in a row, a short packet will be sent so that the
@@ -49,8 +64,8 @@ where
     {
         WebUSB::new_with_store(
             alloc,
-            unsafe { mem::uninitialized() },
-            unsafe { mem::uninitialized() })
+            DefaultBufferStore::default(),
+            DefaultBufferStore::default())
     }
 }
 
@@ -64,23 +79,191 @@ where
     pub fn new_with_store(alloc: &UsbBusAllocator<B>, read_store: RS, write_store: WS)
         -> WebUSB<'_, B, RS, WS>
     {
+        WebUsbBuilder::new(alloc).build_with_store(read_store, write_store)
+    }
+}
+
+/// Builds a `WebUSB` port with other endpoints than `new`'s: 64 byte bulk packets, and an interrupt
+/// endpoint polled every 255ms. The CDC-ACM port's endpoints are fixed by usbd-serial. It can also
+/// take other vendor codes for the WebUSB and Microsoft OS descriptors, and a handler for the vendor
+/// requests (see `VendorRequests`).
+pub struct WebUsbBuilder<'a, B: UsbBus, V = NoVendorRequests> {
+    alloc: &'a UsbBusAllocator<B>,
+    max_packet_size: u16,
+    interval: Option<u8>,
+    vendor_codes: VendorCodes,
+    vendor: V,
+}
+
+impl<'a, B: UsbBus> WebUsbBuilder<'a, B> {
+    /// Starts with `new`'s endpoints.
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        WebUsbBuilder {
+            alloc,
+            max_packet_size: 64,
+            interval: Some(255),
+            vendor_codes: VendorCodes::default(),
+            vendor: NoVendorRequests,
+        }
+    }
+}
+
+impl<'a, B: UsbBus, V: VendorRequests<B>> WebUsbBuilder<'a, B, V> {
+    /// Passes the vendor requests to `vendor`, such as `bridge::Bridge` for the bridge's own.
+    pub fn vendor_requests<W: VendorRequests<B>>(self, vendor: W) -> WebUsbBuilder<'a, B, W> {
+        WebUsbBuilder {
+            alloc: self.alloc,
+            max_packet_size: self.max_packet_size,
+            interval: self.interval,
+            vendor_codes: self.vendor_codes,
+            vendor,
+        }
+    }
+
+    /// Sets the vendor codes the host fetches the WebUSB and Microsoft OS descriptors with, in
+    /// place of 0x42 and 0x43, when they'd clash with another class's vendor requests. The two have
+    /// to differ.
+    pub fn vendor_codes(mut self, vendor_codes: VendorCodes) -> Self {
+        self.vendor_codes = vendor_codes;
+        self
+    }
+
+    /// Sets the bulk endpoints' max packet size, which has to be one of 8, 16, 32 or 64.
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets how often the host polls the interrupt endpoint, in ms.
+    pub fn interrupt_interval(mut self, interval: u8) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Leaves out the interrupt endpoint, saving one for other classes. The host then doesn't get
+    /// SERIAL_STATE or ESP_RESET notifications.
+    pub fn without_interrupt_endpoint(mut self) -> Self {
+        self.interval = None;
+        self
+    }
+
+    /// Builds the port with 128 byte read/write buffers.
+    pub fn build(self) -> WebUSB<'a, B, DefaultBufferStore, DefaultBufferStore, V> {
+        self.build_with_store(DefaultBufferStore::default(), DefaultBufferStore::default())
+    }
+
+    /// Builds the port with the given buffer backing stores.
+    pub fn build_with_store<RS, WS>(self, read_store: RS, write_store: WS)
+        -> WebUSB<'a, B, RS, WS, V>
+    where
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        let inner = WebUsbClass::new_with_vendor_codes(
+            self.alloc,
+            self.max_packet_size,
+            self.interval,
+            self.vendor_codes,
+        );
         WebUSB {
-            inner: WebUsbClass::new(alloc, 64),
+            inner,
+            vendor: self.vendor,
             read_buf: Buffer::new(read_store),
             write_buf: Buffer::new(write_store),
             write_state: WriteState::Idle,
+            frame: [0; frame::HEADER_LENGTH + frame::MAX_DATA_LENGTH],
+            frame_len: 0,
+            hexdump: HexDump::default(),
+            #[cfg(feature = "line-buffer")]
+            held_ms: None,
         }
     }
+}
 
+impl<'a, B, RS, WS, V> WebUSB<'a, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
     /// Gets the current line coding.
     pub fn line_coding(&self) -> &LineCoding { self.inner.line_coding() }
 
+    /// Records that the UART has been set to `rate`, which it really runs at as `achieved`.
+    pub fn set_uart_rate(&mut self, rate: u32, achieved: u32) {
+        self.inner.set_uart_rate(rate, achieved);
+    }
+
+    /// Records that the UART follows the `source` port's line coding rather than the host's on this
+    /// port, and lets the host know.
+    pub fn overrule_line_coding(&mut self, source: Port) { self.inner.overrule_line_coding(source); }
+
+    /// The handler for the vendor requests.
+    pub fn vendor_requests(&mut self) -> &mut V { &mut self.vendor }
+
+    /// The class and the vendor request handler, for the handler's own methods on the port.
+    pub fn parts(&self) -> (&WebUsbClass<'a, B>, &V) { (&self.inner, &self.vendor) }
+
+    /// The class and the vendor request handler, for the handler's own methods on the port.
+    pub fn parts_mut(&mut self) -> (&mut WebUsbClass<'a, B>, &mut V) {
+        (&mut self.inner, &mut self.vendor)
+    }
+
+    /// Gets the comm features set by the host.
+    pub fn comm_features(&self) -> &CommFeatures { self.inner.comm_features() }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool { self.inner.dtr() }
 
     /// Gets the RTS (ready to send) state
     pub fn rts(&self) -> bool { self.inner.rts() }
 
+    /// Takes the oldest change the host has made, if there's one waiting.
+    pub fn poll_event(&mut self) -> Option<WebUsbEvent> { self.inner.poll_event() }
+
+    /// When the oldest waiting change was made, to put it in order with the other port's.
+    pub fn next_event_stamp(&self) -> Option<Stamp> { self.inner.next_event_stamp() }
+
+    /// Whether the host has made the port a read-only monitor.
+    pub fn monitor(&self) -> bool { self.inner.monitor() }
+
+    /// Whether `port`'s data and control lines should reach the ESP32.
+    pub fn may_drive(&self, port: Port) -> bool { self.inner.may_drive(port) }
+
+    /// Advances the clock timestamps are taken from, and sends any UART output held for a
+    /// timestamped frame, or held in line-buffered mode for as long as the host allows.
+    #[inline(never)]
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.inner.tick(elapsed_ms);
+        self.send_frame();
+        #[cfg(feature = "line-buffer")]
+        if let Some(held_ms) = self.held_ms {
+            let held_ms = held_ms.saturating_add(elapsed_ms as u16);
+            self.held_ms = (held_ms < self.inner.line_buffer_ms()).then_some(held_ms);
+        }
+
+        let write_buf = &mut self.write_buf;
+        self.hexdump.tick(|line| write_whole(write_buf, line));
+        self.flush().ok();
+    }
+
+    /// Adds the serial port's traffic to the hex dump, if the host has asked for one.
+    pub fn dump(&mut self, direction: Direction, data: &[u8]) {
+        if !self.inner.hexdump() {
+            return;
+        }
+        let write_buf = &mut self.write_buf;
+        self.hexdump.push(direction, data, |line| write_whole(write_buf, line));
+        self.flush().ok();
+    }
+
+    /// Records bytes of the ESP32's output dropped from a port because its queue was full.
+    pub fn record_dropped(&mut self, port: Port, count: usize) { self.inner.record_dropped(port, count); }
+
+    /// Whether the throughput test has the data interface, so the ESP32's output is dropped.
+    pub fn throughput(&self) -> bool { self.inner.throughput() }
+
     /// Writes bytes from `data` into the port and returns the number of bytes written.
     ///
     /// # Errors
@@ -104,6 +287,73 @@ where
         }
     }
 
+    /// Writes the ESP32's output into the port. In timestamp mode it's held until the next
+    /// `tick` (or until there's a frame's worth), then sent as a timestamped frame. Whatever
+    /// doesn't fit is dropped, and counted.
+    pub fn write_uart(&mut self, data: &[u8]) -> Result<usize> {
+        if self.inner.hexdump() {
+            // The host gets the hex dump instead.
+            return Ok(data.len());
+        }
+        #[cfg(feature = "line-buffer")]
+        if self.inner.line_buffer_ms() != 0 && !self.inner.timestamps() {
+            return Ok(self.write_line(data));
+        }
+        if !self.inner.timestamps() {
+            let result = self.write(data);
+            let written = *result.as_ref().unwrap_or(&0);
+            self.inner.record_dropped(Port::WebUsb, data.len() - written);
+            return result;
+        }
+
+        for &byte in data {
+            if self.frame_len == 0 {
+                self.frame[..4].copy_from_slice(&self.inner.millis().to_le_bytes());
+                self.frame_len = frame::HEADER_LENGTH;
+            }
+            self.frame[self.frame_len] = byte;
+            self.frame_len += 1;
+            if self.frame_len == self.frame.len() {
+                self.send_frame();
+            }
+        }
+        Ok(data.len())
+    }
+
+    /// Writes the ESP32's output in line-buffered mode. It's held until the end of a line, until
+    /// there's a packet's worth, or until `tick` finds it's been held as long as the host
+    /// allows, so chatty output goes out in whole lines and fewer packets. Whatever doesn't fit is
+    /// dropped, and counted.
+    #[cfg(feature = "line-buffer")]
+    fn write_line(&mut self, data: &[u8]) -> usize {
+        let written = self.write_buf.write(data);
+        self.inner.record_dropped(Port::WebUsb, data.len() - written);
+        let packet = self.inner.max_packet_size() as usize;
+        if data.contains(&b'\n') || self.write_buf.available_read() >= packet {
+            self.held_ms = None;
+        } else if self.held_ms.is_none() {
+            self.held_ms = Some(0);
+        }
+        self.flush().ok();
+        written
+    }
+
+    /// Sends the held UART output as a frame. It's dropped if the write buffer doesn't have room
+    /// for all of it, as a partial frame would garble the rest of the stream.
+    fn send_frame(&mut self) {
+        let len = core::mem::replace(&mut self.frame_len, 0);
+        if len == 0 || !self.inner.timestamps() {
+            return;
+        }
+        self.frame[frame::HEADER_LENGTH - 1] = (len - frame::HEADER_LENGTH) as u8;
+        if self.write_buf.available_write() >= len {
+            self.write_buf.write(&self.frame[..len]);
+        } else {
+            self.inner.record_dropped(Port::WebUsb, len - frame::HEADER_LENGTH);
+        }
+        self.flush().ok();
+    }
+
     /// Reads bytes from the port into `data` and returns the number of bytes read.
     ///
     /// # Errors
@@ -112,37 +362,55 @@ where
     ///
     /// Other errors from `usb-device` may also be propagated.
     pub fn read(&mut self, data: &mut [u8]) -> Result<usize> {
-        let buf = &mut self.read_buf;
-        let inner = &mut self.inner;
-
-        // Try to read a packet from the endpoint and write it into the buffer if it fits. Propagate
-        // errors except `WouldBlock`.
-
-        buf.write_all(inner.max_packet_size() as usize, |buf_data| {
-            match inner.read_packet(buf_data) {
-                Ok(c) => Ok(c),
-                Err(UsbError::WouldBlock) => Ok(0),
-                Err(err) => Err(err),
-            }
-        })?;
+        self.fill_read_buf()?;
 
+        let buf = &mut self.read_buf;
         if buf.available_read() == 0 {
             // No data available for reading.
             return Err(UsbError::WouldBlock);
         }
 
         buf.read(data.len(), |buf_data| {
-            &data[..buf_data.len()].copy_from_slice(buf_data);
+            data[..buf_data.len()].copy_from_slice(buf_data);
 
             Ok(buf_data.len())
         })
     }
 
+    /// Tries to read a packet from the endpoint and write it into the buffer if it fits. Propagates
+    /// errors except `WouldBlock`.
+    #[inline(never)]
+    fn fill_read_buf(&mut self) -> Result<()> {
+        let inner = &mut self.inner;
+
+        self.read_buf.write_all(inner.max_packet_size() as usize, |buf_data| {
+            match inner.read_packet(buf_data) {
+                Ok(c) => Ok(c),
+                Err(UsbError::WouldBlock) => Ok(0),
+                Err(err) => Err(err),
+            }
+        })?;
+        Ok(())
+    }
+
     /// Sends as much as possible of the current write buffer. Returns `Ok` if all data that has
     /// been written has been completely written to hardware buffers `Err(WouldBlock)` if there is
     /// still data remaining, and other errors if there's an error sending data to the host. Note
     /// that even if this method returns `Ok`, data may still be in hardware buffers on either side.
+    /// While the throughput test runs, the data is thrown away.
     pub fn flush(&mut self) -> Result<()> {
+        if self.inner.throughput() {
+            // The throughput test has the endpoint, and nothing else gets a look in.
+            self.write_buf.clear();
+            self.write_state = WriteState::Idle;
+            return Ok(());
+        }
+        #[cfg(feature = "line-buffer")]
+        if self.held_ms.is_some() {
+            // The start of a line, waiting for the rest.
+            return Err(UsbError::WouldBlock);
+        }
+
         let buf = &mut self.write_buf;
         let inner = &mut self.inner;
         let write_state = &mut self.write_state;
@@ -193,11 +461,12 @@ where
     }
 }
 
-impl<B, RS, WS> UsbClass<B> for WebUSB<'_, B, RS, WS>
+impl<B, RS, WS, V> UsbClass<B> for WebUSB<'_, B, RS, WS, V>
 where
     B: UsbBus,
     RS: BorrowMut<[u8]>,
     WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
 {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
         self.inner.get_configuration_descriptors(writer)
@@ -207,34 +476,52 @@ where
         self.inner.get_bos_descriptors(writer)
     }
 
+    fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        self.inner.get_string(index, lang_id)
+    }
+
     fn reset(&mut self) {
         self.inner.reset();
+        self.vendor.reset();
         self.read_buf.clear();
         self.write_buf.clear();
         self.write_state = WriteState::Idle;
+        #[cfg(feature = "line-buffer")]
+        {
+            self.held_ms = None;
+        }
+    }
+
+    fn poll(&mut self) {
+        self.inner.poll();
+        self.vendor.poll(&mut self.inner);
     }
 
+    fn endpoint_out(&mut self, addr: EndpointAddress) { self.inner.endpoint_out(addr); }
+
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        self.inner.endpoint_in_complete(addr);
         if addr == self.inner.write_ep_address() {
             self.flush().ok();
         }
     }
 
-    fn control_in(&mut self, xfer: ControlIn<B>) { self.inner.control_in(xfer); }
+    fn control_in(&mut self, xfer: ControlIn<B>) { self.inner.control_in_with(xfer, &mut self.vendor); }
 
-    fn control_out(&mut self, xfer: ControlOut<B>) { self.inner.control_out(xfer); }
+    fn control_out(&mut self, xfer: ControlOut<B>) { self.inner.control_out_with(xfer, &mut self.vendor); }
 }
 
-impl<B, RS, WS> embedded_hal::serial::Write<u8> for WebUSB<'_, B, RS, WS>
+impl<B, RS, WS, V> embedded_hal::serial::Write<u8> for WebUSB<'_, B, RS, WS, V>
 where
     B: UsbBus,
     RS: BorrowMut<[u8]>,
     WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
 {
     type Error = UsbError;
 
     fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
-        match <WebUSB<'_, B, RS, WS>>::write(self, slice::from_ref(&word)) {
+        match <WebUSB<'_, B, RS, WS, V>>::write(self, slice::from_ref(&word)) {
             Ok(0) | Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
             Ok(_) => Ok(()),
             Err(err) => Err(nb::Error::Other(err)),
@@ -242,7 +529,7 @@ where
     }
 
     fn flush(&mut self) -> nb::Result<(), Self::Error> {
-        match <WebUSB<'_, B, RS, WS>>::flush(self) {
+        match <WebUSB<'_, B, RS, WS, V>>::flush(self) {
             Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
             Ok(_) => Ok(()),
             Err(err) => Err(nb::Error::Other(err)),
@@ -250,21 +537,430 @@ where
     }
 }
 
-impl<B, RS, WS> embedded_hal::serial::Read<u8> for WebUSB<'_, B, RS, WS>
+impl<B, RS, WS, V> embedded_hal::serial::Read<u8> for WebUSB<'_, B, RS, WS, V>
 where
     B: UsbBus,
     RS: BorrowMut<[u8]>,
     WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
 {
     type Error = UsbError;
 
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
         let mut buf: u8 = 0;
 
-        match <WebUSB<'_, B, RS, WS>>::read(self, slice::from_mut(&mut buf)) {
+        match <WebUSB<'_, B, RS, WS, V>>::read(self, slice::from_mut(&mut buf)) {
             Ok(0) | Err(UsbError::WouldBlock) => Err(nb::Error::WouldBlock),
             Ok(_) => Ok(buf),
             Err(err) => Err(nb::Error::Other(err)),
         }
     }
 }
+
+/// A USB error, for `embedded-io` users.
+#[derive(Debug)]
+pub struct IoError(pub UsbError);
+
+impl embedded_io::Error for IoError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self.0 {
+            UsbError::ParseError => embedded_io::ErrorKind::InvalidData,
+            UsbError::BufferOverflow => embedded_io::ErrorKind::OutOfMemory,
+            UsbError::Unsupported => embedded_io::ErrorKind::Unsupported,
+            _ => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
+impl<B, RS, WS, V> embedded_io::ErrorType for WebUSB<'_, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
+    type Error = IoError;
+}
+
+/// The blocking `embedded-io` reads and writes spin until the USB peripheral has taken or delivered
+/// a packet, which it does without `UsbDevice::poll` being called. Don't call them from the main
+/// loop without checking `read_ready`/`write_ready` first, or nothing else gets serviced meanwhile.
+impl<B, RS, WS, V> embedded_io::Read for WebUSB<'_, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, IoError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match <WebUSB<'_, B, RS, WS, V>>::read(self, buf) {
+                Err(UsbError::WouldBlock) => {}
+                result => return result.map_err(IoError),
+            }
+        }
+    }
+}
+
+impl<B, RS, WS, V> embedded_io::ReadReady for WebUSB<'_, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
+    fn read_ready(&mut self) -> core::result::Result<bool, IoError> {
+        self.fill_read_buf().map_err(IoError)?;
+        Ok(self.read_buf.available_read() > 0)
+    }
+}
+
+impl<B, RS, WS, V> embedded_io::Write for WebUSB<'_, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
+    fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, IoError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match <WebUSB<'_, B, RS, WS, V>>::write(self, buf) {
+                Err(UsbError::WouldBlock) => {}
+                result => return result.map_err(IoError),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> core::result::Result<(), IoError> {
+        loop {
+            match <WebUSB<'_, B, RS, WS, V>>::flush(self) {
+                Err(UsbError::WouldBlock) => {}
+                result => return result.map_err(IoError),
+            }
+        }
+    }
+}
+
+impl<B, RS, WS, V> embedded_io::WriteReady for WebUSB<'_, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
+    fn write_ready(&mut self) -> core::result::Result<bool, IoError> {
+        Ok(self.write_buf.available_write() > 0)
+    }
+}
+
+/// Writes text to the host, so status and debug messages can be sent with `write!`. Like the
+/// `embedded-io` writes, this waits for room in the write buffer. Data left in the buffer is sent
+/// as the host collects the packets before it.
+impl<B, RS, WS, V> fmt::Write for WebUSB<'_, B, RS, WS, V>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+    V: VendorRequests<B>,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        embedded_io::Write::write_all(self, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Writes all of `data` into the buffer, or none of it if there isn't room, for output which would
+/// be garbled by part of it going missing.
+fn write_whole<S: BorrowMut<[u8]>>(buf: &mut Buffer<S>, data: &[u8]) {
+    if buf.available_write() >= data.len() {
+        buf.write(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::{Bridge, BridgePort};
+    use crate::mock::{MockBus, TransferError};
+    use crate::stats::UartError;
+    use core::fmt::Write as _;
+    use tilda_bridge_protocol::{event, request};
+    use embedded_io::{Read, ReadReady};
+    use usb_device::prelude::*;
+
+    /// A port with the bridge's vendor requests, which set its modes.
+    fn bridge_port(alloc: &UsbBusAllocator<MockBus>) -> BridgePort<'_, MockBus> {
+        WebUsbBuilder::new(alloc).vendor_requests(Bridge::default()).build()
+    }
+
+    #[test]
+    fn embedded_io() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        assert!(!webusb.read_ready().unwrap());
+        bus.push_out(1, b"hello");
+        assert!(webusb.read_ready().unwrap());
+        let mut buf = [0u8; 8];
+        assert_eq!(Read::read(&mut webusb, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        embedded_io::Write::write_all(&mut webusb, b"world").unwrap();
+        embedded_io::Write::flush(&mut webusb).unwrap();
+        assert_eq!(bus.take_in_data(2), b"world");
+    }
+
+    #[test]
+    fn monitor_mode() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        bus.control_write(&mut poll, 0x21, 0x22, 0x0003, 0, &[]).unwrap();
+        bus.control_write(&mut poll, 0x41, request::SET_MONITOR, 1, 0, &[]).unwrap();
+        bus.control_write(&mut poll, 0x21, 0x22, 0x0003, 0, &[]).unwrap();
+        assert!(webusb.monitor());
+        assert!(!webusb.dtr() && !webusb.rts());
+
+        // Writes from the host are dropped, but the ESP32's output still gets through.
+        bus.push_out(1, b"flash");
+        let mut buf = [0u8; 8];
+        assert!(matches!(webusb.read(&mut buf), Err(UsbError::WouldBlock)));
+        webusb.write(b"log").unwrap();
+        assert_eq!(bus.take_in_data(2), b"log");
+
+        bus.bus_reset();
+        usb_dev.poll(&mut [&mut webusb]);
+        assert!(!webusb.monitor());
+    }
+
+    #[test]
+    fn throughput_test() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+        bus.control_write(&mut poll, 0x41, request::SET_THROUGHPUT, 1, 0, &[]).unwrap();
+        assert!(webusb.throughput());
+        // The ESP32's output doesn't get in the way.
+        webusb.write_uart(b"log").unwrap();
+        usb_dev.poll(&mut [&mut webusb]);
+        let sent = bus.take_in_data(2);
+        assert!(sent.len() >= 128 && sent.len().is_multiple_of(64));
+        assert!(sent.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+        bus.push_out(1, &[0, 1, 2, 4]);
+        let mut buf = [0u8; 8];
+        assert!(matches!(webusb.read(&mut buf), Err(UsbError::WouldBlock)));
+        usb_dev.poll(&mut [&mut webusb]);
+
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.control_write(&mut poll, 0x41, request::SET_THROUGHPUT, 0, 0, &[]).unwrap();
+        let sent = sent.len() + bus.take_in_data(2).len();
+        let counts = bus.control_read(&mut poll, 0xC1, request::GET_THROUGHPUT, 0, 0, 64).unwrap();
+        assert_eq!(counts[..4], (sent as u32).to_le_bytes());
+        assert_eq!(counts[4..], [4, 0, 0, 0, 1, 0, 0, 0]);
+        assert!(!webusb.throughput());
+    }
+
+    #[test]
+    fn builder() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUsbBuilder::new(&alloc)
+            .max_packet_size(16)
+            .without_interrupt_endpoint()
+            .vendor_requests(Bridge::default())
+            .build();
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        // Just the two bulk endpoints, with 16 byte packets
+        let config = bus.get_descriptor(&mut poll, 0x02, 0, 0xFF).unwrap();
+        let mut endpoints = Vec::new();
+        let mut rest = &config[..];
+        while let [length, kind, ..] = *rest {
+            if kind == 0x05 {
+                endpoints.push((rest[3], u16::from_le_bytes([rest[4], rest[5]])));
+            }
+            rest = &rest[length as usize..];
+        }
+        assert_eq!(endpoints, [(0x02, 16), (0x02, 16)]);
+
+        // With no interrupt endpoint the notification goes nowhere, and the data still gets out.
+        webusb.record_uart_error(UartError::Overrun);
+        usb_dev.poll(&mut [&mut webusb]);
+        webusb.write(&[0x55; 20]).unwrap();
+        usb_dev.poll(&mut [&mut webusb]);
+        assert_eq!(bus.take_in(1), [vec![0x55; 16], vec![0x55; 4]]);
+    }
+
+    #[test]
+    fn claim() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+        assert!(webusb.may_drive(Port::Serial) && webusb.may_drive(Port::WebUsb));
+
+        // Which ports may drive the ESP32 after the request.
+        let mut claim = |value| {
+            let mut poll = || {
+                usb_dev.poll(&mut [&mut webusb]);
+            };
+            bus.control_write(&mut poll, 0x41, request::CLAIM, value, 0, &[])
+                .map(|_| (webusb.may_drive(Port::Serial), webusb.may_drive(Port::WebUsb)))
+        };
+        assert_eq!(claim(2), Ok((false, true)));
+        assert!(claim(3).is_err());
+        assert_eq!(claim(0), Ok((true, true)));
+        assert_eq!(claim(1), Ok((true, false)));
+
+        // The WebUSB port's writes are dropped, but it still gets the ESP32's output.
+        bus.push_out(1, b"flash");
+        let mut buf = [0u8; 8];
+        assert!(matches!(webusb.read(&mut buf), Err(UsbError::WouldBlock)));
+        webusb.write(b"log").unwrap();
+        assert_eq!(bus.take_in_data(2), b"log");
+
+        bus.bus_reset();
+        usb_dev.poll(&mut [&mut webusb]);
+        assert!(webusb.may_drive(Port::WebUsb));
+    }
+
+
+    #[test]
+    fn timestamped_output() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+        bus.control_write(&mut poll, 0x41, request::SET_TIMESTAMPS, 1, 0, &[]).unwrap();
+
+        webusb.tick(1500);
+        webusb.write_uart(b"rst:").unwrap();
+        webusb.write_uart(b"0x1").unwrap();
+        assert_eq!(bus.take_in_data(2), b"");
+        webusb.tick(1);
+
+        let stream = bus.take_in_data(2);
+        assert_eq!(frame::split(&stream), Some((1500, &b"rst:0x1"[..], &[][..])));
+    }
+
+    #[test]
+    fn line_buffered_output() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = bridge_port(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+        let too_long = request::LINE_BUFFER_MAX_MS + 1;
+        let result = bus.control_write(&mut poll, 0x41, request::SET_LINE_BUFFER, too_long, 0, &[]);
+        assert_eq!(result, Err(TransferError::Stall));
+        let result = bus.control_write(&mut poll, 0x41, request::SET_LINE_BUFFER, 20, 0, &[]);
+        if !cfg!(feature = "line-buffer") {
+            assert_eq!(result, Err(TransferError::Stall));
+            return;
+        }
+        assert_eq!(result, Ok(()));
+
+        // Held until the end of the line
+        webusb.write_uart(b"I (31) boot: ").unwrap();
+        webusb.write_uart(b"ESP-IDF v5.1").unwrap();
+        webusb.tick(1);
+        assert_eq!(bus.take_in_data(2), b"");
+        webusb.write_uart(b"\r\n").unwrap();
+        assert_eq!(bus.take_in_data(2), b"I (31) boot: ESP-IDF v5.1\r\n");
+        usb_dev.poll(&mut [&mut webusb]);
+
+        // ...or as long as the host allows
+        webusb.write_uart(b"> ").unwrap();
+        webusb.tick(19);
+        assert_eq!(bus.take_in_data(2), b"");
+        webusb.tick(1);
+        assert_eq!(bus.take_in_data(2), b"> ");
+        usb_dev.poll(&mut [&mut webusb]);
+
+        // ...or until there's a packet's worth.
+        webusb.write_uart(&[b'.'; 64]).unwrap();
+        assert_eq!(bus.take_in_data(2), [b'.'; 64]);
+    }
+
+
+
+
+    #[test]
+    fn uart_rate_notification() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        webusb.set_uart_rate(921_600, 923_076);
+        webusb.overrule_line_coding(Port::Serial);
+        let notification = [0xC1, event::UART_RATE, 0, 0, 0, 0, 5, 0, 0xC4, 0x15, 0x0E, 0x00, 0];
+        assert_eq!(bus.take_in(1), [notification.to_vec()]);
+    }
+
+
+
+
+
+
+    #[test]
+    fn fmt_write() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUSB::new(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        bus.enumerate(&mut || {
+            usb_dev.poll(&mut [&mut webusb]);
+        })
+        .unwrap();
+
+        writeln!(webusb, "uptime {}s", 42).unwrap();
+        assert_eq!(bus.take_in_data(2), b"uptime 42s\n");
+    }
+}
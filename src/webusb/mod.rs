@@ -1,11 +1,24 @@
-/// WebUSB module
-///
-/// This is a modified clone of the usbd-serial crate https://crates.io/crates/usbd-serial
+//! WebUSB module
+//!
+//! This is a modified clone of the usbd-serial crate https://crates.io/crates/usbd-serial
 
+pub mod asynch;
 mod buffer;
 mod class;
 mod device;
 mod builder;
+mod msos;
+mod vendor;
 
 pub use usb_device::{Result, UsbError};
+pub use crate::webusb::buffer::{Buffer, DefaultBufferStore};
+pub use crate::webusb::builder::DescriptorBuilder;
+pub use crate::webusb::class::{
+    CommFeatures, LineCoding, ParityType, StopBits, VendorCodes, WebUsbClass, WebUsbEvent,
+};
 pub use crate::webusb::device::*;
+pub use crate::webusb::msos::{
+    CompatibleId, ConfigurationSubset, DescriptorSet, FunctionSubset, RegistryProperty,
+    REG_MULTI_SZ, WINDOWS_8_1,
+};
+pub use crate::webusb::vendor::{NoVendorRequests, VendorRequests};
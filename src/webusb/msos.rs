@@ -0,0 +1,260 @@
+//! Microsoft OS 2.0 descriptors
+//!
+//! Windows reads a Microsoft OS 2.0 descriptor set to bind drivers and set registry properties
+//! without an INF file. The set is a tree: a header, configuration subsets, function subsets for
+//! the interfaces they cover, and the features of each function. Every header holds the length of
+//! what's under it, so the set is described with these types and its lengths are worked out from
+//! them, rather than written out byte by byte.
+//!
+//! Everything here is `const`, so a fixed set can be rendered into a static at compile time, where
+//! a wrong length fails the build.
+
+use crate::webusb::builder::{utf16_len, DescriptorBuilder};
+
+const SET_HEADER: u16 = 0x00;
+const SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const FEATURE_REG_PROPERTY: u16 = 0x04;
+
+const SET_HEADER_LENGTH: u16 = 10;
+const SUBSET_HEADER_LENGTH: u16 = 8;
+const COMPATIBLE_ID_LENGTH: u16 = 20;
+
+/// dwWindowsVersion for Windows 8.1, the first to read Microsoft OS 2.0 descriptors.
+pub const WINDOWS_8_1: u32 = 0x0603_0000;
+
+/// wPropertyDataType for a NUL-separated list of strings, ending with an empty one.
+pub const REG_MULTI_SZ: u16 = 0x07;
+
+/// The whole descriptor set, which the host fetches with the Microsoft vendor code.
+pub struct DescriptorSet<'a> {
+    /// The earliest Windows version the set is for.
+    pub windows_version: u32,
+    pub configurations: &'a [ConfigurationSubset<'a>],
+}
+
+/// The functions in one configuration.
+pub struct ConfigurationSubset<'a> {
+    /// Which configuration, counting from 0 rather than by bConfigurationValue.
+    pub configuration: u8,
+    pub functions: &'a [FunctionSubset<'a>],
+}
+
+/// The features which apply to one function: the interfaces from `first_interface`, up to the
+/// next function's.
+pub struct FunctionSubset<'a> {
+    pub first_interface: u8,
+    pub compatible_id: Option<CompatibleId>,
+    pub properties: &'a [RegistryProperty<'a>],
+}
+
+/// The driver Windows binds to a function.
+pub struct CompatibleId {
+    pub compatible_id: [u8; 8],
+    pub sub_compatible_id: [u8; 8],
+}
+
+/// A registry property for a function's device.
+pub struct RegistryProperty<'a> {
+    pub data_type: u16,
+    /// The name, with its terminating NUL.
+    pub name: &'a str,
+    /// The value, with its terminating NUL (two, for `REG_MULTI_SZ`).
+    pub data: &'a str,
+}
+
+impl CompatibleId {
+    /// Binds WinUSB to the function.
+    pub const WINUSB: CompatibleId = CompatibleId {
+        compatible_id: *b"WINUSB\0\0",
+        sub_compatible_id: [0; 8],
+    };
+}
+
+impl DescriptorSet<'_> {
+    /// wTotalLength: the length of the whole set.
+    pub const fn length(&self) -> u16 {
+        let mut length = SET_HEADER_LENGTH;
+        let mut i = 0;
+        while i < self.configurations.len() {
+            length += self.configurations[i].length();
+            i += 1;
+        }
+        length
+    }
+
+    pub const fn write(&self, db: &mut DescriptorBuilder) {
+        let start = db.position();
+        db.write_u16(SET_HEADER_LENGTH);
+        db.write_u16(SET_HEADER);
+        db.write_u32(self.windows_version);
+        let total = db.length_u16(start);
+        let mut i = 0;
+        while i < self.configurations.len() {
+            self.configurations[i].write(db);
+            i += 1;
+        }
+        db.end(total);
+    }
+
+    /// Renders the set, which must be exactly `N` bytes long. The lengths in the headers are counted
+    /// as it's written, so this also checks them against `length`.
+    pub const fn render<const N: usize>(&self) -> [u8; N] {
+        let mut set = [0; N];
+        let mut db = DescriptorBuilder::new(&mut set);
+        self.write(&mut db);
+        assert!(db.position() == N && self.length() as usize == N);
+        set
+    }
+}
+
+impl ConfigurationSubset<'_> {
+    /// wTotalLength: the length of the subset, header included.
+    pub const fn length(&self) -> u16 {
+        let mut length = SUBSET_HEADER_LENGTH;
+        let mut i = 0;
+        while i < self.functions.len() {
+            length += self.functions[i].length();
+            i += 1;
+        }
+        length
+    }
+
+    pub const fn write(&self, db: &mut DescriptorBuilder) {
+        let start = db.position();
+        db.write_u16(SUBSET_HEADER_LENGTH);
+        db.write_u16(SUBSET_HEADER_CONFIGURATION);
+        db.write(&[self.configuration, 0]);
+        let total = db.length_u16(start);
+        let mut i = 0;
+        while i < self.functions.len() {
+            self.functions[i].write(db);
+            i += 1;
+        }
+        db.end(total);
+    }
+}
+
+impl FunctionSubset<'_> {
+    /// wSubsetLength: the length of the subset, header included.
+    pub const fn length(&self) -> u16 {
+        let mut length = SUBSET_HEADER_LENGTH;
+        if self.compatible_id.is_some() {
+            length += COMPATIBLE_ID_LENGTH;
+        }
+        let mut i = 0;
+        while i < self.properties.len() {
+            length += self.properties[i].length();
+            i += 1;
+        }
+        length
+    }
+
+    pub const fn write(&self, db: &mut DescriptorBuilder) {
+        let start = db.position();
+        db.write_u16(SUBSET_HEADER_LENGTH);
+        db.write_u16(SUBSET_HEADER_FUNCTION);
+        db.write(&[self.first_interface, 0]);
+        let total = db.length_u16(start);
+        if let Some(id) = &self.compatible_id {
+            db.write_u16(COMPATIBLE_ID_LENGTH);
+            db.write_u16(FEATURE_COMPATIBLE_ID);
+            db.write(&id.compatible_id);
+            db.write(&id.sub_compatible_id);
+        }
+        let mut i = 0;
+        while i < self.properties.len() {
+            self.properties[i].write(db);
+            i += 1;
+        }
+        db.end(total);
+    }
+}
+
+impl RegistryProperty<'_> {
+    /// wLength: the length of the descriptor.
+    pub const fn length(&self) -> u16 {
+        (10 + utf16_len(self.name) + utf16_len(self.data)) as u16
+    }
+
+    pub const fn write(&self, db: &mut DescriptorBuilder) {
+        let start = db.position();
+        let length = db.length_u16(start);
+        db.write_u16(FEATURE_REG_PROPERTY);
+        db.write_u16(self.data_type);
+        let name = db.length_u16(db.position() + 2);
+        db.write_utf16(self.name);
+        db.end(name);
+        let data = db.length_u16(db.position() + 2);
+        db.write_utf16(self.data);
+        db.end(data);
+        db.end(length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUIDS: RegistryProperty = RegistryProperty {
+        data_type: REG_MULTI_SZ,
+        name: "DeviceInterfaceGUIDs\0",
+        data: "{00000000-0000-0000-0000-000000000000}\0\0",
+    };
+
+    const SET: DescriptorSet = DescriptorSet {
+        windows_version: WINDOWS_8_1,
+        configurations: &[ConfigurationSubset {
+            configuration: 0,
+            functions: &[
+                FunctionSubset {
+                    first_interface: 0,
+                    compatible_id: Some(CompatibleId::WINUSB),
+                    properties: &[GUIDS],
+                },
+                FunctionSubset {
+                    first_interface: 2,
+                    compatible_id: None,
+                    properties: &[GUIDS, GUIDS],
+                },
+            ],
+        }],
+    };
+
+    static RENDERED: [u8; SET.length() as usize] = SET.render();
+
+    /// Splits a descriptor list into (wLength, wDescriptorType, descriptor) triples.
+    fn descriptors(mut set: &[u8]) -> Vec<(usize, u16, &[u8])> {
+        let mut descriptors = Vec::new();
+        while !set.is_empty() {
+            let length = u16::from_le_bytes([set[0], set[1]]) as usize;
+            let kind = u16::from_le_bytes([set[2], set[3]]);
+            descriptors.push((length, kind, &set[..length]));
+            set = &set[length..];
+        }
+        descriptors
+    }
+
+    #[test]
+    fn lengths() {
+        let set = &RENDERED[..];
+        let found = descriptors(set);
+        let kinds: Vec<u16> = found.iter().map(|&(_, kind, _)| kind).collect();
+        assert_eq!(kinds, [0, 1, 2, 3, 4, 2, 4, 4]);
+
+        // The set and each subset cover everything up to the end of what they contain.
+        assert_eq!(u16::from_le_bytes([set[8], set[9]]) as usize, set.len());
+        let subset_length = |descriptor: &[u8]| u16::from_le_bytes([descriptor[6], descriptor[7]]);
+        assert_eq!(subset_length(found[1].2) as usize, set.len() - 10);
+        assert_eq!(subset_length(found[2].2), SET.configurations[0].functions[0].length());
+        assert_eq!(subset_length(found[5].2) as usize, found[5..].iter().map(|d| d.0).sum());
+        assert_eq!(found[5].2[4], 2);
+
+        // The property's name and data lengths
+        let property = found[4].2;
+        assert_eq!(u16::from_le_bytes([property[6], property[7]]), 42);
+        let data = &property[8 + 42..];
+        assert_eq!(u16::from_le_bytes([data[0], data[1]]) as usize, data.len() - 2);
+    }
+}
@@ -0,0 +1,101 @@
+//! Vendor requests
+//!
+//! `WebUsbClass` answers the CDC requests and the host's requests for its WebUSB and Microsoft OS
+//! descriptors. Vendor requests to the interface are for the firmware, so they go to a
+//! `VendorRequests` handler, which has the class to hand for the requests which change how the
+//! port behaves. The bridge's own requests (`tilda_bridge_protocol::request`) are handled by
+//! `bridge::Bridge`; firmware reusing the class can have its own handler, or wrap the bridge's to
+//! add requests or answer some of them differently. The handler is part of the port's type, so
+//! firmware without one pays nothing for it.
+
+use crate::webusb::WebUsbClass;
+use usb_device::class_prelude::*;
+
+/// Handles the vendor requests to the WebUSB interface. By default they're all rejected.
+pub trait VendorRequests<B: UsbBus> {
+    /// Handles an IN (device to host) request, which it must accept or reject.
+    fn control_in(&mut self, _port: &mut WebUsbClass<'_, B>, xfer: ControlIn<B>) {
+        xfer.reject().ok();
+    }
+
+    /// Handles an OUT (host to device) request, returning whether to accept it. The class accepts
+    /// or rejects it, and queues those accepted as `WebUsbEvent::Vendor` events.
+    fn control_out(&mut self, _port: &mut WebUsbClass<'_, B>, _xfer: &ControlOut<B>) -> bool {
+        false
+    }
+
+    /// Whether the host may change DTR or RTS with SET_CONTROL_LINE_STATE, which it always may by
+    /// default. Each change can reset the ESP32, so a handler can rate limit them.
+    fn allow_control_lines(&mut self) -> bool {
+        true
+    }
+
+    /// Called when the bus is reset, after the class has been.
+    fn reset(&mut self) {}
+
+    /// Called whenever the device is polled, after the class, e.g. to send notifications which
+    /// couldn't go straight away.
+    fn poll(&mut self, _port: &mut WebUsbClass<'_, B>) {}
+}
+
+/// Rejects every vendor request.
+#[derive(Default)]
+pub struct NoVendorRequests;
+
+impl<B: UsbBus> VendorRequests<B> for NoVendorRequests {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBus, TransferError};
+    use crate::webusb::{WebUsbBuilder, WebUsbEvent};
+    use usb_device::prelude::*;
+
+    /// Answers request 0x80 with whatever was last written to it.
+    #[derive(Default)]
+    struct Echo {
+        data: Vec<u8>,
+    }
+
+    impl<B: UsbBus> VendorRequests<B> for Echo {
+        fn control_in(&mut self, _port: &mut WebUsbClass<'_, B>, xfer: ControlIn<B>) {
+            if xfer.request().request == 0x80 {
+                xfer.accept_with(&self.data).ok();
+            } else {
+                xfer.reject().ok();
+            }
+        }
+
+        fn control_out(&mut self, _port: &mut WebUsbClass<'_, B>, xfer: &ControlOut<B>) -> bool {
+            if xfer.request().request != 0x80 {
+                return false;
+            }
+            self.data = xfer.data().to_vec();
+            true
+        }
+    }
+
+    #[test]
+    fn vendor_requests() {
+        let bus = MockBus::new();
+        let alloc = UsbBusAllocator::new(bus.clone());
+        let mut webusb = WebUsbBuilder::new(&alloc).vendor_requests(Echo::default()).build();
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd)).build();
+        let mut poll = || {
+            usb_dev.poll(&mut [&mut webusb]);
+        };
+        bus.enumerate(&mut poll).unwrap();
+
+        bus.control_write(&mut poll, 0x41, 0x80, 7, 0, b"hello").unwrap();
+        assert_eq!(bus.control_read(&mut poll, 0xC1, 0x80, 0, 0, 64).unwrap(), b"hello");
+        assert_eq!(bus.control_read(&mut poll, 0xC1, 0x81, 0, 0, 64), Err(TransferError::Stall));
+        // Stalled, so nothing happened
+        assert_eq!(bus.control_write(&mut poll, 0x41, 0x81, 0, 0, &[]), Err(TransferError::Stall));
+
+        // The class still answers its own.
+        assert!(bus.control_read(&mut poll, 0xA1, 0x21, 0, 0, 7).is_ok());
+        assert_eq!(webusb.vendor_requests().data, b"hello");
+        assert_eq!(webusb.poll_event(), Some(WebUsbEvent::Vendor { request: 0x80, value: 7 }));
+        assert_eq!(webusb.poll_event(), None);
+    }
+}
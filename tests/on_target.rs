@@ -0,0 +1,262 @@
+//! Tests which run on the STM32 itself.
+//!
+//! These need a debug probe supported by probe-rs, and [probe-run](https://github.com/knurling-rs/probe-run)
+//! 0.2.x as the runner (the firmware is still on cortex-m-rt 0.6, which limits us to defmt 0.2):
+//!
+//! `cargo test --test on_target --release --features defmt-default --config 'target.thumbv6m-none-eabi.runner="probe-run --chip STM32F042K6Tx"'`
+//!
+//! Note that the boot pin tests drive the ESP32's EN and IO0 lines, so the ESP will be reset, and
+//! the UART loopback test sends a few bytes to it.
+
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+// Built anywhere else, such as by `cargo test --workspace` on the host, there's nothing to run.
+#[cfg(not(target_os = "none"))]
+fn main() {}
+
+#[cfg(target_os = "none")]
+use defmt_rtt as _;
+#[cfg(target_os = "none")]
+use panic_probe as _;
+
+#[cfg(target_os = "none")]
+use stm32f0xx_hal::{
+    gpio::{
+        gpioa::{PA2, PA3},
+        Alternate, Output, Pin, PushPull, AF1,
+    },
+    serial::Serial,
+    stm32::USART2,
+};
+#[cfg(target_os = "none")]
+use cortex_m::peripheral::SYST;
+#[cfg(target_os = "none")]
+use tilda_stm::esp::EspBootControl;
+
+#[cfg(target_os = "none")]
+struct State {
+    boot: EspBootControl<Pin<Output<PushPull>>>,
+    uart: Serial<USART2, PA2<Alternate<AF1>>, PA3<Alternate<AF1>>>,
+    syst: SYST,
+}
+
+/// Waits for the SysTick to wrap, once a millisecond as set up by `init`.
+#[cfg(target_os = "none")]
+fn wait_ms(syst: &mut SYST) {
+    while !syst.has_wrapped() {}
+}
+
+#[cfg(target_os = "none")]
+#[defmt_test::tests]
+mod tests {
+    use super::{wait_ms, State};
+    use cortex_m::peripheral::syst::SystClkSource;
+    use embedded_hal::digital::v2::StatefulOutputPin;
+    use stm32f0xx_hal::{prelude::*, serial::Serial, stm32 as pac};
+    use tilda_stm::{
+        esp::{self, BootState},
+        timing::{Ticks, Timeout},
+        webusb::Buffer,
+    };
+
+    #[init]
+    fn init() -> State {
+        let mut dp = pac::Peripherals::take().unwrap();
+        let mut cp = cortex_m::Peripherals::take().unwrap();
+
+        // Same clock tree as the firmware proper.
+        let mut rcc = dp
+            .RCC
+            .configure()
+            .hsi48()
+            .enable_crs(dp.CRS)
+            .sysclk(48.mhz())
+            .pclk(24.mhz())
+            .freeze(&mut dp.FLASH);
+
+        let gpioa = dp.GPIOA.split(&mut rcc);
+        let (uart_tx, uart_rx, esp_en, esp_gpio0) = cortex_m::interrupt::free(|cs| {
+            (
+                gpioa.pa2.into_alternate_af1(cs),
+                gpioa.pa3.into_alternate_af1(cs),
+                gpioa.pa1.into_push_pull_output(cs).downgrade(),
+                gpioa.pa4.into_push_pull_output(cs).downgrade(),
+            )
+        });
+
+        // A millisecond tick, as the main loop has at full speed.
+        cp.SYST.set_clock_source(SystClkSource::Core);
+        cp.SYST.set_reload(48_000 - 1);
+        cp.SYST.clear_current();
+        cp.SYST.enable_counter();
+
+        State {
+            boot: esp::EspBootControl::new(esp_en, esp_gpio0, esp::CHIP),
+            uart: Serial::usart2(dp.USART2, (uart_tx, uart_rx), 115_200.bps(), &mut rcc),
+            syst: cp.SYST,
+        }
+    }
+
+    #[test]
+    fn hsi48_ready() {
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        defmt::assert!(rcc.cr2.read().hsi48rdy().bit_is_set());
+    }
+
+    #[test]
+    fn uart_baud_rate() {
+        // 24 MHz PCLK / 115200 baud
+        let usart = unsafe { &*pac::USART2::ptr() };
+        defmt::assert_eq!(usart.brr.read().bits(), 208);
+    }
+
+    #[test]
+    fn uart_transmit(state: &mut State) {
+        for &byte in b"TiLDA\r\n" {
+            nb::block!(state.uart.write(byte)).unwrap();
+        }
+
+        // Transmission completes within a few character times at 115200 baud.
+        let mut spins = 0u32;
+        while state.uart.flush().is_err() {
+            spins += 1;
+            defmt::assert!(spins < 1_000_000);
+        }
+    }
+
+    #[test]
+    fn uart_loopback(state: &mut State) {
+        // Half-duplex ties RX to TX inside the USART, so this needs no wiring.
+        let usart = unsafe { &*pac::USART2::ptr() };
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr3.modify(|_, w| w.hdsel().set_bit());
+        usart.icr.write(|w| w.orecf().set_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+
+        for &byte in b"TiLDA" {
+            nb::block!(state.uart.write(byte)).unwrap();
+            let mut spins = 0u32;
+            let received = loop {
+                match state.uart.read() {
+                    Ok(received) => break received,
+                    Err(nb::Error::WouldBlock) => {
+                        spins += 1;
+                        defmt::assert!(spins < 100_000);
+                    }
+                    Err(nb::Error::Other(_)) => defmt::panic!("receive error"),
+                }
+            };
+            defmt::assert_eq!(received, byte);
+        }
+
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr3.modify(|_, w| w.hdsel().clear_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    #[test]
+    fn systick_period(state: &mut State) {
+        // Time ten SysTick wraps on TIM2, prescaled from its 48 MHz clock to 1 MHz.
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+        let tim2 = unsafe { &*pac::TIM2::ptr() };
+        tim2.psc.write(|w| w.psc().bits(47));
+        tim2.arr.write(|w| unsafe { w.bits(u32::MAX) });
+        tim2.egr.write(|w| w.ug().set_bit());
+        tim2.cr1.modify(|_, w| w.cen().set_bit());
+
+        // Clear any stale wrap, then line up with the next one.
+        state.syst.has_wrapped();
+        wait_ms(&mut state.syst);
+        let start = tim2.cnt.read().bits();
+        for _ in 0..10 {
+            wait_ms(&mut state.syst);
+        }
+        let elapsed_us = tim2.cnt.read().bits().wrapping_sub(start);
+        tim2.cr1.modify(|_, w| w.cen().clear_bit());
+
+        defmt::assert!((9_990..=10_010).contains(&elapsed_us), "took {} us", elapsed_us);
+    }
+
+    #[test]
+    fn buffer_discard() {
+        let mut buf = Buffer::new([0u8; 8]);
+
+        defmt::assert_eq!(buf.write(&[1, 2, 3, 4, 5, 6]), 6);
+        buf.read(4, |data| Ok::<_, ()>(data.len())).unwrap();
+
+        // Doesn't fit without moving the unread data back to the start.
+        defmt::assert_eq!(buf.write(&[7, 8, 9, 10, 11, 12]), 6);
+        buf.read(8, |data| {
+            defmt::assert_eq!(data, &[5, 6, 7, 8, 9, 10, 11, 12]);
+            Ok::<_, ()>(data.len())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn boot_pins(state: &mut State) {
+        // (DTR, RTS) -> (EN, IO0)
+        let table = [
+            ((true, true), (true, true)),
+            ((false, false), (true, true)),
+            ((true, false), (false, true)),
+            ((false, true), (true, false)),
+        ];
+
+        for &((dtr, rts), (en, gpio0)) in table.iter() {
+            state.boot.set_lines(dtr, rts);
+            let (esp_en, esp_gpio0) = state.boot.pins();
+            defmt::assert_eq!(esp_en.is_set_high().unwrap(), en);
+            defmt::assert_eq!(esp_gpio0.is_set_high().unwrap(), gpio0);
+            // Edges are paced, so let any hold run out before the next one.
+            state.boot.tick(esp::CHIP.reset_ms + esp::CHIP.strap_hold_ms);
+        }
+
+        // Leave the ESP running.
+        state.boot.set_lines(false, false);
+        state.boot.tick(esp::CHIP.strap_hold_ms);
+    }
+
+    #[test]
+    fn timeout_ticks(state: &mut State) {
+        // There's no host here, so no frames, and the ticks are SysTick's.
+        let mut ticks = Ticks::default();
+        let mut timeout = Timeout::default();
+        timeout.start(5);
+        let mut elapsed_ms = 0;
+        loop {
+            wait_ms(&mut state.syst);
+            elapsed_ms += 1;
+            if timeout.tick(ticks.elapsed(None, 1)) {
+                break;
+            }
+            defmt::assert!(elapsed_ms < 5);
+        }
+        defmt::assert_eq!(elapsed_ms, 5);
+        defmt::assert!(!timeout.active());
+    }
+
+    #[test]
+    fn download_reset_timing(state: &mut State) {
+        // EN is released after `reset_ms`, then IO0 after `strap_hold_ms`.
+        state.boot.reset(true, esp::CHIP.reset_ms);
+        let mut elapsed_ms = 0;
+        while state.boot.state() != BootState::DownloadEntry {
+            wait_ms(&mut state.syst);
+            state.boot.tick(1);
+            elapsed_ms += 1;
+        }
+        defmt::assert_eq!(elapsed_ms, esp::CHIP.reset_ms);
+        let (esp_en, esp_gpio0) = state.boot.pins();
+        defmt::assert!(esp_en.is_set_high().unwrap() && esp_gpio0.is_set_low().unwrap());
+
+        while state.boot.state() != BootState::Running {
+            wait_ms(&mut state.syst);
+            state.boot.tick(1);
+            elapsed_ms += 1;
+        }
+        defmt::assert_eq!(elapsed_ms, esp::CHIP.reset_ms + esp::CHIP.strap_hold_ms);
+    }
+}